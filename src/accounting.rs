@@ -0,0 +1,175 @@
+//! Bandwidth accounting
+//!
+//! `Accounting` tracks bytes downloaded from each upstream host and bytes
+//! uploaded to each target during a run. It is shared by all missions of a
+//! transfer. Only object transfers are counted, while requests made when
+//! taking snapshots are not.
+//!
+//! At the end of a run, the accounting is appended to a report file as a
+//! JSON line. The `accounting` subcommand sums up the report by month.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::Result;
+use crate::utils::{human_size, human_time, unix_time};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct AccountingConfig {
+    #[structopt(long, help = "Accounting report recorded by transfers")]
+    pub report: String,
+    #[structopt(long, help = "Only summarize this month, e.g. 2024-01")]
+    pub month: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Accounting {
+    downloads: Mutex<BTreeMap<String, u64>>,
+    uploads: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Accounting of a single run, as recorded in report.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountingRecord {
+    pub time: String,
+    pub source: String,
+    pub downloads: BTreeMap<String, u64>,
+    pub uploads: BTreeMap<String, u64>,
+}
+
+impl Accounting {
+    /// Record bytes downloaded from `url`, grouped by host.
+    pub fn record_download(&self, url: &str, bytes: u64) {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_else(|| "unknown".to_string());
+        *self.downloads.lock().unwrap().entry(host).or_default() += bytes;
+    }
+
+    /// Record bytes uploaded to `target`.
+    pub fn record_upload(&self, target: &str, bytes: u64) {
+        *self
+            .uploads
+            .lock()
+            .unwrap()
+            .entry(target.to_string())
+            .or_default() += bytes;
+    }
+
+    pub fn record(&self, source: String) -> AccountingRecord {
+        AccountingRecord {
+            time: human_time(unix_time()),
+            source,
+            downloads: self.downloads.lock().unwrap().clone(),
+            uploads: self.uploads.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl AccountingRecord {
+    pub fn total_downloads(&self) -> u64 {
+        self.downloads.values().sum()
+    }
+
+    pub fn total_uploads(&self) -> u64 {
+        self.uploads.values().sum()
+    }
+}
+
+/// Append a record to report.
+pub async fn append_report(path: &str, record: &AccountingRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    let mut report = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    report.write_all(line.as_bytes()).await?;
+    report.flush().await?;
+    Ok(())
+}
+
+/// Sum up records by month (`YYYY-MM`).
+fn monthly_summary(records: &[AccountingRecord]) -> BTreeMap<String, AccountingRecord> {
+    let mut months: BTreeMap<String, AccountingRecord> = BTreeMap::new();
+    for record in records {
+        let month = record.time.get(..7).unwrap_or_default().to_string();
+        let summary = months.entry(month.clone()).or_default();
+        summary.time = month;
+        for (host, bytes) in &record.downloads {
+            *summary.downloads.entry(host.clone()).or_default() += bytes;
+        }
+        for (target, bytes) in &record.uploads {
+            *summary.uploads.entry(target.clone()).or_default() += bytes;
+        }
+    }
+    months
+}
+
+/// Print monthly summary of an accounting report.
+pub async fn summarize(config: AccountingConfig) -> Result<()> {
+    let content = tokio::fs::read_to_string(&config.report).await?;
+    let records = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<std::result::Result<Vec<AccountingRecord>, _>>()?;
+
+    for (month, summary) in monthly_summary(&records) {
+        if let Some(selected) = &config.month {
+            if selected != &month {
+                continue;
+            }
+        }
+        println!(
+            "{}: downloaded {}, uploaded {}",
+            month,
+            human_size(summary.total_downloads()),
+            human_size(summary.total_uploads())
+        );
+        for (host, bytes) in &summary.downloads {
+            println!("  download {:>12}  {}", human_size(*bytes), host);
+        }
+        for (target, bytes) in &summary.uploads {
+            println!("  upload   {:>12}  {}", human_size(*bytes), target);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monthly_summary() {
+        let accounting = Accounting::default();
+        accounting.record_download("https://repo.hex.pm/tarballs/plug-1.0.0.tar", 100);
+        accounting.record_download("https://repo.hex.pm/tarballs/plug-1.1.0.tar", 50);
+        accounting.record_download("https://github.com/a/b/releases/download/c", 10);
+        accounting.record_upload("s3:bucket/hex", 160);
+
+        let mut first = accounting.record("hexpm".to_string());
+        first.time = "2024-01-31T23:00:00Z".to_string();
+        let mut second = first.clone();
+        second.time = "2024-01-01T00:00:00Z".to_string();
+        let mut third = first.clone();
+        third.time = "2024-02-01T00:00:00Z".to_string();
+
+        let months = monthly_summary(&[first, second, third]);
+        assert_eq!(
+            months.keys().collect::<Vec<_>>(),
+            vec!["2024-01", "2024-02"]
+        );
+        assert_eq!(months["2024-01"].downloads["repo.hex.pm"], 300);
+        assert_eq!(months["2024-01"].downloads["github.com"], 20);
+        assert_eq!(months["2024-01"].total_uploads(), 320);
+        assert_eq!(months["2024-02"].total_downloads(), 160);
+    }
+}
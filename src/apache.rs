@@ -0,0 +1,135 @@
+//! Apache source
+//!
+//! Apache source mirrors the Apache dist tree (`downloads.apache.org`)
+//! without rsync access. It reads `zzz/find-ls.gz`, which is a `find -ls`
+//! listing of the whole tree, to get sizes and modification times of all
+//! files. Symbolic links are not mirrored.
+//!
+//! Like GNU source, modification times are truncated to date, as `find -ls`
+//! only shows date of files older than 6 months.
+
+use std::io::Read;
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use flate2::read::GzDecoder;
+use regex::Regex;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{human_size, parse_ls_date};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Apache {
+    #[structopt(
+        long,
+        default_value = "https://downloads.apache.org",
+        help = "Base of Apache dist tree"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "URL of find -ls listing, defaults to {base}/zzz/find-ls.gz"
+    )]
+    pub listing: Option<String>,
+}
+
+/// Parse a `find -ls` listing into snapshot of regular files.
+fn parse_listing(content: &str, today: NaiveDate) -> Vec<SnapshotMeta> {
+    let matcher = Regex::new(
+        r"^\s*\d+\s+\d+\s+-\S+\s+\d+\s+\S+\s+\S+\s+(\d+)\s+(\w{3})\s+(\d{1,2})\s+(\d{1,2}:\d{2}|\d{4})\s(.+)$",
+    )
+    .unwrap();
+    content
+        .lines()
+        .filter_map(|line| {
+            let cap = matcher.captures(line)?;
+            let path = cap[5].trim_start();
+            let key = path.strip_prefix("./").unwrap_or(path);
+            Some(SnapshotMeta {
+                key: key.to_string(),
+                size: cap[1].parse().ok(),
+                last_modified: parse_ls_date(&cap[2], &cap[3], &cap[4], today),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Apache {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let listing = self
+            .listing
+            .clone()
+            .unwrap_or_else(|| format!("{}/zzz/find-ls.gz", self.base));
+        info!(logger, "fetching {}...", listing);
+        progress.set_message("fetching find-ls.gz...");
+        let data = fetch(&client, &listing).await?;
+        let mut buf = vec![];
+        GzDecoder::new(&data[..]).read_to_end(&mut buf)?;
+
+        progress.set_message("parsing find-ls.gz...");
+        let snapshot = parse_listing(&String::from_utf8_lossy(&buf), Utc::now().date_naive());
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("apache, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Apache {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listing() {
+        let listing = "  2752513      4 drwxr-xr-x 220 svnwc    svnwc        4096 Nov 29 10:05 .
+  2752514      4 -rw-r--r--   1 svnwc    svnwc        1263 Mar  9  2021 ./HEADER.html
+  2753041      4 drwxr-xr-x   4 svnwc    svnwc        4096 Nov 27 16:45 ./kafka
+  2753106 105480 -rw-r--r--   1 svnwc    svnwc   108007336 Nov 27 16:44 ./kafka/3.6.1/kafka_2.13-3.6.1.tgz
+  2753107      0 lrwxrwxrwx   1 svnwc    svnwc          10 Oct 10  2023 ./kafka/latest -> 3.6.1
+";
+        let today = NaiveDate::from_ymd_opt(2023, 11, 30).unwrap();
+        let snapshot = parse_listing(listing, today);
+        let keys: Vec<&str> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["HEADER.html", "kafka/3.6.1/kafka_2.13-3.6.1.tgz"]
+        );
+        assert_eq!(snapshot[1].size, Some(108007336));
+        // 2023-11-27
+        assert_eq!(snapshot[1].last_modified, Some(1701043200));
+    }
+}
@@ -0,0 +1,305 @@
+//! APT repository
+//!
+//! `Apt` takes snapshot of suites in an APT repository, which is shared by
+//! sources of Debian-based repositories. For each suite, `Release` lists
+//! components, architectures and index files with sha256 checksums. Binary
+//! packages are read from `Packages` index of every component and
+//! architecture, and yielded with sizes and sha256 checksums. Source
+//! packages are not mirrored.
+//!
+//! Index files are transferred at the end, followed by `Release` files of
+//! the suite, so that clients never see a `Release` referring to missing
+//! indexes.
+
+use std::collections::BTreeSet;
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use regex::Regex;
+use slog::info;
+
+use crate::common::Mission;
+use crate::error::{Error, Result};
+use crate::fetch::{exists, fetch_optional, fetch_text};
+use crate::metadata::SnapshotMeta;
+
+/// Release files of a suite, which are transferred at the end.
+const RELEASE_FILES: &[&str] = &["Release", "Release.gpg", "InRelease"];
+
+/// An APT repository.
+#[derive(Debug, Clone)]
+pub struct Apt {
+    pub base: String,
+    pub suites: Vec<String>,
+    /// components to mirror, all components in `Release` if empty
+    pub components: Vec<String>,
+    /// architectures to mirror, all architectures in `Release` if empty
+    pub architectures: Vec<String>,
+    /// packages with file names matching this pattern are not mirrored
+    pub exclude: Option<Regex>,
+}
+
+/// Fields of a `Release` file.
+#[derive(Debug, Default, PartialEq)]
+struct Release {
+    components: Vec<String>,
+    architectures: Vec<String>,
+    /// (sha256, size, path) of index files
+    files: Vec<(String, u64, String)>,
+}
+
+fn parse_release(content: &str) -> Release {
+    let mut release = Release::default();
+    let mut field = "";
+    for line in content.lines() {
+        if let Some(line) = line.strip_prefix(' ') {
+            if field == "SHA256" {
+                let mut parts = line.split_whitespace();
+                if let (Some(checksum), Some(size), Some(path)) =
+                    (parts.next(), parts.next(), parts.next())
+                {
+                    if let Ok(size) = size.parse() {
+                        release
+                            .files
+                            .push((checksum.to_string(), size, path.to_string()));
+                    }
+                }
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            field = name;
+            let values = || value.split_whitespace().map(|x| x.to_string()).collect();
+            match name {
+                "Components" => release.components = values(),
+                "Architectures" => release.architectures = values(),
+                _ => {}
+            }
+        }
+    }
+    release
+}
+
+/// Parse a `Packages` index of APT or opkg, returning packages with file
+/// name relative to the repository, size and sha256 checksum.
+pub fn parse_packages(content: &str) -> Vec<SnapshotMeta> {
+    let mut packages = vec![];
+    for stanza in content.split("\n\n") {
+        let mut package = SnapshotMeta::default();
+        for line in stanza.lines() {
+            if let Some((field, value)) = line.split_once(':') {
+                let value = value.trim();
+                match field {
+                    "Filename" => package.key = value.to_string(),
+                    "Size" => package.size = value.parse().ok(),
+                    // `SHA256sum` in opkg
+                    "SHA256" | "SHA256sum" => {
+                        package.checksum_method = Some("sha256".to_string());
+                        package.checksum = Some(value.to_string());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if !package.key.is_empty() {
+            packages.push(package);
+        }
+    }
+    packages
+}
+
+impl Apt {
+    /// Fetch `Packages` of a directory, preferring compressed variants
+    /// listed in `Release`. Returns `None` if none of them is present.
+    async fn fetch_packages(
+        &self,
+        mission: &Mission,
+        dist: &str,
+        dir: &str,
+        listed: &BTreeSet<&str>,
+    ) -> Result<Option<String>> {
+        for name in ["Packages.xz", "Packages.gz", "Packages"] {
+            let path = format!("{}/{}", dir, name);
+            if !listed.contains(path.as_str()) {
+                continue;
+            }
+            let url = format!("{}/{}/{}", self.base, dist, path);
+            let data = match fetch_optional(&mission.client, &url).await? {
+                Some(data) => data,
+                None => continue,
+            };
+            let mut content = String::new();
+            match name {
+                "Packages.xz" => {
+                    let mut buf = vec![];
+                    lzma_rs::xz_decompress(&mut Cursor::new(&data[..]), &mut buf).map_err(
+                        |err| Error::ProcessError(format!("invalid {}: {:?}", path, err)),
+                    )?;
+                    content = String::from_utf8_lossy(&buf).into_owned();
+                }
+                "Packages.gz" => {
+                    GzDecoder::new(&data[..]).read_to_string(&mut content)?;
+                }
+                _ => content = String::from_utf8_lossy(&data).into_owned(),
+            }
+            return Ok(Some(content));
+        }
+        Ok(None)
+    }
+
+    /// Take snapshot of all suites.
+    pub async fn snapshot(&self, mission: &Mission) -> Result<Vec<SnapshotMeta>> {
+        let logger = &mission.logger;
+        let progress = &mission.progress;
+        let client = &mission.client;
+
+        let mut keys = BTreeSet::new();
+        let mut snapshot = vec![];
+        let mut metadata = vec![];
+        for suite in &self.suites {
+            let dist = format!("dists/{}", suite);
+            info!(logger, "fetching {}/Release...", dist);
+            progress.set_message(&dist);
+            let release = parse_release(
+                &fetch_text(client, &format!("{}/{}/Release", self.base, dist)).await?,
+            );
+            let components = if self.components.is_empty() {
+                &release.components
+            } else {
+                &self.components
+            };
+            let architectures = if self.architectures.is_empty() {
+                &release.architectures
+            } else {
+                &self.architectures
+            };
+            let listed: BTreeSet<&str> = release
+                .files
+                .iter()
+                .map(|(_, _, path)| path.as_str())
+                .collect();
+
+            let mut dirs = vec![];
+            for component in components {
+                for arch in architectures
+                    .iter()
+                    .map(|arch| arch.as_str())
+                    .chain(["all"])
+                {
+                    let dir = format!("{}/binary-{}", component, arch);
+                    if arch != "source" && !dirs.contains(&dir) {
+                        dirs.push(dir);
+                    }
+                }
+                dirs.push(format!("{}/i18n", component));
+            }
+
+            let packages_before = keys.len();
+            for dir in dirs.iter().filter(|dir| !dir.ends_with("/i18n")) {
+                let content = match self.fetch_packages(mission, &dist, dir, &listed).await? {
+                    Some(content) => content,
+                    None => continue,
+                };
+                // packages of `all` architecture are listed in every architecture
+                snapshot.extend(
+                    parse_packages(&content)
+                        .into_iter()
+                        .filter(|package| match &self.exclude {
+                            Some(exclude) => !exclude.is_match(&package.key),
+                            None => true,
+                        })
+                        .filter(|package| keys.insert(package.key.clone())),
+                );
+            }
+            info!(
+                logger,
+                "{}: {} packages",
+                suite,
+                keys.len() - packages_before
+            );
+
+            // index files are verified with checksums in `Release`, and only
+            // variants present on upstream are mirrored
+            for (checksum, size, path) in &release.files {
+                if !dirs
+                    .iter()
+                    .any(|dir| path.starts_with(&format!("{}/", dir)))
+                {
+                    continue;
+                }
+                let key = format!("{}/{}", dist, path);
+                if exists(client, &format!("{}/{}", self.base, key)).await? {
+                    let mut index = SnapshotMeta::force(key);
+                    index.size = Some(*size);
+                    index.checksum_method = Some("sha256".to_string());
+                    index.checksum = Some(checksum.clone());
+                    metadata.push(index);
+                }
+            }
+            for file in RELEASE_FILES {
+                let key = format!("{}/{}", dist, file);
+                if exists(client, &format!("{}/{}", self.base, key)).await? {
+                    metadata.push(SnapshotMeta::force(key));
+                }
+            }
+        }
+        snapshot.extend(metadata);
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_release() {
+        let release = "Origin: Raspberry Pi Foundation
+Suite: bookworm
+Components: main
+Architectures: armhf arm64
+SHA256:
+ ab01 1234 main/binary-armhf/Packages
+ cd23 567 main/binary-armhf/Packages.gz
+ broken main/binary-arm64/Packages
+";
+        assert_eq!(
+            parse_release(release),
+            Release {
+                components: vec!["main".to_string()],
+                architectures: vec!["armhf".to_string(), "arm64".to_string()],
+                files: vec![
+                    (
+                        "ab01".to_string(),
+                        1234,
+                        "main/binary-armhf/Packages".to_string()
+                    ),
+                    (
+                        "cd23".to_string(),
+                        567,
+                        "main/binary-armhf/Packages.gz".to_string()
+                    ),
+                ],
+            }
+        );
+
+        let packages = "Package: raspi-config
+Version: 20231012~bookworm
+Filename: pool/main/r/raspi-config/raspi-config_20231012~bookworm_all.deb
+Size: 35284
+SHA256: ef45
+Description: Raspberry Pi configuration tool
+ A simple configuration tool for common Raspberry Pi administrative tasks
+
+Package: no-file
+";
+        let snapshot = parse_packages(packages);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot[0].key,
+            "pool/main/r/raspi-config/raspi-config_20231012~bookworm_all.deb"
+        );
+        assert_eq!(snapshot[0].size, Some(35284));
+        assert_eq!(snapshot[0].checksum.as_deref(), Some("ef45"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Bazel Central Registry source
+//!
+//! BCR source mirrors the Bazel Central Registry together with source
+//! archives of modules. The registry is downloaded as a tarball of its GitHub
+//! repository, where every `modules/{module}/{version}/source.json` refers to
+//! a source archive with its SRI integrity. Archives are placed at
+//! `archives/{host}/{path}` of their URLs, and verified with the integrity.
+//!
+//! Files of the registry are transferred from `registry` at the same paths.
+//! Files of module versions never change upstream, and `metadata.json` of
+//! modules and `bazel_registry.json` are transferred at the end. If
+//! `target_mirror` is set, `source.json` should be piped through
+//! `RewritePipe` with `rewrite_source`, so that Bazel downloads archives from
+//! the mirror, with upstream URL as a fallback in `mirror_urls`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct BcrConfig {
+    #[structopt(
+        long,
+        default_value = "https://github.com/bazelbuild/bazel-central-registry/archive/main.tar.gz",
+        help = "Tarball of registry repository"
+    )]
+    pub tarball: String,
+    #[structopt(
+        long,
+        default_value = "https://raw.githubusercontent.com/bazelbuild/bazel-central-registry/main",
+        help = "Base of registry files"
+    )]
+    pub registry: String,
+    #[structopt(long, help = "Mirror URL to rewrite source.json to")]
+    pub target_mirror: Option<String>,
+}
+
+pub struct Bcr {
+    pub config: BcrConfig,
+    /// archive key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+/// Prefix of archive keys in the mirror.
+const ARCHIVES_PREFIX: &str = "archives/";
+
+/// Registry configuration, which is transferred at the end.
+const REGISTRY_KEY: &str = "bazel_registry.json";
+
+#[derive(Deserialize)]
+struct Source {
+    /// absent in sources of other types, e.g. `git_repository`
+    url: Option<String>,
+    integrity: Option<String>,
+}
+
+/// Resolve archive URL to the mirror key.
+fn archive_key(url: &str) -> Result<String> {
+    let parsed = url::Url::parse(url)
+        .map_err(|err| Error::ProcessError(format!("invalid archive URL {}: {:?}", url, err)))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| Error::ProcessError(format!("invalid archive URL {}", url)))?;
+    let path = parsed.path().trim_start_matches('/');
+    if path.is_empty() || path.split('/').any(|part| part == "..") {
+        return Err(Error::ProcessError(format!("invalid archive URL {}", url)));
+    }
+    Ok(format!("{}{}/{}", ARCHIVES_PREFIX, host, path))
+}
+
+/// Convert SRI integrity (e.g. `sha256-{base64}`) to checksum method and
+/// hex digest.
+fn parse_integrity(integrity: &str) -> Option<(String, String)> {
+    let (method, digest) = integrity.split_once('-')?;
+    if method != "sha256" && method != "sha512" {
+        return None;
+    }
+    let digest = base64::decode(digest).ok()?;
+    let digest: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    Some((method.to_string(), digest))
+}
+
+/// Rewrite archive URL in `source.json` to `target_mirror`. Content other
+/// than `source.json` is returned as is.
+pub fn rewrite_source(target_mirror: &str, content: String) -> Result<String> {
+    let mut source: serde_json::Value = match serde_json::from_str(&content) {
+        Ok(source) => source,
+        Err(_) => return Ok(content),
+    };
+    let source_obj = match source.as_object_mut() {
+        Some(source) if source.contains_key("integrity") => source,
+        _ => return Ok(content),
+    };
+    let url = match source_obj.get("url").and_then(|url| url.as_str()) {
+        Some(url) => url.to_string(),
+        None => return Ok(content),
+    };
+    let key = archive_key(&url)?;
+    source_obj.insert(
+        "url".to_string(),
+        serde_json::Value::String(format!("{}/{}", target_mirror, key)),
+    );
+    let mirror_urls = source_obj
+        .entry("mirror_urls")
+        .or_insert_with(|| serde_json::Value::Array(vec![]));
+    if let Some(mirror_urls) = mirror_urls.as_array_mut() {
+        mirror_urls.insert(0, serde_json::Value::String(url));
+    }
+    Ok(serde_json::to_string_pretty(&source)?)
+}
+
+impl Bcr {
+    pub fn new(config: BcrConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Bcr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Bcr {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching registry...");
+        progress.set_message("fetching registry...");
+        let data = fetch(&client, &self.config.tarball).await?;
+
+        info!(logger, "parsing...");
+        let mut files = vec![];
+        let mut metadata = vec![];
+        // path of `source.json` -> content
+        let mut sources = BTreeMap::new();
+        let mut archive = tar::Archive::new(GzDecoder::new(&data[..]));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry.path()?.to_string_lossy().to_string();
+            // strip top-level directory of the tarball, e.g. `bazel-central-registry-main/`
+            let path = match path.split_once('/') {
+                Some((_, path)) => path.to_string(),
+                None => continue,
+            };
+            if path == REGISTRY_KEY {
+                metadata.push(SnapshotMeta::force(path));
+                continue;
+            }
+            let parts: Vec<&str> = path.split('/').collect();
+            match parts[..] {
+                ["modules", _, "metadata.json"] => metadata.push(SnapshotMeta::force(path)),
+                ["modules", _, _, _, ..] => {
+                    if parts.len() == 4 && parts[3] == "source.json" {
+                        let mut content = String::new();
+                        entry.read_to_string(&mut content)?;
+                        sources.insert(path.clone(), content);
+                    }
+                    files.push(SnapshotMeta::new(path));
+                }
+                _ => {}
+            }
+        }
+
+        let mut snapshot = vec![];
+        for (path, content) in sources {
+            let source: Source = match serde_json::from_str(&content) {
+                Ok(source) => source,
+                Err(err) => {
+                    warn!(logger, "invalid {}: {:?}", path, err);
+                    continue;
+                }
+            };
+            let url = match source.url {
+                Some(url) => url,
+                None => continue,
+            };
+            let key = match archive_key(&url) {
+                Ok(key) => key,
+                Err(err) => {
+                    warn!(logger, "failed to resolve {}: {:?}", path, err);
+                    continue;
+                }
+            };
+            if self.urls.contains_key(&key) {
+                continue;
+            }
+            let checksum = source.integrity.as_deref().and_then(parse_integrity);
+            snapshot.push(SnapshotMeta {
+                key: key.clone(),
+                checksum_method: checksum.as_ref().map(|(method, _)| method.clone()),
+                checksum: checksum.map(|(_, digest)| digest),
+                ..Default::default()
+            });
+            self.urls.insert(key, url);
+        }
+        info!(
+            logger,
+            "{} archives, {} registry files",
+            snapshot.len(),
+            files.len() + metadata.len()
+        );
+        snapshot.extend(files);
+        snapshot.extend(metadata);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("bcr, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Bcr {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        if snapshot.key.starts_with(ARCHIVES_PREFIX) {
+            return self
+                .urls
+                .get(&snapshot.key)
+                .map(|url| TransferURL(url.clone()))
+                .ok_or_else(|| Error::ProcessError(format!("unknown key {}", snapshot.key)));
+        }
+        Ok(TransferURL(format!(
+            "{}/{}",
+            self.config.registry, snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_source() {
+        let source = r#"{
+    "url": "https://github.com/madler/zlib/releases/download/v1.3/zlib-1.3.tar.gz",
+    "integrity": "sha256-/wukwpIBPbwnUws6geH5qBPNOd4Byl4Pi/NVcC76WT4=",
+    "strip_prefix": "zlib-1.3"
+}"#;
+        assert_eq!(
+            parse_integrity("sha256-/wukwpIBPbwnUws6geH5qBPNOd4Byl4Pi/NVcC76WT4="),
+            Some((
+                "sha256".to_string(),
+                "ff0ba4c292013dbc27530b3a81e1f9a813cd39de01ca5e0f8bf355702efa593e".to_string()
+            ))
+        );
+        assert_eq!(parse_integrity("md5-AAAA"), None);
+
+        let rewritten =
+            rewrite_source("https://mirror.example.com/bcr", source.to_string()).unwrap();
+        let rewritten: serde_json::Value = serde_json::from_str(&rewritten).unwrap();
+        assert_eq!(
+            rewritten["url"],
+            "https://mirror.example.com/bcr/archives/github.com/madler/zlib/releases/download/v1.3/zlib-1.3.tar.gz"
+        );
+        assert_eq!(
+            rewritten["mirror_urls"][0],
+            "https://github.com/madler/zlib/releases/download/v1.3/zlib-1.3.tar.gz"
+        );
+        assert_eq!(rewritten["strip_prefix"], "zlib-1.3");
+
+        let metadata = r#"{"versions": ["1.3"]}"#;
+        assert_eq!(
+            rewrite_source("https://mirror.example.com/bcr", metadata.to_string()).unwrap(),
+            metadata
+        );
+    }
+}
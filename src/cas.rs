@@ -0,0 +1,301 @@
+//! Content-addressed storage backend
+//!
+//! CAS backend is a target storage on local file system, which stores body
+//! of every object only once, under its SHA-256 checksum at
+//! `{cas_path}/{xx}/{sha256}`. The key space in `base_path` is materialized
+//! as hard links (or symbolic links) to the store, so that identical
+//! artifacts (e.g. rust nightlies and conda packages published under
+//! several names) take space only once.
+//!
+//! Snapshot of this backend only has path and size, as modified time is
+//! shared by all keys linked to the same content. Space saved by the store
+//! is reported when taking snapshot, and space saved in a run is reported
+//! by `report` after transfer.
+//!
+//! Content no longer linked from the key space is left in the store, and
+//! is removed when taking snapshot if `gc` is enabled.
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use slog::{debug, info, Logger};
+use walkdir::WalkDir;
+
+use crate::checksum_pipe::calc_checksum;
+use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::ByteStream;
+use crate::traits::{Key, SnapshotStorage, TargetStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    Hardlink,
+    Symlink,
+}
+
+impl std::str::FromStr for LinkMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hardlink" => Ok(Self::Hardlink),
+            "symlink" => Ok(Self::Symlink),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported link mode {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CasConfig {
+    pub base_path: String,
+    pub cas_path: String,
+    pub link: LinkMode,
+    pub gc: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CasBackend {
+    config: CasConfig,
+    /// bytes not written to store in this run, as the content exists
+    saved: Arc<AtomicU64>,
+}
+
+fn cas_key(sha256: &str) -> String {
+    format!("{}/{}", &sha256[..2], sha256)
+}
+
+impl CasBackend {
+    pub fn new(config: CasConfig) -> Self {
+        Self {
+            config,
+            saved: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Report space saved in this run.
+    pub fn report(&self, logger: &Logger) {
+        info!(
+            logger,
+            "content-addressed store saved {} in this run",
+            human_size(self.saved.load(Ordering::Relaxed))
+        );
+    }
+
+    /// Scan key space, returning snapshot, and paths in store which are
+    /// still linked.
+    fn scan(&self, logger: &Logger) -> Result<(Vec<SnapshotMeta>, HashSet<PathBuf>)> {
+        let base_path = PathBuf::from(&self.config.base_path).canonicalize()?;
+        let cas_path = PathBuf::from(&self.config.cas_path).canonicalize()?;
+        let mut snapshot = vec![];
+        let mut linked = HashSet::new();
+        // (dev, ino) -> size, to find out space actually taken
+        let mut inodes = HashMap::new();
+        let mut logical_size = 0;
+
+        let walker = WalkDir::new(&base_path)
+            .into_iter()
+            .filter_entry(|entry| entry.path() != cas_path);
+        for entry in walker {
+            let entry = entry.map_err(|err| {
+                Error::StorageError(format!("error while scanning file: {:?}", err))
+            })?;
+            let path = entry.path();
+            // follow symbolic links into store
+            let metadata = match std::fs::metadata(path) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            if entry.path_is_symlink() {
+                linked.insert(std::fs::canonicalize(path)?);
+            }
+            logical_size += metadata.len();
+            inodes.insert((metadata.dev(), metadata.ino()), metadata.len());
+            snapshot.push(SnapshotMeta {
+                key: path
+                    .strip_prefix(&base_path)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+                size: Some(metadata.len()),
+                ..Default::default()
+            });
+        }
+
+        let physical_size: u64 = inodes.values().sum();
+        info!(
+            logger,
+            "{} files ({}), taking {} in store",
+            snapshot.len(),
+            human_size(logical_size),
+            human_size(physical_size)
+        );
+        Ok((snapshot, linked))
+    }
+
+    /// Remove content in store not linked from key space.
+    fn gc(&self, linked: &HashSet<PathBuf>, logger: &Logger) -> Result<()> {
+        let mut removed = 0;
+        let mut removed_size = 0;
+        for entry in WalkDir::new(&self.config.cas_path) {
+            let entry = entry.map_err(|err| {
+                Error::StorageError(format!("error while scanning store: {:?}", err))
+            })?;
+            let metadata = entry
+                .metadata()
+                .map_err(|err| Error::StorageError(format!("failed to get metadata {:?}", err)))?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let unused = match self.config.link {
+                LinkMode::Hardlink => metadata.nlink() <= 1,
+                LinkMode::Symlink => !linked.contains(&entry.path().canonicalize()?),
+            };
+            if unused {
+                debug!(logger, "gc: {:?}", entry.path());
+                std::fs::remove_file(entry.path())?;
+                removed += 1;
+                removed_size += metadata.len();
+            }
+        }
+        info!(
+            logger,
+            "gc: removed {} objects ({}) from store",
+            removed,
+            human_size(removed_size)
+        );
+        Ok(())
+    }
+
+    /// Link `key` in key space to `content` in store, replacing the old one.
+    async fn link(&self, key: &str, content: &Path) -> Result<()> {
+        let target = PathBuf::from(format!("{}/{}", self.config.base_path, key));
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+        match tokio::fs::remove_file(&target).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        match self.config.link {
+            LinkMode::Hardlink => tokio::fs::hard_link(content, &target).await?,
+            LinkMode::Symlink => {
+                tokio::fs::symlink(tokio::fs::canonicalize(content).await?, &target).await?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for CasBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        info!(logger, "scanning content-addressed storage...");
+        std::fs::create_dir_all(&self.config.base_path)?;
+        std::fs::create_dir_all(&self.config.cas_path)?;
+
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let (snapshot, linked) = this.scan(&logger)?;
+            if this.config.gc {
+                this.gc(&linked, &logger)?;
+            }
+            progress.finish_with_message("done");
+            Ok::<_, Error>(snapshot)
+        })
+        .await
+        .map_err(|err| Error::ProcessError(format!("error while scanning: {:?}", err)))?
+    }
+
+    fn info(&self) -> String {
+        format!("cas (meta), {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for CasBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        Ok(
+            <Self as SnapshotStorage<SnapshotMeta>>::snapshot(self, mission, config)
+                .await?
+                .into_iter()
+                .map(|x| SnapshotPath::new(x.key))
+                .collect(),
+        )
+    }
+
+    fn info(&self) -> String {
+        format!("cas (path), {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl<Snapshot: Key> TargetStorage<Snapshot, ByteStream> for CasBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        byte_stream: ByteStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        let length = byte_stream.length;
+        let path = byte_stream.object.use_file()?;
+        let result = async {
+            let mut file = tokio::fs::File::open(&path).await?;
+            let sha256 = calc_checksum(&mut file, "sha256").await?;
+            drop(file);
+
+            let content = PathBuf::from(format!("{}/{}", self.config.cas_path, cas_key(&sha256)));
+            if tokio::fs::metadata(&content).await.is_ok() {
+                debug!(mission.logger, "dedup: {} -> {}", snapshot.key(), sha256);
+                self.saved.fetch_add(length, Ordering::Relaxed);
+            } else {
+                tokio::fs::create_dir_all(content.parent().unwrap()).await?;
+                tokio::fs::rename(&path, &content).await?;
+                mission
+                    .accounting
+                    .record_upload(&format!("cas:{}", self.config.cas_path), length);
+            }
+            self.link(snapshot.key(), &content).await
+        }
+        .await;
+        // buffer file is left if the content exists or on errors
+        tokio::fs::remove_file(&path).await.ok();
+        result
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+        let target = format!("{}/{}", self.config.base_path, snapshot.key());
+        tokio::fs::remove_file(target).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cas_key() {
+        assert_eq!(
+            cas_key("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            "e3/e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
@@ -3,18 +3,28 @@
 //! A `ChecksumPipe` is a wrapper on source storages which yields `ByteStream`.
 //! It reads the snapshot checksum meta, and calculates the corresponding checksum of `ByteStream`.
 //! In case of a checksum mismatch, the pipe yields an `ChecksumError`.
-
-use std::io::{Error as IOError, ErrorKind, Result as IOResult, SeekFrom};
+//! Streamed objects are verified while being read. Their last chunk is only
+//! yielded after the checksum matches, and their streams fail instead on
+//! mismatch, so that target doesn't keep them.
+//!
+//! `AugmentChecksumPipe` is for sources without checksums (e.g. rsync). It
+//! computes SHA-256 of buffered objects, which is attached to `ByteStream`
+//! and stored by targets, so that later runs could compare by checksum.
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use sha2::digest::DynDigest;
 use sha2::Digest;
+use slog::debug;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult, SeekFrom};
 use tokio::fs::File;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
 use tokio_io_compat::CompatHelperTrait;
 
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
-use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::stream_pipe::{ByteBody, ByteObject, ByteStream};
 use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
 
 async fn sha256(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
@@ -23,6 +33,24 @@ async fn sha256(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
+async fn sha512(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
+    let mut hasher = sha2::Sha512::new();
+    tokio::io::copy(source, &mut hasher.tokio_io_mut()).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn blake2b(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
+    let mut hasher = blake2::Blake2b::new();
+    tokio::io::copy(source, &mut hasher.tokio_io_mut()).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn md5(source: &mut (impl AsyncRead + Unpin)) -> IOResult<String> {
+    let mut hasher = md5::Md5::new();
+    tokio::io::copy(source, &mut hasher.tokio_io_mut()).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 pub async fn calc_checksum(
     source: &mut (impl AsyncRead + AsyncSeek + Unpin),
     method: &str,
@@ -31,6 +59,9 @@ pub async fn calc_checksum(
 
     let result = match method {
         "sha256" => sha256(source).await,
+        "sha512" => sha512(source).await,
+        "blake2b" => blake2b(source).await,
+        "md5" => md5(source).await,
         _ => Err(IOError::new(
             ErrorKind::Unsupported,
             "unsupported checksum method",
@@ -41,6 +72,60 @@ pub async fn calc_checksum(
     result
 }
 
+/// Verify checksum of `body` while it is read.
+///
+/// The last chunk is held back until the whole body is hashed, so that
+/// target never receives a complete object on mismatch.
+fn verify_stream(body: ByteBody, method: &str, expected: String) -> IOResult<ByteBody> {
+    let hasher: Box<dyn DynDigest + Send> = match method {
+        "sha256" => Box::new(sha2::Sha256::new()),
+        "sha512" => Box::new(sha2::Sha512::new()),
+        "blake2b" => Box::new(blake2::Blake2b::new()),
+        "md5" => Box::new(md5::Md5::new()),
+        _ => {
+            return Err(IOError::new(
+                ErrorKind::Unsupported,
+                "unsupported checksum method",
+            ))
+        }
+    };
+    let method = method.to_string();
+    let state = Some((body, hasher, None::<Bytes>, method, expected));
+    let body = stream::unfold(state, |state| async move {
+        let (mut body, mut hasher, mut held, method, expected) = state?;
+        loop {
+            match body.next().await {
+                Some(Ok(bytes)) => {
+                    hasher.update(&bytes);
+                    if let Some(prev) = held.replace(bytes) {
+                        return Some((Ok(prev), Some((body, hasher, held, method, expected))));
+                    }
+                }
+                Some(Err(err)) => return Some((Err(err), None)),
+                None => {
+                    let got: String = hasher
+                        .finalize()
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect();
+                    if got != expected {
+                        let err = IOError::new(
+                            ErrorKind::InvalidData,
+                            format!(
+                                "checksum mismatch ({}). Expect {}, got {}",
+                                method, expected, got
+                            ),
+                        );
+                        return Some((Err(err), None));
+                    }
+                    return held.map(|bytes| (Ok(bytes), None));
+                }
+            }
+        }
+    });
+    Ok(ByteBody::new(body))
+}
+
 pub struct ChecksumPipe<Source> {
     pub source: Source,
 }
@@ -65,6 +150,10 @@ where
         self.source.snapshot(mission, config).await
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!("ChecksumPipe <{}>", self.source.info())
     }
@@ -93,15 +182,23 @@ where
                 ByteObject::LocalFile {
                     file: None,
                     path: None,
-                } => {
+                }
+                | ByteObject::Stream(None) => {
                     return Err(Error::IoError(IOError::new(
                         ErrorKind::NotFound,
                         "data missing",
                     )));
                 }
+                ByteObject::Stream(body) => {
+                    let verified =
+                        verify_stream(body.take().unwrap(), method, expected_chksum.to_string())?;
+                    *body = Some(verified);
+                    return Ok(source);
+                }
             };
 
             if expected_chksum != got_chksum.as_str() {
+                source.object.discard(&mission.logger, snapshot.key());
                 return Err(Error::ChecksumError {
                     method: method.to_string(),
                     expected: expected_chksum.to_string(),
@@ -112,3 +209,108 @@ where
         Ok(source)
     }
 }
+
+pub struct AugmentChecksumPipe<Source> {
+    pub source: Source,
+}
+
+impl<Source> AugmentChecksumPipe<Source> {
+    pub fn new(source: Source) -> Self {
+        AugmentChecksumPipe { source }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for AugmentChecksumPipe<Source>
+where
+    Snapshot: Send + 'static,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("AugmentChecksumPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for AugmentChecksumPipe<Source>
+where
+    Snapshot: Key + Metadata,
+    Source: SourceStorage<Snapshot, ByteStream>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
+        let mut source = self.source.get_object(snapshot, mission).await?;
+        if snapshot.checksum().is_some() || source.checksum.is_some() {
+            return Ok(source);
+        }
+        let checksum = match &mut source.object {
+            ByteObject::LocalFile { file: Some(f), .. } => calc_checksum(f, "sha256").await?,
+            ByteObject::LocalFile {
+                file: None,
+                path: Some(path),
+            } => calc_checksum(&mut File::open(path).await?, "sha256").await?,
+            ByteObject::LocalFile {
+                file: None,
+                path: None,
+            } => {
+                return Err(Error::IoError(IOError::new(
+                    ErrorKind::NotFound,
+                    "data missing",
+                )));
+            }
+            // metadata is sent before content, so streamed objects can't have
+            // checksum computed
+            ByteObject::Stream(_) => {
+                debug!(mission.logger, "no checksum of streamed {}", snapshot.key());
+                return Ok(source);
+            }
+        };
+        source.checksum = Some(("sha256".to_string(), checksum));
+        Ok(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect(body: ByteBody) -> Vec<IOResult<Bytes>> {
+        body.collect().await
+    }
+
+    fn body(chunks: &[&'static str]) -> ByteBody {
+        let chunks: Vec<IOResult<Bytes>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+            .collect();
+        ByteBody::new(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_verify_stream() {
+        // md5 of "hello world"
+        let expected = "5eb63bbbe01eeed093cb22bb8f5acdc3".to_string();
+        let chunks =
+            collect(verify_stream(body(&["hello", " world"]), "md5", expected).unwrap()).await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.is_ok()));
+
+        let expected = "0".repeat(32);
+        let chunks =
+            collect(verify_stream(body(&["hello", " world"]), "md5", expected).unwrap()).await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap().as_ref(), b"hello");
+        assert!(chunks[1].is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Chocolatey source
+//!
+//! Chocolatey source pages through the OData (NuGet v2) feed of a Chocolatey
+//! repository, e.g. the community repository, and mirrors packages matching
+//! `filter` (latest versions by default). Each feed entry links to its
+//! `.nupkg`, with size and package hash (base64 SHA512).
+//!
+//! Packages are placed in the layout of NuGet flat container, i.e.
+//! `{id}/{version}/{id}.{version}.nupkg` with lowercase id and version.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ChocolateyConfig {
+    #[structopt(
+        long,
+        default_value = "https://community.chocolatey.org/api/v2",
+        help = "Base of OData feed"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        default_value = "IsLatestVersion",
+        help = "OData filter of packages to mirror"
+    )]
+    pub filter: String,
+    #[structopt(long, help = "Stop after this number of feed pages")]
+    pub max_pages: Option<usize>,
+}
+
+pub struct Chocolatey {
+    pub config: ChocolateyConfig,
+    /// key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+/// A package entry in the feed.
+#[derive(Debug, PartialEq)]
+struct Package {
+    id: String,
+    version: String,
+    url: String,
+    size: Option<u64>,
+    /// hex SHA512
+    sha512: Option<String>,
+}
+
+impl Package {
+    fn key(&self) -> String {
+        let id = self.id.to_lowercase();
+        let version = self.version.to_lowercase();
+        format!("{}/{}/{}.{}.nupkg", id, version, id, version)
+    }
+}
+
+/// Parse a page of the feed, returning packages and URL of the next page.
+fn parse_feed(content: &str) -> Result<(Vec<Package>, Option<String>)> {
+    let document = roxmltree::Document::parse(content)
+        .map_err(|err| Error::ProcessError(format!("invalid feed: {:?}", err)))?;
+    let feed = document.root_element();
+    let next = feed
+        .children()
+        .find(|node| node.has_tag_name("link") && node.attribute("rel") == Some("next"))
+        .and_then(|node| node.attribute("href"))
+        .map(|href| href.to_string());
+
+    let mut packages = vec![];
+    for entry in feed.children().filter(|node| node.has_tag_name("entry")) {
+        let property = |name: &str| {
+            entry
+                .descendants()
+                .find(|node| node.has_tag_name(name))
+                .and_then(|node| node.text())
+                .map(|text| text.trim().to_string())
+        };
+        let url = entry
+            .children()
+            .find(|node| node.has_tag_name("content"))
+            .and_then(|node| node.attribute("src"));
+        let (id, version, url) = match (property("Id"), property("Version"), url) {
+            (Some(id), Some(version), Some(url)) => (id, version, url.to_string()),
+            _ => continue,
+        };
+        let sha512 = match property("PackageHashAlgorithm").as_deref() {
+            Some("SHA512") => property("PackageHash")
+                .and_then(|hash| base64::decode(hash).ok())
+                .map(|hash| hash.iter().map(|b| format!("{:02x}", b)).collect()),
+            _ => None,
+        };
+        packages.push(Package {
+            id,
+            version,
+            url,
+            size: property("PackageSize").and_then(|size| size.parse().ok()),
+            sha512,
+        });
+    }
+    Ok((packages, next))
+}
+
+impl Chocolatey {
+    pub fn new(config: ChocolateyConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Chocolatey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Chocolatey {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut snapshot = vec![];
+        let mut next = Some(format!(
+            "{}/Packages()?$filter={}",
+            self.config.base,
+            urlencoding::encode(&self.config.filter)
+        ));
+        let mut pages = 0;
+        while let Some(url) = next {
+            if let Some(max_pages) = self.config.max_pages {
+                if pages >= max_pages {
+                    warn!(logger, "stopped after {} pages", pages);
+                    break;
+                }
+            }
+            progress.set_message(&format!("page {}, {} packages", pages, snapshot.len()));
+            let (packages, next_page) = parse_feed(&fetch_text(&client, &url).await?)?;
+            pages += 1;
+            next = next_page;
+            for package in packages {
+                let key = package.key();
+                if self.urls.contains_key(&key) {
+                    continue;
+                }
+                snapshot.push(SnapshotMeta {
+                    key: key.clone(),
+                    size: package.size,
+                    checksum_method: package.sha512.as_ref().map(|_| "sha512".to_string()),
+                    checksum: package.sha512,
+                    ..Default::default()
+                });
+                self.urls.insert(key, package.url);
+            }
+        }
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} packages in {} pages ({})",
+            snapshot.len(),
+            pages,
+            human_size(total_size)
+        );
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("chocolatey, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Chocolatey {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        self.urls
+            .get(&snapshot.key)
+            .map(|url| TransferURL(url.clone()))
+            .ok_or_else(|| Error::ProcessError(format!("unknown key {}", snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_feed() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xml:base="https://community.chocolatey.org/api/v2/" xmlns="http://www.w3.org/2005/Atom" xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices" xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+  <title type="text">Packages</title>
+  <entry>
+    <id>https://community.chocolatey.org/api/v2/Packages(Id='7zip',Version='23.1.0')</id>
+    <title type="text">7zip</title>
+    <content type="application/zip" src="https://community.chocolatey.org/api/v2/package/7zip/23.1.0" />
+    <m:properties>
+      <d:Id>7Zip</d:Id>
+      <d:Version>23.1.0</d:Version>
+      <d:PackageHash>AAEC</d:PackageHash>
+      <d:PackageHashAlgorithm>SHA512</d:PackageHashAlgorithm>
+      <d:PackageSize m:type="Edm.Int64">6504</d:PackageSize>
+    </m:properties>
+  </entry>
+  <link rel="next" href="https://community.chocolatey.org/api/v2/Packages()?$filter=IsLatestVersion&amp;$skip=40" />
+</feed>"#;
+        let (packages, next) = parse_feed(feed).unwrap();
+        assert_eq!(
+            packages,
+            vec![Package {
+                id: "7Zip".to_string(),
+                version: "23.1.0".to_string(),
+                url: "https://community.chocolatey.org/api/v2/package/7zip/23.1.0".to_string(),
+                size: Some(6504),
+                sha512: Some("000102".to_string()),
+            }]
+        );
+        assert_eq!(packages[0].key(), "7zip/23.1.0/7zip.23.1.0.nupkg");
+        assert_eq!(
+            next.as_deref(),
+            Some("https://community.chocolatey.org/api/v2/Packages()?$filter=IsLatestVersion&$skip=40")
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Circuit breaker
+//!
+//! Upstreams sometimes block mirrors for a while (e.g. responding 403 or 429
+//! to all requests). Instead of requesting every object from such host in
+//! vain, `CircuitBreaker` counts consecutive hard failures of each host.
+//! After `threshold` of them, the circuit of the host is opened, and requests
+//! to it fail immediately with `Error::CircuitOpen` for `cooldown`. After that, one request is let through: the circuit is
+//! closed if it succeeds, and opened again otherwise.
+//!
+//! Objects failing with `Error::CircuitOpen` are counted as skipped instead
+//! of failed by transfer.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+
+use crate::error::{Error, Result};
+
+#[derive(Default)]
+struct HostState {
+    /// consecutive hard failures
+    failures: usize,
+    open_until: Option<Instant>,
+}
+
+pub struct CircuitBreaker {
+    /// consecutive hard failures to open circuit of a host, 0 to disable
+    threshold: usize,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+fn host_of(url: &str) -> String {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_else(|| url.to_string())
+}
+
+/// Whether `err` suggests that the host refuses to serve us, rather than a
+/// problem of a single object. Missing objects (404 and 410) are expected
+/// when upstream removes them, so they are not counted.
+fn is_hard_failure(err: &Error) -> bool {
+    match err {
+        Error::HTTPError(status) => {
+            matches!(
+                *status,
+                StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+            ) || status.is_server_error()
+        }
+        Error::Reqwest(err) => err.is_connect() || err.is_timeout(),
+        Error::RetryAfter(_, _) | Error::TimeoutError(_) => true,
+        _ => false,
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether requests to host of `url` are allowed.
+    pub fn check(&self, url: &str) -> Result<()> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        let host = host_of(url);
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(state) = hosts.get_mut(&host) {
+            if let Some(open_until) = state.open_until {
+                let now = Instant::now();
+                if now < open_until {
+                    return Err(Error::CircuitOpen(host));
+                }
+                // let this request through, and keep others paused until it completes
+                state.open_until = Some(now + self.cooldown);
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a successful request to host of `url`, which closes its circuit.
+    pub fn success(&self, url: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        self.hosts.lock().unwrap().remove(&host_of(url));
+    }
+
+    /// Record a failed request to host of `url`. Returns true if the circuit
+    /// is opened by this failure.
+    pub fn failure(&self, url: &str, err: &Error) -> bool {
+        if self.threshold == 0 || !is_hard_failure(err) {
+            return false;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host_of(url)).or_default();
+        state.failures += 1;
+        if state.failures == self.threshold {
+            state.open_until = Some(Instant::now() + self.cooldown);
+            return true;
+        }
+        if state.failures > self.threshold {
+            // probe after cooldown failed
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+        false
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(100));
+        let url = "https://example.com/a";
+        let forbidden = Error::HTTPError(StatusCode::FORBIDDEN);
+
+        assert!(!breaker.failure(url, &forbidden));
+        breaker.success(url);
+        assert!(!breaker.failure(url, &forbidden));
+        // other errors are not counted
+        assert!(!breaker.failure(url, &Error::NoneError));
+        assert!(!breaker.failure(url, &Error::HTTPError(StatusCode::NOT_FOUND)));
+        assert!(!breaker.failure(url, &Error::HTTPError(StatusCode::GONE)));
+        assert!(breaker.failure(url, &forbidden));
+        assert!(matches!(
+            breaker.check("https://example.com/b"),
+            Err(Error::CircuitOpen(_))
+        ));
+        assert!(breaker.check("https://example.org/b").is_ok());
+
+        // after cooldown, only one request is let through
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(breaker.check(url).is_ok());
+        assert!(breaker.check(url).is_err());
+        breaker.success(url);
+        assert!(breaker.check(url).is_ok());
+    }
+}
@@ -1,12 +1,29 @@
+use std::sync::Arc;
+
 use indicatif::ProgressBar;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use slog::Logger;
 
+use crate::accounting::Accounting;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::stream_pipe::RangeConfig;
+use crate::throttle::Throttle;
+use crate::validator_cache::ValidatorScope;
+
 #[derive(Clone)]
 pub struct Mission {
     pub progress: ProgressBar,
     pub client: Client,
     pub logger: Logger,
+    pub accounting: Arc<Accounting>,
+    pub breaker: Arc<CircuitBreaker>,
+    pub throttle: Arc<Throttle>,
+    /// objects not smaller than this are streamed without buffering
+    pub direct_stream: Option<u64>,
+    pub range: RangeConfig,
+    /// validators of previous download, for conditional requests
+    pub validators: Option<ValidatorScope>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -14,7 +31,7 @@ pub struct SnapshotConfig {
     pub concurrent_resolve: usize,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct SnapshotPath(pub String, pub bool);
 
 impl SnapshotPath {
@@ -29,3 +46,7 @@ impl SnapshotPath {
 
 #[derive(Debug)]
 pub struct TransferURL(pub String);
+
+/// Key of object, for targets which fetch objects by themselves.
+#[derive(Debug)]
+pub struct TransferPath(pub String);
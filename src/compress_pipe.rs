@@ -0,0 +1,368 @@
+//! CompressPipe generates compressed variants of source items.
+//!
+//! For every item whose key matches `pattern`, a `CompressPipe` adds
+//! `{key}.gz`, `{key}.zst` or `{key}.bz2` to snapshot, replacing the ones
+//! from source if any. Content of variants is compressed from the original
+//! content yielded by source, so that compressed and uncompressed metadata
+//! (e.g. rewritten `repodata.json` and `repodata.json.bz2`) are consistent.
+//!
+//! Variants have the same modified time as the original, so they're updated
+//! along with it. The original is fetched from source once, and variants are
+//! compressed from the same buffer file. The original and variants not yet
+//! requested are kept in buffer, so this pipe should only be used for a few
+//! metadata files.
+
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use regex::Regex;
+use slog::debug;
+use tokio::sync::Mutex;
+
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::{hash_string, unix_time};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+            Self::Bzip2 => "bz2",
+        }
+    }
+
+    /// Compress file at `input` to `output`.
+    fn compress(&self, input: &Path, output: &Path) -> std::io::Result<()> {
+        let mut reader = BufReader::new(std::fs::File::open(input)?);
+        let writer = BufWriter::new(std::fs::File::create(output)?);
+        let mut writer = match self {
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(writer, Default::default());
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?
+            }
+            Self::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, 0)?;
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?
+            }
+            Self::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(writer, Default::default());
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?
+            }
+        };
+        writer.flush()
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gz" => Ok(Self::Gzip),
+            "zst" => Ok(Self::Zstd),
+            "bz2" => Ok(Self::Bzip2),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported compression {}",
+                s
+            ))),
+        }
+    }
+}
+
+pub struct CompressPipe<Source> {
+    pub source: Source,
+    pub buffer_path: String,
+    pub pattern: Regex,
+    pub formats: Vec<Compression>,
+    /// key of variant -> original item and compression
+    variants: HashMap<String, (SnapshotMeta, Compression)>,
+    /// key of original -> original and variants fetched but not yet requested
+    buffered: HashMap<String, Mutex<HashMap<String, ByteStream>>>,
+}
+
+impl<Source> CompressPipe<Source> {
+    pub fn new(
+        source: Source,
+        buffer_path: String,
+        pattern: Regex,
+        formats: Vec<Compression>,
+    ) -> Self {
+        Self {
+            source,
+            buffer_path,
+            pattern,
+            formats,
+            variants: HashMap::new(),
+            buffered: HashMap::new(),
+        }
+    }
+}
+
+impl<Source> CompressPipe<Source>
+where
+    Source: SourceStorage<SnapshotMeta, ByteStream>,
+{
+    /// Fetch `original` and compress it to all formats, returning objects of
+    /// the original and its variants.
+    async fn prepare(
+        &self,
+        original: &SnapshotMeta,
+        mission: &Mission,
+    ) -> Result<HashMap<String, ByteStream>> {
+        let mut byte_stream = self.source.get_object(original, mission).await?;
+        byte_stream.object = byte_stream
+            .object
+            .into_local(&self.buffer_path, &mission.logger)
+            .await?;
+        let input = match &byte_stream.object {
+            ByteObject::LocalFile {
+                path: Some(path), ..
+            } => path.clone(),
+            _ => unreachable!(),
+        };
+        let mut objects = HashMap::new();
+        for format in &self.formats {
+            let key = format!("{}.{}", original.key(), format.extension());
+            debug!(mission.logger, "compress: {} -> {}", original.key(), key);
+            match self
+                .compress(&input, &key, *format, byte_stream.modified_at, mission)
+                .await
+            {
+                Ok(object) => {
+                    objects.insert(key, object);
+                }
+                Err(err) => {
+                    for (key, object) in objects {
+                        object.object.discard(&mission.logger, &key);
+                    }
+                    byte_stream.object.discard(&mission.logger, original.key());
+                    return Err(err);
+                }
+            }
+        }
+        objects.insert(original.key().to_string(), byte_stream);
+        Ok(objects)
+    }
+
+    /// Compress file at `input` to a buffer file of `key`.
+    async fn compress(
+        &self,
+        input: &Path,
+        key: &str,
+        format: Compression,
+        modified_at: u64,
+        mission: &Mission,
+    ) -> Result<ByteStream> {
+        let output: PathBuf = format!(
+            "{}/{}.{}.buffer",
+            self.buffer_path,
+            hash_string(key),
+            unix_time()
+        )
+        .into();
+
+        // the buffer file is removed on errors
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(output.clone()),
+        };
+        let compressed: Result<(tokio::fs::File, u64)> = async {
+            let (input, buffer) = (input.to_path_buf(), output.clone());
+            tokio::task::spawn_blocking(move || format.compress(&input, &buffer))
+                .await
+                .map_err(|err| {
+                    Error::ProcessError(format!("error while compressing: {:?}", err))
+                })??;
+            let file = tokio::fs::File::open(&output).await?;
+            let length = file.metadata().await?.len();
+            Ok((file, length))
+        }
+        .await;
+        let length = match compressed {
+            Ok((file, length)) => {
+                if let ByteObject::LocalFile { file: slot, .. } = &mut object {
+                    *slot = Some(file);
+                }
+                length
+            }
+            Err(err) => {
+                object.discard(&mission.logger, key);
+                return Err(err);
+            }
+        };
+        Ok(ByteStream {
+            object,
+            length,
+            modified_at,
+            content_type: crate::content_type::guess(key).map(String::from),
+            checksum: None,
+            etag: None,
+        })
+    }
+}
+
+/// Add variants of items matching `pattern` to `snapshot`, returning them.
+fn add_variants(
+    snapshot: &mut Vec<SnapshotMeta>,
+    pattern: &Regex,
+    formats: &[Compression],
+) -> HashMap<String, (SnapshotMeta, Compression)> {
+    let mut variants = HashMap::new();
+    for item in snapshot.iter().filter(|item| pattern.is_match(&item.key)) {
+        for format in formats {
+            variants.insert(
+                format!("{}.{}", item.key, format.extension()),
+                (item.clone(), *format),
+            );
+        }
+    }
+    snapshot.retain(|item| !variants.contains_key(&item.key));
+    for (key, (original, _)) in variants.iter() {
+        snapshot.push(SnapshotMeta {
+            key: key.clone(),
+            last_modified: original.last_modified,
+            flags: original.flags.clone(),
+            ..Default::default()
+        });
+    }
+    variants
+}
+
+#[async_trait]
+impl<Source> SnapshotStorage<SnapshotMeta> for CompressPipe<Source>
+where
+    Source: SnapshotStorage<SnapshotMeta>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        self.variants = add_variants(&mut snapshot, &self.pattern, &self.formats);
+        self.buffered = self
+            .variants
+            .values()
+            .map(|(original, _)| (original.key.clone(), Mutex::new(HashMap::new())))
+            .collect();
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "compress {} to {:?} <{}>",
+            self.pattern,
+            self.formats,
+            self.source.info()
+        )
+    }
+}
+
+#[async_trait]
+impl<Source> SourceStorage<SnapshotMeta, ByteStream> for CompressPipe<Source>
+where
+    Source: SourceStorage<SnapshotMeta, ByteStream>,
+{
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let original = match self.variants.get(snapshot.key()) {
+            Some((original, _)) => original,
+            None if self.buffered.contains_key(snapshot.key()) => snapshot,
+            None => return self.source.get_object(snapshot, mission).await,
+        };
+        let mut objects = self.buffered[original.key()].lock().await;
+        if !objects.contains_key(snapshot.key()) {
+            // fetched again if requested before, e.g. when retrying
+            *objects = self.prepare(original, mission).await?;
+        }
+        Ok(objects.remove(snapshot.key()).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_variants() {
+        let mut snapshot = vec![
+            SnapshotMeta {
+                key: "linux-64/repodata.json".to_string(),
+                size: Some(1024),
+                last_modified: Some(1600000000),
+                ..Default::default()
+            },
+            SnapshotMeta::new("linux-64/repodata.json.bz2".to_string()),
+            SnapshotMeta::new("linux-64/a.tar.bz2".to_string()),
+        ];
+        let variants = add_variants(
+            &mut snapshot,
+            &Regex::new(r"repodata\.json$").unwrap(),
+            &[Compression::Bzip2, Compression::Zstd],
+        );
+        assert_eq!(variants.len(), 2);
+        assert_eq!(snapshot.len(), 4);
+        let bz2: Vec<_> = snapshot
+            .iter()
+            .filter(|item| item.key == "linux-64/repodata.json.bz2")
+            .collect();
+        assert_eq!(bz2.len(), 1);
+        assert_eq!(bz2[0].last_modified, Some(1600000000));
+        assert_eq!(bz2[0].size, None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let dir = std::env::temp_dir().join(format!("compress-pipe-{}", unix_time()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("repodata.json");
+        std::fs::write(&input, br#"{"packages": {}}"#).unwrap();
+        for format in [Compression::Gzip, Compression::Zstd, Compression::Bzip2].iter() {
+            let output = dir.join(format!("repodata.json.{}", format.extension()));
+            format.compress(&input, &output).unwrap();
+            let compressed = std::fs::File::open(&output).unwrap();
+            let mut content = String::new();
+            match format {
+                Compression::Gzip => {
+                    std::io::Read::read_to_string(
+                        &mut flate2::read::GzDecoder::new(compressed),
+                        &mut content,
+                    )
+                    .unwrap();
+                }
+                Compression::Zstd => {
+                    content =
+                        String::from_utf8(zstd::stream::decode_all(compressed).unwrap()).unwrap();
+                }
+                Compression::Bzip2 => {
+                    std::io::Read::read_to_string(
+                        &mut bzip2::read::BzDecoder::new(compressed),
+                        &mut content,
+                    )
+                    .unwrap();
+                }
+            }
+            assert_eq!(content, r#"{"packages": {}}"#);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Conan source
+//!
+//! Conan source mirrors configured recipes from a Conan 2 remote (e.g.
+//! ConanCenter) with its REST API. For each version of a recipe, only the
+//! latest `recipe_revisions` recipe revisions are kept, and for each binary
+//! package of them, only the latest `package_revisions` package revisions.
+//!
+//! Files are placed at their API paths, e.g.
+//! `v2/conans/zlib/1.3/_/_/revisions/{rrev}/files/conanfile.py`. API
+//! responses listing revisions, packages and files are placed at their API
+//! paths with `.json` appended (e.g. `v2/conans/zlib/1.3/_/_/revisions.json`),
+//! as such paths are also directories in the mirror. They are transferred at
+//! the end, and should be served with a rewrite rule to act as a remote.
+
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_json;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::bar;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ConanConfig {
+    #[structopt(
+        long,
+        default_value = "https://center2.conan.io",
+        help = "Base of Conan remote"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "Recipes to mirror, e.g. zlib or zlib/1.3 for a single version",
+        required = true,
+        use_delimiter = true
+    )]
+    pub recipes: Vec<String>,
+    #[structopt(long, default_value = "1", help = "Recipe revisions to retain")]
+    pub recipe_revisions: usize,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Package revisions to retain of each binary package"
+    )]
+    pub package_revisions: usize,
+    #[structopt(long, help = "Only mirror recipes, without binary packages")]
+    pub recipes_only: bool,
+}
+
+pub struct Conan {
+    pub config: ConanConfig,
+    /// key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct SearchResult {
+    #[serde(default)]
+    results: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Revisions {
+    #[serde(default)]
+    revisions: Vec<Revision>,
+}
+
+#[derive(Deserialize)]
+struct Revision {
+    revision: String,
+    time: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Files {
+    #[serde(default)]
+    files: BTreeMap<String, serde_json::Value>,
+}
+
+/// Files of a reference, as (key, upstream URL, is API response).
+type RefFiles = Vec<(String, String, bool)>;
+
+/// Latest `count` revisions, newest first.
+fn latest_revisions(mut revisions: Vec<Revision>, count: usize) -> Vec<String> {
+    // RFC 3339 times of the same format compare as strings
+    revisions.sort_by(|a, b| b.time.cmp(&a.time));
+    revisions
+        .into_iter()
+        .take(count)
+        .map(|revision| revision.revision)
+        .collect()
+}
+
+/// Parse a reference in search results, e.g. `zlib/1.3@_/_` or `zlib/1.3`,
+/// returning name and version.
+fn parse_reference(reference: &str) -> Option<(&str, &str)> {
+    let reference = reference.split('@').next()?;
+    let (name, version) = reference.split_once('/')?;
+    if name.is_empty() || version.is_empty() || version.contains('/') {
+        return None;
+    }
+    Some((name, version))
+}
+
+/// Collect files of all retained revisions of `{name}/{version}`.
+async fn reference_files(
+    client: &Client,
+    config: &ConanConfig,
+    name: &str,
+    version: &str,
+) -> Result<RefFiles> {
+    let base = &config.base;
+    // API paths of listing responses
+    let mut listings = vec![];
+    let reference = format!("v2/conans/{}/{}/_/_", name, version);
+
+    let path = format!("{}/revisions", reference);
+    let revisions: Revisions = fetch_json(client, &format!("{}/{}", base, path)).await?;
+    listings.push(path);
+    let mut dirs = vec![];
+    for rrev in latest_revisions(revisions.revisions, config.recipe_revisions) {
+        let recipe = format!("{}/revisions/{}", reference, rrev);
+        dirs.push(format!("{}/files", recipe));
+        if config.recipes_only {
+            continue;
+        }
+        let path = format!("{}/search", recipe);
+        let packages: BTreeMap<String, serde_json::Value> =
+            fetch_json(client, &format!("{}/{}", base, path)).await?;
+        listings.push(path);
+        for package_id in packages.keys() {
+            let package = format!("{}/packages/{}", recipe, package_id);
+            let path = format!("{}/revisions", package);
+            let revisions: Revisions = fetch_json(client, &format!("{}/{}", base, path)).await?;
+            listings.push(path);
+            for prev in latest_revisions(revisions.revisions, config.package_revisions) {
+                dirs.push(format!("{}/revisions/{}/files", package, prev));
+            }
+        }
+    }
+
+    let mut files = vec![];
+    for dir in dirs {
+        let listing: Files = fetch_json(client, &format!("{}/{}", base, dir)).await?;
+        for file in listing.files.keys() {
+            let path = format!("{}/{}", dir, file);
+            files.push((path.clone(), format!("{}/{}", base, path), false));
+        }
+        listings.push(dir);
+    }
+    files.extend(
+        listings
+            .into_iter()
+            .map(|path| (format!("{}.json", path), format!("{}/{}", base, path), true)),
+    );
+    Ok(files)
+}
+
+impl Conan {
+    pub fn new(config: ConanConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Conan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Conan {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut references = vec![];
+        for recipe in &self.config.recipes {
+            if let Some((name, version)) = parse_reference(recipe) {
+                references.push((name.to_string(), version.to_string()));
+                continue;
+            }
+            info!(logger, "searching versions of {}...", recipe);
+            progress.set_message(recipe);
+            let result: SearchResult = fetch_json(
+                &client,
+                &format!("{}/v2/conans/search?q={}/*", self.config.base, recipe),
+            )
+            .await?;
+            let versions: Vec<_> = result
+                .results
+                .iter()
+                .filter_map(|reference| parse_reference(reference))
+                .filter(|(name, _)| name == recipe)
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+                .collect();
+            if versions.is_empty() {
+                warn!(logger, "no version found for {}", recipe);
+            }
+            references.extend(versions);
+        }
+
+        info!(logger, "fetching {} references...", references.len());
+        progress.set_length(references.len() as u64);
+        progress.set_style(bar());
+        let results: Vec<RefFiles> = stream::iter(references.into_iter().map(|(name, version)| {
+            let client = client.clone();
+            let progress = progress.clone();
+            let logger = logger.clone();
+            let config = self.config.clone();
+            async move {
+                let reference = format!("{}/{}", name, version);
+                progress.set_message(&reference);
+                let result = match reference_files(&client, &config, &name, &version).await {
+                    Ok(files) => files,
+                    Err(err) => {
+                        warn!(logger, "failed to fetch {}: {:?}", reference, err);
+                        vec![]
+                    }
+                };
+                progress.inc(1);
+                Ok::<_, Error>(result)
+            }
+        }))
+        .buffer_unordered(config.concurrent_resolve)
+        .try_collect()
+        .await?;
+
+        let mut snapshot = vec![];
+        let mut listings = vec![];
+        for (key, url, is_listing) in results.into_iter().flatten() {
+            if is_listing {
+                listings.push(SnapshotMeta::force(key.clone()));
+            } else {
+                snapshot.push(SnapshotMeta::new(key.clone()));
+            }
+            self.urls.insert(key, url);
+        }
+        info!(
+            logger,
+            "{} files, {} API responses",
+            snapshot.len(),
+            listings.len()
+        );
+        snapshot.extend(listings);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("conan, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Conan {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        self.urls
+            .get(&snapshot.key)
+            .map(|url| TransferURL(url.clone()))
+            .ok_or_else(|| Error::ProcessError(format!("unknown key {}", snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revisions() {
+        assert_eq!(parse_reference("zlib/1.3@_/_"), Some(("zlib", "1.3")));
+        assert_eq!(parse_reference("zlib/1.3"), Some(("zlib", "1.3")));
+        assert_eq!(parse_reference("zlib"), None);
+
+        let revisions: Revisions = serde_json::from_str(
+            r#"{"reference": "zlib/1.3", "revisions": [
+                {"revision": "a", "time": "2023-10-01T10:00:00.000+0000"},
+                {"revision": "c", "time": "2023-12-01T10:00:00.000+0000"},
+                {"revision": "b", "time": "2023-11-01T10:00:00.000+0000"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(latest_revisions(revisions.revisions, 2), vec!["c", "b"]);
+    }
+}
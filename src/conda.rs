@@ -4,26 +4,67 @@
 //! This source yields a snapshot with size and checksum metadata.
 //! To ensure consistency, repository data is always transferred
 //! at the end. This is done by setting priority in snapshot metadata.
+//!
+//! `repodata.json.zst` is preferred over `repodata.json` when present.
+//! If a repo provides sharded repodata (CEP-16), the shard index and all
+//! shards are mirrored as well. Shards are content-addressed, so they are
+//! transferred before the index. Packages are enumerated from shards only
+//! when the repo has no `repodata.json`.
+//!
+//! With `--latest-manifest`, `RepodataRunPipe` generates
+//! `{repo}/latest_repodata_run.json` for each repo, recording size and sha256
+//! of repodata files transferred in this run, whose packages are all mirrored.
+//! It is transferred after all other objects, and only if none of them
+//! failed, so it only updates when a run reaches the end. If repodata served
+//! by the mirror doesn't match it, the mirror is in the middle of a sync, and
+//! downstream tooling could retry later.
+//!
+//! With `--compress-repodata`, `repodata.json.bz2` is compressed from
+//! `repodata.json` mirrored in the same run, instead of being fetched from
+//! upstream, so that they never disagree. `repodata.json.zst` is still
+//! fetched, as it is the one recorded in `latest_repodata_run.json`.
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
-use std::io::ErrorKind;
+use std::io::Read;
+use std::sync::Mutex;
 
 use async_trait::async_trait;
 use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::{Client, StatusCode};
 use serde::de::DeserializeSeed;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::Digest;
 use slog::{info, warn};
 use structopt::StructOpt;
 use tokio_util::io::{StreamReader, SyncIoBridge};
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
-use crate::metadata::SnapshotMeta;
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::fetch::{fetch, fetch_optional};
+use crate::filter_pipe::Filtered;
+use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
+use crate::stream_pipe::{ByteObject, ByteStream, ByteStreamPipe};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::unix_time;
+
+const SHARDED_INDEX: &str = "repodata_shards.msgpack.zst";
+const LATEST_MANIFEST: &str = "latest_repodata_run.json";
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct CondaConfig {
     pub repo_config: String,
+    #[structopt(
+        long,
+        help = "Generate latest_repodata_run.json for each repo, recording repodata of this run"
+    )]
+    pub latest_manifest: bool,
+    #[structopt(
+        long,
+        help = "Compress repodata.json.bz2 from repodata.json instead of fetching it"
+    )]
+    pub compress_repodata: bool,
 }
 
 #[derive(Deserialize)]
@@ -37,6 +78,69 @@ pub struct Conda {
     config: CondaConfig,
     /// parsed conda repos
     repos: CondaRepos,
+    /// repo -> repodata scanned in this run
+    runs: BTreeMap<String, RepodataRun>,
+}
+
+/// Repodata scanned in a run, which is written to `latest_repodata_run.json`.
+#[derive(Debug, Serialize)]
+struct RepodataRun {
+    /// unix time when repodata was scanned
+    scanned_at: u64,
+    /// number of packages in repodata
+    packages: usize,
+    /// file name -> size and sha256 of repodata files
+    files: BTreeMap<String, RepodataFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct RepodataFile {
+    size: u64,
+    sha256: String,
+}
+
+impl RepodataFile {
+    fn new(data: &[u8]) -> Self {
+        Self {
+            size: data.len() as u64,
+            sha256: hex_string(&sha2::Sha256::digest(data)),
+        }
+    }
+}
+
+/// Reader which computes size and sha256 of data read through it.
+struct HashReader<R> {
+    inner: R,
+    hasher: sha2::Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// Read the remaining data, and return size and sha256 of all data.
+    fn finish(mut self) -> io::Result<RepodataFile> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(RepodataFile {
+            size: self.size,
+            sha256: hex_string(&self.hasher.finalize()),
+        })
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
 }
 
 mod de {
@@ -194,11 +298,136 @@ mod de {
     }
 }
 
+/// Sharded repodata index (CEP-16), `repodata_shards.msgpack.zst`.
+#[derive(Deserialize)]
+struct ShardedRepodata {
+    info: ShardedInfo,
+    shards: HashMap<String, ByteBuf>,
+}
+
+#[derive(Deserialize)]
+struct ShardedInfo {
+    #[serde(default = "default_shards_base_url")]
+    shards_base_url: String,
+}
+
+fn default_shards_base_url() -> String {
+    "./shards/".to_string()
+}
+
+/// A shard of sharded repodata, which contains all packages of one name.
+#[derive(Deserialize)]
+struct Shard {
+    #[serde(default)]
+    packages: HashMap<String, ShardPackage>,
+    #[serde(rename = "packages.conda", default)]
+    packages_conda: HashMap<String, ShardPackage>,
+}
+
+#[derive(Deserialize)]
+struct ShardPackage {
+    size: Option<u64>,
+    sha256: Option<ByteBuf>,
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Resolve shard URLs of a sharded index located at `{base}/{repo}`.
+/// Returns pairs of snapshot key (`None` if the shard is not under `base`) and URL.
+fn resolve_shards(
+    base: &str,
+    repo: &str,
+    index: &ShardedRepodata,
+) -> Result<Vec<(Option<String>, String)>> {
+    let index_url = url::Url::parse(&format!("{}/{}/{}", base, repo, SHARDED_INDEX))
+        .map_err(|err| Error::ProcessError(format!("invalid url: {:?}", err)))?;
+    let shards_base = index_url
+        .join(&index.info.shards_base_url)
+        .map_err(|err| Error::ProcessError(format!("invalid shards_base_url: {:?}", err)))?;
+    let mut shards = vec![];
+    for hash in index.shards.values() {
+        let url = shards_base
+            .join(&format!("{}.msgpack.zst", hex_string(hash)))
+            .map_err(|err| Error::ProcessError(format!("invalid shard url: {:?}", err)))?
+            .to_string();
+        let key = url
+            .strip_prefix(base)
+            .and_then(|key| key.strip_prefix('/'))
+            .map(|key| key.to_string());
+        shards.push((key, url));
+    }
+    shards.sort();
+    Ok(shards)
+}
+
+/// Fetch sharded index of a repo, returning key of the index, the index file
+/// itself and all shards.
+async fn fetch_sharded_index(
+    client: &Client,
+    base: &str,
+    repo: &str,
+) -> Result<Option<(String, RepodataFile, Vec<(Option<String>, String)>)>> {
+    let key = format!("{}/{}", repo, SHARDED_INDEX);
+    let data = match fetch_optional(client, &format!("{}/{}", base, key)).await? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let file = RepodataFile::new(&data);
+    let data = zstd::stream::decode_all(&data[..])?;
+    let index: ShardedRepodata = rmp_serde::from_slice(&data)?;
+    Ok(Some((key, file, resolve_shards(base, repo, &index)?)))
+}
+
+/// Fetch all shards of a repo, and generate snapshot of packages in them.
+async fn fetch_shards(
+    client: &Client,
+    repo: &str,
+    shards: &[(Option<String>, String)],
+) -> Result<Vec<SnapshotMeta>> {
+    let urls: Vec<String> = shards.iter().map(|(_, url)| url.clone()).collect();
+    let packages: Vec<Vec<SnapshotMeta>> = stream::iter(urls.into_iter().map(|url| {
+        let client = client.clone();
+        let repo = repo.to_string();
+        async move {
+            let data = fetch(&client, &url).await?;
+            let data = zstd::stream::decode_all(&data[..])?;
+            let shard: Shard = rmp_serde::from_slice(&data)?;
+            Ok::<_, Error>(
+                shard
+                    .packages
+                    .into_iter()
+                    .chain(shard.packages_conda)
+                    .map(|(name, package)| {
+                        let sha256 = package.sha256.map(|x| hex_string(&x));
+                        SnapshotMeta {
+                            key: format!("{}/{}", repo, name),
+                            size: package.size,
+                            checksum_method: sha256.as_ref().map(|_| "sha256".to_string()),
+                            checksum: sha256,
+                            ..Default::default()
+                        }
+                    })
+                    .collect(),
+            )
+        }
+    }))
+    .buffer_unordered(16)
+    .try_collect()
+    .await?;
+    Ok(packages.into_iter().flatten().collect())
+}
+
 impl Conda {
     pub fn new(config: CondaConfig) -> Self {
         let content = std::fs::read(&config.repo_config).unwrap();
         let repos = serde_yaml::from_str(std::str::from_utf8(&content).unwrap()).unwrap();
-        Self { config, repos }
+        Self {
+            config,
+            repos,
+            runs: BTreeMap::new(),
+        }
     }
 }
 
@@ -226,34 +455,96 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
             let client = client.clone();
             let logger = logger.clone();
             let repo_ = repo.clone();
+            let logger_ = logger.clone();
 
             let future = async move {
                 let mut snapshot = vec![];
-                let repodata = format!("{}/{}/repodata.json", base, repo);
-                let stream = client
-                    .get(&repodata)
-                    .send()
-                    .await?
-                    .bytes_stream()
-                    .map_err(|e| io::Error::new(ErrorKind::Other, e));
-                let reader = SyncIoBridge::new(StreamReader::new(stream));
-                let mut packages = {
-                    let repo = repo.clone();
-                    tokio::task::spawn_blocking(move || {
-                        let mut deserializer = serde_json::de::Deserializer::from_reader(reader);
-                        de::Snapshot { repo: &repo }.deserialize(&mut deserializer)
-                    })
-                    .await
-                    .expect("task panicked")?
+                let mut files = BTreeMap::new();
+
+                // prefer zstd-compressed repodata, and fallback to plain json
+                let repodata_zst = format!("{}/{}/repodata.json.zst", base, repo);
+                let response = client.get(&repodata_zst).send().await?;
+                let (response, zst) = if response.status().is_success() {
+                    (Some(response), true)
+                } else {
+                    let repodata = format!("{}/{}/repodata.json", base, repo);
+                    let response = client.get(&repodata).send().await?;
+                    let status = response.status();
+                    if status == StatusCode::NOT_FOUND {
+                        (None, false)
+                    } else if status.is_success() {
+                        (Some(response), false)
+                    } else {
+                        return Err(Error::HTTPError(status));
+                    }
+                };
+
+                let sharded = fetch_sharded_index(&client, &base, &repo).await?;
+
+                if let Some(response) = response {
+                    let stream = response.bytes_stream().map_err(io::Error::other);
+                    let reader = SyncIoBridge::new(StreamReader::new(stream));
+                    let (mut packages, file) = {
+                        let repo = repo.clone();
+                        tokio::task::spawn_blocking(move || {
+                            let mut reader = HashReader::new(reader);
+                            let packages = {
+                                let reader: Box<dyn Read> = if zst {
+                                    Box::new(zstd::stream::read::Decoder::new(&mut reader)?)
+                                } else {
+                                    Box::new(&mut reader)
+                                };
+                                let mut deserializer =
+                                    serde_json::de::Deserializer::from_reader(reader);
+                                de::Snapshot { repo: &repo }.deserialize(&mut deserializer)?
+                            };
+                            Ok::<_, Error>((packages, reader.finish()?))
+                        })
+                        .await
+                        .expect("task panicked")?
+                    };
+                    let name = if zst {
+                        "repodata.json.zst"
+                    } else {
+                        "repodata.json"
+                    };
+                    files.insert(name.to_string(), file);
+                    snapshot.append(&mut packages);
+                } else if let Some((_, _, shards)) = &sharded {
+                    info!(logger_, "no repodata.json in {}, using shards", repo);
+                    let mut packages = fetch_shards(&client, &repo, shards).await?;
+                    snapshot.append(&mut packages);
+                } else {
+                    return Err(Error::ProcessError(format!("no repodata in {}", repo)));
+                }
+
+                let mut run = RepodataRun {
+                    scanned_at: unix_time(),
+                    packages: snapshot.len(),
+                    files,
                 };
-                snapshot.append(&mut packages);
+
+                if let Some((index, file, shards)) = sharded {
+                    run.files.insert(SHARDED_INDEX.to_string(), file);
+                    snapshot.extend(
+                        shards
+                            .into_iter()
+                            .filter_map(|(key, _)| key)
+                            .map(SnapshotMeta::new),
+                    );
+                    snapshot.push(SnapshotMeta::force(index));
+                }
+
                 progress.set_message(&repo);
                 snapshot.append(&mut vec![
                     SnapshotMeta::force(format!("{}/repodata.json", repo)),
                     SnapshotMeta::force(format!("{}/repodata.json.bz2", repo)),
                     SnapshotMeta::force(format!("{}/current_repodata.json", repo)),
                 ]);
-                Ok::<_, Error>(snapshot)
+                if zst {
+                    snapshot.push(SnapshotMeta::force(format!("{}/repodata.json.zst", repo)));
+                }
+                Ok::<_, Error>((repo, snapshot, run))
             };
 
             async move {
@@ -265,14 +556,19 @@ impl SnapshotStorage<SnapshotMeta> for Conda {
             }
         };
 
-        let snapshots = stream::iter(self.repos.repos.clone())
+        let results = stream::iter(self.repos.repos.clone())
             .map(fetch)
             .buffer_unordered(4)
             .try_collect::<Vec<_>>()
-            .await?
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
+            .await?;
+
+        let mut snapshots = vec![];
+        for (repo, mut snapshot, run) in results {
+            if self.config.latest_manifest {
+                self.runs.insert(repo, run);
+            }
+            snapshots.append(&mut snapshot);
+        }
 
         Ok(snapshots)
     }
@@ -288,3 +584,202 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Conda {
         Ok(TransferURL(format!("{}/{}", self.repos.base, snapshot.key)))
     }
 }
+
+/// `RepodataRunPipe` adds `latest_repodata_run.json` of repos scanned by
+/// `Conda` source. Repodata is hashed as it is handed over to target, and
+/// manifests are transferred after all other objects, so that they record
+/// repodata actually mirrored.
+pub struct RepodataRunPipe {
+    source: ByteStreamPipe<Filtered<Conda>>,
+    buffer_path: String,
+    /// key -> size and sha256 of repodata transferred in this run
+    transferred: Mutex<HashMap<String, RepodataFile>>,
+}
+
+impl RepodataRunPipe {
+    pub fn new(source: ByteStreamPipe<Filtered<Conda>>, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+            transferred: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `key` is a repodata file recorded in manifest.
+    fn is_recorded(&self, key: &str) -> bool {
+        key.rsplit_once('/').is_some_and(|(repo, name)| {
+            self.source
+                .source
+                .inner()
+                .runs
+                .get(repo)
+                .is_some_and(|run| run.files.contains_key(name))
+        })
+    }
+
+    /// Generate manifest of `repo` from repodata transferred in this run.
+    fn manifest(&self, repo: &str, run: &RepodataRun) -> Result<String> {
+        let transferred = self.transferred.lock().unwrap();
+        let files = run
+            .files
+            .keys()
+            .map(|name| {
+                let key = format!("{}/{}", repo, name);
+                match transferred.get(&key) {
+                    Some(file) => Ok((name.clone(), file.clone())),
+                    None => Err(Error::ProcessError(format!(
+                        "{} not transferred in this run",
+                        key
+                    ))),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(serde_json::to_string_pretty(&RepodataRun {
+            scanned_at: run.scanned_at,
+            packages: run.packages,
+            files,
+        })?)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for RepodataRunPipe {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        let keys: HashSet<&str> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+        // repos with repodata left out by filters are not recorded
+        let repos: Vec<String> = self
+            .source
+            .source
+            .inner()
+            .runs
+            .iter()
+            .filter(|(repo, run)| {
+                run.files
+                    .keys()
+                    .all(|name| keys.contains(format!("{}/{}", repo, name).as_str()))
+            })
+            .map(|(repo, _)| repo.clone())
+            .collect();
+        for repo in repos {
+            snapshot.push(SnapshotMeta {
+                key: format!("{}/{}", repo, LATEST_MANIFEST),
+                flags: SnapshotMetaFlag {
+                    force: true,
+                    force_last: true,
+                    after_all: true,
+                },
+                ..Default::default()
+            });
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("RepodataRunPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for RepodataRunPipe {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        let manifest = key
+            .strip_suffix(LATEST_MANIFEST)
+            .and_then(|repo| repo.strip_suffix('/'))
+            .and_then(|repo| {
+                let run = self.source.source.inner().runs.get(repo)?;
+                Some((repo, run))
+            });
+        if let Some((repo, run)) = manifest {
+            let content = self.manifest(repo, run)?;
+            return ByteStream::from_content(
+                &self.buffer_path,
+                key,
+                content.as_bytes(),
+                Some("application/json"),
+            )
+            .await;
+        }
+
+        let mut stream = self.source.get_object(snapshot, mission).await?;
+        if self.is_recorded(key) {
+            stream.object = stream
+                .object
+                .into_local(&self.buffer_path, &mission.logger)
+                .await?;
+            let path = match &stream.object {
+                ByteObject::LocalFile {
+                    path: Some(path), ..
+                } => path.clone(),
+                _ => unreachable!(),
+            };
+            let file = tokio::task::spawn_blocking(move || {
+                HashReader::new(std::fs::File::open(path)?).finish()
+            })
+            .await
+            .expect("task panicked")?;
+            self.transferred
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), file);
+        }
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_shards() {
+        let mut index = ShardedRepodata {
+            info: ShardedInfo {
+                shards_base_url: default_shards_base_url(),
+            },
+            shards: HashMap::new(),
+        };
+        index
+            .shards
+            .insert("numpy".to_string(), ByteBuf::from(vec![0xab, 0xcd]));
+        assert_eq!(
+            resolve_shards("https://conda.anaconda.org", "conda-forge/noarch", &index).unwrap(),
+            vec![(
+                Some("conda-forge/noarch/shards/abcd.msgpack.zst".to_string()),
+                "https://conda.anaconda.org/conda-forge/noarch/shards/abcd.msgpack.zst".to_string()
+            )]
+        );
+
+        index.info.shards_base_url = "https://shards.example.com/noarch/".to_string();
+        assert_eq!(
+            resolve_shards("https://conda.anaconda.org", "conda-forge/noarch", &index).unwrap(),
+            vec![(
+                None,
+                "https://shards.example.com/noarch/abcd.msgpack.zst".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_hash_reader() {
+        let data = br#"{"packages": {}, "packages.conda": {}}  "#;
+        let compressed = zstd::stream::encode_all(&data[..], 0).unwrap();
+        let mut reader = HashReader::new(&compressed[..]);
+        let value: serde_json::Value = {
+            let decoder = zstd::stream::read::Decoder::new(&mut reader).unwrap();
+            serde_json::from_reader(decoder).unwrap()
+        };
+        assert!(value["packages"].is_object());
+        // size and sha256 are of the compressed file
+        assert_eq!(reader.finish().unwrap(), RepodataFile::new(&compressed));
+    }
+}
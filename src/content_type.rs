@@ -0,0 +1,153 @@
+//! Content type detection
+//!
+//! Many upstreams serve everything as `application/octet-stream`, so that
+//! browsers download HTML indexes and text files instead of rendering them.
+//! `ContentTypes` decides content type of an object when uploading, by
+//! (in order) user overrides by extension, content type given by source
+//! unless it's a generic binary one, and a built-in map of extensions taken
+//! from nginx `mime.types`.
+
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+
+/// extension -> content type, a subset of nginx `mime.types` with types
+/// common on mirrors
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("shtml", "text/html; charset=utf-8"),
+    ("css", "text/css"),
+    ("xml", "text/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/plain; charset=utf-8"),
+    ("asc", "text/plain; charset=utf-8"),
+    ("sig", "application/pgp-signature"),
+    ("js", "application/javascript"),
+    ("json", "application/json"),
+    ("yaml", "text/yaml"),
+    ("yml", "text/yaml"),
+    ("toml", "text/plain; charset=utf-8"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("ico", "image/x-icon"),
+    ("webp", "image/webp"),
+    ("pdf", "application/pdf"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("zip", "application/zip"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("jar", "application/java-archive"),
+    ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-redhat-package-manager"),
+    ("iso", "application/x-iso9660-image"),
+    ("exe", "application/octet-stream"),
+    ("whl", "application/zip"),
+    ("wasm", "application/wasm"),
+];
+
+/// Content types which tell nothing about content.
+const GENERIC_TYPES: &[&str] = &["application/octet-stream", "binary/octet-stream"];
+
+fn extension(key: &str) -> Option<String> {
+    let name = key.rsplit('/').next()?;
+    let (_, ext) = name.rsplit_once('.')?;
+    Some(ext.to_lowercase())
+}
+
+/// Content type of `key` by its extension, from the built-in map.
+pub fn guess(key: &str) -> Option<&'static str> {
+    let ext = extension(key)?;
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| *known == ext)
+        .map(|(_, content_type)| *content_type)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ContentTypes {
+    overrides: HashMap<String, String>,
+}
+
+impl ContentTypes {
+    /// Parse overrides in the form of `ext=type`, e.g. `log=text/plain`.
+    pub fn new(overrides: &[String]) -> Result<Self> {
+        let overrides = overrides
+            .iter()
+            .map(|item| {
+                let (ext, content_type) = item.split_once('=').ok_or_else(|| {
+                    Error::ConfigureError(format!("invalid content type override {}", item))
+                })?;
+                Ok((
+                    ext.trim_start_matches('.').to_lowercase(),
+                    content_type.to_string(),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { overrides })
+    }
+
+    /// Decide content type of `key`, given `upstream` content type from source.
+    pub fn resolve(&self, key: &str, upstream: Option<String>) -> Option<String> {
+        if let Some(content_type) = extension(key).and_then(|ext| self.overrides.get(&ext)) {
+            return Some(content_type.clone());
+        }
+        match upstream {
+            Some(upstream)
+                if !GENERIC_TYPES
+                    .iter()
+                    .any(|generic| upstream.starts_with(generic)) =>
+            {
+                Some(upstream)
+            }
+            upstream => guess(key).map(String::from).or(upstream),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let types = ContentTypes::new(&["log=text/plain".to_string()]).unwrap();
+        assert_eq!(
+            types.resolve("dists/index.HTML", None).as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(
+            types
+                .resolve("a.json", Some("application/octet-stream".to_string()))
+                .as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(
+            types
+                .resolve("a.json", Some("application/x-custom".to_string()))
+                .as_deref(),
+            Some("application/x-custom")
+        );
+        assert_eq!(
+            types
+                .resolve("build.log", Some("text/x-log".to_string()))
+                .as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(
+            types
+                .resolve("v1.0/blob", Some("application/octet-stream".to_string()))
+                .as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(types.resolve("v1.0/blob", None), None);
+        assert!(ContentTypes::new(&["log".to_string()]).is_err());
+    }
+}
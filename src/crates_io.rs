@@ -1,94 +1,292 @@
 //! crates.io Source
 //!
-//! Crates.io source first download current crates.io-index zip from GitHub,
-//! and then extract downloadable crates from crates.io-index in memory.
+//! Crates.io source reads versions of crates from the sparse index
+//! (`index.crates.io`), where each crate has a file of JSON lines. As the
+//! sparse index can't be listed, crate names are listed with crates.io API,
+//! and requests to the API are sent at most once per second as required by
+//! its crawler policy.
+//!
+//! When `state_file` is set, versions of all crates and the last update time
+//! of crates are saved after each snapshot. Following snapshots only list
+//! crates updated since then (sorted by `recent-updates`), and only fetch
+//! index files of those crates. A full listing is done if the state file is
+//! missing or unreadable.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::Result;
+use crate::fetch::{fetch_json, fetch_optional};
 use crate::traits::{SnapshotStorage, SourceStorage};
 
 use crate::metadata::SnapshotMeta;
 use async_trait::async_trait;
-use serde::Deserialize;
-use slog::info;
-use std::io::Read;
+use chrono::{DateTime, FixedOffset};
+use futures_util::{stream, StreamExt};
+use indicatif::ProgressBar;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
 use structopt::StructOpt;
 
 #[derive(Deserialize, Debug)]
 pub struct CratesIoPackage {
-    name: String,
     vers: String,
     cksum: String,
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct CratesIo {
-    #[structopt(
-        long,
-        default_value = "https://github.com/rust-lang/crates.io-index/archive/master.zip"
-    )]
-    pub zip_master: String,
+    #[structopt(long, default_value = "https://index.crates.io")]
+    pub sparse_index: String,
+    #[structopt(long, default_value = "https://crates.io/api/v1")]
+    pub api: String,
     #[structopt(long, default_value = "https://static.crates.io/crates")]
     pub crates_base: String,
+    /// File to save versions of crates and the last update time in. When set,
+    /// only crates updated since the last snapshot are fetched.
+    #[structopt(long)]
+    pub state_file: Option<String>,
     #[structopt(long)]
     pub debug: bool,
 }
 
+/// A crate version in state.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CrateVersion {
+    vers: String,
+    cksum: String,
+}
+
+/// State of incremental snapshot.
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// the latest `updated_at` of crates listed
+    updated_at: Option<String>,
+    crates: BTreeMap<String, Vec<CrateVersion>>,
+    /// crates failed to fetch, which are fetched again next time
+    #[serde(default)]
+    pending: BTreeSet<String>,
+}
+
+#[derive(Deserialize)]
+struct CrateList {
+    crates: Vec<CrateEntry>,
+    meta: CrateListMeta,
+}
+
+#[derive(Deserialize)]
+struct CrateEntry {
+    name: String,
+    updated_at: String,
+}
+
+#[derive(Deserialize)]
+struct CrateListMeta {
+    next_page: Option<String>,
+}
+
+/// Path of a crate in index, e.g. `se/rd/serde` or `3/s/syn`.
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+/// Parse versions in an index file.
+fn parse_index(content: &[u8]) -> Vec<CratesIoPackage> {
+    let mut de = serde_json::Deserializer::from_slice(content);
+    let mut packages = vec![];
+    while let Ok(package) = CratesIoPackage::deserialize(&mut de) {
+        packages.push(package);
+    }
+    packages
+}
+
+fn parse_time(time: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(time).ok()
+}
+
+/// List crates with API, stopping at crates updated before `since` if set.
+/// Returns names of crates and the latest update time.
+async fn list_crates(
+    progress: &ProgressBar,
+    client: &Client,
+    api: &str,
+    since: Option<&str>,
+    debug: bool,
+) -> Result<(Vec<String>, Option<String>)> {
+    let since = since.and_then(parse_time);
+    let sort = if since.is_some() {
+        "recent-updates"
+    } else {
+        "alphabetical"
+    };
+    let mut names = vec![];
+    let mut latest: Option<(DateTime<FixedOffset>, String)> = None;
+    let mut next = Some(format!("?per_page=100&sort={}", sort));
+    while let Some(query) = next {
+        let page: CrateList = fetch_json(client, &format!("{}/crates{}", api, query)).await?;
+        next = page.meta.next_page;
+        for entry in page.crates {
+            let updated_at = parse_time(&entry.updated_at);
+            if let (Some(since), Some(updated_at)) = (since, updated_at) {
+                if updated_at < since {
+                    next = None;
+                    break;
+                }
+            }
+            match (&latest, updated_at) {
+                (Some((latest, _)), Some(updated_at)) if updated_at <= *latest => {}
+                (_, Some(updated_at)) => latest = Some((updated_at, entry.updated_at)),
+                _ => {}
+            }
+            names.push(entry.name);
+        }
+        progress.set_message(&format!("listed {} crates", names.len()));
+        if debug && names.len() >= 100 {
+            break;
+        }
+        if next.is_some() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    Ok((names, latest.map(|(_, latest)| latest)))
+}
+
+async fn load_state(path: &str) -> Result<State> {
+    let data = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Save state to a temporary file and rename it, so that an interrupted save
+/// won't leave a broken state.
+async fn save_state(path: &str, state: &State) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, serde_json::to_vec(state)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
 #[async_trait]
 impl SnapshotStorage<SnapshotMeta> for CratesIo {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
-        info!(logger, "fetching crates.io-index zip...");
-        progress.set_message("fetching crates.io-index zip...");
-        let data = client.get(&self.zip_master).send().await?.bytes().await?;
-        let mut data = std::io::Cursor::new(data);
-        let mut buf = vec![];
-        let mut snapshot = vec![];
-        info!(logger, "parsing...");
-
-        let mut idx = 0;
-        loop {
-            match zip::read::read_zipfile_from_stream(&mut data) {
-                Ok(Some(mut file)) => {
-                    let mut is_first = true;
-                    buf.clear();
-                    file.read_to_end(&mut buf)?;
-
-                    let mut de = serde_json::Deserializer::from_reader(&buf[..]);
-                    while let Ok(package) = CratesIoPackage::deserialize(&mut de) {
-                        let url = format!(
-                            "{crate}/{crate}-{version}.crate",
-                            crate = package.name,
-                            version = package.vers
-                        );
-                        if is_first {
-                            progress.set_message(&url);
-                            is_first = false;
-                        }
-                        idx += 1;
-                        progress.inc(1);
-                        snapshot.push(SnapshotMeta {
-                            key: url,
-                            checksum_method: Some(String::from("sha256")),
-                            checksum: Some(package.cksum),
-                            ..Default::default()
-                        });
-                    }
+        let mut state = match &self.state_file {
+            Some(path) => match load_state(path).await {
+                Ok(state) => state,
+                Err(err) => {
+                    info!(logger, "no previous state, doing full listing: {:?}", err);
+                    State::default()
+                }
+            },
+            None => State::default(),
+        };
+
+        info!(logger, "listing crates...");
+        progress.set_message("listing crates...");
+        let (names, updated_at) = list_crates(
+            &progress,
+            &client,
+            &self.api,
+            state.updated_at.as_deref(),
+            self.debug,
+        )
+        .await?;
+        info!(
+            logger,
+            "{} crates to fetch, {} pending",
+            names.len(),
+            state.pending.len()
+        );
+        let mut to_fetch: BTreeSet<String> = std::mem::take(&mut state.pending);
+        to_fetch.extend(names);
+
+        progress.set_length(to_fetch.len() as u64);
+        progress.set_style(crate::utils::bar());
+        let results: Vec<(String, Result<Option<Vec<CratesIoPackage>>>)> =
+            stream::iter(to_fetch.into_iter().map(|name| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let url = format!("{}/{}", self.sparse_index, index_path(&name));
+                async move {
+                    let result = fetch_optional(&client, &url)
+                        .await
+                        .map(|data| data.map(|data| parse_index(&data)));
+                    progress.set_message(&name);
+                    progress.inc(1);
+                    (name, result)
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .collect()
+            .await;
+
+        for (name, result) in results {
+            match result {
+                Ok(Some(packages)) => {
+                    state.crates.insert(
+                        name,
+                        packages
+                            .into_iter()
+                            .map(|package| CrateVersion {
+                                vers: package.vers,
+                                cksum: package.cksum,
+                            })
+                            .collect(),
+                    );
+                }
+                Ok(None) => {
+                    // crate is deleted
+                    state.crates.remove(&name);
+                }
+                Err(err) => {
+                    warn!(logger, "failed to fetch index of {}: {:?}", name, err);
+                    state.pending.insert(name);
                 }
-                Ok(None) => break,
-                Err(e) => return Err(e.into()),
             }
-            if self.debug && idx >= 10000 {
-                break;
+        }
+
+        let snapshot = state
+            .crates
+            .iter()
+            .flat_map(|(name, versions)| {
+                versions.iter().map(move |version| SnapshotMeta {
+                    key: format!(
+                        "{crate}/{crate}-{version}.crate",
+                        crate = name,
+                        version = version.vers
+                    ),
+                    checksum_method: Some(String::from("sha256")),
+                    checksum: Some(version.cksum.clone()),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        if let Some(path) = &self.state_file {
+            if updated_at.is_some() {
+                state.updated_at = updated_at;
             }
-            tokio::task::yield_now().await;
+            save_state(path, &state).await?;
+            info!(
+                logger,
+                "saved state of {} crates, {} failed",
+                state.crates.len(),
+                state.pending.len()
+            );
         }
 
         progress.finish_with_message("done");
@@ -110,3 +308,24 @@ impl SourceStorage<SnapshotMeta, TransferURL> for CratesIo {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_path_and_parse() {
+        assert_eq!(index_path("a"), "1/a");
+        assert_eq!(index_path("cc"), "2/cc");
+        assert_eq!(index_path("Syn"), "3/s/syn");
+        assert_eq!(index_path("serde"), "se/rd/serde");
+
+        let index = br#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"ab01","features":{},"yanked":false}
+{"name":"serde","vers":"1.0.1","deps":[],"cksum":"cd23","features":{},"yanked":true}
+"#;
+        let packages = parse_index(index);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[1].vers, "1.0.1");
+        assert_eq!(packages[1].cksum, "cd23");
+    }
+}
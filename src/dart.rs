@@ -1,11 +1,28 @@
+//! Dart Pub source
+//!
+//! Dart Pub source lists all packages with `package-name-completion-data`,
+//! falling back to paging through `api/packages` if it is not available,
+//! and then fetches metadata of each package to find its archives.
+//!
+//! When `cache_file` is set, archives of each package are cached along with
+//! `ETag` and `Last-Modified` of its metadata. Metadata is then fetched with
+//! conditional requests, and archives of unchanged packages are taken from
+//! cache. A cached package is only used if it has as many archives as its
+//! version count.
+
+use std::collections::BTreeMap;
+
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
+use crate::fetch::{fetch_conditional, fetch_json, FetchOptions, Validators};
 use crate::metadata::SnapshotMeta;
 use crate::traits::{SnapshotStorage, SourceStorage};
 
 use async_trait::async_trait;
-use futures_util::{stream, StreamExt, TryStreamExt};
-use serde_json::Value;
+use futures_util::{stream, StreamExt};
+use indicatif::ProgressBar;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use slog::{info, warn};
 use structopt::StructOpt;
 
@@ -15,6 +32,105 @@ pub struct Dart {
     pub base: String,
     #[structopt(long)]
     pub debug: bool,
+    /// File to cache archives of packages in. When set, metadata of packages
+    /// are fetched with conditional requests.
+    #[structopt(long)]
+    pub cache_file: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PackagePage {
+    packages: Vec<PackageName>,
+    next_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PackageName {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NameCompletion {
+    packages: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    versions: Vec<PackageVersion>,
+}
+
+#[derive(Deserialize)]
+struct PackageVersion {
+    archive_url: Option<String>,
+}
+
+/// Archives of a package, cached with validators of its metadata.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    validators: Validators,
+    versions: usize,
+    archives: Vec<String>,
+}
+
+impl CacheEntry {
+    fn is_complete(&self) -> bool {
+        self.versions == self.archives.len()
+    }
+}
+
+/// List packages by paging through `api/packages`.
+async fn list_pages(progress: &ProgressBar, client: &Client, base: &str) -> Result<Vec<String>> {
+    let mut next_url = Some(format!("{}/api/packages", base));
+    let mut names = vec![];
+    let mut page: usize = 1;
+    while let Some(url) = next_url {
+        let data: PackagePage = fetch_json(client, &url).await?;
+        names.extend(data.packages.into_iter().map(|package| package.name));
+        next_url = data.next_url;
+        progress.set_message(&format!(
+            "fetching page {}, total packages = {}",
+            page,
+            names.len()
+        ));
+        page += 1;
+    }
+    Ok(names)
+}
+
+/// Parse metadata of a package into keys of archives relative to `base`.
+fn parse_package(base: &str, data: &[u8]) -> Result<CacheEntry> {
+    let package: Package = serde_json::from_slice(data)?;
+    let archives = package
+        .versions
+        .iter()
+        .filter_map(|version| version.archive_url.as_deref())
+        .map(|archive_url| {
+            archive_url
+                .strip_prefix(base)
+                .map(|key| key.to_string())
+                .ok_or_else(|| Error::ProcessError(format!("unmatched base URL {}", archive_url)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(CacheEntry {
+        validators: Validators::default(),
+        versions: package.versions.len(),
+        archives,
+    })
+}
+
+async fn load_cache(path: &str) -> Result<BTreeMap<String, CacheEntry>> {
+    let data = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Save cache to a temporary file and rename it, so that an interrupted save
+/// won't leave a broken cache.
+async fn save_cache(path: &str, cache: &BTreeMap<String, CacheEntry>) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, serde_json::to_vec(cache)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
 }
 
 #[async_trait]
@@ -28,41 +144,26 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
         let progress = mission.progress;
         let client = mission.client;
 
-        let api_base = format!("{}/api/packages", self.base);
+        let mut cache = match &self.cache_file {
+            Some(path) => load_cache(path).await.unwrap_or_else(|err| {
+                info!(logger, "no previous cache: {:?}", err);
+                BTreeMap::new()
+            }),
+            None => BTreeMap::new(),
+        };
 
         info!(logger, "fetching packages...");
-        let mut next_url = api_base.clone();
-        let mut package_name = vec![];
-        let mut page: usize = 1;
-
-        loop {
-            let data = client.get(&next_url).send().await?.text().await?;
-            let data: Value = serde_json::from_str(&data).unwrap();
-            let data = data.as_object().unwrap();
-
-            let packages = data.get("packages").unwrap().as_array().unwrap();
-
-            for package in packages {
-                package_name.push(package.get("name").unwrap().as_str().unwrap().to_string());
-            }
-
-            let next_url_str = data.get("next_url");
-            if let Some(next_url_str) = next_url_str {
-                if !next_url_str.is_null() {
-                    next_url = next_url_str.as_str().unwrap().to_string();
-                } else {
-                    break;
-                }
-            } else {
-                break;
+        let completion_url = format!("{}/api/package-name-completion-data", self.base);
+        let mut package_name = match fetch_json::<NameCompletion>(&client, &completion_url).await {
+            Ok(data) => data.packages,
+            Err(err) => {
+                warn!(
+                    logger,
+                    "failed to fetch package names, paging through packages: {:?}", err
+                );
+                list_pages(&progress, &client, &self.base).await?
             }
-            progress.set_message(&format!(
-                "fetching page {}, total packages = {}",
-                page,
-                package_name.len()
-            ));
-            page += 1;
-        }
+        };
 
         if self.debug {
             package_name.truncate(100);
@@ -70,58 +171,79 @@ impl SnapshotStorage<SnapshotMeta> for Dart {
 
         progress.inc_length(package_name.len() as u64);
 
-        let snapshots: Result<Vec<Vec<SnapshotMeta>>> =
+        let base = format!("{}/", self.base);
+        let results: Vec<(String, Result<Option<CacheEntry>>)> =
             stream::iter(package_name.into_iter().map(|name| {
                 let client = client.clone();
-                let base = format!("{}/", self.base);
+                let base = base.clone();
                 let progress = progress.clone();
-                let logger = logger.clone();
+                let validators = cache
+                    .get(&name)
+                    .filter(|entry| entry.is_complete())
+                    .map(|entry| entry.validators.clone())
+                    .unwrap_or_default();
 
-                let func = async move {
+                async move {
                     progress.set_message(&name);
-                    let package = client
-                        .get(&format!("{}/api/packages/{}", base, name))
-                        .send()
-                        .await?
-                        .text()
-                        .await?;
-
-                    let data: Value = serde_json::from_str(&package).unwrap();
-                    let versions = data.get("versions").unwrap().as_array().unwrap();
-                    let archives: Vec<SnapshotMeta> = versions
-                        .iter()
-                        .filter_map(|version| version.get("archive_url"))
-                        .filter_map(|archive_url| archive_url.as_str())
-                        .map(|archive_url| {
-                            if archive_url.starts_with(&base) {
-                                SnapshotMeta {
-                                    key: archive_url[base.len()..].to_string(),
-                                    ..Default::default()
+                    let url = format!("{}api/packages/{}", base, name);
+                    let result =
+                        fetch_conditional(&client, &url, &FetchOptions::default(), &validators)
+                            .await
+                            .and_then(|response| match response {
+                                Some((headers, data)) => {
+                                    let mut entry = parse_package(&base, &data)?;
+                                    entry.validators = Validators::from_headers(&headers);
+                                    Ok(Some(entry))
                                 }
-                            } else {
-                                panic!("Unmatched base URL {}", archive_url);
-                            }
-                        })
-                        .collect();
-
+                                None => Ok(None),
+                            });
                     progress.inc(1);
-                    Ok::<Vec<SnapshotMeta>, Error>(archives)
-                };
-                async move {
-                    match func.await {
-                        Ok(x) => Ok(x),
-                        Err(err) => {
-                            warn!(logger, "failed to fetch package meta {:?}", err);
-                            Ok(vec![])
-                        }
-                    }
+                    (name, result)
                 }
             }))
             .buffer_unordered(config.concurrent_resolve)
-            .try_collect()
+            .collect()
             .await;
 
-        let snapshot: Vec<_> = snapshots?.into_iter().flatten().collect();
+        let mut updated = 0;
+        let mut new_cache = BTreeMap::new();
+        for (name, result) in results {
+            let entry = match result {
+                Ok(Some(entry)) => {
+                    updated += 1;
+                    entry
+                }
+                Ok(None) => match cache.remove(&name) {
+                    Some(entry) => entry,
+                    None => continue,
+                },
+                Err(err) => {
+                    warn!(
+                        logger,
+                        "failed to fetch package meta of {}: {:?}", name, err
+                    );
+                    // keep archives resolved last time, but fetch it again next time
+                    match cache.remove(&name) {
+                        Some(entry) => CacheEntry {
+                            validators: Validators::default(),
+                            ..entry
+                        },
+                        None => continue,
+                    }
+                }
+            };
+            new_cache.insert(name, entry);
+        }
+        info!(logger, "{} packages, {} updated", new_cache.len(), updated);
+
+        let snapshot: Vec<SnapshotMeta> = new_cache
+            .values()
+            .flat_map(|entry| entry.archives.iter().cloned().map(SnapshotMeta::new))
+            .collect();
+
+        if let Some(path) = &self.cache_file {
+            save_cache(path, &new_cache).await?;
+        }
 
         progress.finish_with_message("done");
 
@@ -139,3 +261,29 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Dart {
         Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_package() {
+        let data = br#"{
+  "name": "path",
+  "versions": [
+    {"version": "1.8.0", "archive_url": "https://pub.dev/packages/path/versions/1.8.0.tar.gz"},
+    {"version": "1.9.0", "archive_url": "https://pub.dev/packages/path/versions/1.9.0.tar.gz"}
+  ]
+}"#;
+        let entry = parse_package("https://pub.dev/", data).unwrap();
+        assert!(entry.is_complete());
+        assert_eq!(
+            entry.archives,
+            vec![
+                "packages/path/versions/1.8.0.tar.gz".to_string(),
+                "packages/path/versions/1.9.0.tar.gz".to_string()
+            ]
+        );
+        assert!(parse_package("https://example.com/", data).is_err());
+    }
+}
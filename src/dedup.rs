@@ -0,0 +1,849 @@
+//! Chunk-level dedup
+//!
+//! `DedupTarget` is a wrapper on target storages. Objects matching
+//! `--dedup-pattern` and larger than `--dedup-min-size` (e.g. daily nightly
+//! artifacts which change little) are split into content-defined chunks.
+//! Only chunks not yet in the chunk store are uploaded, to
+//! `{dedup_prefix}/{xx}/{sha256}`. A reconstruction index, listing chunks of
+//! the object in order, is uploaded to `{dedup_prefix}/index/{key}.json`.
+//!
+//! By default, the object itself is still uploaded as a whole, so that it is
+//! served at its own URL. This mode only publishes indexes and chunks, and
+//! doesn't save any upload bandwidth. What it saves is repeated downloads
+//! of clients: those holding a previous version may fetch the index, and
+//! download only chunks they don't have.
+//!
+//! With `--dedup-chunks-only`, the object itself is not stored, so that
+//! uploads only move changed chunks. Such objects can't be served over
+//! plain HTTP, and are only rebuilt from their chunks when read through
+//! `SourceStorage` of the wrapper.
+//!
+//! In both modes, objects are still downloaded from upstream as a whole, as
+//! upstreams serve nothing but whole objects. Chunks are only computed after
+//! the object is downloaded into buffer.
+//!
+//! Chunk boundaries are found with a gear rolling hash, so that inserting or
+//! removing bytes only changes chunks around the edit.
+//!
+//! When taking snapshot of target, all indexes are read to find chunks in
+//! use. Indexes and chunks in use are hidden from snapshot. Indexes of
+//! removed objects and chunks no longer referenced by any index are left in
+//! snapshot, so that they are deleted by transfer like other objects. Chunks
+//! referenced by objects uploaded in the same run are never deleted.
+//!
+//! With `--dedup-chunks-only`, objects are listed with metadata kept in
+//! their indexes instead. If an object is stored both as is and as an
+//! index, the former is kept and the index is deleted.
+//!
+//! Dedup is only applied to snapshots with `SnapshotMeta`. Otherwise, the
+//! wrapper simply forwards everything to the target.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use slog::{debug, info, warn};
+use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::simple_diff_transfer::KeepFilter;
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Key, SnapshotStorage, SourceStorage, TargetStorage};
+use crate::utils::{hash_string, unix_time};
+
+/// Chunks won't be smaller than this size, except the last one.
+const MIN_CHUNK: usize = 1 << 19;
+/// Chunks won't be larger than this size.
+const MAX_CHUNK: usize = 1 << 23;
+/// A boundary is found when top 21 bits of hash are zero, so that chunks are
+/// about 2 MiB on average.
+const CHUNK_MASK: u64 = !0 << 43;
+
+/// Random numbers for gear hash, generated from a fixed seed, so that chunk
+/// boundaries are stable across runs.
+static GEAR: Lazy<[u64; 256]> = Lazy::new(|| {
+    let mut state: u64 = 0x6d69_7272_6f72_636c;
+    let mut table = [0; 256];
+    for item in table.iter_mut() {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *item = z ^ (z >> 31);
+    }
+    table
+});
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct DedupConfig {
+    #[structopt(
+        long,
+        help = "Publish chunks and reconstruction indexes of objects whose key matches this regex, along with the objects unless --dedup-chunks-only"
+    )]
+    pub dedup_pattern: Option<String>,
+    #[structopt(
+        long,
+        help = "Only deduplicate objects larger than this size (in bytes)",
+        default_value = "16777216"
+    )]
+    pub dedup_min_size: u64,
+    #[structopt(long, help = "Prefix of chunk store", default_value = "_chunks")]
+    pub dedup_prefix: String,
+    #[structopt(
+        long,
+        help = "Store deduplicated objects only as chunks and index, without the object itself, so that only changed chunks are uploaded. Such objects can only be read back by mirror-clone"
+    )]
+    pub dedup_chunks_only: bool,
+}
+
+impl DedupConfig {
+    /// Keys in chunk store are never kept by `keep`, so that unreferenced
+    /// chunks and stale indexes are still deleted.
+    pub fn keep_filter(&self, keep: KeepFilter) -> KeepFilter {
+        if self.dedup_pattern.is_none() {
+            return keep;
+        }
+        let prefix = format!("{}/", self.dedup_prefix);
+        let KeepFilter(keep) = keep;
+        KeepFilter(Arc::new(move |key, size| {
+            !key.starts_with(&prefix) && keep(key, size)
+        }))
+    }
+}
+
+/// Reconstruction index of a deduplicated object.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkIndex {
+    pub size: u64,
+    pub modified_at: u64,
+    pub checksum_method: Option<String>,
+    pub checksum: Option<String>,
+    pub content_type: Option<String>,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Split data from `reader` into content-defined chunks, calling `f` on each chunk.
+fn split_chunks(mut reader: impl Read, mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+    let mut chunk = Vec::with_capacity(MAX_CHUNK);
+    let mut buf = vec![0; 1 << 16];
+    let mut hash: u64 = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in &buf[..n] {
+            chunk.push(byte);
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if (chunk.len() >= MIN_CHUNK && hash & CHUNK_MASK == 0) || chunk.len() >= MAX_CHUNK {
+                f(&chunk)?;
+                chunk.clear();
+                hash = 0;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        f(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Chunks of a file.
+struct SplitFile {
+    chunks: Vec<Chunk>,
+    /// chunks not in chunk store, and their buffer files
+    new_chunks: Vec<(Chunk, PathBuf)>,
+}
+
+/// Split a file into chunks. Chunks not in `known` are written to buffer path.
+fn split_file(path: &Path, buffer_path: &str, known: &Mutex<HashSet<String>>) -> Result<SplitFile> {
+    let mut chunks = vec![];
+    let mut new_chunks: Vec<(Chunk, PathBuf)> = vec![];
+    let mut seen = HashSet::new();
+    let file = std::fs::File::open(path)?;
+    split_chunks(std::io::BufReader::new(file), |data| {
+        let chunk = Chunk {
+            sha256: format!("{:x}", sha2::Sha256::digest(data)),
+            size: data.len() as u64,
+        };
+        let is_new =
+            !known.lock().unwrap().contains(&chunk.sha256) && seen.insert(chunk.sha256.clone());
+        if is_new {
+            let chunk_path = Path::new(buffer_path).join(format!(
+                "{}.{}.chunk.buffer",
+                chunk.sha256,
+                hash_string(&path.to_string_lossy())
+            ));
+            std::fs::File::create(&chunk_path)?.write_all(data)?;
+            new_chunks.push((chunk.clone(), chunk_path));
+        }
+        chunks.push(chunk);
+        Ok(())
+    })?;
+    Ok(SplitFile { chunks, new_chunks })
+}
+
+pub struct DedupTarget<Target> {
+    target: Target,
+    config: DedupConfig,
+    matcher: Option<Regex>,
+    buffer_path: Option<String>,
+    /// sha256 of chunks referenced by indexes
+    chunks: Arc<Mutex<HashSet<String>>>,
+    /// keys of objects with index
+    indexed: Mutex<HashSet<String>>,
+    /// keys of objects matching pattern, but stored as is, only used with
+    /// `--dedup-chunks-only`
+    plain: Mutex<HashSet<String>>,
+}
+
+impl<Target> DedupTarget<Target> {
+    pub fn new(target: Target, config: DedupConfig, buffer_path: Option<String>) -> Result<Self> {
+        let matcher = match &config.dedup_pattern {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|err| {
+                Error::ConfigureError(format!("invalid dedup pattern {}: {}", pattern, err))
+            })?),
+            None => None,
+        };
+        Ok(Self {
+            target,
+            config,
+            matcher,
+            buffer_path,
+            chunks: Arc::new(Mutex::new(HashSet::new())),
+            indexed: Mutex::new(HashSet::new()),
+            plain: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        match &self.matcher {
+            Some(matcher) => {
+                !key.starts_with(&format!("{}/", self.config.dedup_prefix)) && matcher.is_match(key)
+            }
+            None => false,
+        }
+    }
+
+    fn chunk_key(&self, sha256: &str) -> String {
+        format!("{}/{}/{}", self.config.dedup_prefix, &sha256[..2], sha256)
+    }
+
+    fn index_key(&self, key: &str) -> String {
+        format!("{}/index/{}.json", self.config.dedup_prefix, key)
+    }
+
+    fn buffer_path(&self) -> Result<String> {
+        self.buffer_path
+            .clone()
+            .ok_or_else(|| Error::ConfigureError("buffer path is required for dedup".to_string()))
+    }
+
+    /// Split a local file into chunks.
+    async fn split_file(&self, path: PathBuf) -> Result<SplitFile> {
+        let buffer_path = self.buffer_path()?;
+        let known = self.chunks.clone();
+        tokio::task::spawn_blocking(move || split_file(&path, &buffer_path, &known))
+            .await
+            .map_err(|err| Error::ProcessError(format!("error while chunking: {:?}", err)))?
+    }
+}
+
+impl<Target> DedupTarget<Target>
+where
+    Target: SourceStorage<SnapshotMeta, ByteStream>,
+{
+    /// Read an index from target.
+    async fn read_index(&self, index: &SnapshotMeta, mission: &Mission) -> Result<ChunkIndex> {
+        let mut stream = self.target.get_object(index, mission).await?;
+        let mut body = stream.object.as_stream();
+        let mut data = vec![];
+        while let Some(bytes) = body.next().await {
+            data.extend_from_slice(&bytes?);
+        }
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Rebuild a deduplicated object from its chunks into a buffer file.
+    async fn rebuild(&self, key: &str, mission: &Mission) -> Result<ByteStream> {
+        let buffer_path = self.buffer_path()?;
+        let index = self
+            .read_index(&SnapshotMeta::new(self.index_key(key)), mission)
+            .await?;
+        let path = Path::new(&buffer_path).join(format!(
+            "{}.{}.rebuild.buffer",
+            hash_string(key),
+            unix_time()
+        ));
+        // the buffer file is removed when returning early on errors
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(path.clone()),
+        };
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&path).await?);
+        for chunk in &index.chunks {
+            let meta = SnapshotMeta::new(self.chunk_key(&chunk.sha256));
+            let mut body = self
+                .target
+                .get_object(&meta, mission)
+                .await?
+                .object
+                .as_stream();
+            let mut size = 0;
+            while let Some(bytes) = body.next().await {
+                let bytes = bytes?;
+                size += bytes.len() as u64;
+                file.write_all(&bytes).await?;
+            }
+            if size != chunk.size {
+                return Err(Error::ProcessError(format!(
+                    "chunk {} of {}: expect {} bytes, got {}",
+                    chunk.sha256, key, chunk.size, size
+                )));
+            }
+        }
+        file.flush().await?;
+        drop(file);
+        if let ByteObject::LocalFile { file, .. } = &mut object {
+            *file = Some(tokio::fs::File::open(&path).await?);
+        }
+        Ok(ByteStream {
+            object,
+            length: index.size,
+            modified_at: index.modified_at,
+            content_type: index.content_type,
+            checksum: None,
+            etag: None,
+        })
+    }
+}
+
+async fn local_stream(path: PathBuf, length: u64, modified_at: u64) -> Result<ByteStream> {
+    let file = tokio::fs::File::open(&path).await?;
+    Ok(ByteStream {
+        object: ByteObject::LocalFile {
+            file: Some(file),
+            path: Some(path),
+        },
+        length,
+        modified_at,
+        content_type: None,
+        checksum: None,
+        etag: None,
+    })
+}
+
+#[async_trait]
+impl<Target> SnapshotStorage<SnapshotMeta> for DedupTarget<Target>
+where
+    Target: SnapshotStorage<SnapshotMeta> + SourceStorage<SnapshotMeta, ByteStream>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger.clone();
+        let snapshot = self.target.snapshot(mission.clone(), config).await?;
+        if self.matcher.is_none() {
+            return Ok(snapshot);
+        }
+
+        let chunk_prefix = format!("{}/", self.config.dedup_prefix);
+        let index_prefix = format!("{}/index/", self.config.dedup_prefix);
+        let mut stored_chunks = HashMap::new();
+        let mut indexes = vec![];
+        let mut result = vec![];
+        for meta in snapshot {
+            if let Some(key) = meta.key.strip_prefix(&index_prefix) {
+                match key.strip_suffix(".json") {
+                    Some(key) => indexes.push((key.to_string(), meta)),
+                    None => result.push(meta),
+                }
+            } else if let Some(chunk) = meta.key.strip_prefix(&chunk_prefix) {
+                let sha256 = chunk.rsplit('/').next().unwrap_or_default().to_string();
+                stored_chunks.insert(sha256, meta);
+            } else {
+                result.push(meta);
+            }
+        }
+
+        // indexes of removed objects, or of objects stored as is with
+        // `--dedup-chunks-only`, are deleted with transfer
+        let chunks_only = self.config.dedup_chunks_only;
+        let plain: HashSet<String> = result
+            .iter()
+            .filter(|meta| self.matches(&meta.key))
+            .map(|meta| meta.key.clone())
+            .collect();
+        let (indexes, stale): (Vec<_>, Vec<_>) = indexes
+            .into_iter()
+            .partition(|(key, _)| plain.contains(key) != chunks_only);
+        result.extend(stale.into_iter().map(|(_, meta)| meta));
+
+        let this = &*self;
+        let mission = &mission;
+        let mut reads = stream::iter(indexes.clone())
+            .map(|(key, meta)| async move {
+                this.read_index(&meta, mission)
+                    .await
+                    .map(|index| (key, index))
+            })
+            .buffer_unordered(config.concurrent_resolve);
+        let mut chunks = HashSet::new();
+        while let Some(read) = reads.next().await {
+            let (key, index) = read?;
+            if chunks_only {
+                result.push(SnapshotMeta {
+                    key,
+                    size: Some(index.size),
+                    last_modified: Some(index.modified_at),
+                    checksum_method: index.checksum_method,
+                    checksum: index.checksum,
+                    content_type: index.content_type,
+                    ..Default::default()
+                });
+            }
+            chunks.extend(index.chunks.into_iter().map(|chunk| chunk.sha256));
+        }
+        drop(reads);
+
+        // chunks no longer referenced are deleted with transfer
+        let mut orphans = 0;
+        for (sha256, meta) in stored_chunks {
+            if !chunks.contains(&sha256) {
+                orphans += 1;
+                result.push(meta);
+            }
+        }
+
+        info!(
+            logger,
+            "{} deduplicated objects, {} chunks, {} unreferenced",
+            indexes.len(),
+            chunks.len(),
+            orphans
+        );
+        *self.chunks.lock().unwrap() = chunks;
+        *self.indexed.lock().unwrap() = indexes.into_iter().map(|(key, _)| key).collect();
+        *self.plain.lock().unwrap() = plain;
+
+        Ok(result)
+    }
+
+    fn info(&self) -> String {
+        format!("dedup <{}>, {:?}", self.target.info(), self.config)
+    }
+}
+
+#[async_trait]
+impl<Target> TargetStorage<SnapshotMeta, ByteStream> for DedupTarget<Target>
+where
+    Target: TargetStorage<SnapshotMeta, ByteStream>,
+{
+    async fn put_object(
+        &self,
+        snapshot: &SnapshotMeta,
+        item: ByteStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        let key = snapshot.key();
+        let dedup = self.matches(key) && item.length >= self.config.dedup_min_size;
+        let path = match &item.object {
+            ByteObject::LocalFile {
+                path: Some(path), ..
+            } if dedup => Some(path.clone()),
+            ByteObject::Stream(_) if dedup => {
+                warn!(
+                    mission.logger,
+                    "{}: streamed object can't be chunked, stored as is", key
+                );
+                None
+            }
+            _ => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => {
+                self.target.put_object(snapshot, item, mission).await?;
+                if self.matches(key) {
+                    self.plain.lock().unwrap().insert(key.to_string());
+                }
+                if self.indexed.lock().unwrap().remove(key) {
+                    let index = SnapshotMeta::new(self.index_key(key));
+                    self.target.delete_object(&index, mission).await?;
+                }
+                return Ok(());
+            }
+        };
+
+        let length = item.length;
+        let modified_at = item.modified_at;
+        let content_type = item.content_type.clone();
+        let SplitFile { chunks, new_chunks } = self.split_file(path).await?;
+
+        // wrap all chunks first, so that buffer files are removed on failure
+        let mut streams = vec![];
+        for (chunk, path) in new_chunks {
+            let stream = local_stream(path, chunk.size, modified_at).await?;
+            streams.push((chunk, stream));
+        }
+        debug!(
+            mission.logger,
+            "{}: {} chunks, {} new",
+            key,
+            chunks.len(),
+            streams.len()
+        );
+        for (chunk, stream) in streams {
+            let meta = SnapshotMeta {
+                key: self.chunk_key(&chunk.sha256),
+                size: Some(chunk.size),
+                ..Default::default()
+            };
+            self.target.put_object(&meta, stream, mission).await?;
+            self.chunks.lock().unwrap().insert(chunk.sha256);
+        }
+
+        // chunks of this object are in use, even if they were unreferenced
+        self.chunks
+            .lock()
+            .unwrap()
+            .extend(chunks.iter().map(|chunk| chunk.sha256.clone()));
+        if self.config.dedup_chunks_only {
+            // chunks are all stored, the object itself is no longer needed
+            drop(item);
+        } else {
+            self.target.put_object(snapshot, item, mission).await?;
+        }
+
+        let index = serde_json::to_vec(&ChunkIndex {
+            size: length,
+            modified_at,
+            checksum_method: snapshot.checksum_method.clone(),
+            checksum: snapshot.checksum.clone(),
+            content_type,
+            chunks,
+        })?;
+        let path =
+            Path::new(&self.buffer_path()?).join(format!("{}.index.buffer", hash_string(key)));
+        tokio::fs::write(&path, &index).await?;
+        let mut stream = local_stream(path, index.len() as u64, modified_at).await?;
+        stream.content_type = Some("application/json".to_string());
+        let meta = SnapshotMeta {
+            key: self.index_key(key),
+            size: Some(index.len() as u64),
+            last_modified: Some(modified_at),
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        };
+        self.target.put_object(&meta, stream, mission).await?;
+        self.indexed.lock().unwrap().insert(key.to_string());
+        if self.config.dedup_chunks_only && self.plain.lock().unwrap().remove(key) {
+            self.target.delete_object(snapshot, mission).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<()> {
+        let key = snapshot.key();
+        if let Some(chunk) = key.strip_prefix(&format!("{}/", self.config.dedup_prefix)) {
+            let sha256 = chunk.rsplit('/').next().unwrap_or_default();
+            if self.chunks.lock().unwrap().contains(sha256) {
+                debug!(mission.logger, "keep chunk {} in use", key);
+                return Ok(());
+            }
+        }
+        if !self.config.dedup_chunks_only {
+            self.target.delete_object(snapshot, mission).await?;
+        }
+        if self.indexed.lock().unwrap().remove(key) {
+            let index = SnapshotMeta::new(self.index_key(key));
+            return self.target.delete_object(&index, mission).await;
+        }
+        if self.config.dedup_chunks_only {
+            self.plain.lock().unwrap().remove(key);
+            self.target.delete_object(snapshot, mission).await?;
+        }
+        Ok(())
+    }
+
+    async fn update_metadata(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<()> {
+        // metadata of objects stored only as chunks is kept in index, which
+        // is rewritten with a full update
+        if self.config.dedup_chunks_only && self.indexed.lock().unwrap().contains(snapshot.key()) {
+            return Err(Error::ProcessError(format!(
+                "{}: metadata of deduplicated object can't be updated in place",
+                snapshot.key()
+            )));
+        }
+        self.target.update_metadata(snapshot, mission).await
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>> {
+        self.target.available_space().await
+    }
+}
+
+#[async_trait]
+impl<Target> SourceStorage<SnapshotMeta, ByteStream> for DedupTarget<Target>
+where
+    Target: SourceStorage<SnapshotMeta, ByteStream>,
+{
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        if self.config.dedup_chunks_only && self.indexed.lock().unwrap().contains(snapshot.key()) {
+            return self.rebuild(snapshot.key(), mission).await;
+        }
+        self.target.get_object(snapshot, mission).await
+    }
+}
+
+#[async_trait]
+impl<Target> SnapshotStorage<SnapshotPath> for DedupTarget<Target>
+where
+    Target: SnapshotStorage<SnapshotPath>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        self.target.snapshot(mission, config).await
+    }
+
+    fn info(&self) -> String {
+        self.target.info()
+    }
+}
+
+#[async_trait]
+impl<Target> TargetStorage<SnapshotPath, ByteStream> for DedupTarget<Target>
+where
+    Target: TargetStorage<SnapshotPath, ByteStream>,
+{
+    async fn put_object(
+        &self,
+        snapshot: &SnapshotPath,
+        item: ByteStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        self.target.put_object(snapshot, item, mission).await
+    }
+
+    async fn delete_object(&self, snapshot: &SnapshotPath, mission: &Mission) -> Result<()> {
+        self.target.delete_object(snapshot, mission).await
+    }
+
+    async fn update_metadata(&self, snapshot: &SnapshotPath, mission: &Mission) -> Result<()> {
+        self.target.update_metadata(snapshot, mission).await
+    }
+
+    async fn available_space(&self) -> Result<Option<u64>> {
+        self.target.available_space().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{RngCore, SeedableRng};
+
+    fn chunks_of(data: &[u8]) -> Vec<String> {
+        let mut chunks = vec![];
+        split_chunks(data, |chunk| {
+            assert!(chunk.len() <= MAX_CHUNK);
+            chunks.push(format!("{:x}", sha2::Sha256::digest(chunk)));
+            Ok(())
+        })
+        .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn test_split_chunks() {
+        let mut data = vec![0; 16 << 20];
+        rand::rngs::StdRng::seed_from_u64(42).fill_bytes(&mut data);
+        let chunks = chunks_of(&data);
+        assert!(chunks.len() > 2);
+
+        // insert some bytes in the middle, only chunks around are changed
+        let mut edited = data.clone();
+        edited.splice(8 << 20..8 << 20, b"nightly".iter().cloned());
+        let edited = chunks_of(&edited);
+        let changed = edited.iter().filter(|x| !chunks.contains(x)).count();
+        assert!(
+            changed <= 2,
+            "{} of {} chunks changed",
+            changed,
+            edited.len()
+        );
+    }
+
+    fn mission() -> Mission {
+        Mission {
+            progress: indicatif::ProgressBar::hidden(),
+            client: reqwest::Client::new(),
+            logger: crate::utils::create_logger(0),
+            accounting: Default::default(),
+            breaker: Arc::new(crate::circuit_breaker::CircuitBreaker::new(
+                0,
+                std::time::Duration::from_secs(0),
+            )),
+            throttle: Arc::new(crate::throttle::Throttle::new(None)),
+            direct_stream: None,
+            range: Default::default(),
+            validators: None,
+        }
+    }
+
+    /// Put a random 4 MiB object to `nightly/a.tar` of a file backend in
+    /// `dir/base`.
+    async fn put_nightly(
+        dir: &Path,
+        chunks_only: bool,
+    ) -> (
+        DedupTarget<crate::file_backend::FileBackend>,
+        SnapshotMeta,
+        Vec<u8>,
+    ) {
+        let base = dir.join("base");
+        let buffer = dir.join("buffer");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::create_dir_all(&buffer).unwrap();
+        let buffer_path = buffer.to_string_lossy().to_string();
+        let mut backend = crate::file_backend::FileBackend::new(base.to_string_lossy().to_string());
+        backend.buffer_path = Some(buffer_path.clone());
+        let config = DedupConfig {
+            dedup_pattern: Some("^nightly/".to_string()),
+            dedup_min_size: 0,
+            dedup_prefix: "_chunks".to_string(),
+            dedup_chunks_only: chunks_only,
+        };
+        let target = DedupTarget::new(backend, config, Some(buffer_path)).unwrap();
+
+        let mut data = vec![0; 4 << 20];
+        rand::rngs::StdRng::seed_from_u64(42).fill_bytes(&mut data);
+        let path = buffer.join("a.buffer");
+        std::fs::write(&path, &data).unwrap();
+        let snapshot = SnapshotMeta {
+            key: "nightly/a.tar".to_string(),
+            size: Some(data.len() as u64),
+            checksum_method: Some("sha256".to_string()),
+            checksum: Some(format!("{:x}", sha2::Sha256::digest(&data))),
+            ..Default::default()
+        };
+        let item = local_stream(path, data.len() as u64, 42).await.unwrap();
+        target
+            .put_object(&snapshot, item, &mission())
+            .await
+            .unwrap();
+        (target, snapshot, data)
+    }
+
+    async fn list(target: &mut DedupTarget<crate::file_backend::FileBackend>) -> Vec<SnapshotMeta> {
+        target
+            .snapshot(
+                mission(),
+                &SnapshotConfig {
+                    concurrent_resolve: 4,
+                },
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_store_with_object() {
+        let dir = std::env::temp_dir().join(format!("dedup-{}", unix_time()));
+        let (mut target, snapshot, data) = put_nightly(&dir, false).await;
+
+        // the object is served at its own URL, along with chunks and index
+        let base = dir.join("base");
+        assert_eq!(std::fs::read(base.join("nightly/a.tar")).unwrap(), data);
+        assert!(base.join("_chunks/index/nightly/a.tar.json").exists());
+
+        // chunks and index in use are hidden from snapshot
+        let listed = list(&mut target).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, snapshot.key);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_chunks_only() {
+        let dir = std::env::temp_dir().join(format!("dedup-chunks-only-{}", unix_time()));
+        let (mut target, snapshot, data) = put_nightly(&dir, true).await;
+
+        // only chunks and index are stored
+        let base = dir.join("base");
+        assert!(!base.join("nightly/a.tar").exists());
+        assert!(base.join("_chunks/index/nightly/a.tar.json").exists());
+
+        // the object is listed with metadata in index
+        let listed = list(&mut target).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, snapshot.key);
+        assert_eq!(listed[0].size, snapshot.size);
+        assert_eq!(listed[0].checksum, snapshot.checksum);
+
+        // and rebuilt from chunks
+        let mut rebuilt = target.get_object(&listed[0], &mission()).await.unwrap();
+        assert_eq!(rebuilt.length, data.len() as u64);
+        let mut body = rebuilt.object.as_stream();
+        let mut content = vec![];
+        while let Some(bytes) = body.next().await {
+            content.extend_from_slice(&bytes.unwrap());
+        }
+        assert!(content == data);
+        drop(rebuilt);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_filter() {
+        let config = DedupConfig {
+            dedup_pattern: Some("^nightly/".to_string()),
+            dedup_min_size: 0,
+            dedup_prefix: "_chunks".to_string(),
+            dedup_chunks_only: false,
+        };
+        let KeepFilter(keep) = config.keep_filter(KeepFilter(Arc::new(|_, _| true)));
+        assert!(keep("stable/a.tar", None));
+        assert!(!keep("_chunks/ab/abcd", Some(1)));
+        assert!(!keep("_chunks/index/nightly/a.tar.json", Some(1)));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let config = DedupConfig {
+            dedup_pattern: Some("nightly-(".to_string()),
+            dedup_min_size: 0,
+            dedup_prefix: "_chunks".to_string(),
+            dedup_chunks_only: false,
+        };
+        assert!(matches!(
+            DedupTarget::new((), config, None),
+            Err(Error::ConfigureError(_))
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+//! Distribution image source
+//!
+//! Distribution image source mirrors image trees of distributions, such as
+//! Ubuntu cloud images and Debian CDs. Such trees consist of releases, and
+//! each release contains several image sets (e.g. `release-20240101`),
+//! which are listed in `SHA256SUMS` files.
+//!
+//! This source lists image sets of every release from the HTML index, and
+//! only keeps the latest `sets_to_retain` of them. Images are yielded with
+//! sha256 checksums from `SHA256SUMS`, so that they could be verified with
+//! `ChecksumPipe`. Checksum files and their signatures are always
+//! transferred at the end.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use regex::Regex;
+use reqwest::Client;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct DistroImage {
+    #[structopt(long, help = "Base of image tree")]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "Release directories under base, use `.` for base itself",
+        required = true,
+        min_values = 1
+    )]
+    pub releases: Vec<String>,
+    #[structopt(
+        long,
+        help = "Pattern of image set directories in a release",
+        default_value = r"^release-\d{8}(\.\d+)?$"
+    )]
+    pub set_pattern: String,
+    #[structopt(
+        long,
+        help = "Image sets to retain in each release",
+        default_value = "3"
+    )]
+    pub sets_to_retain: usize,
+    #[structopt(
+        long,
+        help = "Paths of checksum files in an image set",
+        default_value = "SHA256SUMS"
+    )]
+    pub sums: Vec<String>,
+}
+
+/// Signatures of checksum files that may be published along with them.
+const SIGNATURE_SUFFIXES: &[&str] = &[".gpg", ".sign", ".asc"];
+
+/// Join non-empty path segments with `/`.
+fn join_path(segments: &[&str]) -> String {
+    segments
+        .iter()
+        .map(|segment| segment.trim_matches('/'))
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Key for sorting image sets, which compares numbers in names.
+fn set_order(name: &str) -> (Vec<u64>, String) {
+    let numbers = name
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().unwrap_or(u64::MAX))
+        .collect();
+    (numbers, name.to_string())
+}
+
+/// Select latest image sets from hrefs of an HTML index.
+pub fn latest_sets(hrefs: &[String], pattern: &Regex, sets_to_retain: usize) -> Vec<String> {
+    let mut sets: Vec<String> = hrefs
+        .iter()
+        .map(|href| href.trim_end_matches('/').to_string())
+        .filter(|href| pattern.is_match(href))
+        .collect();
+    sets.sort_by_key(|set| set_order(set));
+    sets.dedup();
+    let skip = sets.len().saturating_sub(sets_to_retain);
+    sets.split_off(skip)
+}
+
+/// Parse a `SHA256SUMS` file in GNU coreutils format, returning (checksum, file).
+pub fn parse_sums(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (checksum, file) = line.trim().split_once(' ')?;
+            let file = file.trim_start().trim_start_matches('*');
+            if checksum.len() == 64 && !file.is_empty() {
+                Some((
+                    checksum.to_lowercase(),
+                    file.trim_start_matches("./").to_string(),
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Generate snapshot of a checksum file `{dir}/{sums}` with its images and signatures.
+async fn sums_snapshot(
+    client: &Client,
+    base: &str,
+    dir: &str,
+    sums: &str,
+) -> Result<Vec<SnapshotMeta>> {
+    let sums_key = join_path(&[dir, sums]);
+    let sums_dir = join_path(&[dir, sums.rsplit_once('/').map_or("", |x| x.0)]);
+    let content = fetch_text(client, &format!("{}/{}", base, sums_key)).await?;
+
+    let mut snapshot: Vec<SnapshotMeta> = parse_sums(&content)
+        .into_iter()
+        .map(|(checksum, file)| SnapshotMeta {
+            key: join_path(&[&sums_dir, &file]),
+            checksum_method: Some("sha256".to_string()),
+            checksum: Some(checksum),
+            ..Default::default()
+        })
+        .collect();
+
+    for suffix in SIGNATURE_SUFFIXES {
+        let signature = format!("{}{}", sums_key, suffix);
+        let response = client
+            .head(format!("{}/{}", base, signature))
+            .send()
+            .timeout(Duration::from_secs(60))
+            .await
+            .into_result()?;
+        if response.status().is_success() {
+            snapshot.push(SnapshotMeta::force(signature));
+        }
+    }
+    snapshot.push(SnapshotMeta::force(sums_key));
+
+    Ok(snapshot)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for DistroImage {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let pattern = Regex::new(&self.set_pattern)
+            .map_err(|err| Error::ConfigureError(format!("invalid set pattern: {:?}", err)))?;
+        let href = Regex::new(r#"<a[^>]*href="([^"]*)""#).unwrap();
+
+        let mut sets = vec![];
+        for release in &self.releases {
+            info!(logger, "listing image sets of {}...", release);
+            progress.set_message(release);
+            let release_dir = join_path(&[release]);
+            let url = if release_dir.is_empty() {
+                format!("{}/", self.base)
+            } else {
+                format!("{}/{}/", self.base, release_dir)
+            };
+            let index = fetch_text(&client, &url).await?;
+            let hrefs: Vec<String> = href
+                .captures_iter(&index)
+                .map(|cap| cap[1].to_string())
+                .collect();
+            let latest = latest_sets(&hrefs, &pattern, self.sets_to_retain);
+            if latest.is_empty() {
+                warn!(logger, "no image set found in {}", release);
+            }
+            info!(logger, "{}: {:?}", release, latest);
+            sets.extend(
+                latest
+                    .into_iter()
+                    .map(|set| join_path(&[&release_dir, &set])),
+            );
+        }
+
+        let sums: Vec<(String, String)> = sets
+            .iter()
+            .flat_map(|set| {
+                self.sums
+                    .iter()
+                    .map(move |sums| (set.clone(), sums.clone()))
+            })
+            .collect();
+        let snapshots: Vec<Vec<SnapshotMeta>> =
+            stream::iter(sums.into_iter().map(|(set, sums)| {
+                let client = client.clone();
+                let base = self.base.clone();
+                let logger = logger.clone();
+                let progress = progress.clone();
+                async move {
+                    progress.set_message(&set);
+                    // images of a set would all be deleted if its checksum
+                    // file were skipped, so the whole snapshot fails instead
+                    sums_snapshot(&client, &base, &set, &sums)
+                        .await
+                        .map_err(|err| {
+                            warn!(logger, "failed to fetch {}/{}: {:?}", set, sums, err);
+                            err
+                        })
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        progress.finish_with_message("done");
+
+        Ok(snapshots.into_iter().flatten().collect())
+    }
+
+    fn info(&self) -> String {
+        format!("distro_image, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for DistroImage {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_sets() {
+        let hrefs: Vec<String> = [
+            "../",
+            "release/",
+            "release-20240110/",
+            "release-20240102.1/",
+            "release-20240102/",
+            "release-20231231/",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let pattern = Regex::new(r"^release-\d{8}(\.\d+)?$").unwrap();
+        assert_eq!(
+            latest_sets(&hrefs, &pattern, 2),
+            vec!["release-20240102.1", "release-20240110"]
+        );
+    }
+
+    #[test]
+    fn test_parse_sums() {
+        let sums = "\
+2d5b2e5e8f7f3a8a4a0f36c3b3d7c1e0e1f5e8d0b6a2c9e7f4d3c2b1a0f9e8d7 *jammy-server-cloudimg-amd64.img
+2D5B2E5E8F7F3A8A4A0F36C3B3D7C1E0E1F5E8D0B6A2C9E7F4D3C2B1A0F9E8D7  ./debian-12.5.0-amd64-netinst.iso
+not a checksum line
+";
+        assert_eq!(
+            parse_sums(sums),
+            vec![
+                (
+                    "2d5b2e5e8f7f3a8a4a0f36c3b3d7c1e0e1f5e8d0b6a2c9e7f4d3c2b1a0f9e8d7".to_string(),
+                    "jammy-server-cloudimg-amd64.img".to_string()
+                ),
+                (
+                    "2d5b2e5e8f7f3a8a4a0f36c3b3d7c1e0e1f5e8d0b6a2c9e7f4d3c2b1a0f9e8d7".to_string(),
+                    "debian-12.5.0-amd64-netinst.iso".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            join_path(&["jammy", ".", "release-20240110/"]),
+            "jammy/release-20240110"
+        );
+    }
+}
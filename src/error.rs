@@ -24,10 +24,24 @@ pub enum Error {
     ConfigureError(String),
     #[error("HTTP Error {0}")]
     HTTPError(reqwest::StatusCode),
+    #[error("HTTP Error {0}, retry after {1:?}")]
+    RetryAfter(reqwest::StatusCode, std::time::Duration),
     #[error("Pipe Error {0}")]
     PipeError(String),
+    #[error("Circuit Open {0}")]
+    CircuitOpen(String),
+    #[error("Not Modified {0}")]
+    NotModified(String),
+    #[error("Absent {0}")]
+    Absent(String),
     #[error("Json Decode Error {0}")]
     JsonDecodeError(#[from] serde_json::Error),
+    #[error("Msgpack Decode Error {0}")]
+    MsgpackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("Protobuf Decode Error {0}")]
+    ProtobufDecodeError(#[from] prost::DecodeError),
+    #[error("Toml Decode Error {0}")]
+    TomlDecodeError(#[from] toml::de::Error),
     #[error("Yaml Decode Error {0}")]
     YamlDecodeError(#[from] serde_yaml::Error),
     #[error("Datetime Parse Error {0}")]
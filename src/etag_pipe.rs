@@ -0,0 +1,226 @@
+//! EtagPipe fills expected S3 ETags of source items.
+//!
+//! Objects uploaded by `PutObject` without KMS encryption have MD5 of their
+//! content as ETag. An `EtagPipe` is a wrapper on sources, which sets
+//! expected ETag of snapshot items with MD5 checksum, so that objects changed
+//! in place upstream are detected by comparing with ETags in target
+//! snapshot, even if size and modified time are unchanged.
+//!
+//! ETags of multipart uploads are not MD5 of content, and are not compared
+//! with expected ETags, see `Diff` of `SnapshotMeta`. Neither are ETags of
+//! objects encrypted with KMS, which S3 target doesn't record in snapshot.
+//!
+//! Most sources don't provide MD5 checksums. `ComputeEtagPipe` computes
+//! expected ETags of buffered objects instead, which are attached to
+//! `ByteStream`. With `part_size`, objects larger than it get ETags of
+//! multipart uploads with parts of this size (MD5 of MD5 of all parts,
+//! followed by `-{parts}`), as uploaded by tools like aws-cli. S3 target
+//! skips uploading objects whose ETag is the same as the stored one, and only
+//! updates their metadata.
+
+use async_trait::async_trait;
+use sha2::Digest;
+use std::io::{Result as IOResult, SeekFrom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio_io_compat::CompatHelperTrait;
+
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::Result;
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
+
+pub struct EtagPipe<Source> {
+    pub source: Source,
+}
+
+impl<Source> EtagPipe<Source> {
+    pub fn new(source: Source) -> Self {
+        Self { source }
+    }
+}
+
+fn expected_etag(meta: &SnapshotMeta) -> Option<String> {
+    match (meta.checksum_method.as_deref(), &meta.checksum) {
+        (Some("md5"), Some(checksum)) => Some(checksum.to_lowercase()),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl<Source> SnapshotStorage<SnapshotMeta> for EtagPipe<Source>
+where
+    Source: SnapshotStorage<SnapshotMeta>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        for meta in snapshot.iter_mut() {
+            if meta.etag.is_none() {
+                meta.etag = expected_etag(meta);
+            }
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("expected etag <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Source, SourceItem> SourceStorage<SnapshotMeta, SourceItem> for EtagPipe<Source>
+where
+    Source: SourceStorage<SnapshotMeta, SourceItem>,
+{
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<SourceItem> {
+        self.source.get_object(snapshot, mission).await
+    }
+}
+
+/// Compute expected ETag of `source`, which is MD5 of its content, or ETag
+/// of a multipart upload if it is larger than `part_size`.
+pub async fn calc_etag(
+    source: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    length: u64,
+    part_size: Option<u64>,
+) -> IOResult<String> {
+    let orig_pos = source.seek(SeekFrom::Current(0)).await?;
+
+    let result = match part_size.filter(|part_size| *part_size > 0 && length > *part_size) {
+        None => {
+            let mut hasher = md5::Md5::new();
+            tokio::io::copy(source, &mut hasher.tokio_io_mut())
+                .await
+                .map(|_| format!("{:x}", hasher.finalize()))
+        }
+        Some(part_size) => {
+            let mut digests = md5::Md5::new();
+            let mut parts = 0;
+            let mut result = Ok(());
+            loop {
+                let mut hasher = md5::Md5::new();
+                let mut part = (&mut *source).take(part_size);
+                match tokio::io::copy(&mut part, &mut hasher.tokio_io_mut()).await {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        digests.update(hasher.finalize());
+                        parts += 1;
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            result.map(|_| format!("{:x}-{}", digests.finalize(), parts))
+        }
+    };
+
+    source.seek(SeekFrom::Start(orig_pos)).await?;
+    result
+}
+
+pub struct ComputeEtagPipe<Source> {
+    pub source: Source,
+    pub part_size: Option<u64>,
+}
+
+impl<Source> ComputeEtagPipe<Source> {
+    pub fn new(source: Source, part_size: Option<u64>) -> Self {
+        Self { source, part_size }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for ComputeEtagPipe<Source>
+where
+    Snapshot: Send + 'static,
+    Source: SnapshotStorage<Snapshot>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("compute etag <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, ByteStream> for ComputeEtagPipe<Source>
+where
+    Snapshot: Key + Metadata,
+    Source: SourceStorage<Snapshot, ByteStream>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
+        let mut source = self.source.get_object(snapshot, mission).await?;
+        if source.etag.is_some() {
+            return Ok(source);
+        }
+        let multipart = self
+            .part_size
+            .is_some_and(|part_size| part_size > 0 && source.length > part_size);
+        source.etag = match (snapshot.checksum_method(), snapshot.checksum()) {
+            // MD5 of source has been verified by checksum pipe, if any
+            (Some("md5"), Some(checksum)) if !multipart => Some(checksum.to_lowercase()),
+            _ => match &mut source.object {
+                ByteObject::LocalFile {
+                    file: Some(file), ..
+                } => Some(calc_etag(file, source.length, self.part_size).await?),
+                // streamed objects are sent without reading them twice
+                _ => None,
+            },
+        };
+        Ok(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn md5_hex(data: &[u8]) -> String {
+        format!("{:x}", md5::Md5::digest(data))
+    }
+
+    #[tokio::test]
+    async fn test_calc_etag() {
+        let data = b"0123456789";
+        let mut source = std::io::Cursor::new(data.to_vec());
+        assert_eq!(
+            calc_etag(&mut source, 10, None).await.unwrap(),
+            md5_hex(data)
+        );
+        assert_eq!(
+            calc_etag(&mut source, 10, Some(10)).await.unwrap(),
+            md5_hex(data)
+        );
+
+        // parts of 4, 4 and 2 bytes
+        let mut digests = vec![];
+        for part in data.chunks(4) {
+            digests.extend_from_slice(&md5::Md5::digest(part));
+        }
+        assert_eq!(
+            calc_etag(&mut source, 10, Some(4)).await.unwrap(),
+            format!("{}-3", md5_hex(&digests))
+        );
+        assert_eq!(source.position(), 0);
+    }
+}
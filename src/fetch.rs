@@ -0,0 +1,403 @@
+//! Fetch metadata
+//!
+//! Sources download metadata (indexes, manifests and API responses) when
+//! taking snapshots. Helpers in this module send such requests with timeout,
+//! and retry on transient errors (timeouts, connection errors, 5xx and 429
+//! responses) with exponential backoff, so that a snapshot won't fail on a
+//! single hiccup of upstream.
+//!
+//! Responses larger than `max_size`, or of an unexpected content type (e.g.
+//! an HTML error page instead of JSON) are rejected without retry.
+//!
+//! Responses may be cached by sources along with their `Validators`, which
+//! are sent with `fetch_conditional` so that unchanged responses won't be
+//! downloaded again.
+
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// timeout of sending request and of reading each chunk of response
+    pub timeout: Duration,
+    /// retries after the first attempt
+    pub retries: u32,
+    /// max size of response body
+    pub max_size: u64,
+    /// substring expected in `Content-Type` of response, e.g. `json`
+    pub content_type: Option<&'static str>,
+    /// `Accept` header of request, e.g. to negotiate a JSON index
+    pub accept: Option<&'static str>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            retries: 3,
+            max_size: 1 << 30,
+            content_type: None,
+            accept: None,
+        }
+    }
+}
+
+impl FetchOptions {
+    pub fn content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    pub fn accept(mut self, accept: &'static str) -> Self {
+        self.accept = Some(accept);
+        self
+    }
+}
+
+/// Validators of a cached response, i.e. its `ETag` and `Last-Modified`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    /// Make `request` conditional on these validators.
+    pub fn apply(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+/// Whether a request failing with `err` should be retried.
+pub fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::TimeoutError(_) | Error::RetryAfter(_, _) => true,
+        Error::HTTPError(status) => {
+            status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        Error::Reqwest(err) => !err.is_builder() && !err.is_redirect() && !err.is_status(),
+        _ => false,
+    }
+}
+
+/// Error of an unsuccessful response. 429 and 503 responses with
+/// `Retry-After` in seconds fail with `Error::RetryAfter`.
+pub fn status_error(status: StatusCode, headers: &HeaderMap) -> Error {
+    let retry_after = headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    match retry_after {
+        Some(secs)
+            if status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::SERVICE_UNAVAILABLE =>
+        {
+            Error::RetryAfter(status, Duration::from_secs(secs))
+        }
+        _ => Error::HTTPError(status),
+    }
+}
+
+/// Send a request once, returning `None` if it is not modified since
+/// `validators`.
+async fn fetch_once(
+    client: &Client,
+    url: &str,
+    options: &FetchOptions,
+    validators: Option<&Validators>,
+) -> Result<Option<(HeaderMap, Bytes)>> {
+    let mut request = client.get(url);
+    if let Some(accept) = options.accept {
+        request = request.header(reqwest::header::ACCEPT, accept);
+    }
+    if let Some(validators) = validators {
+        request = validators.apply(request);
+    }
+    let mut response = request
+        .send()
+        .timeout(options.timeout)
+        .await
+        .into_result()?;
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED && validators.is_some() {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(Error::HTTPError(status));
+    }
+
+    let headers = response.headers().clone();
+    if let Some(expected) = options.content_type {
+        let content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !content_type.contains(expected) {
+            return Err(Error::ProcessError(format!(
+                "unexpected content type of {}: expect {}, got {:?}",
+                url, expected, content_type
+            )));
+        }
+    }
+
+    let too_large = || {
+        Error::ProcessError(format!(
+            "response of {} is larger than {} bytes",
+            url, options.max_size
+        ))
+    };
+    if response.content_length().unwrap_or(0) > options.max_size {
+        return Err(too_large());
+    }
+    let mut data = BytesMut::new();
+    while let Some(chunk) = response
+        .chunk()
+        .timeout(options.timeout)
+        .await
+        .into_result()?
+    {
+        if (data.len() + chunk.len()) as u64 > options.max_size {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some((headers, data.freeze())))
+}
+
+async fn fetch_retry(
+    client: &Client,
+    url: &str,
+    options: &FetchOptions,
+    validators: Option<&Validators>,
+) -> Result<Option<(HeaderMap, Bytes)>> {
+    let mut attempt = 0;
+    loop {
+        match fetch_once(client, url, options, validators).await {
+            Err(err) if attempt < options.retries && is_transient(&err) => {
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Download `url` with options, retrying on transient errors, and return
+/// headers of the response along with its body.
+pub async fn fetch_with_headers(
+    client: &Client,
+    url: &str,
+    options: &FetchOptions,
+) -> Result<(HeaderMap, Bytes)> {
+    fetch_retry(client, url, options, None)
+        .await?
+        .ok_or(Error::HTTPError(StatusCode::NOT_MODIFIED))
+}
+
+/// Download `url` if it is modified since `validators` of a cached response,
+/// returning `None` if it is not.
+pub async fn fetch_conditional(
+    client: &Client,
+    url: &str,
+    options: &FetchOptions,
+    validators: &Validators,
+) -> Result<Option<(HeaderMap, Bytes)>> {
+    fetch_retry(client, url, options, Some(validators)).await
+}
+
+/// Download `url` with options, retrying on transient errors.
+pub async fn fetch_with(client: &Client, url: &str, options: &FetchOptions) -> Result<Bytes> {
+    Ok(fetch_with_headers(client, url, options).await?.1)
+}
+
+/// Download `url` with default options.
+pub async fn fetch(client: &Client, url: &str) -> Result<Bytes> {
+    fetch_with(client, url, &FetchOptions::default()).await
+}
+
+/// Download `url`, returning `None` if upstream responds with 404 or 410,
+/// which usually means the file is optional and not present. Other client
+/// errors (e.g. 403 or 429) are not taken as absence.
+pub async fn fetch_optional(client: &Client, url: &str) -> Result<Option<Bytes>> {
+    match fetch(client, url).await {
+        Ok(data) => Ok(Some(data)),
+        Err(Error::HTTPError(StatusCode::NOT_FOUND | StatusCode::GONE)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Check whether `url` exists with a HEAD request.
+pub async fn exists(client: &Client, url: &str) -> Result<bool> {
+    let response = client
+        .head(url)
+        .send()
+        .timeout(FetchOptions::default().timeout)
+        .await
+        .into_result()?;
+    Ok(response.status().is_success())
+}
+
+/// Download `url` as text with options.
+pub async fn fetch_text_with(client: &Client, url: &str, options: &FetchOptions) -> Result<String> {
+    let data = fetch_with(client, url, options).await?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Download `url` as text.
+pub async fn fetch_text(client: &Client, url: &str) -> Result<String> {
+    fetch_text_with(client, url, &FetchOptions::default()).await
+}
+
+/// Download `url` and deserialize it as JSON.
+pub async fn fetch_json<T: serde::de::DeserializeOwned>(client: &Client, url: &str) -> Result<T> {
+    let data = fetch(client, url).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve canned HTTP responses in order, one per connection.
+    async fn serve(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = socket.read(&mut buf).await.unwrap();
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.ok();
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    fn options() -> FetchOptions {
+        FetchOptions {
+            timeout: Duration::from_secs(5),
+            retries: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_retry() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+        ])
+        .await;
+        let client = Client::new();
+        let data = fetch_with(&client, &url, &options().content_type("json"))
+            .await
+            .unwrap();
+        assert_eq!(&data[..], b"{}");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reject() {
+        let url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 6\r\nConnection: close\r\n\r\n<html>",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let client = Client::new();
+        assert!(matches!(
+            fetch_with(&client, &url, &options().content_type("json")).await,
+            Err(Error::ProcessError(_))
+        ));
+        assert!(matches!(
+            fetch_with(&client, &url, &options()).await,
+            Err(Error::HTTPError(StatusCode::NOT_FOUND))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_optional() {
+        let url = serve(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let client = Client::new();
+        assert!(matches!(fetch_optional(&client, &url).await, Ok(None)));
+        assert!(matches!(
+            fetch_optional(&client, &url).await,
+            Err(Error::HTTPError(StatusCode::FORBIDDEN))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_conditional() {
+        let url = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let client = Client::new();
+        let (headers, data) = fetch_conditional(&client, &url, &options(), &Validators::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&data[..], b"{}");
+        let validators = Validators::from_headers(&headers);
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+        assert!(fetch_conditional(&client, &url, &options(), &validators)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_status_error() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "120".parse().unwrap());
+        assert!(matches!(
+            status_error(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Error::RetryAfter(StatusCode::TOO_MANY_REQUESTS, delay) if delay == Duration::from_secs(120)
+        ));
+        assert!(matches!(
+            status_error(StatusCode::NOT_FOUND, &headers),
+            Error::HTTPError(StatusCode::NOT_FOUND)
+        ));
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert!(matches!(
+            status_error(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Error::HTTPError(StatusCode::SERVICE_UNAVAILABLE)
+        ));
+    }
+}
@@ -5,31 +5,486 @@
 //!
 //! File backend snapshots contains metadata (size + last modified).
 //! It only accepts ByteStream.
+//!
+//! File backend could also be used as a source storage, so that a local
+//! directory (e.g. synced by rsync) could be pushed to other targets. Files
+//! are hard linked (or copied, if not on the same file system) into
+//! `buffer_path`, as buffer files are removed after transfer.
+//!
+//! With `link_cache`, content of every file put is kept in a cache under its
+//! SHA-256 checksum, and a file with the same content as an existing one is
+//! reflinked (`FICLONE`, on btrfs or xfs) or hard linked from the cache,
+//! instead of taking space again. Hard linked files share modified time, so
+//! they are only linked when the modified time is the same.
+//!
+//! With `durable`, files are first placed at a temporary path (`.{name}.tmp`)
+//! next to their targets, and renamed to targets after the file and its
+//! directory are synced to disk, so that a crash never leaves a truncated
+//! file at the target path. Buffer path should be on the same file system as
+//! base path, which is checked when taking snapshot.
+//!
+//! With `trash_path`, deleted files are moved to `{trash_path}/{date}/{key}`
+//! instead of being removed, so that a bad upstream snapshot won't wipe out
+//! the mirror. Trash path should be on the same file system as base path,
+//! and outside of it. Trash of old dates is removed by `purge_trash`.
+//!
+//! Files put and directories created could be given a mode and an owner by
+//! `permissions`, so that they are readable by the web server regardless of
+//! umask of mirror-clone.
+//!
+//! Space left on the file system is reported to the transfer engine, which
+//! checks the transfer plan against it before transferring. With
+//! `max_disk_usage` (in percent of the file system), space beyond it is not
+//! counted, and files are rejected if they don't fit in space left. Space of
+//! files replaced, and of buffer files on the same file system, which are
+//! only renamed, is counted as left.
+//!
+//! With `sidecar`, a `{name}.mirror-clone.sha256` file in the format of
+//! `sha256sum` is written next to every file put, from SHA-256 checksum in
+//! snapshot or computed from the file. Sidecar files are not in snapshot.
+//! They have their own suffix, so that `.sha256` files of sources are never
+//! taken as sidecars.
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
+use crate::checksum_pipe::calc_checksum;
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
-use crate::stream_pipe::ByteStream;
-use crate::traits::{Key, Metadata, SnapshotStorage, TargetStorage};
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage, TargetStorage};
+use crate::utils::{hash_string, unix_time};
 
 use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
 use filetime::FileTime;
-use slog::info;
+use slog::{debug, info, warn, Logger};
 use structopt::StructOpt;
 use walkdir::WalkDir;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloneMode {
+    Hardlink,
+    Reflink,
+}
+
+impl std::str::FromStr for CloneMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hardlink" => Ok(Self::Hardlink),
+            "reflink" => Ok(Self::Reflink),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported clone mode {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Mode and owner of files put and directories created.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Permissions {
+    fn apply(&self, path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.file_mode.is_none()
+            && self.dir_mode.is_none()
+            && self.uid.is_none()
+            && self.gid.is_none()
+    }
+}
+
+/// Parse an octal file mode, e.g. `644` or `0o755`.
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map_err(|_| Error::ConfigureError(format!("invalid file mode {}", mode)))
+}
+
+fn lookup_id(name: &str, user: bool) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| Error::ConfigureError(format!("invalid name {}", name)))?;
+    // SAFETY: entries returned are only read before next call
+    let id = unsafe {
+        if user {
+            let passwd = libc::getpwnam(c_name.as_ptr());
+            (!passwd.is_null()).then(|| (*passwd).pw_uid)
+        } else {
+            let group = libc::getgrnam(c_name.as_ptr());
+            (!group.is_null()).then(|| (*group).gr_gid)
+        }
+    };
+    id.ok_or_else(|| Error::ConfigureError(format!("unknown user or group {}", name)))
+}
+
+/// Parse owner in the form of `user`, `user:group` or `:group`, where user
+/// and group could be names or ids.
+pub fn parse_owner(owner: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, group),
+        None => (owner, ""),
+    };
+    let uid = match user {
+        "" => None,
+        user => Some(lookup_id(user, true)?),
+    };
+    let gid = match group {
+        "" => None,
+        group => Some(lookup_id(group, false)?),
+    };
+    Ok((uid, gid))
+}
+
+/// Cache of file content, to clone files with the same content from.
+#[derive(Debug, Clone)]
+pub struct LinkCache {
+    pub path: String,
+    pub mode: CloneMode,
+}
+
+impl LinkCache {
+    fn cache_path(&self, sha256: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{}/{}", self.path, &sha256[..2], sha256))
+    }
+}
+
 #[derive(StructOpt, Debug)]
 pub struct FileBackend {
     #[structopt(long)]
     pub base_path: String,
+    #[structopt(skip)]
+    pub buffer_path: Option<String>,
+    #[structopt(skip)]
+    pub link_cache: Option<LinkCache>,
+    #[structopt(skip)]
+    pub durable: bool,
+    #[structopt(skip)]
+    pub trash_path: Option<String>,
+    #[structopt(skip)]
+    pub permissions: Permissions,
+    #[structopt(skip)]
+    pub max_disk_usage: Option<u64>,
+    #[structopt(skip)]
+    pub sidecar: bool,
 }
 
 impl FileBackend {
     pub fn new(base_path: String) -> Self {
-        Self { base_path }
+        Self {
+            base_path,
+            buffer_path: None,
+            link_cache: None,
+            durable: false,
+            trash_path: None,
+            permissions: Permissions::default(),
+            max_disk_usage: None,
+            sidecar: false,
+        }
+    }
+
+    /// Write sidecar checksum file of `target`, computing SHA-256 of it if
+    /// `sha256` is not known.
+    async fn write_sidecar(
+        &self,
+        target: &Path,
+        sha256: Option<String>,
+        last_modified: Option<u64>,
+    ) -> Result<()> {
+        let sha256 = match sha256 {
+            Some(sha256) => sha256.to_lowercase(),
+            None => calc_checksum(&mut tokio::fs::File::open(target).await?, "sha256").await?,
+        };
+        let name = target.file_name().unwrap().to_string_lossy();
+        let sidecar = sidecar_path(target);
+        let staging = if self.durable {
+            staging_path(&sidecar)
+        } else {
+            sidecar.clone()
+        };
+        tokio::fs::write(&staging, format!("{}  {}\n", sha256, name)).await?;
+        if let Some(last_modified) = last_modified {
+            filetime::set_file_mtime(&staging, FileTime::from_unix_time(last_modified as i64, 0))?;
+        }
+        self.permissions
+            .apply(&staging, self.permissions.file_mode)?;
+        if self.durable {
+            if let Err(err) = commit(&staging, &sidecar).await {
+                tokio::fs::remove_file(&staging).await.ok();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove file of `key`, or move it to trash.
+    async fn discard(&self, key: &str, mission: &Mission) -> Result<()> {
+        let target = format!("{}/{}", self.base_path, key);
+        match &self.trash_path {
+            Some(trash_path) => {
+                let trash: PathBuf =
+                    format!("{}/{}/{}", trash_path, Utc::now().format("%Y-%m-%d"), key).into();
+                debug!(mission.logger, "trash: {}", key);
+                tokio::fs::create_dir_all(trash.parent().unwrap()).await?;
+                tokio::fs::rename(target, trash).await?;
+            }
+            None => tokio::fs::remove_file(target).await?,
+        }
+        Ok(())
+    }
+
+    /// Bytes which could still be written to base path, by free space of the
+    /// file system and `max_disk_usage`.
+    fn space_left(&self) -> Result<u64> {
+        let path = std::ffi::CString::new(self.base_path.as_bytes())
+            .map_err(|_| Error::ConfigureError(format!("invalid path {}", self.base_path)))?;
+        // SAFETY: `stat` is only read after being filled by `statvfs`
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let block_size = stat.f_frsize as u64;
+        let total = stat.f_blocks as u64 * block_size;
+        let used = total - stat.f_bfree as u64 * block_size;
+        let available = stat.f_bavail as u64 * block_size;
+        Ok(match self.max_disk_usage {
+            Some(percent) => {
+                let allowed = (total as u128 * percent.min(100) as u128 / 100) as u64;
+                available.min(allowed.saturating_sub(used))
+            }
+            None => available,
+        })
+    }
+
+    /// Create directory `dir` and its parents, with mode and owner set for
+    /// those created.
+    async fn create_dir(&self, dir: &Path) -> Result<()> {
+        if self.permissions.is_empty() {
+            tokio::fs::create_dir_all(dir).await?;
+            return Ok(());
+        }
+        let mut missing = vec![];
+        let mut current = Some(dir);
+        while let Some(path) = current {
+            if tokio::fs::metadata(path).await.is_ok() {
+                break;
+            }
+            missing.push(path.to_path_buf());
+            current = path.parent();
+        }
+        tokio::fs::create_dir_all(dir).await?;
+        for path in missing.iter().rev() {
+            self.permissions.apply(path, self.permissions.dir_mode)?;
+        }
+        Ok(())
+    }
+
+    /// Remove trash of dates older than `keep_days`. If `dry_run` is set, only
+    /// print what would be removed.
+    pub async fn purge_trash(&self, logger: &Logger, keep_days: i64, dry_run: bool) -> Result<()> {
+        let trash_path = self.trash_path.as_ref().ok_or_else(|| {
+            Error::ConfigureError("trash path is required to purge trash".to_string())
+        })?;
+        let expire = Utc::now().date_naive() - chrono::Duration::days(keep_days);
+
+        let mut entries = tokio::fs::read_dir(trash_path).await?;
+        let mut purged = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let date = match NaiveDate::parse_from_str(&name, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => {
+                    warn!(logger, "unknown entry {} in trash", name);
+                    continue;
+                }
+            };
+            if date >= expire {
+                continue;
+            }
+            info!(logger, "purge trash of {}", name);
+            if !dry_run {
+                tokio::fs::remove_dir_all(entry.path()).await?;
+            }
+            purged += 1;
+        }
+        info!(logger, "purged trash of {} days", purged);
+        Ok(())
+    }
+
+    /// Check that buffer files could be renamed into base path.
+    fn check_same_fs(&self) -> Result<()> {
+        if let Some(buffer_path) = &self.buffer_path {
+            let base_dev = std::fs::metadata(&self.base_path)?.dev();
+            let buffer_dev = std::fs::metadata(buffer_path)?.dev();
+            if base_dev != buffer_dev {
+                return Err(Error::ConfigureError(format!(
+                    "buffer path {} and base path {} are not on the same file system",
+                    buffer_path, self.base_path
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Move buffer file at `path` to `target`, or clone `target` from file
+    /// with the same content in cache. Returns whether content in cache is
+    /// used.
+    async fn put_with_cache(
+        &self,
+        cache: &LinkCache,
+        path: &Path,
+        target: &Path,
+        last_modified: Option<u64>,
+        mission: &Mission,
+    ) -> Result<bool> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let sha256 = calc_checksum(&mut file, "sha256").await?;
+        drop(file);
+        let cached = cache.cache_path(&sha256);
+
+        if let Ok(metadata) = tokio::fs::metadata(&cached).await {
+            let usable = match cache.mode {
+                CloneMode::Reflink => true,
+                CloneMode::Hardlink => last_modified.is_none_or(|last_modified| {
+                    FileTime::from_last_modification_time(&metadata).unix_seconds() as u64
+                        == last_modified
+                }),
+            };
+            if usable {
+                match clone_file(cache.mode, &cached, target).await {
+                    Ok(()) => {
+                        debug!(mission.logger, "clone {:?} from {}", target, sha256);
+                        tokio::fs::remove_file(path).await.ok();
+                        return Ok(true);
+                    }
+                    Err(err) => warn!(
+                        mission.logger,
+                        "failed to clone {:?} from cache: {:?}", target, err
+                    ),
+                }
+            }
+        }
+
+        tokio::fs::rename(path, target).await?;
+        if tokio::fs::metadata(&cached).await.is_err() {
+            tokio::fs::create_dir_all(cached.parent().unwrap()).await?;
+            // content is still in place without cache
+            if let Err(err) = clone_file(cache.mode, target, &cached).await {
+                warn!(
+                    mission.logger,
+                    "failed to add {:?} to cache: {:?}", target, err
+                );
+            }
+        }
+        Ok(false)
     }
 }
 
+/// Suffix of sidecar checksum files.
+const SIDECAR_SUFFIX: &str = ".mirror-clone.sha256";
+
+fn sidecar_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap().to_string_lossy();
+    target.with_file_name(format!("{}{}", name, SIDECAR_SUFFIX))
+}
+
+/// Remove sidecar files of files in snapshot.
+fn remove_sidecars(snapshot: &mut Vec<SnapshotMeta>) {
+    let keys: std::collections::HashSet<String> =
+        snapshot.iter().map(|meta| meta.key.clone()).collect();
+    snapshot.retain(|meta| match meta.key.strip_suffix(SIDECAR_SUFFIX) {
+        Some(key) => !keys.contains(key),
+        None => true,
+    });
+}
+
+/// Temporary path of `target` in the same directory.
+fn staging_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap().to_string_lossy();
+    target.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Sync `staging` to disk and rename it to `target`, then sync the directory,
+/// so that `target` is either the old file or the complete new one.
+async fn commit(staging: &Path, target: &Path) -> Result<()> {
+    let (staging, target) = (staging.to_path_buf(), target.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        std::fs::File::open(&staging)?.sync_all()?;
+        std::fs::rename(&staging, &target)?;
+        std::fs::File::open(target.parent().unwrap())?.sync_all()?;
+        Ok::<_, Error>(())
+    })
+    .await
+    .map_err(|err| Error::ProcessError(format!("error while commit: {:?}", err)))?
+}
+
+/// Clone file `src` to `dst`, replacing `dst` if it exists.
+async fn clone_file(mode: CloneMode, src: &Path, dst: &Path) -> Result<()> {
+    match tokio::fs::remove_file(dst).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    match mode {
+        CloneMode::Hardlink => tokio::fs::hard_link(src, dst).await?,
+        CloneMode::Reflink => {
+            let (src, dst) = (src.to_path_buf(), dst.to_path_buf());
+            tokio::task::spawn_blocking(move || reflink(&src, &dst))
+                .await
+                .map_err(|err| Error::ProcessError(format!("error while reflink: {:?}", err)))??
+        }
+    }
+    Ok(())
+}
+
+/// Clone `src` to a new file `dst` with `FICLONE`, sharing extents of them
+/// on copy-on-write file systems.
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = std::fs::File::open(src)?;
+    let dst_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    // SAFETY: both file descriptors are valid until the end of this function
+    let ret = unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        drop(dst_file);
+        std::fs::remove_file(dst).ok();
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &Path, _dst: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "reflink is only supported on Linux",
+    ))
+}
+
 #[async_trait]
 impl SnapshotStorage<SnapshotMeta> for FileBackend {
     async fn snapshot(
@@ -41,8 +496,12 @@ impl SnapshotStorage<SnapshotMeta> for FileBackend {
         let progress = mission.progress;
 
         info!(logger, "scanning local storage...");
+        if self.durable {
+            self.check_same_fs()?;
+        }
 
         let base_path = self.base_path.clone();
+        let sidecar = self.sidecar;
         tokio::task::spawn_blocking(move || {
             let mut snapshot = vec![];
             let base_path = std::path::PathBuf::from(base_path).canonicalize().unwrap();
@@ -69,6 +528,9 @@ impl SnapshotStorage<SnapshotMeta> for FileBackend {
                     });
                 }
             }
+            if sidecar {
+                remove_sidecars(&mut snapshot);
+            }
             Ok::<_, Error>(snapshot)
         })
         .await
@@ -86,24 +548,166 @@ impl<Snapshot: Key + Metadata> TargetStorage<Snapshot, ByteStream> for FileBacke
         &self,
         snapshot: &Snapshot,
         byte_stream: ByteStream,
-        _mission: &Mission,
+        mission: &Mission,
     ) -> Result<()> {
-        let path = byte_stream.object.use_file();
+        let length = byte_stream.length;
+        // buffer file is removed when rejected
+        if let Some(percent) = self.max_disk_usage {
+            let left = self.space_left()?;
+            let target = format!("{}/{}", self.base_path, snapshot.key());
+            // space of replaced file is freed, and buffer file on the same
+            // file system is only renamed, taking no more space
+            let replaced = match tokio::fs::metadata(&target).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            };
+            let buffered = match &byte_stream.object {
+                ByteObject::LocalFile {
+                    path: Some(path), ..
+                } => {
+                    let base_dev = tokio::fs::metadata(&self.base_path).await?.dev();
+                    match tokio::fs::metadata(path).await {
+                        Ok(metadata) if metadata.dev() == base_dev => metadata.len(),
+                        _ => 0,
+                    }
+                }
+                _ => 0,
+            };
+            if left.saturating_add(replaced).saturating_add(buffered) < length {
+                return Err(Error::StorageError(format!(
+                    "{}: {} bytes exceed {} bytes left under {}% disk usage of {}",
+                    snapshot.key(),
+                    length,
+                    left,
+                    percent,
+                    self.base_path
+                )));
+            }
+        }
+        let path = byte_stream.object.use_file()?;
         let target: std::path::PathBuf = format!("{}/{}", self.base_path, snapshot.key()).into();
         let parent = target.parent().unwrap();
-        tokio::fs::create_dir_all(parent).await?;
-        tokio::fs::rename(&path, &target).await?;
+        self.create_dir(parent).await?;
+        let staging = if self.durable {
+            staging_path(&target)
+        } else {
+            target.clone()
+        };
+        let cloned = match &self.link_cache {
+            Some(cache) => {
+                let result = self
+                    .put_with_cache(cache, &path, &staging, snapshot.last_modified(), mission)
+                    .await;
+                if result.is_err() {
+                    tokio::fs::remove_file(&path).await.ok();
+                }
+                result?
+            }
+            None => {
+                tokio::fs::rename(&path, &staging).await?;
+                false
+            }
+        };
         if let Some(last_modified) = snapshot.last_modified() {
-            filetime::set_file_mtime(&target, FileTime::from_unix_time(last_modified as i64, 0))?;
+            filetime::set_file_mtime(&staging, FileTime::from_unix_time(last_modified as i64, 0))?;
+        }
+        self.permissions
+            .apply(&staging, self.permissions.file_mode)?;
+        if self.durable {
+            if let Err(err) = commit(&staging, &target).await {
+                tokio::fs::remove_file(&staging).await.ok();
+                return Err(err);
+            }
+        }
+        if self.sidecar {
+            let sha256 = match (
+                snapshot.checksum_method(),
+                snapshot.checksum(),
+                byte_stream.checksum,
+            ) {
+                (Some("sha256"), Some(checksum), _) => Some(checksum.to_string()),
+                (_, _, Some((method, checksum))) if method == "sha256" => Some(checksum),
+                _ => None,
+            };
+            self.write_sidecar(&target, sha256, snapshot.last_modified())
+                .await?;
+        }
+        if !cloned {
+            mission
+                .accounting
+                .record_upload(&format!("file:{}", self.base_path), length);
+        }
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        self.discard(snapshot.key(), mission).await?;
+        if self.sidecar {
+            let sidecar = format!("{}{}", snapshot.key(), SIDECAR_SUFFIX);
+            if tokio::fs::metadata(format!("{}/{}", self.base_path, sidecar))
+                .await
+                .is_ok()
+            {
+                self.discard(&sidecar, mission).await?;
+            }
         }
         Ok(())
     }
 
-    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+    async fn update_metadata(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
         let target = format!("{}/{}", self.base_path, snapshot.key());
-        tokio::fs::remove_file(target).await?;
+        if let Some(last_modified) = snapshot.last_modified() {
+            filetime::set_file_mtime(&target, FileTime::from_unix_time(last_modified as i64, 0))?;
+        }
         Ok(())
     }
+
+    async fn available_space(&self) -> Result<Option<u64>> {
+        Ok(Some(self.space_left()?))
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for FileBackend {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<ByteStream> {
+        let buffer_path = self.buffer_path.as_ref().ok_or_else(|| {
+            Error::ConfigureError("buffer path is required to use file as source".to_string())
+        })?;
+        let source = format!("{}/{}", self.base_path, snapshot.key);
+        // copying a file doesn't keep its modified time
+        let metadata = tokio::fs::metadata(&source).await?;
+        let path = format!(
+            "{}/{}.{}.buffer",
+            buffer_path,
+            hash_string(&source),
+            unix_time()
+        );
+        if tokio::fs::hard_link(&source, &path).await.is_err() {
+            tokio::fs::copy(&source, &path).await?;
+        }
+        // the buffer file is removed when returning early on errors
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(path.clone().into()),
+        };
+
+        let file = tokio::fs::File::open(&path).await?;
+        if let ByteObject::LocalFile {
+            file: object_file, ..
+        } = &mut object
+        {
+            *object_file = Some(file);
+        }
+
+        Ok(ByteStream {
+            object,
+            length: metadata.len(),
+            modified_at: FileTime::from_last_modification_time(&metadata).unix_seconds() as u64,
+            content_type: crate::content_type::guess(&snapshot.key).map(String::from),
+            checksum: None,
+            etag: None,
+        })
+    }
 }
 
 #[async_trait]
@@ -126,3 +730,101 @@ impl SnapshotStorage<SnapshotPath> for FileBackend {
         format!("file (path), {:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_sidecars() {
+        let mut snapshot = crate::utils::snapshot_string_to_meta(vec![
+            "a.tar.gz".to_string(),
+            "a.tar.gz.mirror-clone.sha256".to_string(),
+            "a.tar.gz.sha256".to_string(),
+            "b.mirror-clone.sha256".to_string(),
+        ]);
+        remove_sidecars(&mut snapshot);
+        let keys: Vec<_> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec!["a.tar.gz", "a.tar.gz.sha256", "b.mirror-clone.sha256"]
+        );
+    }
+
+    #[test]
+    fn test_parse_permissions() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0o2775").unwrap(), 0o2775);
+        assert!(parse_mode("rw-r--r--").is_err());
+        assert_eq!(parse_owner("1000:33").unwrap(), (Some(1000), Some(33)));
+        assert_eq!(parse_owner("0").unwrap(), (Some(0), None));
+        assert_eq!(parse_owner(":33").unwrap(), (None, Some(33)));
+    }
+
+    fn mission() -> Mission {
+        Mission {
+            progress: indicatif::ProgressBar::hidden(),
+            client: reqwest::Client::new(),
+            logger: crate::utils::create_logger(0),
+            accounting: Default::default(),
+            breaker: std::sync::Arc::new(crate::circuit_breaker::CircuitBreaker::new(
+                0,
+                std::time::Duration::from_secs(0),
+            )),
+            throttle: std::sync::Arc::new(crate::throttle::Throttle::new(None)),
+            direct_stream: None,
+            range: Default::default(),
+            validators: None,
+        }
+    }
+
+    /// Buffer file of 1 KiB, claimed to be of `length`.
+    async fn buffered(dir: &Path, length: u64) -> ByteStream {
+        let path = dir.join(format!("{}.buffer", length));
+        std::fs::write(&path, vec![0; 1024]).unwrap();
+        ByteStream {
+            object: ByteObject::LocalFile {
+                file: Some(tokio::fs::File::open(&path).await.unwrap()),
+                path: Some(path),
+            },
+            length,
+            modified_at: 0,
+            content_type: None,
+            checksum: None,
+            etag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_disk_usage() {
+        let dir = std::env::temp_dir().join(format!("file-backend-usage-{}", unix_time()));
+        let base = dir.join("base");
+        std::fs::create_dir_all(&base).unwrap();
+        let mut backend = FileBackend::new(base.to_string_lossy().to_string());
+        // nothing is left under 0% disk usage
+        backend.max_disk_usage = Some(0);
+        let snapshot = SnapshotMeta::new("a.bin".to_string());
+
+        // buffer file on the same file system is only renamed
+        let stream = buffered(&dir, 1024).await;
+        backend
+            .put_object(&snapshot, stream, &mission())
+            .await
+            .unwrap();
+
+        // but bytes beyond it don't fit, unless they replace a file
+        let stream = buffered(&dir, 2048).await;
+        let err = backend
+            .put_object(&SnapshotMeta::new("b.bin".to_string()), stream, &mission())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::StorageError(_)));
+        let stream = buffered(&dir, 2048).await;
+        backend
+            .put_object(&snapshot, stream, &mission())
+            .await
+            .unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
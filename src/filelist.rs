@@ -0,0 +1,213 @@
+//! File list source
+//!
+//! File list source mirrors files listed in a manifest, which could be a
+//! local file or a URL (gzipped if ending with `.gz`). By default, each line
+//! of the manifest contains columns given by `--columns`, separated by tabs,
+//! or by whitespaces if there is no tab in the line. If `key` is the last
+//! column, the rest of the line is taken as the key, so that it may contain
+//! spaces. For example, the output of
+//! `find . -type f -printf '%s\t%T@\t%P\n'` could be read with
+//! `--columns size,mtime,key`.
+//!
+//! With `--ls-lr`, the manifest is read as a recursive `ls -lR` listing
+//! instead, the same as GNU source.
+
+use std::io::Read;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{human_size, CommaSplitVecString};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct FileList {
+    #[structopt(long, help = "Base URL of files")]
+    pub base: String,
+    #[structopt(long, help = "Path or URL of file list")]
+    pub list: String,
+    #[structopt(
+        long,
+        help = "Columns of file list, among key, size, mtime, md5, sha256, sha512 and blake2b",
+        default_value = "key"
+    )]
+    pub columns: CommaSplitVecString,
+    #[structopt(long, help = "Read file list as `ls -lR` output")]
+    pub ls_lr: bool,
+}
+
+const CHECKSUM_COLUMNS: [&str; 4] = ["md5", "sha256", "sha512", "blake2b"];
+
+fn check_columns(columns: &[String]) -> Result<()> {
+    if !columns.iter().any(|column| column == "key") {
+        return Err(Error::ConfigureError(String::from(
+            "columns of file list should contain key",
+        )));
+    }
+    for column in columns {
+        if column != "key"
+            && column != "size"
+            && column != "mtime"
+            && !CHECKSUM_COLUMNS.contains(&column.as_str())
+        {
+            return Err(Error::ConfigureError(format!("unknown column {}", column)));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a line of file list with `columns`. Lines without enough columns
+/// are skipped.
+fn parse_line(line: &str, columns: &[String]) -> Option<SnapshotMeta> {
+    let mut meta = SnapshotMeta::default();
+    let mut rest = line.trim_end_matches('\r');
+    let by_tab = rest.contains('\t');
+    for (idx, column) in columns.iter().enumerate() {
+        let value = if idx + 1 == columns.len() {
+            std::mem::take(&mut rest)
+        } else {
+            let rest_trimmed = if by_tab { rest } else { rest.trim_start() };
+            let end = if by_tab {
+                rest_trimmed.find('\t')
+            } else {
+                rest_trimmed.find(char::is_whitespace)
+            }?;
+            rest = &rest_trimmed[end + 1..];
+            &rest_trimmed[..end]
+        };
+        let value = match column.as_str() {
+            "key" if by_tab => value,
+            "key" => value.trim_start(),
+            _ => value.trim(),
+        };
+        match column.as_str() {
+            "key" => {
+                let key = value.trim_start_matches("./");
+                if key.is_empty() {
+                    return None;
+                }
+                meta.key = key.to_string();
+            }
+            "size" => meta.size = Some(value.parse().ok()?),
+            // `find -printf %T@` prints fractional seconds
+            "mtime" => meta.last_modified = Some(value.parse::<f64>().ok()? as u64),
+            algo => {
+                meta.checksum_method = Some(algo.to_string());
+                meta.checksum = Some(value.to_lowercase());
+            }
+        }
+    }
+    Some(meta)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for FileList {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let columns: Vec<String> = self.columns.clone().into();
+        if !self.ls_lr {
+            check_columns(&columns)?;
+        }
+
+        info!(logger, "fetching {}...", self.list);
+        progress.set_message("fetching file list...");
+        let data = if self.list.starts_with("http://") || self.list.starts_with("https://") {
+            fetch(&client, &self.list).await?.to_vec()
+        } else {
+            tokio::fs::read(&self.list).await?
+        };
+        let data = if self.list.ends_with(".gz") {
+            let mut buf = vec![];
+            GzDecoder::new(&data[..]).read_to_end(&mut buf)?;
+            buf
+        } else {
+            data
+        };
+        let content = String::from_utf8_lossy(&data);
+
+        progress.set_message("parsing file list...");
+        let snapshot = if self.ls_lr {
+            crate::gnu::parse_listing(&content, Utc::now().date_naive())
+        } else {
+            let mut snapshot = vec![];
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                match parse_line(line, &columns) {
+                    Some(meta) => snapshot.push(meta),
+                    None => warn!(logger, "invalid line in file list: {}", line),
+                }
+            }
+            snapshot
+        };
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("filelist, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for FileList {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!(
+            "{}/{}",
+            self.base.trim_end_matches('/'),
+            snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(columns: &str) -> Vec<String> {
+        columns.split(',').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let meta = parse_line(
+            "1024\t1678615500.1234567890\tdir/foo bar.tar.gz",
+            &columns("size,mtime,key"),
+        )
+        .unwrap();
+        assert_eq!(meta.key, "dir/foo bar.tar.gz");
+        assert_eq!(meta.size, Some(1024));
+        assert_eq!(meta.last_modified, Some(1678615500));
+
+        let meta = parse_line("./foo.tar.gz  ABCD", &columns("key,sha256")).unwrap();
+        assert_eq!(meta.key, "foo.tar.gz");
+        assert_eq!(meta.checksum_method.as_deref(), Some("sha256"));
+        assert_eq!(meta.checksum.as_deref(), Some("abcd"));
+
+        assert!(parse_line("foo.tar.gz", &columns("key,sha256")).is_none());
+        assert!(parse_line("abc foo.tar.gz", &columns("size,key")).is_none());
+        assert!(check_columns(&columns("size,mtime")).is_err());
+        assert!(check_columns(&columns("key,crc32")).is_err());
+    }
+}
@@ -1,24 +1,118 @@
-//! FilterPipe excludes source items by regex pattern.
+//! FilterPipe selects source items by regex pattern.
+//!
+//! Include patterns are applied first: if any is given, only items matching
+//! one of them are kept. Items matching any exclude pattern are then
+//! dropped, so that excludes carve exceptions out of includes.
+//! Filters given on command line are applied before all pipes of a source,
+//! so that files generated by pipes (e.g. indexes) only list selected items.
+//! Target objects left out by them are kept out of deletions, as they are
+//! missing in source only because they're filtered.
+//!
+//! SizeFilterPipe drops source items smaller or larger than given sizes, so
+//! that huge artifacts can be skipped. Items of unknown size are kept. Target
+//! objects beyond the sizes are kept as well, so that artifacts mirrored
+//! before a limit is set are not removed.
+
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use regex::RegexSet;
+use structopt::StructOpt;
 
 use crate::common::{Mission, SnapshotConfig};
-use crate::error::Result;
-use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::error::{Error, Result};
+use crate::simple_diff_transfer::KeepFilter;
+use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
+
+/// Source with filters given on command line applied.
+pub type Filtered<Source> = SizeFilterPipe<FilterPipe<Source>>;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct FilterConfig {
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Only transfer objects whose key matches this regex, can be given multiple times"
+    )]
+    pub filter_include: Vec<String>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Don't transfer objects whose key matches this regex, can be given multiple times"
+    )]
+    pub filter_exclude: Vec<String>,
+    #[structopt(
+        long,
+        help = "Don't transfer objects smaller than this size (in bytes)"
+    )]
+    pub filter_min_size: Option<u64>,
+    #[structopt(long, help = "Don't transfer objects larger than this size (in bytes)")]
+    pub filter_max_size: Option<u64>,
+}
+
+fn compile(patterns: Vec<String>) -> Result<RegexSet> {
+    RegexSet::new(patterns)
+        .map_err(|err| Error::ConfigureError(format!("invalid filter pattern: {}", err)))
+}
+
+impl FilterConfig {
+    pub fn into_pipe<Source>(self, source: Source) -> Result<Filtered<Source>> {
+        let source = FilterPipe::new(
+            source,
+            compile(self.filter_include)?,
+            compile(self.filter_exclude)?,
+        );
+        Ok(SizeFilterPipe::new(
+            source,
+            self.filter_min_size,
+            self.filter_max_size,
+        ))
+    }
+
+    /// Predicate on target objects left out by these filters, if any filter
+    /// is given.
+    pub fn keep_filter(&self) -> Result<Option<KeepFilter>> {
+        if self.filter_include.is_empty()
+            && self.filter_exclude.is_empty()
+            && self.filter_min_size.is_none()
+            && self.filter_max_size.is_none()
+        {
+            return Ok(None);
+        }
+        let pipe = SizeFilterPipe::new(
+            FilterPipe::new(
+                (),
+                compile(self.filter_include.clone())?,
+                compile(self.filter_exclude.clone())?,
+            ),
+            self.filter_min_size,
+            self.filter_max_size,
+        );
+        Ok(Some(KeepFilter(Arc::new(move |key, size| {
+            !pipe.source.is_selected(key) || !pipe.is_selected(size)
+        }))))
+    }
+}
 
 pub struct FilterPipe<Source> {
     pub source: Source,
+    pub include_patterns: RegexSet,
     pub exclude_patterns: RegexSet,
 }
 
 impl<Source> FilterPipe<Source> {
-    pub fn new(source: Source, exclude_patterns: RegexSet) -> Self {
+    pub fn new(source: Source, include_patterns: RegexSet, exclude_patterns: RegexSet) -> Self {
         FilterPipe {
             source,
+            include_patterns,
             exclude_patterns,
         }
     }
+
+    fn is_selected(&self, key: &str) -> bool {
+        (self.include_patterns.is_empty() || self.include_patterns.is_match(key))
+            && !self.exclude_patterns.is_match(key)
+    }
 }
 
 #[async_trait]
@@ -38,14 +132,19 @@ where
             .map(|snapshots| {
                 snapshots
                     .into_iter()
-                    .filter(|snapshot| !self.exclude_patterns.is_match(snapshot.key()))
+                    .filter(|snapshot| self.is_selected(snapshot.key()))
                     .collect()
             })
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!(
-            "Filter by exclude patterns {:?} <{}>",
+            "Filter by include patterns {:?}, exclude patterns {:?} <{}>",
+            self.include_patterns,
             self.exclude_patterns,
             self.source.info()
         )
@@ -62,3 +161,147 @@ where
         self.source.get_object(snapshot, mission).await
     }
 }
+
+pub struct SizeFilterPipe<Source> {
+    pub source: Source,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl<Source> SizeFilterPipe<Source> {
+    pub fn new(source: Source, min_size: Option<u64>, max_size: Option<u64>) -> Self {
+        SizeFilterPipe {
+            source,
+            min_size,
+            max_size,
+        }
+    }
+
+    fn is_selected(&self, size: Option<u64>) -> bool {
+        match size {
+            Some(size) => {
+                self.min_size.is_none_or(|min_size| size >= min_size)
+                    && self.max_size.is_none_or(|max_size| size <= max_size)
+            }
+            None => true,
+        }
+    }
+}
+
+impl<Source> Filtered<Source> {
+    /// Unfiltered source, for pipes generating files from source internals.
+    pub fn inner(&self) -> &Source {
+        &self.source.source
+    }
+
+    pub fn inner_mut(&mut self) -> &mut Source {
+        &mut self.source.source
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for SizeFilterPipe<Source>
+where
+    Snapshot: Metadata + Send + 'static,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        if self.min_size.is_some() || self.max_size.is_some() {
+            snapshot.retain(|item| self.is_selected(item.size()));
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "Filter by size, min {:?}, max {:?} <{}>",
+            self.min_size,
+            self.max_size,
+            self.source.info()
+        )
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source, SourceItem> SourceStorage<Snapshot, SourceItem> for SizeFilterPipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SourceStorage<Snapshot, SourceItem>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<SourceItem> {
+        self.source.get_object(snapshot, mission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_selected() {
+        let pipe = FilterPipe::new(
+            (),
+            RegexSet::new([r"^dists/", r"^pool/main/"]).unwrap(),
+            RegexSet::new([r"\.iso$"]).unwrap(),
+        );
+        assert!(pipe.is_selected("dists/stable/Release"));
+        assert!(pipe.is_selected("pool/main/a/a.deb"));
+        assert!(!pipe.is_selected("pool/contrib/a/a.deb"));
+        assert!(!pipe.is_selected("dists/stable/installer.iso"));
+
+        let pipe = FilterPipe::new((), RegexSet::empty(), RegexSet::new([r"\.iso$"]).unwrap());
+        assert!(pipe.is_selected("pool/contrib/a/a.deb"));
+        assert!(!pipe.is_selected("a.iso"));
+    }
+
+    #[test]
+    fn test_size_is_selected() {
+        let pipe = SizeFilterPipe::new((), Some(1024), Some(1 << 30));
+        assert!(pipe.is_selected(Some(4096)));
+        assert!(pipe.is_selected(None));
+        assert!(!pipe.is_selected(Some(100)));
+        assert!(!pipe.is_selected(Some(4 << 30)));
+    }
+
+    #[test]
+    fn test_keep_filter() {
+        let config = FilterConfig {
+            filter_include: vec![r"^pool/".to_string()],
+            filter_exclude: vec![r"\.iso$".to_string()],
+            filter_min_size: None,
+            filter_max_size: None,
+        };
+        let KeepFilter(keep) = config.keep_filter().unwrap().unwrap();
+        assert!(!keep("pool/main/a/a.deb", Some(4096)));
+        assert!(keep("dists/stable/Release", Some(4096)));
+        assert!(keep("pool/main/a/a.iso", Some(4096)));
+
+        let config = FilterConfig {
+            filter_include: vec![],
+            filter_exclude: vec![],
+            filter_min_size: None,
+            filter_max_size: Some(1 << 30),
+        };
+        let KeepFilter(keep) = config.keep_filter().unwrap().unwrap();
+        assert!(!keep("pool/main/a/a.deb", Some(4096)));
+        assert!(!keep("pool/main/a/a.deb", None));
+        assert!(keep("a.iso", Some(4 << 30)));
+
+        let config = FilterConfig {
+            filter_include: vec![],
+            filter_exclude: vec![],
+            filter_min_size: None,
+            filter_max_size: None,
+        };
+        assert!(config.keep_filter().unwrap().is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! Flutter source
+//!
+//! Flutter source parses release lists of Flutter SDK
+//! (`releases_{platform}.json`), and yields SDK archives of configured
+//! channels with sha256 checksums. Files are placed at the same paths as
+//! upstream, e.g. `stable/linux/flutter_linux_3.16.3-stable.tar.xz`.
+//!
+//! Release lists are always transferred at the end. If `target_mirror` is
+//! set, they should be piped through `RewritePipe` with `rewrite_releases`,
+//! so that `base_url` points to the mirror.
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use serde::Deserialize;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::fetch::fetch_json;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Flutter {
+    #[structopt(
+        long,
+        default_value = "https://storage.googleapis.com/flutter_infra_release/releases",
+        help = "Base of Flutter SDK releases"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        default_value = "linux,macos,windows",
+        use_delimiter = true,
+        help = "Platforms of release lists to mirror"
+    )]
+    pub platforms: Vec<String>,
+    #[structopt(long, help = "Channels to mirror, e.g. stable (all if not set)")]
+    pub channels: Vec<String>,
+    #[structopt(long, help = "Mirror URL to rewrite release lists to")]
+    pub target_mirror: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseList {
+    releases: Vec<Release>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    channel: String,
+    archive: String,
+    sha256: Option<String>,
+    release_date: Option<String>,
+}
+
+/// Rewrite `base_url` of a release list to `target_mirror`. Content other
+/// than release lists is returned as is.
+pub fn rewrite_releases(target_mirror: &str, content: String) -> Result<String> {
+    let mut releases: serde_json::Value = match serde_json::from_str(&content) {
+        Ok(releases) => releases,
+        Err(_) => return Ok(content),
+    };
+    match releases.get_mut("base_url") {
+        Some(base_url) => *base_url = serde_json::Value::String(target_mirror.to_string()),
+        None => return Ok(content),
+    }
+    Ok(serde_json::to_string_pretty(&releases)?)
+}
+
+impl Flutter {
+    fn select(&self, releases: ReleaseList) -> Vec<SnapshotMeta> {
+        releases
+            .releases
+            .into_iter()
+            .filter(|release| self.channels.is_empty() || self.channels.contains(&release.channel))
+            .map(|release| SnapshotMeta {
+                key: release.archive,
+                last_modified: release
+                    .release_date
+                    .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                    .map(|date| date.timestamp() as u64),
+                checksum_method: release.sha256.as_ref().map(|_| "sha256".to_string()),
+                checksum: release.sha256,
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Flutter {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut snapshot = vec![];
+        let mut release_lists = vec![];
+        for platform in &self.platforms {
+            let key = format!("releases_{}.json", platform);
+            info!(logger, "fetching {}...", key);
+            progress.set_message(&key);
+            let releases: ReleaseList =
+                fetch_json(&client, &format!("{}/{}", self.base, key)).await?;
+            snapshot.extend(self.select(releases));
+            release_lists.push(SnapshotMeta::force(key));
+        }
+        // the same archive may be listed in release lists of different platforms
+        snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+        snapshot.dedup_by(|a, b| a.key == b.key);
+        info!(logger, "{} archives", snapshot.len());
+
+        snapshot.extend(release_lists);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("flutter, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Flutter {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELEASES: &str = r#"{
+  "base_url": "https://storage.googleapis.com/flutter_infra_release/releases",
+  "current_release": {"beta": "1a2b", "stable": "3c4d"},
+  "releases": [
+    {
+      "hash": "3c4d",
+      "channel": "stable",
+      "version": "3.16.3",
+      "release_date": "2023-12-06T18:39:23.391Z",
+      "archive": "stable/linux/flutter_linux_3.16.3-stable.tar.xz",
+      "sha256": "abcd"
+    },
+    {
+      "hash": "1a2b",
+      "channel": "beta",
+      "version": "3.17.0-0.0.pre",
+      "release_date": "2023-11-15T17:20:23.122Z",
+      "archive": "beta/linux/flutter_linux_3.17.0-0.0.pre-beta.tar.xz",
+      "sha256": "ef01"
+    }
+  ]
+}"#;
+
+    #[test]
+    fn test_select_and_rewrite() {
+        let config = Flutter {
+            base: "https://storage.googleapis.com/flutter_infra_release/releases".to_string(),
+            platforms: vec!["linux".to_string()],
+            channels: vec!["stable".to_string()],
+            target_mirror: None,
+        };
+        let snapshot = config.select(serde_json::from_str(RELEASES).unwrap());
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot[0].key,
+            "stable/linux/flutter_linux_3.16.3-stable.tar.xz"
+        );
+        assert_eq!(snapshot[0].checksum.as_deref(), Some("abcd"));
+        assert_eq!(snapshot[0].last_modified, Some(1701887963));
+
+        let rewritten: serde_json::Value = serde_json::from_str(
+            &rewrite_releases(
+                "https://mirror.example.com/flutter/releases",
+                RELEASES.to_string(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            rewritten["base_url"],
+            "https://mirror.example.com/flutter/releases"
+        );
+        assert_eq!(rewritten["releases"][1]["sha256"], "ef01");
+        assert_eq!(
+            rewrite_releases("https://mirror.example.com", "{}".to_string()).unwrap(),
+            "{}"
+        );
+    }
+}
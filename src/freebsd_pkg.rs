@@ -0,0 +1,227 @@
+//! FreeBSD pkg source
+//!
+//! FreeBSD pkg source mirrors binary package repositories of FreeBSD, e.g.
+//! `FreeBSD:14:amd64/latest`. `meta.conf` of a repository names the archive
+//! of the package manifests (`packagesite.pkg`, or `packagesite.txz` in older
+//! repositories), which is a compressed tarball of `packagesite.yaml` with a
+//! JSON object per line. Packages are yielded with sizes and sha256
+//! checksums from the manifests.
+//!
+//! Metadata of every repository (`meta.conf` and archives like `meta`,
+//! `packagesite`, `filesite`, `data` and `digests`) are transferred at the
+//! end.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::{exists, fetch_optional, fetch_text};
+use crate::metadata::SnapshotMeta;
+use crate::pacman::decompress;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct FreeBsdPkg {
+    #[structopt(
+        long,
+        default_value = "https://pkg.freebsd.org",
+        help = "Base of FreeBSD package repositories"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "ABIs to mirror, e.g. FreeBSD:14:amd64",
+        required = true,
+        use_delimiter = true
+    )]
+    pub abis: Vec<String>,
+    #[structopt(long, default_value = "latest,quarterly", use_delimiter = true)]
+    pub branches: Vec<String>,
+}
+
+/// Archives of repository metadata, which are mirrored as-is if present.
+const METADATA_ARCHIVES: &[&str] = &["meta", "packagesite", "filesite", "data", "digests"];
+
+/// Extensions of metadata archives.
+const ARCHIVE_EXTENSIONS: &[&str] = &["pkg", "txz"];
+
+/// A package in `packagesite.yaml`.
+#[derive(Deserialize)]
+struct Package {
+    path: Option<String>,
+    repopath: Option<String>,
+    pkgsize: Option<u64>,
+    sum: Option<String>,
+}
+
+/// Parse `key = "value";` entries in `meta.conf`.
+fn parse_meta_conf(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim().trim_end_matches(';').trim_matches('"');
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Parse packages in `packagesite.yaml`.
+fn parse_packagesite(content: &str) -> Result<Vec<SnapshotMeta>> {
+    let mut packages = vec![];
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let package: Package = serde_json::from_str(line)?;
+        if let Some(path) = package.repopath.or(package.path) {
+            packages.push(SnapshotMeta {
+                key: path.trim_start_matches('/').to_string(),
+                size: package.pkgsize,
+                checksum_method: package.sum.as_ref().map(|_| "sha256".to_string()),
+                checksum: package.sum,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(packages)
+}
+
+/// Read a file from a compressed metadata archive.
+fn read_archive(data: &[u8], name: &str) -> Result<String> {
+    let data = decompress(data)?;
+    let mut archive = tar::Archive::new(&data[..]);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy().trim_start_matches("./") == name {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            return Ok(content);
+        }
+    }
+    Err(Error::ProcessError(format!(
+        "{} not found in archive",
+        name
+    )))
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for FreeBsdPkg {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut snapshot = vec![];
+        let mut metadata = vec![];
+        for abi in &self.abis {
+            for branch in &self.branches {
+                let repo = format!("{}/{}", abi, branch);
+                let url = format!("{}/{}", self.base, repo);
+                info!(logger, "fetching {}/meta.conf...", repo);
+                progress.set_message(&repo);
+                let meta =
+                    parse_meta_conf(&fetch_text(&client, &format!("{}/meta.conf", url)).await?);
+                let archive_name = meta
+                    .get("manifests_archive")
+                    .map(|name| name.as_str())
+                    .unwrap_or("packagesite");
+                let manifests = meta
+                    .get("manifests")
+                    .map(|name| name.as_str())
+                    .unwrap_or("packagesite.yaml");
+
+                let mut packagesite = None;
+                for extension in ARCHIVE_EXTENSIONS {
+                    let archive_url = format!("{}/{}.{}", url, archive_name, extension);
+                    if let Some(data) = fetch_optional(&client, &archive_url).await? {
+                        packagesite = Some(read_archive(&data, manifests)?);
+                        break;
+                    }
+                }
+                let packagesite = match packagesite {
+                    Some(packagesite) => packagesite,
+                    None => {
+                        warn!(logger, "no {} in {}", archive_name, repo);
+                        continue;
+                    }
+                };
+                let packages = parse_packagesite(&packagesite)?;
+                let total_size: u64 = packages.iter().filter_map(|package| package.size).sum();
+                info!(
+                    logger,
+                    "{}: {} packages ({})",
+                    repo,
+                    packages.len(),
+                    human_size(total_size)
+                );
+                snapshot.extend(packages.into_iter().map(|mut package| {
+                    package.key = format!("{}/{}", repo, package.key);
+                    package
+                }));
+
+                for archive in METADATA_ARCHIVES {
+                    for extension in ARCHIVE_EXTENSIONS {
+                        let key = format!("{}/{}.{}", repo, archive, extension);
+                        if exists(&client, &format!("{}/{}", self.base, key)).await? {
+                            metadata.push(SnapshotMeta::force(key));
+                        }
+                    }
+                }
+                metadata.push(SnapshotMeta::force(format!("{}/meta.conf", repo)));
+            }
+        }
+        snapshot.extend(metadata);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("freebsd pkg, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for FreeBsdPkg {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meta_and_packagesite() {
+        let meta = parse_meta_conf(
+            r#"version = 2;
+packing_format = "tzst";
+manifests = "packagesite.yaml";
+manifests_archive = "packagesite";
+"#,
+        );
+        assert_eq!(meta["manifests_archive"], "packagesite");
+        assert_eq!(meta["version"], "2");
+
+        let packagesite = r#"{"name":"zsh","origin":"shells/zsh","version":"5.9_4","path":"All/zsh-5.9_4.pkg","repopath":"All/zsh-5.9_4.pkg","sum":"ab01","pkgsize":4287472}
+{"name":"old","version":"1.0","path":"All/old-1.0.txz","sum":"cd23","pkgsize":12}
+"#;
+        let packages = parse_packagesite(packagesite).unwrap();
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].key, "All/zsh-5.9_4.pkg");
+        assert_eq!(packages[0].size, Some(4287472));
+        assert_eq!(packages[0].checksum.as_deref(), Some("ab01"));
+        assert_eq!(packages[1].key, "All/old-1.0.txz");
+    }
+}
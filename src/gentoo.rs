@@ -0,0 +1,270 @@
+//! Gentoo source
+//!
+//! Gentoo source mirrors distfiles of Gentoo. Distfiles are read from a
+//! listing in Manifest format, where each `DIST` line has name, size and
+//! BLAKE2B / SHA512 checksums of a distfile, e.g. concatenated `Manifest`s of
+//! the Gentoo repository. The listing may be compressed with gzip, xz or
+//! zstd.
+//!
+//! Distfiles are placed under `distfiles/` with the layout in upstream
+//! `distfiles/layout.conf`, which is usually `filename-hash BLAKE2B 8`, i.e.
+//! `distfiles/{first 2 hex digits of BLAKE2B of file name}/{file name}`.
+//! `layout.conf` is transferred at the end.
+
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use blake2::Digest;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::{fetch, fetch_text};
+use crate::metadata::SnapshotMeta;
+use crate::pacman::decompress;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Gentoo {
+    #[structopt(
+        long,
+        default_value = "https://distfiles.gentoo.org",
+        help = "Base of Gentoo distfiles mirror"
+    )]
+    pub base: String,
+    #[structopt(long, help = "Listing of distfiles in Manifest format")]
+    pub listing: String,
+}
+
+const LAYOUT_KEY: &str = "distfiles/layout.conf";
+
+/// A layout of distfiles in `layout.conf`.
+#[derive(Debug, PartialEq)]
+enum Layout {
+    /// all files in one directory
+    Flat,
+    /// directories named after hex digits of filename hash, e.g. `BLAKE2B`
+    /// with cutoffs `[8]`
+    FilenameHash(String, Vec<usize>),
+}
+
+impl Layout {
+    /// Path of a distfile relative to `distfiles/`.
+    fn path(&self, filename: &str) -> String {
+        match self {
+            Layout::Flat => filename.to_string(),
+            Layout::FilenameHash(algorithm, cutoffs) => {
+                let hash = match algorithm.as_str() {
+                    "BLAKE2B" => format!("{:x}", blake2::Blake2b::digest(filename.as_bytes())),
+                    _ => format!("{:x}", sha2::Sha512::digest(filename.as_bytes())),
+                };
+                let mut path = String::new();
+                let mut pos = 0;
+                for cutoff in cutoffs {
+                    path.push_str(&hash[pos..pos + cutoff / 4]);
+                    path.push('/');
+                    pos += cutoff / 4;
+                }
+                path.push_str(filename);
+                path
+            }
+        }
+    }
+}
+
+/// Parse the preferred supported layout in `layout.conf`.
+fn parse_layout(content: &str) -> Option<Layout> {
+    let mut in_structure = false;
+    let mut layouts = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_structure = line == "[structure]";
+            continue;
+        }
+        if !in_structure {
+            continue;
+        }
+        if let Some((priority, value)) = line.split_once('=') {
+            if let Ok(priority) = priority.trim().parse::<u32>() {
+                layouts.push((priority, value.trim().to_string()));
+            }
+        }
+    }
+    layouts.sort();
+    layouts.into_iter().find_map(|(_, value)| {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        match parts[..] {
+            ["flat"] => Some(Layout::Flat),
+            ["filename-hash", algorithm @ ("BLAKE2B" | "SHA512"), cutoffs] => {
+                let cutoffs: Vec<usize> = cutoffs
+                    .split(':')
+                    .map(|cutoff| cutoff.parse().ok())
+                    .collect::<Option<_>>()?;
+                // hash of 512 bits has 128 hex digits
+                if cutoffs.iter().any(|cutoff| cutoff % 4 != 0)
+                    || cutoffs.iter().sum::<usize>() > 512
+                {
+                    return None;
+                }
+                Some(Layout::FilenameHash(algorithm.to_string(), cutoffs))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// A `DIST` entry in Manifest.
+#[derive(Debug, PartialEq)]
+struct Distfile {
+    name: String,
+    size: Option<u64>,
+    blake2b: Option<String>,
+    sha512: Option<String>,
+}
+
+fn parse_manifest(content: &str) -> Vec<Distfile> {
+    let mut distfiles = vec![];
+    for line in content.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 3 || parts[0] != "DIST" || parts[1].contains('/') {
+            continue;
+        }
+        let mut distfile = Distfile {
+            name: parts[1].to_string(),
+            size: parts[2].parse().ok(),
+            blake2b: None,
+            sha512: None,
+        };
+        for hash in parts[3..].chunks(2) {
+            match hash {
+                ["BLAKE2B", value] => distfile.blake2b = Some(value.to_string()),
+                ["SHA512", value] => distfile.sha512 = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        distfiles.push(distfile);
+    }
+    distfiles
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Gentoo {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching layout.conf...");
+        progress.set_message("fetching layout.conf...");
+        let layout =
+            parse_layout(&fetch_text(&client, &format!("{}/{}", self.base, LAYOUT_KEY)).await?)
+                .ok_or_else(|| Error::ProcessError("no supported layout".to_string()))?;
+        info!(logger, "layout: {:?}", layout);
+
+        info!(logger, "fetching listing...");
+        progress.set_message("fetching listing...");
+        let data = decompress(&fetch(&client, &self.listing).await?)?;
+        let distfiles = parse_manifest(&String::from_utf8_lossy(&data));
+
+        let mut names = BTreeSet::new();
+        let mut snapshot = vec![];
+        for distfile in distfiles {
+            if !names.insert(distfile.name.clone()) {
+                continue;
+            }
+            let (checksum_method, checksum) = match (distfile.blake2b, distfile.sha512) {
+                (Some(blake2b), _) => (Some("blake2b".to_string()), Some(blake2b)),
+                (None, Some(sha512)) => (Some("sha512".to_string()), Some(sha512)),
+                (None, None) => {
+                    warn!(logger, "no checksum for {}", distfile.name);
+                    (None, None)
+                }
+            };
+            snapshot.push(SnapshotMeta {
+                key: format!("distfiles/{}", layout.path(&distfile.name)),
+                size: distfile.size,
+                checksum_method,
+                checksum,
+                ..Default::default()
+            });
+        }
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} distfiles ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+        snapshot.push(SnapshotMeta::force(LAYOUT_KEY.to_string()));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("gentoo, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Gentoo {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_and_manifest() {
+        let layout = parse_layout(
+            "[structure]
+0=filename-hash BLAKE2B 8
+1=flat
+",
+        )
+        .unwrap();
+        assert_eq!(layout, Layout::FilenameHash("BLAKE2B".to_string(), vec![8]));
+        assert_eq!(
+            layout.path("zlib-1.3.tar.xz").len(),
+            "zlib-1.3.tar.xz".len() + 3
+        );
+        assert_eq!(
+            parse_layout("[structure]\n0=content-hash SHA512 8:8:8\n1=flat\n"),
+            Some(Layout::Flat)
+        );
+        assert_eq!(parse_layout("[structure]\n0=unknown\n"), None);
+
+        let manifest = "DIST zlib-1.3.tar.xz 1495873 BLAKE2B ab01 SHA512 cd23
+EBUILD zlib-1.3.ebuild 1234 BLAKE2B ef45 SHA512 6789
+DIST foo.tar.gz 12 SHA512 0a1b
+";
+        assert_eq!(
+            parse_manifest(manifest),
+            vec![
+                Distfile {
+                    name: "zlib-1.3.tar.xz".to_string(),
+                    size: Some(1495873),
+                    blake2b: Some("ab01".to_string()),
+                    sha512: Some("cd23".to_string()),
+                },
+                Distfile {
+                    name: "foo.tar.gz".to_string(),
+                    size: Some(12),
+                    blake2b: None,
+                    sha512: Some("0a1b".to_string()),
+                },
+            ]
+        );
+    }
+}
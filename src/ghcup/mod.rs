@@ -1,32 +1,42 @@
 //! Ghcup source
 //!
-//! The mirroring of ghcup is split into four sources.
+//! The mirroring of ghcup is split into five sources.
 //!
 //! `GhcupPackages` source provides a file list of all required packages for
 //! ghcup hosted on haskell.org.
 //! The snapshot is generated by parsing the latest ghcup version, fetch
 //! its config, and capture all files located at downloads.haskell.org.
-//! All item inside it is immutable.
+//! Only configs of version between `min_config_version` and
+//! `max_config_version` are considered, as newer ones may not be parsed.
+//! All item inside it is immutable, except unversioned GHCup binaries of the
+//! latest release, which are fetched by the installation script.
 //!
 //! `GhcupConfig` fetches ghcup config.
 //!
 //! `GhcupScript` fetches ghcup installation script.
 //!
+//! `GhcupStackSetup` fetches `stack-setup-2.yaml`, which is used by stack to
+//! install GHC and its tools.
+//!
 //! It's recommended to mirror HLS packages using `GithubRelease` source.
 //!
-//! Do not forget to apply rewrite_pipe to `GhcupConfig` and `GhcupScript`.
+//! Do not forget to apply rewrite_pipe to `GhcupConfig`, `GhcupScript` and
+//! `GhcupStackSetup`.
 //! You may want to merge three (or four) sources into one using `MergePipe`.
 
 use structopt::StructOpt;
 
 use crate::ghcup::packages::GhcupPackages;
 use crate::ghcup::script::GhcupScript;
+use crate::ghcup::stack_setup::GhcupStackSetup;
+use crate::ghcup::utils::Version;
 use crate::ghcup::yaml::GhcupYaml;
 use crate::utils::CommaSplitVecString;
 
 mod packages;
 mod parser;
 mod script;
+mod stack_setup;
 mod utils;
 mod yaml;
 
@@ -36,6 +46,11 @@ pub struct Ghcup {
     pub ghcup_repo_config: GhcupRepoConfig,
     #[structopt(long, default_value = "https://get-ghcup.haskell.org/")]
     pub script_url: String,
+    #[structopt(
+        long,
+        default_value = "https://raw.githubusercontent.com/commercialhaskell/stackage-content/master/stack/stack-setup-2.yaml"
+    )]
+    pub stack_setup_url: String,
     #[structopt(long, help = "Include legacy versions of packages")]
     pub include_old_versions: bool,
     #[structopt(long, help = "mirror url for packages")]
@@ -49,6 +64,18 @@ pub struct Ghcup {
         default_value = "ghcup-0.0.4.yaml,ghcup-0.0.5.yaml,ghcup-0.0.6.yaml"
     )]
     pub additional_yaml: CommaSplitVecString,
+    #[structopt(
+        long,
+        help = "Oldest ghcup config version to mirror packages from",
+        default_value = "0.0.8"
+    )]
+    pub min_config_version: Version,
+    #[structopt(
+        long,
+        help = "Newest ghcup config version to mirror packages from",
+        default_value = "0.0.8"
+    )]
+    pub max_config_version: Version,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -69,6 +96,11 @@ impl Ghcup {
             script_url: self.script_url.clone(),
         }
     }
+    pub fn get_stack_setup(&self) -> GhcupStackSetup {
+        GhcupStackSetup {
+            stack_setup_url: self.stack_setup_url.clone(),
+        }
+    }
     pub fn get_yaml(&self, legacy: bool) -> GhcupYaml {
         GhcupYaml::new(self.ghcup_repo_config.clone(), legacy)
     }
@@ -76,6 +108,8 @@ impl Ghcup {
         GhcupPackages {
             ghcup_repo_config: self.ghcup_repo_config.clone(),
             include_old_versions: self.include_old_versions,
+            min_config_version: self.min_config_version,
+            max_config_version: self.max_config_version,
         }
     }
 }
@@ -3,18 +3,21 @@ use slog::{info, warn};
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
+use crate::fetch::fetch;
 use crate::ghcup::utils::get_raw_blob_url;
 use crate::metadata::SnapshotMeta;
 use crate::traits::{SnapshotStorage, SourceStorage};
 
-use super::parser::{GhcupYamlParser, EXPECTED_CONFIG_VERSION};
-use super::utils::{filter_map_file_objs, list_files};
+use super::parser::GhcupYamlParser;
+use super::utils::{filter_map_file_objs, list_files, Version};
 use super::GhcupRepoConfig;
 
 #[derive(Debug, Clone)]
 pub struct GhcupPackages {
     pub ghcup_repo_config: GhcupRepoConfig,
     pub include_old_versions: bool,
+    pub min_config_version: Version,
+    pub max_config_version: Version,
 }
 
 #[async_trait]
@@ -31,30 +34,41 @@ impl SnapshotStorage<SnapshotMeta> for GhcupPackages {
 
         info!(logger, "fetching ghcup config...");
         progress.set_message("querying version files");
-        let latest_yaml_obj = filter_map_file_objs(
+        let yaml_objs: Vec<_> = filter_map_file_objs(
             list_files(&client, repo_config, &self.ghcup_repo_config.branch).await?,
         )
         .filter(|obj| !obj.is_sig)
-        .max_by(|x, y| x.version.cmp(&y.version))
-        .ok_or_else(|| Error::ProcessError(String::from("no config file found")))?;
+        .collect();
+        let newest_version = yaml_objs.iter().map(|obj| obj.version).max();
 
-        if latest_yaml_obj.version != EXPECTED_CONFIG_VERSION {
-            warn!(
-                logger,
-                "unmatched ghcup config yaml. expected: {}, got: {}",
-                EXPECTED_CONFIG_VERSION,
-                latest_yaml_obj.version
-            )
+        // select the newest config which we know how to parse
+        let latest_yaml_obj = yaml_objs
+            .into_iter()
+            .filter(|obj| {
+                self.min_config_version <= obj.version && obj.version <= self.max_config_version
+            })
+            .max_by(|x, y| x.version.cmp(&y.version))
+            .ok_or_else(|| {
+                Error::ProcessError(format!(
+                    "no config file of version {} to {} found",
+                    self.min_config_version, self.max_config_version
+                ))
+            })?;
+
+        if let Some(newest_version) = newest_version {
+            if newest_version > latest_yaml_obj.version {
+                warn!(
+                    logger,
+                    "newer ghcup config yaml available. using: {}, newest: {}",
+                    latest_yaml_obj.version,
+                    newest_version
+                )
+            }
         }
 
         progress.set_message("downloading yaml config");
         let latest_yaml_blob_url = get_raw_blob_url(&client, repo_config, latest_yaml_obj).await?;
-        let yaml_data = client
-            .get(latest_yaml_blob_url.url)
-            .send()
-            .await?
-            .bytes()
-            .await?;
+        let yaml_data = fetch(&client, &latest_yaml_blob_url.url).await?;
         let ghcup_config: GhcupYamlParser = serde_yaml::from_slice(&yaml_data)?;
 
         let fetch_uris: Vec<_> = ghcup_config
@@ -65,8 +79,18 @@ impl SnapshotStorage<SnapshotMeta> for GhcupPackages {
             .map(String::from)
             .collect();
 
+        let mut snapshot = crate::utils::snapshot_string_to_meta(fetch_uris);
+        // unversioned binaries are replaced on every GHCup release
+        snapshot.extend(
+            ghcup_config
+                .ghcup_downloads
+                .latest_ghcup_binaries()
+                .into_iter()
+                .map(SnapshotMeta::force),
+        );
+
         progress.finish_with_message("done");
-        Ok(crate::utils::snapshot_string_to_meta(fetch_uris))
+        Ok(snapshot)
     }
 
     fn info(&self) -> String {
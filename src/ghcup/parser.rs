@@ -2,10 +2,6 @@ use std::collections::{HashMap, HashSet};
 
 use serde::Deserialize;
 
-use super::utils::Version;
-
-pub const EXPECTED_CONFIG_VERSION: Version = Version::new(0, 0, 8);
-
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DownloadSource {
@@ -46,6 +42,10 @@ impl Release {
     pub fn is_old(&self) -> bool {
         self.vi_tags.iter().any(|item| item == "old")
     }
+
+    pub fn is_latest(&self) -> bool {
+        self.vi_tags.iter().any(|item| item == "Latest")
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -79,6 +79,34 @@ impl Components {
             })
             .collect()
     }
+
+    /// Keys of unversioned GHCup binaries of the latest release, which are
+    /// downloaded by the installation script, e.g. `~ghcup/x86_64-linux-ghcup`.
+    pub fn latest_ghcup_binaries(&self) -> HashSet<String> {
+        self.ghcup
+            .iter()
+            .filter(|(_, release)| release.is_latest())
+            .flat_map(|(version, release)| {
+                release
+                    .uris()
+                    .into_iter()
+                    .filter_map(move |uri| unversioned_ghcup_binary(uri, version))
+            })
+            .collect()
+    }
+}
+
+fn unversioned_ghcup_binary(uri: &str, version: &str) -> Option<String> {
+    let name = uri.strip_prefix(&format!(
+        "https://downloads.haskell.org/~ghcup/{}/",
+        version
+    ))?;
+    let suffix = format!("-{}", version);
+    let (stem, ext) = match name.split_once(&suffix)? {
+        (stem, ext) if ext.is_empty() || ext.starts_with('.') => (stem, ext),
+        _ => return None,
+    };
+    Some(format!("~ghcup/{}{}", stem, ext))
 }
 
 #[derive(Debug, Deserialize)]
@@ -86,3 +114,33 @@ impl Components {
 pub struct GhcupYamlParser {
     pub ghcup_downloads: Components,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unversioned_ghcup_binary() {
+        assert_eq!(
+            unversioned_ghcup_binary(
+                "https://downloads.haskell.org/~ghcup/0.1.19.2/x86_64-linux-ghcup-0.1.19.2",
+                "0.1.19.2"
+            ),
+            Some(String::from("~ghcup/x86_64-linux-ghcup"))
+        );
+        assert_eq!(
+            unversioned_ghcup_binary(
+                "https://downloads.haskell.org/~ghcup/0.1.19.2/x86_64-mingw64-ghcup-0.1.19.2.exe",
+                "0.1.19.2"
+            ),
+            Some(String::from("~ghcup/x86_64-mingw64-ghcup.exe"))
+        );
+        assert_eq!(
+            unversioned_ghcup_binary(
+                "https://github.com/haskell/ghcup-hs/archive/v0.1.19.2.tar.gz",
+                "0.1.19.2"
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use async_trait::async_trait;
+use slog::info;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone)]
+pub struct GhcupStackSetup {
+    pub stack_setup_url: String,
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for GhcupStackSetup {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+
+        info!(logger, "fetching metadata of stack setup info...");
+
+        progress.finish_with_message("done");
+        Ok(vec![SnapshotMeta::force(String::from(
+            "stack-setup-2.yaml",
+        ))])
+    }
+
+    fn info(&self) -> String {
+        format!("ghcup_stack_setup, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for GhcupStackSetup {
+    async fn get_object(
+        &self,
+        _snapshot: &SnapshotMeta,
+        _mission: &Mission,
+    ) -> Result<TransferURL> {
+        Ok(TransferURL(self.stack_setup_url.clone()))
+    }
+}
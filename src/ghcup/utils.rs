@@ -8,6 +8,7 @@ use reqwest::Client;
 use serde::Deserialize;
 
 use crate::error::Result;
+use crate::fetch::fetch_json;
 
 use super::GhcupRepoConfig;
 
@@ -16,12 +17,12 @@ lazy_static! {
         regex::Regex::new(r"ghcup-(?P<ver>\d.\d.\d).yaml(?P<sig>.sig)?$").unwrap();
 }
 
-// order is reverted to derive Ord ;)
+// fields are ordered from major to patch to derive Ord
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Version {
-    pub patch: usize,
-    pub minor: usize,
     pub major: usize,
+    pub minor: usize,
+    pub patch: usize,
 }
 
 impl Version {
@@ -41,7 +42,7 @@ impl Display for Version {
 }
 
 impl FromStr for Version {
-    type Err = ();
+    type Err = String;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         s.split('.')
@@ -53,7 +54,7 @@ impl FromStr for Version {
                     patch.parse().ok()?,
                 ))
             })
-            .ok_or(())
+            .ok_or_else(|| format!("invalid version {}", s))
     }
 }
 
@@ -109,7 +110,7 @@ pub async fn list_files(
         config.repo, commit
     );
 
-    let tree_meta: TreeMeta = client.get(tree_url).send().await?.json().await?;
+    let tree_meta: TreeMeta = fetch_json(client, &tree_url).await?;
     Ok(tree_meta
         .tree
         .into_iter()
@@ -140,15 +141,14 @@ pub async fn get_raw_blob_url(
     config: &GhcupRepoConfig,
     object: ObjectInfo,
 ) -> Result<ObjectInfoWithUrl> {
-    let content: ContentMeta = client
-        .get(format!(
+    let content: ContentMeta = fetch_json(
+        client,
+        &format!(
             "https://api.github.com/repos/{}/contents/{}",
             config.repo, object.path
-        ))
-        .send()
-        .await?
-        .json()
-        .await?;
+        ),
+    )
+    .await?;
     Ok(ObjectInfoWithUrl {
         name: object.name,
         path: object.path,
@@ -78,6 +78,7 @@ impl SnapshotStorage<SnapshotMeta> for GhcupYaml {
                 flags: SnapshotMetaFlag {
                     force: true,
                     force_last: true,
+                    after_all: false,
                 },
                 ..Default::default()
             })
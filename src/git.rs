@@ -0,0 +1,293 @@
+//! Git mirror
+//!
+//! Git mirror maintains bare mirrors of upstream git repositories (e.g.
+//! crates.io-index, or Homebrew taps) in a local directory, which are served
+//! directly instead of going through a target. A repository is cloned with
+//! `git clone --mirror` at the first run, and updated with
+//! `git fetch --prune` later, so that all refs are kept the same as upstream.
+//! `git update-server-info` is run after each update, so that repositories
+//! can also be served as static files with the dumb HTTP protocol.
+//!
+//! Each repository is placed at `{base_path}/{name}.git`, where name is
+//! either given (`name=url`) or the path of the upstream URL, e.g.
+//! `rust-lang/crates.io-index` of `https://github.com/rust-lang/crates.io-index`.
+//! Failed git commands are retried with exponential backoff.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use indicatif::ProgressBar;
+use reqwest::ClientBuilder;
+use slog::{info, o, warn};
+use structopt::StructOpt;
+use tokio::process::Command;
+
+use crate::accounting::Accounting;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::common::Mission;
+use crate::error::{Error, Result};
+use crate::simple_diff_transfer::SimpleDiffTransferConfig;
+use crate::throttle::Throttle;
+use crate::utils::{bar, create_logger, human_duration};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct GitConfig {
+    #[structopt(long, help = "Directory of bare mirrors")]
+    pub base_path: String,
+    #[structopt(
+        long,
+        help = "Repositories to mirror, as url or name=url",
+        required = true,
+        use_delimiter = true
+    )]
+    pub repos: Vec<String>,
+    #[structopt(long, default_value = "3", help = "Retries of failed git commands")]
+    pub retries: u32,
+    #[structopt(
+        long,
+        default_value = "3600",
+        help = "Timeout of each git command in seconds"
+    )]
+    pub timeout: u64,
+    #[structopt(
+        long,
+        default_value = "4",
+        help = "Repositories to update concurrently"
+    )]
+    pub concurrent: usize,
+}
+
+/// An upstream repository.
+#[derive(Debug, PartialEq)]
+struct Repo {
+    name: String,
+    url: String,
+}
+
+/// Parse a repository in config, as `name=url` or `url`.
+fn parse_repo(repo: &str) -> Result<Repo> {
+    let (name, url) = match repo.split_once('=') {
+        // part before `=` of a URL always contains `:`
+        Some((name, url)) if !name.contains(':') => (name.to_string(), url.to_string()),
+        _ => {
+            let path = match url::Url::parse(repo) {
+                Ok(url) => url.path().to_string(),
+                // scp-like URL, e.g. `git@github.com:rust-lang/crates.io-index.git`
+                Err(_) => repo
+                    .split_once(':')
+                    .map(|x| x.1)
+                    .unwrap_or(repo)
+                    .to_string(),
+            };
+            (path, repo.to_string())
+        }
+    };
+    let name = name.trim_matches('/');
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() || name.split('/').any(|part| part.is_empty() || part == "..") {
+        return Err(Error::ConfigureError(format!(
+            "invalid repository name of {}",
+            repo
+        )));
+    }
+    Ok(Repo {
+        name: name.to_string(),
+        url,
+    })
+}
+
+/// Run a git command, failing if it exits with non-zero status.
+async fn git(args: &[&str], timeout: Duration) -> Result<()> {
+    let mut cmd = Command::new("git");
+    cmd.kill_on_drop(true);
+    cmd.args(args);
+    cmd.stdin(Stdio::null());
+    cmd.env("GIT_TERMINAL_PROMPT", "0");
+    let output = tokio::time::timeout(timeout, cmd.output())
+        .await
+        .map_err(|_| Error::TimeoutError(()))??;
+    if !output.status.success() {
+        return Err(Error::ProcessError(format!(
+            "git {} exited with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Clone or update the mirror of a repository once.
+async fn update(repo: &Repo, path: &Path, timeout: Duration) -> Result<()> {
+    let path_str = path.to_string_lossy();
+    if path.join("HEAD").exists() {
+        git(
+            &["-C", &path_str, "remote", "set-url", "origin", &repo.url],
+            timeout,
+        )
+        .await?;
+        git(
+            &["-C", &path_str, "fetch", "--prune", "--quiet", "origin"],
+            timeout,
+        )
+        .await?;
+    } else {
+        // clone into a temporary directory, so that an interrupted clone
+        // won't be taken as a mirror
+        let tmp = PathBuf::from(format!("{}.tmp", path_str));
+        if tmp.exists() {
+            tokio::fs::remove_dir_all(&tmp).await?;
+        }
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        git(
+            &[
+                "clone",
+                "--mirror",
+                "--quiet",
+                &repo.url,
+                &tmp.to_string_lossy(),
+            ],
+            timeout,
+        )
+        .await?;
+        tokio::fs::rename(&tmp, path).await?;
+    }
+    git(&["-C", &path_str, "update-server-info"], timeout).await
+}
+
+/// Clone or update the mirror of a repository, retrying on failure.
+async fn mirror(mission: &Mission, config: &GitConfig, repo: &Repo) -> Result<()> {
+    let logger = &mission.logger;
+    let path = Path::new(&config.base_path).join(format!("{}.git", repo.name));
+    let timeout = Duration::from_secs(config.timeout);
+    let mut attempt = 0;
+    loop {
+        match update(repo, &path, timeout).await {
+            Err(err) if attempt < config.retries => {
+                warn!(
+                    logger,
+                    "failed to update {} (attempt {}): {:?}",
+                    repo.name,
+                    attempt + 1,
+                    err
+                );
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub async fn run(config: GitConfig, transfer_config: SimpleDiffTransferConfig) -> Result<()> {
+    let logger = create_logger(transfer_config.verbose);
+    let repos = config
+        .repos
+        .iter()
+        .map(|repo| parse_repo(repo))
+        .collect::<Result<Vec<_>>>()?;
+
+    let progress = if transfer_config.progress {
+        ProgressBar::new(repos.len() as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    progress.set_style(bar());
+    progress.set_prefix("[git]");
+    let mission = Mission {
+        progress,
+        client: ClientBuilder::new()
+            .user_agent(crate::utils::user_agent())
+            .build()?,
+        logger: logger.new(o!("task" => "git")),
+        accounting: Arc::new(Accounting::default()),
+        breaker: Arc::new(CircuitBreaker::new(
+            transfer_config.circuit_breaker_threshold,
+            transfer_config.circuit_breaker_cooldown,
+        )),
+        throttle: Arc::new(Throttle::new(transfer_config.max_bandwidth)),
+        direct_stream: None,
+        range: Default::default(),
+        validators: None,
+    };
+
+    info!(
+        mission.logger,
+        "mirroring {} repositories to {}",
+        repos.len(),
+        config.base_path
+    );
+    let results: Vec<(String, Result<()>)> = stream::iter(repos.iter().map(|repo| {
+        let mission = &mission;
+        let config = &config;
+        async move {
+            let start = std::time::Instant::now();
+            let result = mirror(mission, config, repo).await;
+            match &result {
+                Ok(()) => info!(
+                    mission.logger,
+                    "updated {} in {}",
+                    repo.name,
+                    human_duration(start.elapsed())
+                ),
+                Err(err) => warn!(mission.logger, "failed to update {}: {:?}", repo.name, err),
+            }
+            mission.progress.set_message(&repo.name);
+            mission.progress.inc(1);
+            (repo.name.clone(), result)
+        }
+    }))
+    .buffer_unordered(config.concurrent)
+    .collect()
+    .await;
+    mission.progress.finish_with_message("done");
+
+    let failed: Vec<String> = results
+        .into_iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(name, _)| name)
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ProcessError(format!(
+            "failed to update {}",
+            failed.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_repo() {
+        assert_eq!(
+            parse_repo("https://github.com/rust-lang/crates.io-index").unwrap(),
+            Repo {
+                name: "rust-lang/crates.io-index".to_string(),
+                url: "https://github.com/rust-lang/crates.io-index".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_repo("homebrew-core=https://github.com/Homebrew/homebrew-core.git").unwrap(),
+            Repo {
+                name: "homebrew-core".to_string(),
+                url: "https://github.com/Homebrew/homebrew-core.git".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_repo("git@github.com:Homebrew/homebrew-cask.git")
+                .unwrap()
+                .name,
+            "Homebrew/homebrew-cask"
+        );
+        assert!(parse_repo("../x=https://example.com/x").is_err());
+    }
+}
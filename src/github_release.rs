@@ -2,18 +2,24 @@
 //!
 //! GitHubRelease source will fetch the GitHub API when taking snapshots.
 //! Then, it will construct a list of downloadable URLs.
+//!
+//! Releases are fetched page by page, until `version_to_retain` releases with
+//! matching assets are collected. Pre-releases are skipped unless
+//! `include_prerelease` is set, and only assets whose names match
+//! `asset_filter` are mirrored if it is set.
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::fetch::fetch_json;
 use crate::metadata::SnapshotMeta;
-use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
 use crate::traits::{SnapshotStorage, SourceStorage};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use regex::Regex;
 use serde::Deserialize;
 use slog::info;
-use std::time::Duration;
+use std::collections::HashSet;
 use structopt::StructOpt;
 
 #[derive(Deserialize, Debug)]
@@ -31,6 +37,8 @@ pub struct GitHubReleaseAsset {
 #[derive(Deserialize, Debug)]
 pub struct GitHubReleaseItem {
     tag_name: String,
+    #[serde(default)]
+    prerelease: bool,
     assets: Vec<GitHubReleaseAsset>,
 }
 
@@ -40,15 +48,61 @@ pub struct GitHubRelease {
     pub repo: String,
     #[structopt(long, help = "Version numbers to retain")]
     pub version_to_retain: usize,
+    #[structopt(long, help = "Only mirror assets with names matching this regex")]
+    pub asset_filter: Option<String>,
+    #[structopt(long, help = "Also mirror pre-releases")]
+    pub include_prerelease: bool,
 }
 
 impl GitHubRelease {
+    /// Mirror all assets of latest `version_to_retain` releases, including
+    /// pre-releases.
     pub fn new(repo: String, version_to_retain: usize) -> Self {
         Self {
             repo,
             version_to_retain,
+            asset_filter: None,
+            include_prerelease: true,
+        }
+    }
+}
+
+const PER_PAGE: usize = 100;
+
+/// Select assets of a page of releases, skipping releases in `seen` and
+/// stopping when `version_to_retain` releases are selected. Returns whether
+/// enough releases are selected.
+fn select_assets(
+    releases: Vec<GitHubReleaseItem>,
+    matcher: Option<&Regex>,
+    include_prerelease: bool,
+    version_to_retain: usize,
+    seen: &mut HashSet<String>,
+    assets: &mut Vec<GitHubReleaseAsset>,
+) -> bool {
+    for release in releases {
+        if seen.len() >= version_to_retain {
+            break;
+        }
+        // releases may shift between pages when a new one is published
+        if (release.prerelease && !include_prerelease) || seen.contains(&release.tag_name) {
+            continue;
+        }
+        let matched: Vec<GitHubReleaseAsset> = release
+            .assets
+            .into_iter()
+            .filter(|asset| match matcher {
+                Some(matcher) => matcher.is_match(&asset.name),
+                None => true,
+            })
+            .collect();
+        if matched.is_empty() {
+            continue;
         }
+        seen.insert(release.tag_name);
+        assets.extend(matched);
     }
+    seen.len() >= version_to_retain
 }
 
 #[async_trait]
@@ -62,32 +116,52 @@ impl SnapshotStorage<SnapshotMeta> for GitHubRelease {
         let progress = mission.progress;
         let client = mission.client;
 
+        let matcher = match &self.asset_filter {
+            Some(filter) => Some(Regex::new(filter).map_err(|err| {
+                Error::ConfigureError(format!("invalid asset filter {}: {}", filter, err))
+            })?),
+            None => None,
+        };
+
         info!(logger, "fetching GitHub json...");
-        let data = client
-            .get(&format!(
-                "https://api.github.com/repos/{}/releases",
-                self.repo
-            ))
-            .send()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?
-            .text()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?;
-
-        info!(logger, "parsing...");
-        let releases = serde_json::from_str::<Vec<GitHubReleaseItem>>(&data)?;
+        let mut seen = HashSet::new();
+        let mut assets = vec![];
+        let mut page = 1;
+        loop {
+            progress.set_message(&format!("page {}, {} releases", page, seen.len()));
+            let releases: Vec<GitHubReleaseItem> = fetch_json(
+                &client,
+                &format!(
+                    "https://api.github.com/repos/{}/releases?per_page={}&page={}",
+                    self.repo, PER_PAGE, page
+                ),
+            )
+            .await?;
+            let last_page = releases.len() < PER_PAGE;
+            let enough = select_assets(
+                releases,
+                matcher.as_ref(),
+                self.include_prerelease,
+                self.version_to_retain,
+                &mut seen,
+                &mut assets,
+            );
+            if enough || last_page {
+                break;
+            }
+            page += 1;
+        }
+        info!(
+            logger,
+            "{} assets of {} releases in {} pages",
+            assets.len(),
+            seen.len(),
+            page
+        );
+
         let replace_string = format!("https://github.com/{}/", self.repo);
-        let snapshot: Vec<SnapshotMeta> = releases
+        let snapshot: Vec<SnapshotMeta> = assets
             .into_iter()
-            .map(|release| {
-                progress.set_message(&release.tag_name);
-                release.assets
-            })
-            .take(self.version_to_retain)
-            .flatten()
             .map(|asset| SnapshotMeta {
                 key: if asset.browser_download_url.starts_with(&replace_string) {
                     asset.browser_download_url[replace_string.len()..].to_string()
@@ -119,3 +193,44 @@ impl SourceStorage<SnapshotMeta, TransferURL> for GitHubRelease {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_assets() {
+        let asset = |name: &str| {
+            serde_json::json!({
+                "url": "", "id": 0, "name": name, "content_type": "", "size": 1,
+                "created_at": "2021-01-01T00:00:00Z", "updated_at": "2021-01-01T00:00:00Z",
+                "browser_download_url": format!("https://github.com/a/b/releases/download/{}", name),
+            })
+        };
+        let releases: Vec<GitHubReleaseItem> = serde_json::from_value(serde_json::json!([
+            {"tag_name": "v3-rc1", "prerelease": true, "assets": [asset("x-linux.tar.gz")]},
+            {"tag_name": "v2", "assets": [asset("x-linux.tar.gz"), asset("x-windows.zip")]},
+            {"tag_name": "v1.1", "assets": [asset("x-windows.zip")]},
+            {"tag_name": "v1", "assets": [asset("x-linux.tar.gz")]},
+        ]))
+        .unwrap();
+        let matcher = Regex::new(r"linux").unwrap();
+        let mut seen = HashSet::new();
+        let mut assets = vec![];
+        assert!(select_assets(
+            releases,
+            Some(&matcher),
+            false,
+            2,
+            &mut seen,
+            &mut assets
+        ));
+        assert_eq!(
+            seen,
+            vec!["v2".to_string(), "v1".to_string()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(assets.len(), 2);
+    }
+}
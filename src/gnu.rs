@@ -0,0 +1,158 @@
+//! GNU source
+//!
+//! GNU source mirrors the GNU FTP tree. Instead of scanning directories over
+//! HTTP, it reads the published `ls-lR.gz`, which is a recursive `ls -lR`
+//! listing of the tree, to get sizes and modification times of all files.
+//! Symbolic links are not mirrored.
+//!
+//! `ls` only shows time of files modified within 6 months, and only date of
+//! older ones. Therefore, modification times are truncated to date, so that
+//! they won't change as files get older.
+
+use std::io::Read;
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use flate2::read::GzDecoder;
+use regex::Regex;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{human_size, parse_ls_date};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Gnu {
+    #[structopt(
+        long,
+        default_value = "https://ftp.gnu.org/gnu",
+        help = "Base of GNU FTP tree"
+    )]
+    pub base: String,
+    #[structopt(long, help = "URL of recursive listing, defaults to {base}/ls-lR.gz")]
+    pub listing: Option<String>,
+}
+
+/// Parse a recursive `ls -lR` listing into snapshot of regular files.
+pub(crate) fn parse_listing(content: &str, today: NaiveDate) -> Vec<SnapshotMeta> {
+    let matcher = Regex::new(
+        r"^-\S+\s+\d+\s+\S+\s+\S+\s+(\d+)\s+(\w{3})\s+(\d{1,2})\s+(\d{1,2}:\d{2}|\d{4})\s(.+)$",
+    )
+    .unwrap();
+    let mut dir = String::new();
+    let mut snapshot = vec![];
+    for line in content.lines() {
+        if let Some(cap) = matcher.captures(line) {
+            let name = cap[5].trim_start();
+            let key = if dir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            snapshot.push(SnapshotMeta {
+                key,
+                size: cap[1].parse().ok(),
+                last_modified: parse_ls_date(&cap[2], &cap[3], &cap[4], today),
+                ..Default::default()
+            });
+        } else if let Some(path) = line.strip_suffix(':') {
+            // directory header, e.g. `.:` or `./gcc/gcc-13.2.0:`
+            let path = path.strip_prefix('.').unwrap_or(path);
+            dir = path.trim_start_matches('/').to_string();
+        }
+    }
+    snapshot
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Gnu {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let listing = self
+            .listing
+            .clone()
+            .unwrap_or_else(|| format!("{}/ls-lR.gz", self.base));
+        info!(logger, "fetching {}...", listing);
+        progress.set_message("fetching ls-lR.gz...");
+        let data = fetch(&client, &listing).await?;
+        let mut buf = vec![];
+        GzDecoder::new(&data[..]).read_to_end(&mut buf)?;
+
+        progress.set_message("parsing ls-lR.gz...");
+        let snapshot = parse_listing(&String::from_utf8_lossy(&buf), Utc::now().date_naive());
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("gnu, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Gnu {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listing() {
+        let listing = ".:
+total 12
+drwxr-xr-x    4 3003     3003         4096 Nov 28  2023 gcc
+-rw-r--r--    1 3003     3003          924 Feb 11  2019 README
+lrwxrwxrwx    1 0        0              12 Aug 12  2020 gnu-keyring.gpg -> ../keyring
+
+./gcc:
+total 8
+drwxr-xr-x    2 3003     3003         4096 Jul 27  2023 gcc-13.2.0
+
+./gcc/gcc-13.2.0:
+total 171240
+-rw-r--r--    1 3003     3003     87858592 Jul 27 08:43 gcc-13.2.0.tar.xz
+-rw-r--r--    1 3003     3003          833 Dec 30 08:43 gcc 13.2.0.tar.xz.sig
+";
+        let today = NaiveDate::from_ymd_opt(2023, 11, 30).unwrap();
+        let snapshot = parse_listing(listing, today);
+        let keys: Vec<&str> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "README",
+                "gcc/gcc-13.2.0/gcc-13.2.0.tar.xz",
+                "gcc/gcc-13.2.0/gcc 13.2.0.tar.xz.sig",
+            ]
+        );
+        assert_eq!(snapshot[0].size, Some(924));
+        assert_eq!(snapshot[0].last_modified, Some(1549843200));
+        // 2023-07-27
+        assert_eq!(snapshot[1].last_modified, Some(1690416000));
+        // a date in the future belongs to last year, i.e. 2022-12-30
+        assert_eq!(snapshot[2].last_modified, Some(1672358400));
+    }
+}
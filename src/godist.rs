@@ -0,0 +1,183 @@
+//! Go toolchain source
+//!
+//! Go toolchain source reads the release list of go.dev
+//! (`/dl/?mode=json&include=all`), and yields archives, installers and
+//! source tarballs of all releases with sha256 checksums and sizes. Files
+//! are placed at the root of the mirror, the same as `dl.google.com/go`.
+//!
+//! If `minor_versions_to_retain` is set, only releases of the latest minor
+//! versions (e.g. `go1.22` and `go1.21`) are kept, including all their
+//! patch releases and pre-releases.
+
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::fetch::fetch_json;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct GoDist {
+    #[structopt(
+        long,
+        default_value = "https://go.dev/dl/?mode=json&include=all",
+        help = "URL of release list"
+    )]
+    pub releases: String,
+    #[structopt(
+        long,
+        default_value = "https://dl.google.com/go",
+        help = "Base of release files"
+    )]
+    pub base: String,
+    #[structopt(long, help = "Minor versions to retain, e.g. 2 for go1.22 and go1.21")]
+    pub minor_versions_to_retain: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    version: String,
+    #[serde(default)]
+    files: Vec<ReleaseFile>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseFile {
+    filename: String,
+    #[serde(default)]
+    sha256: String,
+    size: Option<u64>,
+}
+
+/// Minor version of a release, e.g. `(1, 21)` of `go1.21.5` and `go1.22rc1`.
+fn minor_version(version: &str) -> Option<(u64, u64)> {
+    let version = version.strip_prefix("go")?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()
+        .and_then(|minor| minor.parse().ok())
+        .unwrap_or(0);
+    Some((major, minor))
+}
+
+/// Select files of releases to mirror.
+fn select_files(
+    releases: Vec<Release>,
+    minor_versions_to_retain: Option<usize>,
+) -> Vec<ReleaseFile> {
+    let minors: BTreeSet<(u64, u64)> = releases
+        .iter()
+        .filter_map(|release| minor_version(&release.version))
+        .collect();
+    let retained: BTreeSet<(u64, u64)> = match minor_versions_to_retain {
+        Some(count) => minors.into_iter().rev().take(count).collect(),
+        None => minors,
+    };
+    releases
+        .into_iter()
+        .filter(|release| {
+            minor_version(&release.version)
+                .map(|minor| retained.contains(&minor))
+                .unwrap_or(false)
+        })
+        .flat_map(|release| release.files)
+        .collect()
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for GoDist {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching release list...");
+        progress.set_message("fetching release list...");
+        let releases: Vec<Release> = fetch_json(&client, &self.releases).await?;
+        info!(logger, "{} releases", releases.len());
+
+        let mut keys = BTreeSet::new();
+        let snapshot: Vec<SnapshotMeta> = select_files(releases, self.minor_versions_to_retain)
+            .into_iter()
+            .filter(|file| keys.insert(file.filename.clone()))
+            .map(|file| {
+                let checksum = Some(file.sha256).filter(|sha256| !sha256.is_empty());
+                SnapshotMeta {
+                    key: file.filename,
+                    size: file.size,
+                    checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+                    checksum,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("godist, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for GoDist {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_files() {
+        assert_eq!(minor_version("go1.21.5"), Some((1, 21)));
+        assert_eq!(minor_version("go1.22rc1"), Some((1, 22)));
+        assert_eq!(minor_version("go1"), Some((1, 0)));
+        assert_eq!(minor_version("weekly"), None);
+
+        let releases: Vec<Release> = serde_json::from_str(
+            r#"[
+                {"version": "go1.22rc1", "stable": false, "files": [
+                    {"filename": "go1.22rc1.src.tar.gz", "sha256": "ab", "size": 1, "kind": "source"}
+                ]},
+                {"version": "go1.21.5", "stable": true, "files": [
+                    {"filename": "go1.21.5.linux-amd64.tar.gz", "os": "linux", "arch": "amd64", "sha256": "cd", "size": 2, "kind": "archive"}
+                ]},
+                {"version": "go1.20.12", "stable": true, "files": [
+                    {"filename": "go1.20.12.src.tar.gz", "sha256": "ef", "size": 3, "kind": "source"}
+                ]}
+            ]"#,
+        )
+        .unwrap();
+        let files = select_files(releases, Some(2));
+        let names: Vec<&str> = files.iter().map(|file| file.filename.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["go1.22rc1.src.tar.gz", "go1.21.5.linux-amd64.tar.gz"]
+        );
+    }
+}
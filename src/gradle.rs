@@ -1,20 +1,101 @@
+//! Gradle source
+//!
+//! Gradle source mirrors distributions listed in `versions/all`, along with
+//! their `.sha256` files and `.sha256` files of wrappers. Checksums of
+//! distributions are fetched from their `checksumUrl`, so that they can be
+//! verified by `ChecksumPipe`.
+//!
+//! If `mirror_base` is set, `versions/all` is generated by `VersionsPipe`, in
+//! which URLs of mirrored files point to `mirror_base`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::Result;
-use crate::metadata::SnapshotMeta;
-use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::fetch::{fetch_text, fetch_text_with, FetchOptions};
+use crate::filter_pipe::Filtered;
+use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
+use crate::stream_pipe::{ByteObject, ByteStream, ByteStreamPipe};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::{hash_string, unix_time};
+
 use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
 use serde_json::Value;
-use slog::info;
-use std::time::Duration;
+use sha2::Digest;
+use slog::{info, warn};
 use structopt::StructOpt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
 
-#[derive(Debug, StructOpt)]
-pub struct Gradle {
+#[derive(Debug, Clone, StructOpt)]
+pub struct GradleConfig {
     #[structopt(long, default_value = "https://services.gradle.org/versions/all")]
     pub api_base: String,
     #[structopt(long, default_value = "https://services.gradle.org/distributions/")]
     pub distribution_base: String,
+    #[structopt(
+        long,
+        help = "Base URL of mirrored distributions, to generate versions/all pointing to them"
+    )]
+    pub mirror_base: Option<String>,
+}
+
+pub struct Gradle {
+    pub config: GradleConfig,
+    /// upstream `versions/all`, kept for `VersionsPipe`
+    versions_json: Option<String>,
+}
+
+impl Gradle {
+    pub fn new(config: GradleConfig) -> Self {
+        Self {
+            config,
+            versions_json: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Gradle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Version {
+    rc_for: Option<String>,
+    download_url: Option<String>,
+    checksum_url: Option<String>,
+    wrapper_checksum_url: Option<String>,
+}
+
+const URL_FIELDS: [&str; 3] = ["downloadUrl", "checksumUrl", "wrapperChecksumUrl"];
+
+/// Point URLs of mirrored files in `versions/all` to `mirror_base`.
+fn rewrite_versions(
+    versions: &mut Value,
+    distribution_base: &str,
+    mirror_base: &str,
+    keys: &HashSet<String>,
+) {
+    let versions = match versions.as_array_mut() {
+        Some(versions) => versions,
+        None => return,
+    };
+    for version in versions.iter_mut().filter_map(|x| x.as_object_mut()) {
+        for field in URL_FIELDS.iter() {
+            if let Some(Value::String(url)) = version.get_mut(*field) {
+                if let Some(key) = url.strip_prefix(distribution_base) {
+                    if keys.contains(key) {
+                        *url = format!("{}/{}", mirror_base, key);
+                    }
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -22,58 +103,79 @@ impl SnapshotStorage<SnapshotMeta> for Gradle {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
+        config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
         info!(logger, "fetching API json...");
-        let data = client
-            .get(&self.api_base)
-            .send()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?
-            .text()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?;
+        let options = FetchOptions::default().content_type("json");
+        let data = fetch_text_with(&client, &self.config.api_base, &options).await?;
 
         info!(logger, "parsing...");
-        let json: Value = serde_json::from_str(&data).unwrap();
-        let packages = json.as_array().unwrap();
-        let snapshot: Vec<SnapshotMeta> = packages
-            .iter()
-            .filter_map(|package| package.as_object())
-            .filter_map(|package| {
-                progress.set_message(
-                    package
-                        .get("version")
-                        .and_then(|version| version.as_str())
-                        .unwrap_or(""),
-                );
-                if let Some(rc_for) = package.get("rcFor") {
-                    if let Some(rc_for) = rc_for.as_str() {
-                        if !rc_for.is_empty() {
-                            return None;
-                        }
+        let versions: Vec<Version> = serde_json::from_str(&data)?;
+        let base = &self.config.distribution_base;
+        let key_of = |url: &Option<String>| {
+            url.as_deref()
+                .and_then(|url| url.strip_prefix(base.as_str()))
+                .map(|key| key.to_string())
+        };
+        // (key of distribution, URL of its checksum)
+        let mut distributions = vec![];
+        let mut checksum_files = vec![];
+        for version in &versions {
+            if matches!(version.rc_for.as_deref(), Some(rc_for) if !rc_for.is_empty()) {
+                continue;
+            }
+            if let Some(key) = key_of(&version.download_url) {
+                distributions.push((key, version.checksum_url.clone()));
+                checksum_files.extend(key_of(&version.checksum_url));
+                checksum_files.extend(key_of(&version.wrapper_checksum_url));
+            }
+        }
+
+        info!(
+            logger,
+            "fetching checksums of {} distributions...",
+            distributions.len()
+        );
+        progress.set_length(distributions.len() as u64);
+        progress.set_style(crate::utils::bar());
+        let mut snapshot: Vec<SnapshotMeta> =
+            stream::iter(distributions.into_iter().map(|(key, checksum_url)| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let logger = logger.clone();
+                async move {
+                    let checksum = match checksum_url {
+                        Some(url) => match fetch_text(&client, &url).await {
+                            Ok(checksum) => Some(checksum.trim().to_string()),
+                            Err(err) => {
+                                warn!(logger, "failed to fetch checksum of {}: {:?}", key, err);
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    progress.set_message(&key);
+                    progress.inc(1);
+                    SnapshotMeta {
+                        key,
+                        checksum_method: checksum.as_ref().map(|_| "sha256".to_string()),
+                        checksum,
+                        ..Default::default()
                     }
                 }
-                package.get("downloadUrl")
-            })
-            .filter_map(|url| url.as_str())
-            .filter(|url| url.starts_with(&self.distribution_base))
-            .map(|url| url.to_string())
-            .map(|url| {
-                if url.starts_with(&self.distribution_base) {
-                    url[self.distribution_base.len()..].to_string()
-                } else {
-                    panic!("package doesn't lay at its base {}", url)
-                }
-            })
-            .map(SnapshotMeta::new)
-            .collect();
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .collect()
+            .await;
+        snapshot.extend(checksum_files.into_iter().map(SnapshotMeta::new));
+
+        if self.config.mirror_base.is_some() {
+            self.versions_json = Some(data);
+        }
 
         progress.finish_with_message("done");
 
@@ -90,7 +192,153 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Gradle {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
         Ok(TransferURL(format!(
             "{}/{}",
-            self.distribution_base, snapshot.key
+            self.config.distribution_base.trim_end_matches('/'),
+            snapshot.key
         )))
     }
 }
+
+/// `VersionsPipe` adds `versions/all` generated by `Gradle` source, if
+/// `mirror_base` is set. Only URLs of files left by filters are pointed to
+/// the mirror.
+pub struct VersionsPipe {
+    source: ByteStreamPipe<Filtered<Gradle>>,
+    buffer_path: String,
+    files: BTreeMap<String, String>,
+}
+
+impl VersionsPipe {
+    pub fn new(source: ByteStreamPipe<Filtered<Gradle>>, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for VersionsPipe {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        let gradle = self.source.source.inner_mut();
+        if let (Some(mirror_base), Some(data)) =
+            (&gradle.config.mirror_base, gradle.versions_json.take())
+        {
+            let keys: HashSet<String> = snapshot.iter().map(|meta| meta.key.clone()).collect();
+            let mut json: Value = serde_json::from_str(&data)?;
+            rewrite_versions(
+                &mut json,
+                &gradle.config.distribution_base,
+                mirror_base.trim_end_matches('/'),
+                &keys,
+            );
+            self.files
+                .insert("versions/all".to_string(), serde_json::to_string(&json)?);
+        }
+        // `versions/all` is transferred after distributions, so that it never
+        // points to files not mirrored yet.
+        for (key, content) in &self.files {
+            snapshot.push(SnapshotMeta {
+                key: key.clone(),
+                size: Some(content.len() as u64),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some(format!("{:x}", sha2::Sha256::digest(content.as_bytes()))),
+                flags: SnapshotMetaFlag {
+                    force: false,
+                    force_last: true,
+                    after_all: false,
+                },
+                ..Default::default()
+            });
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("VersionsPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for VersionsPipe {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        if let Some(content) = self.files.get(key) {
+            let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+            let path = Path::new(&self.buffer_path).join(pipe_file);
+            let mut f = BufWriter::new(
+                tokio::fs::OpenOptions::default()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .read(true)
+                    .open(&path)
+                    .await?,
+            );
+            f.write_all(content.as_bytes()).await?;
+            f.flush().await?;
+            let mut f = f.into_inner();
+            f.seek(std::io::SeekFrom::Start(0)).await?;
+            Ok(ByteStream {
+                object: ByteObject::LocalFile {
+                    file: Some(f),
+                    path: Some(path),
+                },
+                length: content.len() as u64,
+                modified_at: unix_time(),
+                content_type: Some("application/json".to_string()),
+                checksum: None,
+                etag: None,
+            })
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_versions() {
+        let base = "https://services.gradle.org/distributions/";
+        let mut versions = serde_json::json!([{
+            "version": "8.5",
+            "downloadUrl": format!("{}gradle-8.5-bin.zip", base),
+            "checksumUrl": format!("{}gradle-8.5-bin.zip.sha256", base),
+            "wrapperChecksumUrl": format!("{}gradle-8.5-wrapper.jar.sha256", base),
+        }]);
+        let keys = vec!["gradle-8.5-bin.zip", "gradle-8.5-bin.zip.sha256"]
+            .into_iter()
+            .map(|key| key.to_string())
+            .collect();
+        rewrite_versions(
+            &mut versions,
+            base,
+            "https://mirror.example.com/gradle",
+            &keys,
+        );
+        assert_eq!(
+            versions[0]["downloadUrl"],
+            "https://mirror.example.com/gradle/gradle-8.5-bin.zip"
+        );
+        assert_eq!(
+            versions[0]["checksumUrl"],
+            "https://mirror.example.com/gradle/gradle-8.5-bin.zip.sha256"
+        );
+        assert_eq!(
+            versions[0]["wrapperChecksumUrl"],
+            format!("{}gradle-8.5-wrapper.jar.sha256", base)
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Helm source
+//!
+//! Helm source parses `index.yaml` of a Helm chart repository, and yields
+//! chart tarballs with sha256 digests. Chart URLs may be either relative to
+//! the repository, or point to other hosts (e.g. GitHub Releases). Charts
+//! hosted elsewhere are placed under `charts/` of the mirror.
+//!
+//! `index.yaml` is always transferred at the end. If `target_mirror` is set,
+//! it should be piped through `RewritePipe` with `rewrite_index`, so that
+//! chart URLs point to the mirror.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct HelmConfig {
+    #[structopt(long, help = "Base of Helm chart repository")]
+    pub base: String,
+    #[structopt(long, help = "Mirror URL to rewrite index.yaml to")]
+    pub target_mirror: Option<String>,
+    /// When debug mode is enabled, only first 100 charts will be selected.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+pub struct Helm {
+    pub config: HelmConfig,
+    /// chart key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    #[serde(default)]
+    entries: HashMap<String, Vec<ChartVersion>>,
+}
+
+#[derive(Deserialize)]
+struct ChartVersion {
+    #[serde(default)]
+    urls: Vec<String>,
+    digest: Option<String>,
+}
+
+/// Resolve chart URL in `index.yaml`, returning the mirror key and the absolute URL.
+fn chart_key(base: &str, chart_url: &str) -> Result<(String, String)> {
+    let index_url = url::Url::parse(&format!("{}/index.yaml", base))
+        .map_err(|err| Error::ProcessError(format!("invalid base: {:?}", err)))?;
+    let url = index_url
+        .join(chart_url)
+        .map_err(|err| Error::ProcessError(format!("invalid chart url: {:?}", err)))?;
+    let key = match url.as_str().strip_prefix(base) {
+        Some(key) if key.starts_with('/') => key.trim_start_matches('/').to_string(),
+        _ => format!(
+            "charts/{}",
+            url.path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .unwrap_or_default()
+        ),
+    };
+    Ok((key, url.to_string()))
+}
+
+/// Rewrite chart URLs in `index.yaml` to `target_mirror`.
+/// Content other than an index with `entries` is returned as-is.
+pub fn rewrite_index(base: &str, target_mirror: &str, content: String) -> Result<String> {
+    let mut index: serde_yaml::Value = match serde_yaml::from_str(&content) {
+        Ok(index) => index,
+        Err(_) => return Ok(content),
+    };
+    let entries = match index
+        .get_mut("entries")
+        .and_then(|entries| entries.as_mapping_mut())
+    {
+        Some(entries) => entries,
+        None => return Ok(content),
+    };
+    for (_, versions) in entries.iter_mut() {
+        for version in versions.as_sequence_mut().into_iter().flatten() {
+            let urls = version
+                .get_mut("urls")
+                .and_then(|urls| urls.as_sequence_mut());
+            for url in urls.into_iter().flatten() {
+                if let Some(chart_url) = url.as_str() {
+                    let (key, _) = chart_key(base, chart_url)?;
+                    *url = serde_yaml::Value::String(format!("{}/{}", target_mirror, key));
+                }
+            }
+        }
+    }
+    Ok(serde_yaml::to_string(&index)?)
+}
+
+impl Helm {
+    pub fn new(config: HelmConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Helm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Helm {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let base = &self.config.base;
+
+        info!(logger, "fetching index.yaml...");
+        progress.set_message("fetching index.yaml...");
+        let data = fetch_text(&client, &format!("{}/index.yaml", base)).await?;
+
+        info!(logger, "parsing...");
+        progress.set_message("parsing...");
+        let index: Index = serde_yaml::from_str(&data)?;
+        let mut charts: Vec<_> = index.entries.into_iter().collect();
+        charts.sort_by(|a, b| a.0.cmp(&b.0));
+        if self.config.debug {
+            charts.truncate(100);
+        }
+
+        let mut snapshot = vec![];
+        for (name, versions) in charts {
+            for version in versions {
+                for chart_url in &version.urls {
+                    let (key, url) = match chart_key(base, chart_url) {
+                        Ok(result) => result,
+                        Err(err) => {
+                            warn!(logger, "failed to resolve {}: {:?}", name, err);
+                            continue;
+                        }
+                    };
+                    snapshot.push(SnapshotMeta {
+                        key: key.clone(),
+                        checksum_method: version.digest.as_ref().map(|_| "sha256".to_string()),
+                        checksum: version.digest.clone(),
+                        ..Default::default()
+                    });
+                    self.urls.insert(key, url);
+                }
+            }
+        }
+        info!(logger, "{} charts in index", snapshot.len());
+
+        snapshot.push(SnapshotMeta::force("index.yaml".to_string()));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("helm, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Helm {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(match self.urls.get(&snapshot.key) {
+            Some(url) => url.clone(),
+            None => format!("{}/{}", self.config.base, snapshot.key),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chart_key() {
+        let base = "https://charts.example.com/stable";
+        assert_eq!(
+            chart_key(base, "nginx-1.0.0.tgz").unwrap(),
+            (
+                "nginx-1.0.0.tgz".to_string(),
+                "https://charts.example.com/stable/nginx-1.0.0.tgz".to_string()
+            )
+        );
+        assert_eq!(
+            chart_key(
+                base,
+                "https://charts.example.com/stable/packages/nginx-1.0.0.tgz"
+            )
+            .unwrap()
+            .0,
+            "packages/nginx-1.0.0.tgz"
+        );
+        assert_eq!(
+            chart_key(
+                base,
+                "https://github.com/example/charts/releases/download/nginx-1.0.0/nginx-1.0.0.tgz"
+            )
+            .unwrap()
+            .0,
+            "charts/nginx-1.0.0.tgz"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_index() {
+        let index = r#"
+apiVersion: v1
+entries:
+  nginx:
+    - name: nginx
+      version: 1.0.0
+      digest: abcd
+      urls:
+        - https://github.com/example/charts/releases/download/nginx-1.0.0/nginx-1.0.0.tgz
+"#;
+        let rewritten = rewrite_index(
+            "https://charts.example.com",
+            "https://mirror.example.com/helm",
+            index.to_string(),
+        )
+        .unwrap();
+        assert!(rewritten.contains("https://mirror.example.com/helm/charts/nginx-1.0.0.tgz"));
+        assert!(!rewritten.contains("github.com"));
+
+        let prov = "-----BEGIN PGP SIGNED MESSAGE-----\n".to_string();
+        assert_eq!(
+            rewrite_index("https://charts.example.com", "https://mirror", prov.clone()).unwrap(),
+            prov
+        );
+    }
+}
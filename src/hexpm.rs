@@ -0,0 +1,274 @@
+//! hex.pm source
+//!
+//! Hex.pm source scans the Hex registry of Elixir and Erlang packages.
+//! The registry is served as gzipped, signed protobuf messages. This source
+//! first fetches `versions` to enumerate all packages and their versions,
+//! then fetches `packages/<name>` of every package for release checksums.
+//!
+//! This source yields a snapshot with checksum metadata of package tarballs.
+//! Documentation tarballs and registry files are also included. Registry
+//! files are always transferred at the end, so that clients never see a
+//! package before its tarball is ready.
+//!
+//! The registry doesn't record which releases have documentation, so a
+//! documentation tarball is listed for every release. Before downloading
+//! one, it's checked with a HEAD request, and left out as absent if upstream
+//! doesn't have it.
+//!
+//! Signatures of registry files are not verified. They are mirrored as-is,
+//! so clients may still verify them against the public key of hex.pm.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use prost::Message;
+use reqwest::{Client, StatusCode};
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::bar;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Hexpm {
+    #[structopt(
+        long,
+        default_value = "https://repo.hex.pm",
+        help = "Base of Hex repository"
+    )]
+    pub repo_base: String,
+    #[structopt(long, help = "Don't mirror documentation tarballs")]
+    pub no_docs: bool,
+    /// When debug mode is enabled, only first 100 packages will be selected.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Protobuf messages of Hex registry.
+///
+/// Reference: https://github.com/hexpm/specifications/blob/main/registry-v2.md
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Signed {
+        #[prost(bytes = "vec", tag = "1")]
+        pub payload: Vec<u8>,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub signature: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Versions {
+        #[prost(message, repeated, tag = "1")]
+        pub packages: Vec<VersionsPackage>,
+        #[prost(string, tag = "2")]
+        pub repository: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct VersionsPackage {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, repeated, tag = "2")]
+        pub versions: Vec<String>,
+        #[prost(int32, repeated, packed = "true", tag = "3")]
+        pub retired: Vec<i32>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Package {
+        #[prost(message, repeated, tag = "1")]
+        pub releases: Vec<Release>,
+        #[prost(string, tag = "2")]
+        pub name: String,
+        #[prost(string, tag = "3")]
+        pub repository: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Release {
+        #[prost(string, tag = "1")]
+        pub version: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub inner_checksum: Vec<u8>,
+        #[prost(bytes = "vec", optional, tag = "5")]
+        pub outer_checksum: Option<Vec<u8>>,
+    }
+}
+
+/// Download a registry file, and decode the payload of it.
+async fn fetch_registry<T: Message + Default>(client: &Client, url: &str) -> Result<T> {
+    let data = fetch(client, url).await?;
+    let mut buf = vec![];
+    GzDecoder::new(&data[..]).read_to_end(&mut buf)?;
+    let signed = proto::Signed::decode(&buf[..])?;
+    Ok(T::decode(&signed.payload[..])?)
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Generate snapshot of a package. `checksums` maps version to sha256 of tarball.
+fn package_snapshot(
+    name: &str,
+    versions: &[String],
+    checksums: &HashMap<String, String>,
+    docs: bool,
+) -> Vec<SnapshotMeta> {
+    let mut snapshot = vec![];
+    for version in versions {
+        let checksum = checksums.get(version);
+        snapshot.push(SnapshotMeta {
+            key: format!("tarballs/{}-{}.tar", name, version),
+            checksum_method: checksum.map(|_| "sha256".to_string()),
+            checksum: checksum.cloned(),
+            ..Default::default()
+        });
+        if docs {
+            snapshot.push(SnapshotMeta::new(format!(
+                "docs/{}-{}.tar.gz",
+                name, version
+            )));
+        }
+    }
+    snapshot.push(SnapshotMeta::force(format!("packages/{}", name)));
+    snapshot
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Hexpm {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching versions...");
+        progress.set_message("fetching versions...");
+        let mut versions: proto::Versions =
+            fetch_registry(&client, &format!("{}/versions", self.repo_base)).await?;
+
+        if self.debug {
+            versions.packages.truncate(100);
+        }
+
+        info!(logger, "fetching {} packages...", versions.packages.len());
+        progress.set_length(versions.packages.len() as u64);
+        progress.set_style(bar());
+
+        let snapshots: Result<Vec<Vec<SnapshotMeta>>> =
+            stream::iter(versions.packages.into_iter().map(|package| {
+                let client = client.clone();
+                let repo_base = self.repo_base.clone();
+                let docs = !self.no_docs;
+                let progress = progress.clone();
+
+                async move {
+                    progress.set_message(&package.name);
+                    let url = format!("{}/packages/{}", repo_base, package.name);
+                    // registry files are fetched with retries, and a package
+                    // without checksums would never be verified
+                    let checksums = fetch_registry::<proto::Package>(&client, &url)
+                        .await?
+                        .releases
+                        .into_iter()
+                        .filter_map(|release| {
+                            let proto::Release {
+                                version,
+                                outer_checksum,
+                                ..
+                            } = release;
+                            outer_checksum.map(|checksum| (version, hex_string(&checksum)))
+                        })
+                        .collect();
+                    progress.inc(1);
+                    Ok::<_, Error>(package_snapshot(
+                        &package.name,
+                        &package.versions,
+                        &checksums,
+                        docs,
+                    ))
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await;
+
+        let mut snapshot: Vec<SnapshotMeta> = snapshots?.into_iter().flatten().collect();
+        for key in ["names", "versions", "public_key"] {
+            snapshot.push(SnapshotMeta::force(key.to_string()));
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("hexpm, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Hexpm {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<TransferURL> {
+        let url = format!("{}/{}", self.repo_base, snapshot.key);
+        if snapshot.key.starts_with("docs/") {
+            let status = mission
+                .client
+                .head(&url)
+                .send()
+                .timeout(Duration::from_secs(60))
+                .await
+                .into_result()?
+                .status();
+            if matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE) {
+                return Err(Error::Absent(url));
+            }
+            if !status.is_success() {
+                return Err(Error::HTTPError(status));
+            }
+        }
+        Ok(TransferURL(url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_snapshot() {
+        let versions = vec!["1.0.0".to_string(), "1.1.0".to_string()];
+        let mut checksums = HashMap::new();
+        checksums.insert("1.1.0".to_string(), hex_string(&[0xde, 0xad, 0xbe, 0xef]));
+
+        let snapshot = package_snapshot("plug", &versions, &checksums, true);
+        let keys: Vec<_> = snapshot.iter().map(|x| x.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "tarballs/plug-1.0.0.tar",
+                "docs/plug-1.0.0.tar.gz",
+                "tarballs/plug-1.1.0.tar",
+                "docs/plug-1.1.0.tar.gz",
+                "packages/plug"
+            ]
+        );
+        assert_eq!(snapshot[0].checksum, None);
+        assert_eq!(snapshot[2].checksum.as_deref(), Some("deadbeef"));
+        assert!(snapshot[4].flags.force_last);
+    }
+}
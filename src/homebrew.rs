@@ -3,34 +3,70 @@
 //! Homebrew source will use brew.sh API to fetch all available bottles.
 //! It will generate a list of URLs.
 //!
+//! Casks are read from the cask API as well. As most casks are downloaded
+//! from websites of their vendors, only artifacts hosted on `cask_domains`
+//! (e.g. ghcr.io) are mirrored, under `cask/{token}/{version}/`.
+//!
+//! If `mirror_base` is set, `api/formula.json` and `api/cask.json` are
+//! generated by `ApiPipe`, in which URLs of mirrored bottles and artifacts
+//! left by filters point to `mirror_base`, so that `HOMEBREW_API_DOMAIN` can be set to
+//! `{mirror_base}/api`.
+//!
 //! Reference: https://github.com/ustclug/ustcmirror-images/blob/master/homebrew-bottles/bottles-json/src/main.rs
 //! MIT License, Copyright (c) 2017 Jian Zeng
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
-use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::fetch::fetch_text;
+use crate::filter_pipe::Filtered;
+use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
+use crate::stream_pipe::{ByteObject, ByteStream, ByteStreamPipe};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::{hash_string, unix_time};
 
-use std::collections::{BTreeMap, HashMap};
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 
-use crate::metadata::SnapshotMeta;
 use async_trait::async_trait;
 use serde::Deserialize;
+use serde_json::Value;
+use sha2::Digest;
 use slog::info;
 use structopt::StructOpt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+/// Content type of bottles, which are gzipped tarballs
+const BOTTLE_CONTENT_TYPE: &str = "application/x-gzip";
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct HomebrewConfig {
     #[structopt(long, default_value = "https://formulae.brew.sh/api/formula.json")]
     pub api_base: String,
+    #[structopt(long, default_value = "https://formulae.brew.sh/api/cask.json")]
+    pub cask_api: String,
     #[structopt(long, default_value = "all")]
     pub arch: String,
+    #[structopt(
+        long,
+        default_value = "ghcr.io",
+        use_delimiter = true,
+        help = "Domains of cask artifacts to mirror, including their subdomains"
+    )]
+    pub cask_domains: Vec<String>,
+    #[structopt(
+        long,
+        help = "Base URL of this mirror, to generate API json pointing to mirrored files"
+    )]
+    pub mirror_base: Option<String>,
 }
 
 pub struct Homebrew {
     pub config: HomebrewConfig,
     url_mapping: BTreeMap<String, String>,
+    /// key -> content of upstream API json, kept for `ApiPipe`
+    api_files: BTreeMap<String, String>,
+    /// upstream URL -> key of mirrored bottles and artifacts
+    mirrored: HashMap<String, String>,
 }
 
 #[derive(Deserialize)]
@@ -68,11 +104,98 @@ struct BottleInfo {
     sha256: String,
 }
 
+#[derive(Deserialize)]
+struct Cask {
+    token: String,
+    version: String,
+    url: Option<String>,
+    sha256: Option<String>,
+    /// artifacts for other macOS versions or architectures
+    #[serde(default)]
+    variations: HashMap<String, CaskVariation>,
+}
+
+#[derive(Deserialize)]
+struct CaskVariation {
+    url: Option<String>,
+    sha256: Option<String>,
+}
+
+/// Whether `url` is hosted on one of `domains` or their subdomains.
+fn hosted_on(url: &str, domains: &[String]) -> bool {
+    let host = match url::Url::parse(url) {
+        Ok(url) => url.host_str().map(|host| host.to_string()),
+        Err(_) => None,
+    };
+    match host {
+        Some(host) => domains
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain))),
+        None => false,
+    }
+}
+
+/// Artifacts of a cask, as (key, url, sha256).
+fn cask_artifacts(cask: &Cask) -> Vec<(String, String, Option<String>)> {
+    let mut urls = vec![(cask.url.as_ref(), cask.sha256.as_ref())];
+    for variation in cask.variations.values() {
+        urls.push((variation.url.as_ref(), variation.sha256.as_ref()));
+    }
+    let mut artifacts: Vec<(String, String, Option<String>)> = vec![];
+    for (url, sha256) in urls {
+        let url = match url {
+            Some(url) => url,
+            None => continue,
+        };
+        let filename = match url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.path_segments()?.next_back().map(|x| x.to_string()))
+        {
+            Some(filename) if !filename.is_empty() => filename,
+            _ => continue,
+        };
+        let key = format!("cask/{}/{}/{}", cask.token, cask.version, filename);
+        if artifacts.iter().any(|(k, _, _)| *k == key) {
+            continue;
+        }
+        // sha256 is `no_check` for artifacts changing without a new version
+        let sha256 = sha256.filter(|sha256| sha256.len() == 64).cloned();
+        artifacts.push((key, url.clone(), sha256));
+    }
+    artifacts
+}
+
+/// Point `url` fields found in `mapping` (upstream URL -> key) to `mirror_base`.
+fn rewrite_urls(value: &mut Value, mapping: &HashMap<String, String>, mirror_base: &str) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object.iter_mut() {
+                match value {
+                    Value::String(url) if name == "url" => {
+                        if let Some(key) = mapping.get(url.as_str()) {
+                            *url = format!("{}/{}", mirror_base, key);
+                        }
+                    }
+                    _ => rewrite_urls(value, mapping, mirror_base),
+                }
+            }
+        }
+        Value::Array(array) => {
+            for value in array {
+                rewrite_urls(value, mapping, mirror_base);
+            }
+        }
+        _ => {}
+    }
+}
+
 impl Homebrew {
     pub fn new(config: HomebrewConfig) -> Self {
         Self {
             config,
             url_mapping: BTreeMap::new(),
+            api_files: BTreeMap::new(),
+            mirrored: HashMap::new(),
         }
     }
 }
@@ -88,22 +211,15 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
         let progress = mission.progress;
         let client = mission.client;
         let gen_map = crate::utils::generate_s3_url_reverse_encode_map();
+        // upstream URL -> key
+        let mut mirrored = HashMap::new();
 
         info!(logger, "fetching API json...");
         progress.set_message("fetching API json...");
-        let data = client
-            .get(&self.config.api_base)
-            .send()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?
-            .text()
-            .timeout(Duration::from_secs(60))
-            .await
-            .into_result()?;
+        let data = fetch_text(&client, &self.config.api_base).await?;
 
         info!(logger, "parsing...");
-        let formulae: Formulae = serde_json::from_str(&data).unwrap();
+        let formulae: Formulae = serde_json::from_str(&data)?;
         let mut snapshots = vec![];
         for f in formulae.0 {
             progress.set_message(&f.name);
@@ -133,11 +249,14 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
                                     },
                                 );
                                 let key = crate::utils::rewrite_url_string(&gen_map, &key);
+                                mirrored.insert(v.url.clone(), key.clone());
                                 self.url_mapping.insert(key.clone(), v.url);
                                 snapshots.push(SnapshotMeta {
                                     key,
                                     checksum_method: Some(String::from("sha256")),
                                     checksum: Some(v.sha256),
+                                    // ghcr serves blobs as `application/octet-stream`
+                                    content_type: Some(BOTTLE_CONTENT_TYPE.to_string()),
                                     ..Default::default()
                                 });
                             }
@@ -147,6 +266,42 @@ impl SnapshotStorage<SnapshotMeta> for Homebrew {
             }
         }
 
+        info!(logger, "fetching cask API json...");
+        progress.set_message("fetching cask API json...");
+        let cask_data = fetch_text(&client, &self.config.cask_api).await?;
+        let casks: Vec<Cask> = serde_json::from_str(&cask_data)?;
+        let bottles = snapshots.len();
+        for cask in &casks {
+            for (key, url, sha256) in cask_artifacts(cask) {
+                if !hosted_on(&url, &self.config.cask_domains) {
+                    continue;
+                }
+                let key = crate::utils::rewrite_url_string(&gen_map, &key);
+                mirrored.insert(url.clone(), key.clone());
+                self.url_mapping.insert(key.clone(), url);
+                snapshots.push(SnapshotMeta {
+                    key,
+                    checksum_method: sha256.as_ref().map(|_| String::from("sha256")),
+                    checksum: sha256,
+                    ..Default::default()
+                });
+            }
+        }
+        info!(
+            logger,
+            "{} bottles, {} artifacts of {} casks",
+            bottles,
+            snapshots.len() - bottles,
+            casks.len()
+        );
+
+        if self.config.mirror_base.is_some() {
+            self.api_files.insert("api/formula.json".to_string(), data);
+            self.api_files
+                .insert("api/cask.json".to_string(), cask_data);
+            self.mirrored = mirrored;
+        }
+
         progress.finish_with_message("done");
 
         Ok(snapshots)
@@ -164,6 +319,9 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Homebrew {
             .url_mapping
             .get(&snapshot.key)
             .expect("no URL for bottle");
+        if !url.starts_with("https://ghcr.io/") {
+            return Ok(TransferURL(url.clone()));
+        }
         let resp = mission
             .client
             .get(url)
@@ -180,3 +338,155 @@ impl SourceStorage<SnapshotMeta, TransferURL> for Homebrew {
         Ok(TransferURL(resp.url().as_str().to_string()))
     }
 }
+
+/// `ApiPipe` adds API json generated by `Homebrew` source, if `mirror_base`
+/// is set. Only URLs of files left by filters are pointed to the mirror.
+pub struct ApiPipe {
+    source: ByteStreamPipe<Filtered<Homebrew>>,
+    buffer_path: String,
+    files: BTreeMap<String, String>,
+}
+
+impl ApiPipe {
+    pub fn new(source: ByteStreamPipe<Filtered<Homebrew>>, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for ApiPipe {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        let homebrew = self.source.source.inner_mut();
+        if let Some(mirror_base) = &homebrew.config.mirror_base {
+            let mirror_base = mirror_base.trim_end_matches('/');
+            let selected: HashSet<&str> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+            let mut mirrored = std::mem::take(&mut homebrew.mirrored);
+            mirrored.retain(|_, key| selected.contains(key.as_str()));
+            for (key, data) in std::mem::take(&mut homebrew.api_files) {
+                let mut value: Value = serde_json::from_str(&data)?;
+                rewrite_urls(&mut value, &mirrored, mirror_base);
+                self.files.insert(key, serde_json::to_string(&value)?);
+            }
+        }
+        // API json is transferred after bottles, so that it never points to
+        // files not mirrored yet.
+        for (key, content) in &self.files {
+            snapshot.push(SnapshotMeta {
+                key: key.clone(),
+                size: Some(content.len() as u64),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some(format!("{:x}", sha2::Sha256::digest(content.as_bytes()))),
+                flags: SnapshotMetaFlag {
+                    force: false,
+                    force_last: true,
+                    after_all: false,
+                },
+                ..Default::default()
+            });
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("ApiPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for ApiPipe {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        if let Some(content) = self.files.get(key) {
+            let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+            let path = Path::new(&self.buffer_path).join(pipe_file);
+            let mut f = BufWriter::new(
+                tokio::fs::OpenOptions::default()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .read(true)
+                    .open(&path)
+                    .await?,
+            );
+            f.write_all(content.as_bytes()).await?;
+            f.flush().await?;
+            let mut f = f.into_inner();
+            f.seek(std::io::SeekFrom::Start(0)).await?;
+            Ok(ByteStream {
+                object: ByteObject::LocalFile {
+                    file: Some(f),
+                    path: Some(path),
+                },
+                length: content.len() as u64,
+                modified_at: unix_time(),
+                content_type: Some("application/json".to_string()),
+                checksum: None,
+                etag: None,
+            })
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_casks_and_rewrite() {
+        let cask: Cask = serde_json::from_value(serde_json::json!({
+            "token": "foo",
+            "version": "1.2",
+            "url": "https://ghcr.io/v2/homebrew/foo/blobs/sha256:ab",
+            "sha256": "no_check",
+            "variations": {
+                "arm64_sonoma": {
+                    "url": "https://example.com/foo-arm.dmg",
+                    "sha256": "a".repeat(64)
+                }
+            }
+        }))
+        .unwrap();
+        let artifacts = cask_artifacts(&cask);
+        assert_eq!(artifacts.len(), 2);
+        assert_eq!(artifacts[0].0, "cask/foo/1.2/sha256:ab");
+        assert_eq!(artifacts[0].2, None);
+        assert_eq!(artifacts[1].2, Some("a".repeat(64)));
+
+        let domains = vec!["ghcr.io".to_string()];
+        assert!(hosted_on(&artifacts[0].1, &domains));
+        assert!(!hosted_on(&artifacts[1].1, &domains));
+        assert!(!hosted_on("https://notghcr.io/x", &domains));
+
+        let mut mapping = HashMap::new();
+        mapping.insert(artifacts[0].1.clone(), artifacts[0].0.clone());
+        let mut value = serde_json::json!([{
+            "token": "foo",
+            "url": "https://ghcr.io/v2/homebrew/foo/blobs/sha256:ab",
+            "variations": {"arm64_sonoma": {"url": "https://example.com/foo-arm.dmg"}}
+        }]);
+        rewrite_urls(&mut value, &mapping, "https://mirror.example.com/homebrew");
+        assert_eq!(
+            value[0]["url"],
+            "https://mirror.example.com/homebrew/cask/foo/1.2/sha256:ab"
+        );
+        assert_eq!(
+            value[0]["variations"]["arm64_sonoma"]["url"],
+            "https://example.com/foo-arm.dmg"
+        );
+    }
+}
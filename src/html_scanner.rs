@@ -1,42 +1,302 @@
-use crate::common::{Mission, SnapshotConfig, SnapshotPath};
-use crate::error::Result;
-use crate::traits::SnapshotStorage;
+//! HTTP directory source
+//!
+//! `HttpDir` mirrors a plain HTTP directory tree, by crawling autoindex pages
+//! generated by web servers like nginx and Apache. Links are resolved against
+//! the page, and only those under the current directory are followed, so
+//! parent directories and sorting links are skipped. Directories are crawled
+//! level by level, up to `max_depth`, and only paths under `include_prefix`
+//! are crawled if it is set.
+//!
+//! Sizes and modification times are read from autoindex pages when present.
+//! Human-readable sizes (e.g. `1.2M` of Apache) are not exact, so they are
+//! ignored. This source yields meta snapshots.
 
 use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use futures_util::{stream, StreamExt};
+use lazy_static::lazy_static;
 use regex::Regex;
-use slog::info;
+use slog::{info, warn};
+use structopt::StructOpt;
+use url::Url;
 
-#[derive(Debug)]
-pub struct HtmlScanner {
-    pub url: String,
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+lazy_static! {
+    static ref LINK: Regex = Regex::new(r#"(?i)<a\s[^>]*href\s*=\s*"([^"]*)"[^>]*>"#).unwrap();
+    static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref DATE_SIZE: Regex = Regex::new(
+        r"(\d{2}-[A-Za-z]{3}-\d{4} \d{2}:\d{2}(?::\d{2})?|\d{4}-\d{2}-\d{2} \d{2}:\d{2}(?::\d{2})?)\s+(\S+)?"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct HttpDir {
+    #[structopt(long, help = "Base URL of directory tree")]
+    pub base: String,
+    #[structopt(long, help = "Max depth of directories to crawl", default_value = "32")]
+    pub max_depth: usize,
+    #[structopt(
+        long,
+        help = "Only crawl paths under this prefix, e.g. `pub/releases/`"
+    )]
+    pub include_prefix: Option<String>,
+}
+
+/// An entry in an autoindex page. Keys of directories end with `/`.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    key: String,
+    size: Option<u64>,
+    last_modified: Option<u64>,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.key.ends_with('/')
+    }
+}
+
+fn parse_date(date: &str) -> Option<u64> {
+    [
+        "%d-%b-%Y %H:%M",
+        "%d-%b-%Y %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%d %H:%M:%S",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+    .map(|date| date.and_utc().timestamp() as u64)
+}
+
+/// Parse entries in autoindex page of `dir` (relative to `base`, with
+/// trailing slash). Only entries under `dir` are returned.
+fn parse_index(base: &Url, dir: &str, html: &str) -> Vec<Entry> {
+    let dir_url = match base.join(dir) {
+        Ok(url) => url,
+        Err(_) => return vec![],
+    };
+    let links: Vec<_> = LINK.captures_iter(html).collect();
+    let mut entries = vec![];
+    for (idx, link) in links.iter().enumerate() {
+        let href = html_escape::decode_html_entities(&link[1]).to_string();
+        if href.starts_with('?') || href.starts_with('#') {
+            continue;
+        }
+        let mut url = match dir_url.join(&href) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        url.set_query(None);
+        url.set_fragment(None);
+        let key = match url.as_str().strip_prefix(base.as_str()) {
+            Some(key) if key.len() > dir.len() && key.starts_with(dir) => key,
+            _ => continue,
+        };
+        let key = match urlencoding::decode(key) {
+            Ok(key) => key.to_string(),
+            Err(_) => continue,
+        };
+
+        // columns following the link, till the next link
+        let end = links
+            .get(idx + 1)
+            .map(|next| next.get(0).unwrap().start())
+            .unwrap_or(html.len());
+        let columns = TAG.replace_all(&html[link.get(0).unwrap().end()..end], " ");
+        let (size, last_modified) = match DATE_SIZE.captures(&columns) {
+            Some(cap) => (
+                cap.get(2).and_then(|size| size.as_str().parse().ok()),
+                parse_date(&cap[1]),
+            ),
+            None => (None, None),
+        };
+        entries.push(Entry {
+            key,
+            size,
+            last_modified,
+        });
+    }
+    entries
+}
+
+impl HttpDir {
+    fn base_url(&self) -> Result<Url> {
+        let base = format!("{}/", self.base.trim_end_matches('/'));
+        Url::parse(&base)
+            .map_err(|err| Error::ConfigureError(format!("invalid base {}: {}", base, err)))
+    }
+
+    fn should_crawl(&self, dir: &str) -> bool {
+        match &self.include_prefix {
+            Some(prefix) => dir.starts_with(prefix.as_str()) || prefix.starts_with(dir),
+            None => true,
+        }
+    }
+
+    fn should_include(&self, key: &str) -> bool {
+        match &self.include_prefix {
+            Some(prefix) => key.starts_with(prefix.as_str()),
+            None => true,
+        }
+    }
 }
 
 #[async_trait]
-impl SnapshotStorage<SnapshotPath> for HtmlScanner {
+impl SnapshotStorage<SnapshotMeta> for HttpDir {
     async fn snapshot(
         &mut self,
         mission: Mission,
-        _config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotPath>> {
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
+        let base = self.base_url()?;
 
-        info!(logger, "downloading web content...");
-        let index = client.get(&self.url).send().await?.text().await?;
-        let matcher = Regex::new(r#"<a.*href="(.*?)".*"#).unwrap();
+        info!(logger, "crawling {}...", base);
 
-        let snapshot: Vec<String> = matcher
-            .captures_iter(&index)
-            .map(|cap| cap[1].to_string())
-            .collect();
+        let mut snapshot = vec![];
+        let mut dirs = vec![String::new()];
+        for depth in 0..=self.max_depth {
+            if dirs.is_empty() {
+                break;
+            }
+            let pages: Vec<_> = stream::iter(dirs.into_iter().map(|dir| {
+                let client = client.clone();
+                let url = format!("{}{}", base, dir);
+                async move { (fetch_text(&client, &url).await, dir) }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .collect()
+            .await;
+
+            let mut next_dirs = vec![];
+            for (page, dir) in pages {
+                let page = match page {
+                    Ok(page) => page,
+                    Err(err) => {
+                        warn!(logger, "failed to fetch {}: {:?}", dir, err);
+                        continue;
+                    }
+                };
+                for entry in parse_index(&base, &dir, &page) {
+                    if entry.is_dir() {
+                        if depth < self.max_depth && self.should_crawl(&entry.key) {
+                            next_dirs.push(entry.key);
+                        }
+                    } else if self.should_include(&entry.key) {
+                        snapshot.push(SnapshotMeta {
+                            key: entry.key,
+                            size: entry.size,
+                            last_modified: entry.last_modified,
+                            ..Default::default()
+                        });
+                    }
+                }
+                progress.set_message(&format!("{} files, crawling {}", snapshot.len(), dir));
+            }
+            next_dirs.sort();
+            next_dirs.dedup();
+            dirs = next_dirs;
+        }
+
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files (at least {})",
+            snapshot.len(),
+            human_size(total_size)
+        );
 
         progress.finish_with_message("done");
 
-        Ok(crate::utils::snapshot_string_to_path(snapshot))
+        Ok(snapshot)
     }
 
     fn info(&self) -> String {
-        format!("html_scanner, {:?}", self)
+        format!("http_dir, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for HttpDir {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        let path: Vec<_> = snapshot
+            .key
+            .split('/')
+            .map(|segment| urlencoding::encode(segment).to_string())
+            .collect();
+        Ok(TransferURL(format!(
+            "{}{}",
+            self.base_url()?,
+            path.join("/")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nginx_index() {
+        let html = r#"<html>
+<head><title>Index of /pub/</title></head>
+<body>
+<h1>Index of /pub/</h1><hr><pre><a href="../">../</a>
+<a href="releases/">releases/</a>                                          12-Mar-2023 10:05                   -
+<a href="foo%20bar.tar.gz">foo bar.tar.gz</a>                                    12-Mar-2023 10:05            12345678
+<a href="/other/">other/</a>                                             12-Mar-2023 10:05                   -
+</pre><hr></body>
+</html>"#;
+        let base = Url::parse("https://example.com/").unwrap();
+        assert_eq!(
+            parse_index(&base, "pub/", html),
+            vec![
+                Entry {
+                    key: "pub/releases/".to_string(),
+                    size: None,
+                    last_modified: Some(1678615500),
+                },
+                Entry {
+                    key: "pub/foo bar.tar.gz".to_string(),
+                    size: Some(12345678),
+                    last_modified: Some(1678615500),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_apache_index() {
+        let html = r#"<table>
+<tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+<tr><td><a href="/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td></tr>
+<tr><td><a href="KEYS">KEYS</a></td><td align="right">2023-03-12 10:05  </td><td align="right">1.2M</td></tr>
+<tr><td><a href="3.6.1/">3.6.1/</a></td><td align="right">2023-11-27 16:44  </td><td align="right">  - </td></tr>
+</table>"#;
+        let base = Url::parse("https://example.com/dist/").unwrap();
+        assert_eq!(
+            parse_index(&base, "", html),
+            vec![
+                Entry {
+                    key: "KEYS".to_string(),
+                    size: None,
+                    last_modified: Some(1678615500),
+                },
+                Entry {
+                    key: "3.6.1/".to_string(),
+                    size: None,
+                    last_modified: Some(1701103440),
+                },
+            ]
+        );
     }
 }
@@ -0,0 +1,308 @@
+//! HTTP PUT backend
+//!
+//! HTTP PUT backend is a target storage for artifact servers like
+//! Artifactory and Nexus. Objects are uploaded with `PUT {base}/{key}` and
+//! deleted with `DELETE {base}/{key}`. An optional header (e.g.
+//! `Authorization: Bearer xxx`) is sent with every request.
+//!
+//! Snapshot is taken from the listing API of the server, given by
+//! `listing_url`:
+//!
+//! * `artifactory`: the storage API of repository, e.g.
+//!   `https://example.com/artifactory/api/storage/repo`, which is listed
+//!   with `?list&deep=1`.
+//! * `nexus`: the assets API, e.g.
+//!   `https://example.com/service/rest/v1/assets?repository=repo`, which
+//!   is paginated with continuation tokens.
+//!
+//! Snapshot only has path and size, as modified time on these servers is
+//! the time of uploading.
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use slog::{debug, info};
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::ByteStream;
+use crate::throttle::throttled;
+use crate::traits::{Key, SnapshotStorage, TargetStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Listing {
+    Artifactory,
+    Nexus,
+}
+
+impl std::str::FromStr for Listing {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "artifactory" => Ok(Self::Artifactory),
+            "nexus" => Ok(Self::Nexus),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported listing API {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpPutConfig {
+    pub base: String,
+    pub header: Option<(String, String)>,
+    pub listing: Listing,
+    pub listing_url: String,
+}
+
+pub struct HttpPutBackend {
+    config: HttpPutConfig,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct ArtifactoryFile {
+    uri: String,
+    size: u64,
+    folder: bool,
+}
+
+#[derive(Deserialize)]
+struct ArtifactoryList {
+    files: Vec<ArtifactoryFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NexusAsset {
+    path: String,
+    file_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NexusAssets {
+    items: Vec<NexusAsset>,
+    continuation_token: Option<String>,
+}
+
+/// Parse a header in the form of `Name: value`.
+pub fn parse_header(header: &str) -> Result<(String, String)> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| Error::ConfigureError(format!("invalid header {}", header)))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+impl HttpPutBackend {
+    pub fn new(config: HttpPutConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let req = self.client.request(method, url);
+        match &self.config.header {
+            Some((name, value)) => req.header(name.as_str(), value.as_str()),
+            None => req,
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        let path: Vec<_> = key
+            .split('/')
+            .map(|segment| urlencoding::encode(segment).to_string())
+            .collect();
+        format!(
+            "{}/{}",
+            self.config.base.trim_end_matches('/'),
+            path.join("/")
+        )
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let resp = self.request(reqwest::Method::GET, url).send().await?;
+        if !resp.status().is_success() {
+            return Err(Error::HTTPError(resp.status()));
+        }
+        Ok(resp.json().await?)
+    }
+
+    async fn list_artifactory(&self) -> Result<Vec<SnapshotMeta>> {
+        let list: ArtifactoryList = self
+            .get_json(&format!("{}?list&deep=1", self.config.listing_url))
+            .await?;
+        Ok(list
+            .files
+            .into_iter()
+            .filter(|file| !file.folder)
+            .map(|file| SnapshotMeta {
+                key: file.uri.trim_start_matches('/').to_string(),
+                size: Some(file.size),
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn list_nexus(&self, mission: &Mission) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let url = match &token {
+                Some(token) => format!(
+                    "{}&continuationToken={}",
+                    self.config.listing_url,
+                    urlencoding::encode(token)
+                ),
+                None => self.config.listing_url.clone(),
+            };
+            let assets: NexusAssets = self.get_json(&url).await?;
+            snapshot.extend(assets.items.into_iter().map(|asset| SnapshotMeta {
+                key: asset.path.trim_start_matches('/').to_string(),
+                size: asset.file_size,
+                ..Default::default()
+            }));
+            mission
+                .progress
+                .set_message(&format!("{} files", snapshot.len()));
+            token = assets.continuation_token;
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+impl std::fmt::Debug for HttpPutBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't print credentials in header
+        f.debug_struct("HttpPutBackend")
+            .field("base", &self.config.base)
+            .field("listing", &self.config.listing)
+            .field("listing_url", &self.config.listing_url)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for HttpPutBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        info!(mission.logger, "listing {}...", self.config.listing_url);
+        let snapshot = match self.config.listing {
+            Listing::Artifactory => self.list_artifactory().await?,
+            Listing::Nexus => self.list_nexus(&mission).await?,
+        };
+
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            mission.logger,
+            "{} files ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+        mission.progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("http put (meta), {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for HttpPutBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        Ok(
+            <Self as SnapshotStorage<SnapshotMeta>>::snapshot(self, mission, config)
+                .await?
+                .into_iter()
+                .map(|x| SnapshotPath::new(x.key))
+                .collect(),
+        )
+    }
+
+    fn info(&self) -> String {
+        format!("http put (path), {:?}", self)
+    }
+}
+
+#[async_trait]
+impl<Snapshot: Key> TargetStorage<Snapshot, ByteStream> for HttpPutBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        byte_stream: ByteStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        debug!(mission.logger, "upload: {}", snapshot.key());
+        let ByteStream {
+            mut object,
+            length,
+            content_type,
+            ..
+        } = byte_stream;
+
+        let mut req = self
+            .request(reqwest::Method::PUT, &self.url(snapshot.key()))
+            .header(reqwest::header::CONTENT_LENGTH, length)
+            .body(reqwest::Body::wrap_stream(throttled(
+                object.as_stream(),
+                mission.throttle.clone(),
+            )));
+        if let Some(content_type) =
+            crate::content_type::ContentTypes::default().resolve(snapshot.key(), content_type)
+        {
+            req = req.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            return Err(Error::HTTPError(resp.status()));
+        }
+        mission
+            .accounting
+            .record_upload(&format!("http:{}", self.config.base), length);
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+        let resp = self
+            .request(reqwest::Method::DELETE, &self.url(snapshot.key()))
+            .send()
+            .await?;
+        // the object may be already gone
+        if !resp.status().is_success() && resp.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(Error::HTTPError(resp.status()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("Authorization: Bearer abc:def").unwrap(),
+            ("Authorization".to_string(), "Bearer abc:def".to_string())
+        );
+        assert!(parse_header("Authorization").is_err());
+    }
+}
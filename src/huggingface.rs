@@ -0,0 +1,231 @@
+//! Hugging Face source
+//!
+//! Hugging Face source lists files of configured model and dataset repos
+//! with the tree API of Hugging Face Hub. Files stored in Git LFS are yielded
+//! with sha256 checksums from LFS metadata, so that they could be verified
+//! with `ChecksumPipe`. Files larger than `max_size` (e.g. giant checkpoints)
+//! are skipped.
+//!
+//! Files are placed as they are resolved on Hub, i.e.
+//! `{repo}/resolve/{revision}/{path}` for models, and
+//! `datasets/{repo}/resolve/{revision}/{path}` for datasets.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct HuggingFace {
+    #[structopt(
+        long,
+        default_value = "https://huggingface.co",
+        help = "Base of Hugging Face Hub"
+    )]
+    pub base: String,
+    #[structopt(long, help = "Model repos to mirror, e.g. bert-base-uncased")]
+    pub models: Vec<String>,
+    #[structopt(long, help = "Dataset repos to mirror, e.g. openai/gsm8k")]
+    pub datasets: Vec<String>,
+    #[structopt(long, help = "Revision of repos to mirror", default_value = "main")]
+    pub revision: String,
+    #[structopt(long, help = "Skip files larger than this size (in bytes)")]
+    pub max_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct TreeEntry {
+    #[serde(rename = "type")]
+    entry_type: String,
+    path: String,
+    size: Option<u64>,
+    lfs: Option<LfsInfo>,
+}
+
+#[derive(Deserialize)]
+struct LfsInfo {
+    /// sha256 of file content
+    oid: String,
+    size: u64,
+}
+
+/// Fetch one page of tree API, returning entries and URL of the next page.
+async fn fetch_tree_page(client: &Client, url: &str) -> Result<(Vec<TreeEntry>, Option<String>)> {
+    let response = client
+        .get(url)
+        .send()
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::HTTPError(status));
+    }
+    let next = response
+        .headers()
+        .get(reqwest::header::LINK)
+        .and_then(|link| link.to_str().ok())
+        .and_then(|link| parse_link_header::parse(link).ok())
+        .and_then(|mut links| links.remove(&Some("next".to_string())))
+        .map(|link| link.raw_uri);
+    let data = response
+        .text()
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?;
+    Ok((serde_json::from_str(&data)?, next))
+}
+
+/// Convert file entries of a repo into snapshot, where `prefix` is the
+/// resolve path of repo. Returns the snapshot and count of skipped files.
+fn tree_snapshot(
+    entries: Vec<TreeEntry>,
+    prefix: &str,
+    max_size: Option<u64>,
+) -> (Vec<SnapshotMeta>, usize) {
+    let mut skipped = 0;
+    let snapshot = entries
+        .into_iter()
+        .filter(|entry| entry.entry_type == "file")
+        .filter_map(|entry| {
+            let size = entry.lfs.as_ref().map(|lfs| lfs.size).or(entry.size);
+            if let (Some(size), Some(max_size)) = (size, max_size) {
+                if size > max_size {
+                    skipped += 1;
+                    return None;
+                }
+            }
+            Some(SnapshotMeta {
+                key: format!("{}/{}", prefix, entry.path),
+                size,
+                checksum_method: entry.lfs.as_ref().map(|_| "sha256".to_string()),
+                checksum: entry.lfs.map(|lfs| lfs.oid),
+                ..Default::default()
+            })
+        })
+        .collect();
+    (snapshot, skipped)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for HuggingFace {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        if self.models.is_empty() && self.datasets.is_empty() {
+            return Err(Error::ConfigureError(
+                "no model or dataset to mirror".to_string(),
+            ));
+        }
+
+        let repos = self
+            .models
+            .iter()
+            .map(|repo| ("models", repo.clone(), repo.clone()))
+            .chain(
+                self.datasets
+                    .iter()
+                    .map(|repo| ("datasets", repo.clone(), format!("datasets/{}", repo))),
+            );
+
+        let mut snapshot = vec![];
+        for (repo_type, repo, path) in repos {
+            info!(logger, "listing {} {}...", repo_type, repo);
+            progress.set_message(&repo);
+
+            let mut entries = vec![];
+            let mut next = Some(format!(
+                "{}/api/{}/{}/tree/{}?recursive=true",
+                self.base, repo_type, repo, self.revision
+            ));
+            while let Some(url) = next {
+                let (page, next_page) = match fetch_tree_page(&client, &url).await {
+                    Ok(result) => result,
+                    Err(err) => {
+                        warn!(logger, "failed to list {}: {:?}", repo, err);
+                        entries.clear();
+                        break;
+                    }
+                };
+                entries.extend(page);
+                next = next_page;
+            }
+
+            let prefix = format!("{}/resolve/{}", path, self.revision);
+            let (repo_snapshot, skipped) = tree_snapshot(entries, &prefix, self.max_size);
+            let total_size: u64 = repo_snapshot.iter().filter_map(|meta| meta.size).sum();
+            info!(
+                logger,
+                "{}: {} files ({}), {} skipped",
+                repo,
+                repo_snapshot.len(),
+                human_size(total_size),
+                skipped
+            );
+            snapshot.extend(repo_snapshot);
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("huggingface, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for HuggingFace {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_snapshot() {
+        let tree = r#"[
+            {"type": "directory", "oid": "a", "size": 0, "path": "onnx"},
+            {"type": "file", "oid": "b", "size": 570, "path": "config.json"},
+            {"type": "file", "oid": "c", "size": 135, "path": "onnx/model.onnx",
+             "lfs": {"oid": "d3a6d1d9e7f2c5a1b0e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d9c8b7a6", "size": 440000000, "pointerSize": 135}}
+        ]"#;
+        let entries: Vec<TreeEntry> = serde_json::from_str(tree).unwrap();
+        let (snapshot, skipped) =
+            tree_snapshot(entries, "bert-base-uncased/resolve/main", Some(100_000_000));
+        assert_eq!(skipped, 1);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot[0].key,
+            "bert-base-uncased/resolve/main/config.json"
+        );
+        assert_eq!(snapshot[0].size, Some(570));
+        assert_eq!(snapshot[0].checksum, None);
+
+        let entries: Vec<TreeEntry> = serde_json::from_str(tree).unwrap();
+        let (snapshot, _) = tree_snapshot(entries, "datasets/openai/gsm8k/resolve/main", None);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[1].size, Some(440000000));
+        assert_eq!(snapshot[1].checksum_method.as_deref(), Some("sha256"));
+    }
+}
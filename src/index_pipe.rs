@@ -1,4 +1,10 @@
 //! IndexPipe adds Index to every directory of source.
+//!
+//! Index pages are named `mirror_clone_list.html` by default. Several names
+//! could be given with `--index-filename` (e.g. `index.html`, so that web
+//! servers serve directory pages at the natural URL without rewrite rules),
+//! and the same page is generated under each of them, linking to the first
+//! one. Objects of source are never replaced by index pages of the same key.
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::Result;
@@ -9,17 +15,34 @@ use crate::utils::{hash_string, unix_time};
 
 use async_trait::async_trait;
 use itertools::Itertools;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::path::Path;
+use structopt::StructOpt;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
 
 static LIST_URL: &str = "mirror_clone_list.html";
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct IndexConfig {
+    #[structopt(
+        long,
+        number_of_values = 1,
+        default_value = "mirror_clone_list.html",
+        help = "File name of generated index pages, can be given multiple times to generate the same page under several names"
+    )]
+    pub index_filename: Vec<String>,
+}
+
 pub struct IndexPipe<Source> {
     source: Source,
     index: Index,
     buffer_path: String,
     base_path: String,
     max_depth: usize,
+    /// file names of index pages, of which the first is linked in pages
+    filenames: Vec<String>,
+    /// keys of index pages generated in snapshot
+    generated: HashSet<String>,
 }
 
 #[derive(Debug)]
@@ -195,7 +218,17 @@ impl<Source> IndexPipe<Source> {
             buffer_path,
             base_path,
             max_depth,
+            filenames: vec![LIST_URL.to_string()],
+            generated: HashSet::new(),
+        }
+    }
+
+    /// Generate index pages named `filenames` instead of the default one.
+    pub fn filenames(mut self, filenames: Vec<String>) -> Self {
+        if !filenames.is_empty() {
+            self.filenames = filenames;
         }
+        self
     }
 
     fn snapshot_index_keys(&mut self, mut snapshot: Vec<String>) -> Vec<String> {
@@ -204,7 +237,26 @@ impl<Source> IndexPipe<Source> {
         // This warning will be handled on transfer.
         snapshot.dedup();
         self.index = generate_index(&snapshot, self.max_depth);
-        self.index.snapshot("", LIST_URL)
+        let index_keys: Vec<String> = self
+            .filenames
+            .iter()
+            .flat_map(|filename| self.index.snapshot("", filename))
+            // objects of source take precedence over index pages
+            .filter(|key| snapshot.binary_search(key).is_err())
+            .collect();
+        self.generated = index_keys.iter().cloned().collect();
+        index_keys
+    }
+
+    /// Directory of index page `key`, if it's generated by this pipe.
+    fn index_prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        if !self.generated.contains(key) {
+            return None;
+        }
+        self.filenames
+            .iter()
+            .filter_map(|filename| key.strip_suffix(filename.as_str()))
+            .find(|prefix| prefix.is_empty() || prefix.ends_with('/'))
     }
 }
 
@@ -225,6 +277,10 @@ where
         Ok(snapshot)
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!("IndexPipe (path) <{}>", self.source.info())
     }
@@ -247,6 +303,10 @@ where
         Ok(snapshot)
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!("IndexPipe (meta) <{}>", self.source.info())
     }
@@ -260,10 +320,10 @@ where
 {
     async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
         let key = snapshot.key();
-        if let Some(prefix) = key.strip_suffix(LIST_URL) {
+        if let Some(prefix) = self.index_prefix(key) {
             let content = self
                 .index
-                .index_for(prefix, &[&self.base_path], LIST_URL)
+                .index_for(prefix, &[&self.base_path], &self.filenames[0])
                 .into_bytes();
             let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
             let path = Path::new(&self.buffer_path).join(pipe_file);
@@ -288,6 +348,8 @@ where
                 length: content.len() as u64,
                 modified_at: unix_time(),
                 content_type: None, // use `text/html` by default
+                checksum: None,
+                etag: None,
             })
         } else {
             self.source.get_object(snapshot, mission).await
@@ -357,4 +419,30 @@ mod tests {
             vec!["list.html", "c/list.html", "c/a/list.html"]
         );
     }
+
+    #[test]
+    fn test_filenames() {
+        let mut pipe = IndexPipe::new((), String::new(), String::new(), 999)
+            .filenames(vec![LIST_URL.to_string(), "index.html".to_string()]);
+        let keys = pipe.snapshot_index_keys(
+            ["a", "c/a", "c/index.html", "d/xindex.html"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect_vec(),
+        );
+        assert_eq!(
+            keys,
+            vec![
+                "mirror_clone_list.html",
+                "c/mirror_clone_list.html",
+                "d/mirror_clone_list.html",
+                "index.html",
+                "d/index.html"
+            ]
+        );
+        assert_eq!(pipe.index_prefix("c/mirror_clone_list.html"), Some("c/"));
+        assert_eq!(pipe.index_prefix("index.html"), Some(""));
+        assert_eq!(pipe.index_prefix("c/index.html"), None);
+        assert_eq!(pipe.index_prefix("d/xindex.html"), None);
+    }
 }
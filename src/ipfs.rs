@@ -0,0 +1,291 @@
+//! IPFS backend
+//!
+//! IPFS backend is a target storage, which adds objects to an IPFS node via
+//! its HTTP API. Objects are written into the mutable file system (MFS) of
+//! the node, under `ipfs_root`, so that the directory tree mirrors the key
+//! space. Snapshot of this storage is taken by listing MFS recursively, and
+//! only has path and size.
+//!
+//! After a transfer succeeds, `publish` should be called to publish CID of
+//! the root directory via IPNS, with key `ipfs_key`.
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use slog::{debug, info, Logger};
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::ByteStream;
+use crate::throttle::throttled;
+use crate::traits::{Key, SnapshotStorage, TargetStorage};
+use crate::utils::human_size;
+
+const MULTIPART_BOUNDARY: &str = "mirror-clone-ipfs-boundary";
+
+#[derive(Debug, Clone)]
+pub struct IpfsConfig {
+    pub api: String,
+    pub root: String,
+    pub key: String,
+}
+
+#[derive(Clone)]
+pub struct IpfsBackend {
+    config: IpfsConfig,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct LsEntry {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Type")]
+    entry_type: u32,
+    #[serde(rename = "Size")]
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct LsResponse {
+    #[serde(rename = "Entries")]
+    entries: Option<Vec<LsEntry>>,
+}
+
+#[derive(Deserialize)]
+struct StatResponse {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+impl IpfsBackend {
+    pub fn new(config: IpfsConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn mfs_path(&self, key: &str) -> String {
+        format!("{}/{}", self.config.root.trim_end_matches('/'), key)
+    }
+
+    /// Call `command` of IPFS HTTP API, which only accepts POST.
+    async fn call(
+        &self,
+        command: &str,
+        query: &[(&str, &str)],
+        body: Option<reqwest::Body>,
+    ) -> Result<reqwest::Response> {
+        let mut req = self
+            .client
+            .post(format!(
+                "{}/api/v0/{}",
+                self.config.api.trim_end_matches('/'),
+                command
+            ))
+            .query(query);
+        if let Some(body) = body {
+            req = req
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY),
+                )
+                .body(body);
+        }
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let message = resp.text().await.unwrap_or_default();
+            return Err(Error::StorageError(format!(
+                "ipfs {} failed with {}: {}",
+                command, status, message
+            )));
+        }
+        Ok(resp)
+    }
+
+    /// List a directory in MFS, returning keys of files with their sizes,
+    /// and keys of sub-directories.
+    async fn list(&self, dir: String) -> Result<(Vec<(String, u64)>, Vec<String>)> {
+        let resp: LsResponse = self
+            .call(
+                "files/ls",
+                &[("arg", self.mfs_path(&dir).as_str()), ("long", "true")],
+                None,
+            )
+            .await?
+            .json()
+            .await?;
+        let mut files = vec![];
+        let mut dirs = vec![];
+        for entry in resp.entries.unwrap_or_default() {
+            let key = if dir.is_empty() {
+                entry.name
+            } else {
+                format!("{}/{}", dir, entry.name)
+            };
+            if entry.entry_type == 1 {
+                dirs.push(key);
+            } else {
+                files.push((key, entry.size));
+            }
+        }
+        Ok((files, dirs))
+    }
+
+    /// Publish CID of root directory via IPNS.
+    pub async fn publish(&self, logger: &Logger) -> Result<()> {
+        let stat: StatResponse = self
+            .call("files/stat", &[("arg", self.config.root.as_str())], None)
+            .await?
+            .json()
+            .await?;
+        info!(logger, "publishing /ipfs/{} to IPNS...", stat.hash);
+        self.call(
+            "name/publish",
+            &[
+                ("arg", format!("/ipfs/{}", stat.hash).as_str()),
+                ("key", self.config.key.as_str()),
+            ],
+            None,
+        )
+        .await?;
+        info!(logger, "published with key {}", self.config.key);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for IpfsBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for IpfsBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+
+        info!(logger, "listing MFS {}...", self.config.root);
+        self.call(
+            "files/mkdir",
+            &[("arg", self.config.root.as_str()), ("parents", "true")],
+            None,
+        )
+        .await?;
+
+        let mut snapshot = vec![];
+        let mut dirs = vec![String::new()];
+        while !dirs.is_empty() {
+            let this = &*self;
+            let results: Vec<_> = stream::iter(dirs.into_iter().map(|dir| this.list(dir)))
+                .buffer_unordered(config.concurrent_resolve)
+                .try_collect()
+                .await?;
+            dirs = vec![];
+            for (files, sub_dirs) in results {
+                snapshot.extend(files.into_iter().map(|(key, size)| SnapshotMeta {
+                    key,
+                    size: Some(size),
+                    ..Default::default()
+                }));
+                dirs.extend(sub_dirs);
+            }
+            progress.set_message(&format!("{} files", snapshot.len()));
+        }
+
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("ipfs (meta), {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for IpfsBackend {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        Ok(
+            <Self as SnapshotStorage<SnapshotMeta>>::snapshot(self, mission, config)
+                .await?
+                .into_iter()
+                .map(|x| SnapshotPath::new(x.key))
+                .collect(),
+        )
+    }
+
+    fn info(&self) -> String {
+        format!("ipfs (path), {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl<Snapshot: Key> TargetStorage<Snapshot, ByteStream> for IpfsBackend {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        byte_stream: ByteStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        debug!(mission.logger, "upload: {}", snapshot.key());
+        let ByteStream {
+            mut object, length, ..
+        } = byte_stream;
+
+        // build multipart body by hand, so that the file is streamed
+        let head = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"file\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            MULTIPART_BOUNDARY
+        );
+        let tail = format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY);
+        let body = stream::once(async move { Ok(bytes::Bytes::from(head)) })
+            .chain(throttled(object.as_stream(), mission.throttle.clone()))
+            .chain(stream::once(async move { Ok(bytes::Bytes::from(tail)) }));
+
+        self.call(
+            "files/write",
+            &[
+                ("arg", self.mfs_path(snapshot.key()).as_str()),
+                ("create", "true"),
+                ("parents", "true"),
+                ("truncate", "true"),
+            ],
+            Some(reqwest::Body::wrap_stream(body)),
+        )
+        .await?;
+        mission
+            .accounting
+            .record_upload(&format!("ipfs:{}", self.config.root), length);
+        Ok(())
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+        self.call(
+            "files/rm",
+            &[("arg", self.mfs_path(snapshot.key()).as_str())],
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+//! JetBrains source
+//!
+//! JetBrains source queries the plugin list of a JetBrains plugin repository
+//! for every configured IDE build (e.g. `IC-233.11799`), and yields plugin
+//! artifacts compatible with them. The plugin list doesn't contain file names
+//! of artifacts, so download URLs are resolved with HEAD requests. Artifacts
+//! are placed at `plugins/{id}/{version}/{file}` of the mirror.
+//!
+//! IDEs use `updatePlugins.xml` to install plugins from a custom repository.
+//! It is generated by `UpdatePluginsPipe` with URLs pointing to the mirror,
+//! and always transferred at the end.
+
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::Client;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::filter_pipe::Filtered;
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::{ByteStream, ByteStreamPipe};
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::bar;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct JetbrainsConfig {
+    #[structopt(
+        long,
+        default_value = "https://plugins.jetbrains.com",
+        help = "Base of JetBrains plugin repository"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "IDE builds to mirror plugins for, e.g. IC-233.11799",
+        required = true,
+        min_values = 1
+    )]
+    pub builds: Vec<String>,
+    #[structopt(long, help = "Mirror URL to rewrite updatePlugins.xml to")]
+    pub target_mirror: String,
+    /// When debug mode is enabled, only first 100 plugins of each build will be selected.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// A plugin version in the plugin list.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Plugin {
+    id: String,
+    version: String,
+    since_build: Option<String>,
+    until_build: Option<String>,
+    size: Option<u64>,
+    /// release date in milliseconds
+    date: Option<u64>,
+}
+
+/// A plugin artifact resolved to a mirror key.
+struct PluginFile {
+    plugin: Plugin,
+    key: String,
+    url: String,
+}
+
+pub struct Jetbrains {
+    pub config: JetbrainsConfig,
+    files: Vec<PluginFile>,
+}
+
+/// Parse plugin list (`/plugins/list?build=...`) of a plugin repository.
+fn parse_plugin_list(content: &str) -> Result<Vec<Plugin>> {
+    let document = roxmltree::Document::parse(content)
+        .map_err(|err| Error::ProcessError(format!("invalid plugin list: {:?}", err)))?;
+    let child_text = |node: roxmltree::Node, name: &str| {
+        node.children()
+            .find(|child| child.has_tag_name(name))
+            .and_then(|child| child.text())
+            .map(|text| text.trim().to_string())
+    };
+    Ok(document
+        .descendants()
+        .filter(|node| node.has_tag_name("idea-plugin"))
+        .filter_map(|node| {
+            let idea_version = node
+                .children()
+                .find(|child| child.has_tag_name("idea-version"));
+            let build_attribute = |name: &str| {
+                idea_version
+                    .and_then(|idea_version| idea_version.attribute(name))
+                    .map(|build| build.to_string())
+            };
+            Some(Plugin {
+                id: child_text(node, "id")?,
+                version: child_text(node, "version")?,
+                since_build: build_attribute("since-build"),
+                until_build: build_attribute("until-build"),
+                size: node.attribute("size").and_then(|size| size.parse().ok()),
+                date: node.attribute("date").and_then(|date| date.parse().ok()),
+            })
+        })
+        .collect())
+}
+
+/// Generate `updatePlugins.xml` of a custom plugin repository.
+fn generate_update_plugins<'a>(
+    files: impl IntoIterator<Item = &'a PluginFile>,
+    target_mirror: &str,
+) -> String {
+    let mut content = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<plugins>\n");
+    for file in files {
+        let plugin = &file.plugin;
+        content += &format!(
+            "  <plugin id=\"{}\" url=\"{}/{}\" version=\"{}\">\n",
+            html_escape::encode_double_quoted_attribute(&plugin.id),
+            target_mirror,
+            html_escape::encode_double_quoted_attribute(&file.key),
+            html_escape::encode_double_quoted_attribute(&plugin.version)
+        );
+        content += "    <idea-version";
+        if let Some(since_build) = &plugin.since_build {
+            content += &format!(
+                " since-build=\"{}\"",
+                html_escape::encode_double_quoted_attribute(since_build)
+            );
+        }
+        if let Some(until_build) = &plugin.until_build {
+            content += &format!(
+                " until-build=\"{}\"",
+                html_escape::encode_double_quoted_attribute(until_build)
+            );
+        }
+        content += "/>\n  </plugin>\n";
+    }
+    content += "</plugins>\n";
+    content
+}
+
+/// Resolve download URL of a plugin to a mirror key and the artifact URL.
+async fn resolve_plugin(client: &Client, base: &str, plugin: &Plugin) -> Result<(String, String)> {
+    let response = client
+        .head(format!("{}/plugin/download", base))
+        .query(&[("pluginId", &plugin.id), ("version", &plugin.version)])
+        .send()
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::HTTPError(status));
+    }
+    let url = response.url().clone();
+    let file = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|file| !file.is_empty())
+        .ok_or_else(|| Error::ProcessError(format!("no file name in {}", url)))?;
+    let key = format!("plugins/{}/{}/{}", plugin.id, plugin.version, file);
+    Ok((key, url.to_string()))
+}
+
+impl Jetbrains {
+    pub fn new(config: JetbrainsConfig) -> Self {
+        Self {
+            config,
+            files: vec![],
+        }
+    }
+}
+
+impl std::fmt::Debug for Jetbrains {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Jetbrains {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let base = self.config.base.clone();
+
+        // (id, version) -> plugin
+        let mut plugins = BTreeMap::new();
+        for build in &self.config.builds {
+            info!(logger, "fetching plugin list of {}...", build);
+            progress.set_message(build);
+            let content = fetch_text(
+                &client,
+                &format!("{}/plugins/list?build={}", base, urlencoding::encode(build)),
+            )
+            .await?;
+            let mut list = parse_plugin_list(&content)?;
+            if self.config.debug {
+                list.truncate(100);
+            }
+            info!(logger, "{}: {} plugins", build, list.len());
+            for plugin in list {
+                plugins
+                    .entry((plugin.id.clone(), plugin.version.clone()))
+                    .or_insert(plugin);
+            }
+        }
+
+        progress.set_length(plugins.len() as u64);
+        progress.set_style(bar());
+        progress.set_position(0);
+
+        let files: Vec<Option<PluginFile>> = stream::iter(plugins.into_values().map(|plugin| {
+            let client = client.clone();
+            let base = base.clone();
+            let logger = logger.clone();
+            let progress = progress.clone();
+            async move {
+                progress.set_message(&plugin.id);
+                let result = resolve_plugin(&client, &base, &plugin).await;
+                progress.inc(1);
+                match result {
+                    Ok((key, url)) => Ok::<_, Error>(Some(PluginFile { plugin, key, url })),
+                    Err(err) => {
+                        warn!(
+                            logger,
+                            "failed to resolve {} {}: {:?}", plugin.id, plugin.version, err
+                        );
+                        Ok(None)
+                    }
+                }
+            }
+        }))
+        .buffer_unordered(config.concurrent_resolve)
+        .try_collect()
+        .await?;
+
+        self.files = files.into_iter().flatten().collect();
+        self.files.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let snapshot = self
+            .files
+            .iter()
+            .map(|file| SnapshotMeta {
+                key: file.key.clone(),
+                size: file.plugin.size,
+                last_modified: file.plugin.date.map(|date| date / 1000),
+                ..Default::default()
+            })
+            .collect();
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("jetbrains, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Jetbrains {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        // files are sorted by key
+        self.files
+            .binary_search_by(|file| file.key.cmp(&snapshot.key))
+            .map(|idx| TransferURL(self.files[idx].url.clone()))
+            .map_err(|_| Error::ProcessError(format!("unknown key {}", snapshot.key)))
+    }
+}
+
+/// `UpdatePluginsPipe` adds `updatePlugins.xml` generated from plugins of
+/// `Jetbrains` source, listing only plugins left by filters.
+pub struct UpdatePluginsPipe {
+    source: ByteStreamPipe<Filtered<Jetbrains>>,
+    buffer_path: String,
+    content: String,
+}
+
+impl UpdatePluginsPipe {
+    pub fn new(source: ByteStreamPipe<Filtered<Jetbrains>>, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+            content: String::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for UpdatePluginsPipe {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        let jetbrains = self.source.source.inner();
+        let selected: HashSet<&str> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+        self.content = generate_update_plugins(
+            jetbrains
+                .files
+                .iter()
+                .filter(|file| selected.contains(file.key.as_str())),
+            &jetbrains.config.target_mirror,
+        );
+        snapshot.push(SnapshotMeta::force("updatePlugins.xml".to_string()));
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("UpdatePluginsPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for UpdatePluginsPipe {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        if key == "updatePlugins.xml" {
+            ByteStream::from_content(
+                &self.buffer_path,
+                key,
+                self.content.as_bytes(),
+                Some("application/xml"),
+            )
+            .await
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_plugins() {
+        let list = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plugin-repository>
+  <ff>"Languages"</ff>
+  <category name="Languages">
+    <idea-plugin downloads="100" size="1024" date="1700000000000" url="">
+      <name>Rust</name>
+      <id>org.rust.lang</id>
+      <version>0.4.200</version>
+      <idea-version min="n/a" max="n/a" since-build="233.11799" until-build="233.*"/>
+    </idea-plugin>
+    <idea-plugin downloads="1" size="10" date="1600000000000" url="">
+      <name>No Version</name>
+      <id>broken</id>
+    </idea-plugin>
+  </category>
+</plugin-repository>"#;
+        let plugins = parse_plugin_list(list).unwrap();
+        assert_eq!(
+            plugins,
+            vec![Plugin {
+                id: "org.rust.lang".to_string(),
+                version: "0.4.200".to_string(),
+                since_build: Some("233.11799".to_string()),
+                until_build: Some("233.*".to_string()),
+                size: Some(1024),
+                date: Some(1700000000000),
+            }]
+        );
+
+        let files = vec![PluginFile {
+            plugin: plugins[0].clone(),
+            key: "plugins/org.rust.lang/0.4.200/intellij-rust-0.4.200.zip".to_string(),
+            url: String::new(),
+        }];
+        assert_eq!(
+            generate_update_plugins(&files, "https://mirror.example.com/jetbrains"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<plugins>
+  <plugin id="org.rust.lang" url="https://mirror.example.com/jetbrains/plugins/org.rust.lang/0.4.200/intellij-rust-0.4.200.zip" version="0.4.200">
+    <idea-version since-build="233.11799" until-build="233.*"/>
+  </plugin>
+</plugins>
+"#
+        );
+    }
+}
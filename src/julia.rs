@@ -0,0 +1,361 @@
+//! Julia source
+//!
+//! Julia source implements the Julia PkgServer protocol. All resources
+//! served by a PkgServer are content-addressed:
+//!
+//! * `/registries` lists current registries as `/registry/<uuid>/<hash>`.
+//! * `/registry/<uuid>/<hash>` is a tarball of a registry tree.
+//! * `/package/<uuid>/<hash>` is a tarball of a package version.
+//! * `/artifact/<hash>` is a tarball of an artifact.
+//!
+//! This source downloads all registries, and enumerates packages from
+//! `Registry.toml` and `Versions.toml` inside them. Artifacts are only listed
+//! inside package tarballs, so they are enumerated only when `artifacts` is
+//! enabled. In that case, the latest version of every package is downloaded
+//! and its `Artifacts.toml` is scanned.
+//!
+//! As all objects except `registries` are immutable, mirrors built by this
+//! source can serve as a StorageServer for PkgServers.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::bar;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Julia {
+    #[structopt(
+        long,
+        default_value = "https://pkg.julialang.org",
+        help = "Base of upstream PkgServer"
+    )]
+    pub pkg_server: String,
+    #[structopt(
+        long,
+        help = "Enumerate artifacts of latest package versions (downloads package tarballs)"
+    )]
+    pub artifacts: bool,
+    /// When debug mode is enabled, only first 100 packages of each registry will be selected.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+#[derive(Deserialize)]
+struct RegistryToml {
+    packages: HashMap<String, RegistryPackage>,
+}
+
+#[derive(Deserialize)]
+struct RegistryPackage {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct VersionInfo {
+    #[serde(rename = "git-tree-sha1")]
+    git_tree_sha1: String,
+}
+
+/// A package in registry, with its versions and corresponding tree hashes.
+#[derive(Debug, PartialEq)]
+struct Package {
+    uuid: String,
+    versions: Vec<(String, String)>,
+}
+
+impl Package {
+    /// Tree hash of the latest version
+    fn latest(&self) -> Option<&str> {
+        self.versions
+            .iter()
+            .max_by_key(|(version, _)| version_key(version))
+            .map(|(_, hash)| hash.as_str())
+    }
+}
+
+/// Numeric components of a version string, used for sorting.
+fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|x| x.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Read files with given names (or suffixes) from a `.tar.gz` archive.
+fn read_tarball(data: &[u8], filter: impl Fn(&str) -> bool) -> Result<HashMap<String, String>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(data));
+    let mut files = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let path = path.trim_start_matches("./").to_string();
+        if filter(&path) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            files.insert(path, content);
+        }
+    }
+    Ok(files)
+}
+
+/// Parse packages of a registry from its `Registry.toml` and `Versions.toml`s.
+fn parse_registry(files: &HashMap<String, String>) -> Result<Vec<Package>> {
+    let registry: RegistryToml = toml::from_str(
+        files
+            .get("Registry.toml")
+            .ok_or_else(|| Error::ProcessError("no Registry.toml in registry".to_string()))?,
+    )?;
+    let mut packages = vec![];
+    for (uuid, package) in registry.packages {
+        let versions = match files.get(&format!("{}/Versions.toml", package.path)) {
+            Some(versions) => toml::from_str::<HashMap<String, VersionInfo>>(versions)?
+                .into_iter()
+                .map(|(version, info)| (version, info.git_tree_sha1))
+                .collect(),
+            None => vec![],
+        };
+        packages.push(Package { uuid, versions });
+    }
+    packages.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+    Ok(packages)
+}
+
+/// Collect all `git-tree-sha1` in an `Artifacts.toml`.
+fn parse_artifacts(value: &toml::Value, artifacts: &mut Vec<String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                match (key.as_str(), value) {
+                    ("git-tree-sha1", toml::Value::String(hash)) => artifacts.push(hash.clone()),
+                    _ => parse_artifacts(value, artifacts),
+                }
+            }
+        }
+        toml::Value::Array(array) => {
+            for value in array {
+                parse_artifacts(value, artifacts);
+            }
+        }
+        _ => {}
+    }
+}
+
+async fn fetch_artifacts(client: &Client, url: &str) -> Result<Vec<String>> {
+    let data = fetch(client, url).await?;
+    let files = tokio::task::spawn_blocking(move || {
+        read_tarball(&data, |path| {
+            path == "Artifacts.toml" || path == "JuliaArtifacts.toml"
+        })
+    })
+    .await
+    .map_err(|err| Error::ProcessError(format!("error while reading tarball: {:?}", err)))??;
+    let mut artifacts = vec![];
+    for content in files.values() {
+        parse_artifacts(&toml::from_str(content)?, &mut artifacts);
+    }
+    Ok(artifacts)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Julia {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching registries...");
+        progress.set_message("fetching registries...");
+        let registries = fetch(&client, &format!("{}/registries", self.pkg_server)).await?;
+        let registries: Vec<String> = String::from_utf8_lossy(&registries)
+            .lines()
+            .map(|line| line.trim().trim_start_matches('/').to_string())
+            .filter(|line| line.starts_with("registry/"))
+            .collect();
+
+        let mut snapshot = vec![];
+        let mut packages = vec![];
+        for registry in registries {
+            info!(logger, "fetching {}...", registry);
+            progress.set_message(&registry);
+            let data = fetch(&client, &format!("{}/{}", self.pkg_server, registry)).await?;
+            let mut registry_packages = tokio::task::spawn_blocking(move || {
+                let files = read_tarball(&data, |path| {
+                    path == "Registry.toml" || path.ends_with("/Versions.toml")
+                })?;
+                parse_registry(&files)
+            })
+            .await
+            .map_err(|err| Error::ProcessError(format!("error while parsing: {:?}", err)))??;
+            if self.debug {
+                registry_packages.truncate(100);
+            }
+            info!(
+                logger,
+                "{} packages in {}",
+                registry_packages.len(),
+                registry
+            );
+            packages.append(&mut registry_packages);
+            snapshot.push(SnapshotMeta::new(registry));
+        }
+
+        for package in &packages {
+            for (_, hash) in &package.versions {
+                snapshot.push(SnapshotMeta::new(format!(
+                    "package/{}/{}",
+                    package.uuid, hash
+                )));
+            }
+        }
+
+        if self.artifacts {
+            info!(
+                logger,
+                "fetching artifacts of {} packages...",
+                packages.len()
+            );
+            progress.set_length(packages.len() as u64);
+            progress.set_style(bar());
+
+            let latest: Vec<String> = packages
+                .iter()
+                .filter_map(|package| {
+                    package
+                        .latest()
+                        .map(|hash| format!("package/{}/{}", package.uuid, hash))
+                })
+                .collect();
+            let artifacts: Result<Vec<Vec<String>>> = stream::iter(latest.into_iter().map(|key| {
+                let client = client.clone();
+                let url = format!("{}/{}", self.pkg_server, key);
+                let progress = progress.clone();
+                let logger = logger.clone();
+                async move {
+                    progress.set_message(&key);
+                    let artifacts = match fetch_artifacts(&client, &url).await {
+                        Ok(artifacts) => artifacts,
+                        Err(err) => {
+                            warn!(logger, "failed to fetch artifacts of {}: {:?}", key, err);
+                            vec![]
+                        }
+                    };
+                    progress.inc(1);
+                    Ok::<_, Error>(artifacts)
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await;
+
+            // artifacts may be shared among packages
+            let artifacts: BTreeSet<String> = artifacts?.into_iter().flatten().collect();
+            snapshot.extend(
+                artifacts
+                    .into_iter()
+                    .map(|hash| SnapshotMeta::new(format!("artifact/{}", hash))),
+            );
+        }
+
+        snapshot.push(SnapshotMeta::force("registries".to_string()));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("julia, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Julia {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.pkg_server, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registry() {
+        let mut files = HashMap::new();
+        files.insert(
+            "Registry.toml".to_string(),
+            r#"
+name = "General"
+uuid = "23338594-aafe-5451-b93e-139f81909106"
+
+[packages]
+00000000-1111-2222-3333-444444444444 = { name = "Example", path = "E/Example" }
+"#
+            .to_string(),
+        );
+        files.insert(
+            "E/Example/Versions.toml".to_string(),
+            r#"
+["0.5.3"]
+git-tree-sha1 = "46e44e869b4d90b96bd8ed1fdcf32244fddfb6cc"
+
+["0.5.10"]
+git-tree-sha1 = "2b6bda5b8e0d1d3a5ff1e0e1e1c3f16a7b4a1f3e"
+yanked = true
+"#
+            .to_string(),
+        );
+        let packages = parse_registry(&files).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].uuid, "00000000-1111-2222-3333-444444444444");
+        assert_eq!(packages[0].versions.len(), 2);
+        assert_eq!(
+            packages[0].latest(),
+            Some("2b6bda5b8e0d1d3a5ff1e0e1e1c3f16a7b4a1f3e")
+        );
+    }
+
+    #[test]
+    fn test_parse_artifacts() {
+        let artifacts_toml: toml::Value = toml::from_str(
+            r#"
+[[libfoo]]
+arch = "x86_64"
+git-tree-sha1 = "aaaa"
+
+[[libfoo]]
+arch = "aarch64"
+git-tree-sha1 = "bbbb"
+
+[data]
+git-tree-sha1 = "cccc"
+lazy = true
+"#,
+        )
+        .unwrap();
+        let mut artifacts = vec![];
+        parse_artifacts(&artifacts_toml, &mut artifacts);
+        artifacts.sort();
+        assert_eq!(artifacts, vec!["aaaa", "bbbb", "cccc"]);
+    }
+}
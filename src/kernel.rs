@@ -0,0 +1,261 @@
+//! kernel.org source
+//!
+//! Kernel source reads `releases.json` of kernel.org to find series (e.g.
+//! `v6.x`) of current releases, and scans directory listings of them. It
+//! yields tarballs, signatures, patches and changelogs with size and
+//! modification time from listings.
+//!
+//! Like rustup source, only files modified within `days_to_retain` days are
+//! mirrored, except that files of releases in `releases.json` are always
+//! kept. Checksum files and `releases.json` are transferred at the end.
+
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use regex::Regex;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::{fetch_json, fetch_text};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Kernel {
+    #[structopt(
+        long,
+        default_value = "https://cdn.kernel.org/pub/linux/kernel",
+        help = "Base of kernel release directories"
+    )]
+    pub base: String,
+    #[structopt(long, default_value = "https://www.kernel.org/releases.json")]
+    pub releases_json: String,
+    #[structopt(long, default_value = "365")]
+    pub days_to_retain: usize,
+}
+
+/// Files which are always transferred at the end of a series.
+const CHECKSUM_FILES: &[&str] = &["sha256sums.asc"];
+
+#[derive(Deserialize)]
+struct Releases {
+    releases: Vec<Release>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    version: String,
+    moniker: String,
+}
+
+/// A file in directory listing.
+#[derive(Debug, PartialEq)]
+struct ListingEntry {
+    name: String,
+    modified: u64,
+    size: Option<u64>,
+}
+
+/// Series directory of a release version, e.g. `v6.x` of `6.6.1`.
+fn release_series(release: &Release) -> Option<String> {
+    if release.moniker == "linux-next" {
+        return None;
+    }
+    let major = release.version.split(['.', '-']).next()?;
+    major.parse::<u64>().ok()?;
+    Some(format!("v{}.x", major))
+}
+
+/// Parse an nginx-style directory listing, ignoring subdirectories.
+fn parse_listing(content: &str) -> Vec<ListingEntry> {
+    let matcher =
+        Regex::new(r#"<a href="([^"/?]+)">[^<]*</a>\s+(\d{2}-\w{3}-\d{4} \d{2}:\d{2})\s+(\d+|-)"#)
+            .unwrap();
+    matcher
+        .captures_iter(content)
+        .filter_map(|cap| {
+            let modified = NaiveDateTime::parse_from_str(&cap[2], "%d-%b-%Y %H:%M").ok()?;
+            Some(ListingEntry {
+                name: html_escape::decode_html_entities(&cap[1]).to_string(),
+                modified: modified.and_utc().timestamp() as u64,
+                size: cap[3].parse().ok(),
+            })
+        })
+        .collect()
+}
+
+/// Whether a file belongs to one of `versions`, e.g. `patch-6.6.1.xz` of `6.6.1`.
+fn is_release_file(name: &str, versions: &BTreeSet<String>) -> bool {
+    versions.iter().any(|version| {
+        let pattern = format!("-{}", version);
+        name.match_indices(&pattern).any(|(pos, _)| {
+            let rest = &name[pos + pattern.len()..];
+            rest.is_empty() || rest.starts_with('.')
+        })
+    })
+}
+
+/// Select files to mirror from a series listing.
+fn select_files(
+    series: &str,
+    listing: Vec<ListingEntry>,
+    versions: &BTreeSet<String>,
+    not_before: u64,
+) -> Vec<SnapshotMeta> {
+    listing
+        .into_iter()
+        .filter_map(|entry| {
+            let key = format!("{}/{}", series, entry.name);
+            if CHECKSUM_FILES.contains(&entry.name.as_str()) {
+                Some(SnapshotMeta::force(key))
+            } else if entry.modified >= not_before || is_release_file(&entry.name, versions) {
+                Some(SnapshotMeta {
+                    key,
+                    size: entry.size,
+                    last_modified: Some(entry.modified),
+                    ..Default::default()
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Kernel {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching releases.json...");
+        progress.set_message("fetching releases.json...");
+        let releases: Releases = fetch_json(&client, &self.releases_json).await?;
+        let versions: BTreeSet<String> = releases
+            .releases
+            .iter()
+            .map(|release| release.version.clone())
+            .collect();
+        let series: BTreeSet<String> = releases
+            .releases
+            .iter()
+            .filter_map(release_series)
+            .collect();
+        info!(logger, "releases: {:?}, series: {:?}", versions, series);
+
+        let not_before = (Utc::now().timestamp() as u64)
+            .saturating_sub(self.days_to_retain as u64 * 24 * 60 * 60);
+
+        let snapshots: Vec<Vec<SnapshotMeta>> = stream::iter(series.into_iter().map(|series| {
+            let client = client.clone();
+            let base = self.base.clone();
+            let versions = &versions;
+            let logger = logger.clone();
+            let progress = progress.clone();
+            async move {
+                progress.set_message(&series);
+                match fetch_text(&client, &format!("{}/{}/", base, series)).await {
+                    Ok(content) => {
+                        let listing = parse_listing(&content);
+                        let files = select_files(&series, listing, versions, not_before);
+                        info!(logger, "{}: {} files", series, files.len());
+                        Ok::<_, Error>(files)
+                    }
+                    Err(err) => {
+                        warn!(logger, "failed to list {}: {:?}", series, err);
+                        Ok(vec![])
+                    }
+                }
+            }
+        }))
+        .buffer_unordered(config.concurrent_resolve)
+        .try_collect()
+        .await?;
+
+        let mut snapshot: Vec<SnapshotMeta> = snapshots.into_iter().flatten().collect();
+        snapshot.push(SnapshotMeta::force("releases.json".to_string()));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("kernel, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Kernel {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        if snapshot.key == "releases.json" {
+            Ok(TransferURL(self.releases_json.clone()))
+        } else {
+            Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_files() {
+        let listing = r#"<html>
+<head><title>Index of /pub/linux/kernel/v6.x/</title></head>
+<body>
+<h1>Index of /pub/linux/kernel/v6.x/</h1><hr><pre><a href="../">../</a>
+<a href="incr/">incr/</a>                                              08-Nov-2023 11:09       -
+<a href="ChangeLog-6.1.62">ChangeLog-6.1.62</a>                                   08-Nov-2023 11:02     108744
+<a href="linux-6.1.62.tar.xz">linux-6.1.62.tar.xz</a>                                08-Nov-2023 11:02  134788244
+<a href="linux-6.1.62.tar.sign">linux-6.1.62.tar.sign</a>                              08-Nov-2023 11:02        991
+<a href="linux-6.1.6.tar.xz">linux-6.1.6.tar.xz</a>                                 12-Jan-2023 11:33  134455092
+<a href="patch-6.0.xz">patch-6.0.xz</a>                                       02-Oct-2022 22:04   12380000
+<a href="sha256sums.asc">sha256sums.asc</a>                                     08-Nov-2023 11:10     412345
+</pre><hr></body>
+</html>"#;
+        let listing = parse_listing(listing);
+        assert_eq!(listing.len(), 6);
+        assert_eq!(
+            listing[1],
+            ListingEntry {
+                name: "linux-6.1.62.tar.xz".to_string(),
+                modified: 1699441320,
+                size: Some(134788244),
+            }
+        );
+
+        let versions: BTreeSet<String> = vec!["6.1.62".to_string()].into_iter().collect();
+        // retain files modified since 2023-01-01
+        let files = select_files("v6.x", listing, &versions, 1672531200);
+        let keys: Vec<&str> = files.iter().map(|file| file.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "v6.x/ChangeLog-6.1.62",
+                "v6.x/linux-6.1.62.tar.xz",
+                "v6.x/linux-6.1.62.tar.sign",
+                "v6.x/linux-6.1.6.tar.xz",
+                "v6.x/sha256sums.asc",
+            ]
+        );
+        assert!(files[4].flags.force_last);
+
+        assert!(is_release_file(
+            "patch-6.0.xz",
+            &vec!["6.0".to_string()].into_iter().collect()
+        ));
+        assert!(!is_release_file("linux-6.1.6.tar.xz", &versions));
+    }
+}
@@ -1 +1,2 @@
 pub mod elan;
+pub mod release;
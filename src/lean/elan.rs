@@ -1,5 +1,19 @@
 use structopt::StructOpt;
 
+use crate::error::Result;
+use crate::utils::CommaSplitVecString;
+
+use super::release::LeanRelease;
+
+/// GitHub repositories mirrored for elan, and their paths in the mirror.
+pub const ELAN_REPOS: [(&str, &str); 5] = [
+    ("leanprover/elan", "elan"),
+    ("leanprover/lean4", "leanprover/lean4"),
+    ("leanprover/lean4-nightly", "leanprover/lean4_nightly"),
+    ("alissa-tung/glean", "glean"),
+    ("leanprover-community/ProofWidgets4", "proofwidgets"),
+];
+
 #[derive(Debug, StructOpt)]
 pub struct ElanConfig {
     #[structopt(long, default_value = "3")]
@@ -12,4 +26,59 @@ pub struct ElanConfig {
     pub retain_glean_versions: usize,
     #[structopt(long, default_value = "10")]
     pub retain_proofwidgets_versions: usize,
+    #[structopt(long, default_value = "https://release.lean-lang.org")]
+    pub release_base: String,
+    #[structopt(long, help = "Channel files to mirror", default_value = "index.json")]
+    pub channel_files: CommaSplitVecString,
+    #[structopt(
+        long,
+        help = "Rewrite GitHub asset URLs in channel files to this mirror"
+    )]
+    pub target_mirror: Option<String>,
+}
+
+impl ElanConfig {
+    pub fn get_release(&self) -> LeanRelease {
+        LeanRelease {
+            base: self.release_base.clone(),
+            channel_files: self.channel_files.clone().into(),
+        }
+    }
+}
+
+/// Rewrite GitHub asset URLs of mirrored repositories to the mirror, or keep
+/// them unchanged if no mirror is given.
+pub fn rewrite_fn(
+    target_mirror: Option<String>,
+) -> impl Fn(String) -> Result<String> + Send + Sync {
+    let target_mirror = target_mirror.map(|mirror| mirror.trim_end_matches('/').to_string());
+    move |src: String| -> Result<String> {
+        let target_mirror = match &target_mirror {
+            Some(target_mirror) => target_mirror,
+            None => return Ok(src),
+        };
+        Ok(ELAN_REPOS.iter().fold(src, |src, (repo, path)| {
+            src.replace(
+                &format!("https://github.com/{}/", repo),
+                &format!("{}/{}/", target_mirror, path),
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_fn() {
+        let rewrite = rewrite_fn(Some(String::from("https://mirror.sjtu.edu.cn/elan/")));
+        assert_eq!(
+            rewrite(String::from(
+                r#"["https://github.com/leanprover/lean4/releases/download/v4.0.0/lean-4.0.0-linux.zip", "https://github.com/leanprover/lean4-nightly/releases/download/nightly-2023-09-01/lean-linux.zip"]"#
+            ))
+            .unwrap(),
+            r#"["https://mirror.sjtu.edu.cn/elan/leanprover/lean4/releases/download/v4.0.0/lean-4.0.0-linux.zip", "https://mirror.sjtu.edu.cn/elan/leanprover/lean4_nightly/releases/download/nightly-2023-09-01/lean-linux.zip"]"#
+        );
+    }
 }
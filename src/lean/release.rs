@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use slog::info;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+/// `LeanRelease` fetches channel files of release.lean-lang.org, which are
+/// used by elan to resolve channels like `stable` to toolchain releases.
+#[derive(Debug, Clone)]
+pub struct LeanRelease {
+    pub base: String,
+    pub channel_files: Vec<String>,
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for LeanRelease {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+
+        info!(logger, "fetching lean release channels...");
+
+        progress.finish_with_message("done");
+        Ok(self
+            .channel_files
+            .iter()
+            .cloned()
+            .map(SnapshotMeta::force)
+            .collect())
+    }
+
+    fn info(&self) -> String {
+        format!("lean_release, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for LeanRelease {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!(
+            "{}/{}",
+            self.base.trim_end_matches('/'),
+            snapshot.key
+        )))
+    }
+}
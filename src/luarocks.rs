@@ -0,0 +1,401 @@
+//! LuaRocks source
+//!
+//! LuaRocks source downloads the `manifest` file of a LuaRocks server, which is
+//! a Lua script assigning a nested table to `repository`. It lists all versions
+//! of all rocks, together with architectures available. This source parses the
+//! manifest, and yields `.rockspec`, `.src.rock` and binary rock files.
+//!
+//! Manifests are always transferred at the end. If `target_mirror` is set,
+//! they should be piped through `RewritePipe` so that URLs point to the mirror.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Luarocks {
+    #[structopt(
+        long,
+        default_value = "https://luarocks.org",
+        help = "Base of LuaRocks server"
+    )]
+    pub base: String,
+    #[structopt(long, help = "Mirror URL to rewrite manifests to")]
+    pub target_mirror: Option<String>,
+    /// When debug mode is enabled, only first 100 rocks will be selected.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+/// Manifests of all Lua versions supported by LuaRocks clients.
+const MANIFESTS: &[&str] = &[
+    "manifest",
+    "manifest-5.1",
+    "manifest-5.2",
+    "manifest-5.3",
+    "manifest-5.4",
+];
+
+/// A minimal parser for the subset of Lua used in LuaRocks manifests,
+/// which only contains assignments of table constructors.
+mod lua {
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq)]
+    pub enum Value {
+        String(String),
+        Number(String),
+        Boolean(bool),
+        Nil,
+        Table(Table),
+    }
+
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Table {
+        /// positional items, e.g. `{ a, b }`
+        pub items: Vec<Value>,
+        /// keyed fields, e.g. `{ a = 1, ["b"] = 2 }`
+        pub fields: BTreeMap<String, Value>,
+    }
+
+    struct Parser<'a> {
+        input: &'a [u8],
+        pos: usize,
+    }
+
+    type ParseResult<T> = std::result::Result<T, String>;
+
+    impl<'a> Parser<'a> {
+        fn peek(&self) -> Option<u8> {
+            self.input.get(self.pos).copied()
+        }
+
+        fn error<T>(&self, message: &str) -> ParseResult<T> {
+            Err(format!("{} at byte {}", message, self.pos))
+        }
+
+        fn skip_whitespace(&mut self) {
+            loop {
+                match self.peek() {
+                    Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                    Some(b'-') if self.input.get(self.pos + 1) == Some(&b'-') => {
+                        while !matches!(self.peek(), None | Some(b'\n')) {
+                            self.pos += 1;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        fn expect(&mut self, c: u8) -> ParseResult<()> {
+            self.skip_whitespace();
+            if self.peek() == Some(c) {
+                self.pos += 1;
+                Ok(())
+            } else {
+                self.error(&format!("expect `{}`", c as char))
+            }
+        }
+
+        fn identifier(&mut self) -> Option<String> {
+            let start = self.pos;
+            while let Some(c) = self.peek() {
+                if c.is_ascii_alphanumeric() || c == b'_' {
+                    self.pos += 1;
+                } else {
+                    break;
+                }
+            }
+            if start == self.pos || self.input[start].is_ascii_digit() {
+                self.pos = start;
+                None
+            } else {
+                Some(String::from_utf8_lossy(&self.input[start..self.pos]).to_string())
+            }
+        }
+
+        fn string(&mut self) -> ParseResult<String> {
+            let quote = self.peek().unwrap();
+            self.pos += 1;
+            let mut result = vec![];
+            loop {
+                match self.peek() {
+                    None => return self.error("unterminated string"),
+                    Some(c) if c == quote => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(b'\\') => {
+                        self.pos += 1;
+                        let escaped = match self.peek() {
+                            Some(b'n') => b'\n',
+                            Some(b't') => b'\t',
+                            Some(b'r') => b'\r',
+                            Some(c) => c,
+                            None => return self.error("unterminated string"),
+                        };
+                        result.push(escaped);
+                        self.pos += 1;
+                    }
+                    Some(c) => {
+                        result.push(c);
+                        self.pos += 1;
+                    }
+                }
+            }
+            Ok(String::from_utf8_lossy(&result).to_string())
+        }
+
+        fn number(&mut self) -> String {
+            let start = self.pos;
+            while let Some(c) = self.peek() {
+                if c.is_ascii_alphanumeric() || c == b'.' || c == b'-' || c == b'+' {
+                    self.pos += 1;
+                } else {
+                    break;
+                }
+            }
+            String::from_utf8_lossy(&self.input[start..self.pos]).to_string()
+        }
+
+        fn value(&mut self) -> ParseResult<Value> {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b'{') => Ok(Value::Table(self.table()?)),
+                Some(b'"') | Some(b'\'') => Ok(Value::String(self.string()?)),
+                Some(c) if c.is_ascii_digit() || c == b'-' || c == b'.' => {
+                    Ok(Value::Number(self.number()))
+                }
+                _ => match self.identifier().as_deref() {
+                    Some("true") => Ok(Value::Boolean(true)),
+                    Some("false") => Ok(Value::Boolean(false)),
+                    Some("nil") => Ok(Value::Nil),
+                    _ => self.error("unexpected token"),
+                },
+            }
+        }
+
+        fn key(&mut self) -> ParseResult<String> {
+            self.expect(b'[')?;
+            let key = match self.value()? {
+                Value::String(key) | Value::Number(key) => key,
+                _ => return self.error("unsupported key"),
+            };
+            self.expect(b']')?;
+            Ok(key)
+        }
+
+        fn table(&mut self) -> ParseResult<Table> {
+            self.expect(b'{')?;
+            let mut table = Table::default();
+            loop {
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b'}') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(b'[') => {
+                        let key = self.key()?;
+                        self.expect(b'=')?;
+                        table.fields.insert(key, self.value()?);
+                    }
+                    _ => {
+                        let start = self.pos;
+                        let field = self.identifier();
+                        self.skip_whitespace();
+                        match field {
+                            Some(key) if self.peek() == Some(b'=') => {
+                                self.pos += 1;
+                                table.fields.insert(key, self.value()?);
+                            }
+                            _ => {
+                                self.pos = start;
+                                table.items.push(self.value()?);
+                            }
+                        }
+                    }
+                }
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') | Some(b';') => self.pos += 1,
+                    Some(b'}') => {}
+                    _ => return self.error("expect `,` or `}`"),
+                }
+            }
+            Ok(table)
+        }
+    }
+
+    /// Parse a Lua script consisting of global assignments.
+    pub fn parse(input: &str) -> ParseResult<BTreeMap<String, Value>> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            pos: 0,
+        };
+        let mut globals = BTreeMap::new();
+        loop {
+            parser.skip_whitespace();
+            if parser.peek().is_none() {
+                break;
+            }
+            let name = match parser.identifier() {
+                Some(name) => name,
+                None => return parser.error("expect identifier"),
+            };
+            parser.expect(b'=')?;
+            globals.insert(name, parser.value()?);
+        }
+        Ok(globals)
+    }
+}
+
+/// Generate rock file names from `repository` table of manifest.
+fn rocks_from_manifest(repository: &lua::Table) -> Vec<String> {
+    let mut rocks = vec![];
+    for (name, versions) in &repository.fields {
+        if let lua::Value::Table(versions) = versions {
+            for (version, archs) in &versions.fields {
+                if let lua::Value::Table(archs) = archs {
+                    for arch in &archs.items {
+                        let arch = match arch {
+                            lua::Value::Table(arch) => arch.fields.get("arch"),
+                            _ => None,
+                        };
+                        match arch {
+                            Some(lua::Value::String(arch)) if arch == "rockspec" => {
+                                rocks.push(format!("{}-{}.rockspec", name, version))
+                            }
+                            Some(lua::Value::String(arch)) => {
+                                rocks.push(format!("{}-{}.{}.rock", name, version, arch))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+    rocks
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Luarocks {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching manifest...");
+        progress.set_message("fetching manifest...");
+        let data = fetch_text(&client, &format!("{}/manifest", self.base)).await?;
+
+        info!(logger, "parsing...");
+        progress.set_message("parsing...");
+        let mut globals = lua::parse(&data)
+            .map_err(|err| Error::ProcessError(format!("failed to parse manifest: {}", err)))?;
+        let mut repository = match globals.remove("repository") {
+            Some(lua::Value::Table(repository)) => repository,
+            _ => {
+                return Err(Error::ProcessError(String::from(
+                    "no repository in manifest",
+                )))
+            }
+        };
+
+        if self.debug {
+            repository.fields = repository
+                .fields
+                .into_iter()
+                .take(100)
+                .collect::<BTreeMap<_, _>>();
+        }
+
+        let mut snapshot = crate::utils::snapshot_string_to_meta(rocks_from_manifest(&repository));
+        for manifest in MANIFESTS {
+            snapshot.push(SnapshotMeta::force(manifest.to_string()));
+            snapshot.push(SnapshotMeta::force(format!("{}.zip", manifest)));
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("luarocks, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Luarocks {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = r#"
+commands = {}
+modules = {}
+repository = {
+   ["30log"] = {
+      ["1.3.0-1"] = {
+         {
+            arch = "rockspec"
+         },
+         {
+            arch = "src"
+         }
+      }
+   },
+   lpeg = {
+      ["1.0.2-1"] = {
+         {
+            arch = "rockspec"
+         }, {
+            arch = "linux-x86_64"
+         };
+      },
+   },
+}
+-- comment
+"#;
+        let mut globals = lua::parse(manifest).unwrap();
+        assert_eq!(
+            globals.remove("commands"),
+            Some(lua::Value::Table(Default::default()))
+        );
+        let repository = match globals.remove("repository") {
+            Some(lua::Value::Table(repository)) => repository,
+            other => panic!("unexpected repository {:?}", other),
+        };
+        assert_eq!(
+            rocks_from_manifest(&repository),
+            vec![
+                "30log-1.3.0-1.rockspec",
+                "30log-1.3.0-1.src.rock",
+                "lpeg-1.0.2-1.rockspec",
+                "lpeg-1.0.2-1.linux-x86_64.rock"
+            ]
+        );
+    }
+}
@@ -1,111 +1,292 @@
 #![deny(clippy::all)]
 #![allow(clippy::enum_variant_names)]
 
+use std::convert::TryInto;
 use std::path::Path;
 
 use lazy_static::lazy_static;
 use structopt::StructOpt;
 
-use common::SnapshotConfig;
-use error::Result;
+use common::{SnapshotConfig, TransferURL};
+use dedup::DedupTarget;
+use error::{Error, Result};
 use file_backend::FileBackend;
+use filter_pipe::{FilterConfig, Filtered};
+use http_put::HttpPutBackend;
+use index_pipe::IndexPipe;
+use ipfs::IpfsBackend;
+use merge_pipe::MergePipe;
+use metadata::SnapshotMeta;
+use mirror_intel::MirrorIntel;
 use opts::{Source, Target};
+use pipeline::PipeSpec::{AugmentChecksum, Checksum, Index};
+use pipeline::{boxed, BoxedSource, Pipe, PipelineBuilder};
+use priority_pipe::{PriorityConfig, PriorityPipe};
+use retry_pipe::{RetryConfig, RetryPipe};
 use s3::S3Backend;
 use simple_diff_transfer::SimpleDiffTransfer;
+use stream_pipe::ByteStream;
+use traits::{Key, Metadata};
 
 use crate::github_release::GitHubRelease;
 use crate::homebrew::Homebrew;
 
+mod accounting;
+mod apache;
+mod apt;
+mod bcr;
+mod cas;
 mod checksum_pipe;
+mod chocolatey;
+mod circuit_breaker;
 mod common;
+mod compress_pipe;
+mod conan;
 mod conda;
+mod content_type;
 mod crates_io;
 mod dart;
+mod dedup;
+mod distro_image;
 mod error;
+mod etag_pipe;
+mod fetch;
 mod file_backend;
+mod filelist;
 mod filter_pipe;
+mod flutter;
+mod freebsd_pkg;
+mod gentoo;
 mod ghcup;
+mod git;
 mod github_release;
+mod gnu;
+mod godist;
 mod gradle;
+mod helm;
+mod hexpm;
 mod homebrew;
 mod html_scanner;
+mod http_put;
+mod huggingface;
 mod index_pipe;
-#[macro_use]
-mod merge_pipe;
+mod ipfs;
+mod jetbrains;
+mod julia;
+mod kernel;
 mod lean;
+mod luarocks;
+mod merge_pipe;
 mod metadata;
+mod mirror_intel;
+mod msys2;
+mod multi_target;
+mod opam;
+mod openwrt;
 mod opts;
+mod p2;
+mod pacman;
+mod pipeline;
+mod priority_pipe;
 mod pypi;
 mod python_version;
+mod quicklisp;
+mod raspbian;
+mod retry_pipe;
 mod rewrite_pipe;
+mod ros;
 mod rsync;
 mod rustup;
 mod s3;
+mod s3_retry;
+mod self_test;
 mod simple_diff_transfer;
+mod snapshot_cache_pipe;
 mod stream_pipe;
+mod terraform;
+mod throttle;
 mod timeout;
 mod traits;
 mod utils;
-
-macro_rules! index_bytes_pipe {
-    ($buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr) => {
-        |source| {
-            let source = stream_pipe::ByteStreamPipe::new(
-                source,
-                $buffer_path.clone().unwrap(),
-                $use_snapshot_last_modified,
-            );
-            index_pipe::IndexPipe::new(
-                source,
-                $buffer_path.clone().unwrap(),
-                $prefix.clone().unwrap(),
-                $max_depth,
-            )
-        }
-    };
-}
-
-macro_rules! index_checksum_bytes_pipe {
-    ($buffer_path: expr, $prefix: expr, $use_snapshot_last_modified: expr, $max_depth: expr) => {
-        |source| {
-            let bytestream = stream_pipe::ByteStreamPipe::new(
-                source,
-                $buffer_path.clone().unwrap(),
-                $use_snapshot_last_modified,
-            );
-            let checksum = checksum_pipe::ChecksumPipe::new(bytestream);
-            index_pipe::IndexPipe::new(
-                checksum,
-                $buffer_path.clone().unwrap(),
-                $prefix.clone().unwrap(),
-                $max_depth,
-            )
-        }
-    };
-}
-
-macro_rules! id_pipe {
-    () => {
-        |src| src
-    };
-}
+mod validator_cache;
+mod vcpkg;
+mod vsx;
+mod zig;
 
 macro_rules! transfer {
     ($opts: expr, $source: expr, $transfer_config: expr, $pipes: expr) => {
-        match $opts.target_type {
+        match &$opts.target_type {
             Target::S3 => {
-                let target: S3Backend = $opts.s3_config.clone().into();
-                let pipes = $pipes;
-                let source = pipes($source);
-                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
-                transfer.transfer().await.unwrap();
+                let target: S3Backend = $opts.s3_config.clone().try_into()?;
+                let target = DedupTarget::new(
+                    target,
+                    $opts.dedup_config.clone(),
+                    $opts.s3_config.s3_buffer_path.clone(),
+                )?;
+                let source = wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    $pipes,
+                )?;
+                // unchanged objects are skipped by comparing ETags
+                let source =
+                    etag_pipe::ComputeEtagPipe::new(source, $opts.s3_config.s3_etag_part_size);
+                // deduplicated objects are chunked from buffer files
+                let no_dedup = $opts.dedup_config.dedup_pattern.is_none();
+                let transfer_config = $transfer_config;
+                let transfer_config = simple_diff_transfer::SimpleDiffTransferConfig {
+                    direct_stream: $opts.s3_config.s3_direct_stream.filter(|_| no_dedup),
+                    // deletions are batched by S3 target
+                    concurrent_delete: transfer_config
+                        .concurrent_delete
+                        .max($opts.s3_config.s3_delete_batch_size),
+                    ..transfer_config
+                };
+                let transfer = SimpleDiffTransfer::new(source, target, transfer_config);
+                transfer.transfer().await?;
             }
             Target::File => {
                 let target: FileBackend = $opts.file_config.clone().into();
-                let pipes = $pipes;
-                let source = pipes($source);
+                let target = DedupTarget::new(
+                    target,
+                    $opts.dedup_config.clone(),
+                    $opts.file_config.file_buffer_path.clone(),
+                )?;
+                let source = wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    $pipes,
+                )?;
+                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                transfer.transfer().await?;
+            }
+            Target::Ipfs => {
+                // IPFS deduplicates blocks by itself
+                let target: IpfsBackend = $opts.ipfs_config.clone().into();
+                let publisher = target.clone();
+                let source = wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    $pipes,
+                )?;
+                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                transfer.transfer().await?;
+                publisher
+                    .publish(&utils::create_logger($opts.verbose))
+                    .await?;
+            }
+            Target::HttpPut => {
+                let target: HttpPutBackend = $opts.http_put_config.clone().into();
+                let source = wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    $pipes,
+                )?;
+                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                transfer.transfer().await?;
+            }
+            Target::Cas => {
+                let target = $opts
+                    .cas_config
+                    .clone()
+                    .into_backend($opts.file_config.clone());
+                let reporter = target.clone();
+                let source = wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    $pipes,
+                )?;
+                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                transfer.transfer().await?;
+                reporter.report(&utils::create_logger($opts.verbose));
+            }
+            Target::MirrorIntel => {
+                // intel fetches objects by itself, so pipes are skipped
+                let target: MirrorIntel = $opts.mirror_intel_config.clone().into();
+                let source = mirror_intel::PathPipe::new(wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    Ok,
+                )?);
+                let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
+                transfer.transfer().await?;
+            }
+            Target::Multi(targets) => {
+                let mut publishers = vec![];
+                let mut target = multi_target::MultiTarget::new($opts.multi_target_config.clone());
+                for inner in targets {
+                    match inner {
+                        Target::S3 => {
+                            let inner: S3Backend = $opts.s3_config.clone().try_into()?;
+                            target.push(DedupTarget::new(
+                                inner,
+                                $opts.dedup_config.clone(),
+                                $opts.s3_config.s3_buffer_path.clone(),
+                            )?);
+                        }
+                        Target::File => {
+                            let inner: FileBackend = $opts.file_config.clone().into();
+                            target.push(DedupTarget::new(
+                                inner,
+                                $opts.dedup_config.clone(),
+                                $opts.file_config.file_buffer_path.clone(),
+                            )?);
+                        }
+                        Target::Ipfs => {
+                            let inner: IpfsBackend = $opts.ipfs_config.clone().into();
+                            publishers.push(inner.clone());
+                            target.push(inner);
+                        }
+                        Target::HttpPut => {
+                            let inner: HttpPutBackend = $opts.http_put_config.clone().into();
+                            target.push(inner);
+                        }
+                        Target::Cas => {
+                            let inner = $opts
+                                .cas_config
+                                .clone()
+                                .into_backend($opts.file_config.clone());
+                            target.push(inner);
+                        }
+                        Target::MirrorIntel => {
+                            return Err(Error::ConfigureError(
+                                "mirror-intel target can't be combined with other targets"
+                                    .to_string(),
+                            ));
+                        }
+                        Target::Multi(_) => {
+                            return Err(Error::ConfigureError(
+                                "nested multi target is not supported".to_string(),
+                            ));
+                        }
+                    }
+                }
+                let source = wrap_source(
+                    &$opts.filter_config,
+                    &$opts.priority_config,
+                    &$opts.retry_config,
+                    $source,
+                    $pipes,
+                )?;
                 let transfer = SimpleDiffTransfer::new(source, target, $transfer_config);
-                transfer.transfer().await.unwrap();
+                transfer.transfer().await?;
+                for publisher in publishers {
+                    publisher
+                        .publish(&utils::create_logger($opts.verbose))
+                        .await?;
+                }
             }
         }
     };
@@ -119,8 +300,82 @@ const HLS_URL: &str = "https://github.com/haskell/haskell-language-server";
 const STACK_URL: &str = "https://github.com/commercialhaskell/stack";
 const HASKELL_URL: &str = "https://downloads.haskell.org";
 
+/// Rewrite haskell.org and GitHub URLs in ghcup configs to the mirror.
+fn ghcup_rewrite_fn(target_mirror: String) -> impl Fn(String) -> Result<String> + Send + Sync {
+    move |src: String| -> Result<String> {
+        Ok(src
+            .replace(
+                HASKELL_URL,
+                Path::new(&target_mirror).join("packages").to_str().unwrap(),
+            )
+            .replace(
+                STACK_URL,
+                Path::new(&target_mirror).join("stack").to_str().unwrap(),
+            )
+            .replace(
+                HLS_URL,
+                Path::new(&target_mirror).join("hls").to_str().unwrap(),
+            ))
+    }
+}
+
+/// Wrap `source` with pipes given on command line. Objects are filtered
+/// before `pipes`, so that files generated by pipes (e.g. indexes) only list
+/// selected objects. Priorities and retries are applied on top.
+fn wrap_source<Source, Piped>(
+    filter: &FilterConfig,
+    priority: &PriorityConfig,
+    retry: &RetryConfig,
+    source: Source,
+    pipes: impl FnOnce(Filtered<Source>) -> Result<Piped>,
+) -> Result<RetryPipe<PriorityPipe<Piped>>> {
+    let source = filter.clone().into_pipe(source)?;
+    let source = priority.clone().into_pipe(pipes(source)?)?;
+    Ok(retry.clone().into_pipe(source))
+}
+
+/// Download objects of `source`, and generate indexes for them.
+fn index_bytes<Snapshot, Source>(
+    pipeline: &PipelineBuilder,
+    source: Source,
+    use_snapshot_last_modified: bool,
+) -> Result<BoxedSource<Snapshot>>
+where
+    Snapshot: Key + Metadata,
+    Source: Pipe<Snapshot, TransferURL>,
+    IndexPipe<BoxedSource<Snapshot>>: Pipe<Snapshot, ByteStream>,
+{
+    pipeline
+        .bytes(source, use_snapshot_last_modified)
+        .configurable(vec![Index { max_depth: 999 }])
+        .build()
+}
+
+/// Download objects of `source`, verify their checksums, and generate
+/// indexes for them.
+fn index_checksum_bytes<Snapshot, Source>(
+    pipeline: &PipelineBuilder,
+    source: Source,
+    use_snapshot_last_modified: bool,
+) -> Result<BoxedSource<Snapshot>>
+where
+    Snapshot: Key + Metadata,
+    Source: Pipe<Snapshot, TransferURL>,
+    IndexPipe<BoxedSource<Snapshot>>: Pipe<Snapshot, ByteStream>,
+{
+    pipeline
+        .bytes(source, use_snapshot_last_modified)
+        .configurable(vec![Checksum, Index { max_depth: 999 }])
+        .build()
+}
+
 fn main() {
-    let opts: opts::Opts = opts::Opts::from_args();
+    let mut opts: opts::Opts = opts::Opts::from_args();
+
+    // metadata missing on S3 can only be found by scanning it
+    if opts.transfer_config.backfill_metadata {
+        opts.s3_config.s3_scan_metadata = true;
+    }
 
     // create runtime
     let mut runtime = tokio::runtime::Builder::new_multi_thread();
@@ -137,15 +392,43 @@ fn main() {
     };
     let transfer_config = simple_diff_transfer::SimpleDiffTransferConfig {
         progress: opts.progress,
+        verbose: opts.verbose,
         concurrent_transfer: opts.transfer_config.concurrent_transfer,
+        concurrent_delete: opts.transfer_config.concurrent_transfer,
         no_delete: opts.transfer_config.no_delete,
         print_plan: opts.transfer_config.print_plan,
         dry_run: opts.transfer_config.dry_run,
         force_all: opts.transfer_config.force_all,
+        update_metadata: opts.transfer_config.update_metadata,
+        backfill_metadata: opts.transfer_config.backfill_metadata,
+        trim_to_space: opts.transfer_config.trim_to_space,
+        accounting_report: opts.transfer_config.accounting_report.clone(),
+        circuit_breaker_threshold: opts.transfer_config.circuit_breaker_threshold,
+        circuit_breaker_cooldown: std::time::Duration::from_secs(
+            opts.transfer_config.circuit_breaker_cooldown,
+        ),
+        max_bandwidth: opts.transfer_config.max_bandwidth,
+        direct_stream: None,
+        range: stream_pipe::RangeConfig {
+            resume: opts.transfer_config.range_resume,
+            parallel: opts.transfer_config.range_parallel,
+            parallel_min_size: opts.transfer_config.range_parallel_min_size,
+        },
+        validator_cache: opts.transfer_config.validator_cache.clone(),
         snapshot_config,
+        delete_filter: None,
+        keep_filter: None,
     };
 
-    runtime.block_on(async {
+    let result: Result<()> = runtime.block_on(async {
+        // objects left out by filters are missing in source, but not expired
+        let transfer_config = simple_diff_transfer::SimpleDiffTransferConfig {
+            keep_filter: opts
+                .filter_config
+                .keep_filter()?
+                .map(|keep| opts.dedup_config.keep_filter(keep)),
+            ..transfer_config
+        };
         let buffer_path = opts
             .s3_config
             .s3_buffer_path
@@ -156,232 +439,632 @@ fn main() {
             .s3_prefix
             .clone()
             .or_else(|| Some(String::from("Root")));
+        let pipeline = PipelineBuilder {
+            buffer_path: buffer_path.clone(),
+            prefix,
+            pipes: opts.pipeline_config.load()?,
+            index_filenames: opts.index_config.index_filename.clone(),
+        };
         match opts.source {
-            Source::Pypi(source) => {
+            Source::Pypi(config) => {
+                let mut cache_config = opts.snapshot_cache_config.clone();
+                if config.simple_index {
+                    // simple index pages are generated from projects found in snapshot
+                    cache_config.snapshot_cache = None;
+                }
+                let source = cache_config.into_pipe(pypi::Pypi::new(config));
                 let pipe = |source| {
-                    stream_pipe::ByteStreamPipe::new(source, buffer_path.clone().unwrap(), false)
+                    let bytestream =
+                        stream_pipe::ByteStreamPipe::new(source, pipeline.buffer_path()?, true);
+                    pipeline
+                        .stream(pypi::SimpleIndexPipe::new(
+                            bytestream,
+                            pipeline.buffer_path()?,
+                        ))
+                        .configurable(vec![Checksum])
+                        .build()
                 };
                 transfer!(opts, source, transfer_config, pipe);
             }
             Source::Homebrew(config) => {
                 let source = Homebrew::new(config);
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
+                let pipe = |source| {
+                    let bytestream =
+                        stream_pipe::ByteStreamPipe::new(source, pipeline.buffer_path()?, false);
+                    let api = homebrew::ApiPipe::new(bytestream, pipeline.buffer_path()?);
+                    pipeline
+                        .stream(api)
+                        .configurable(vec![Checksum, Index { max_depth: 999 }])
+                        .build()
+                };
+                transfer!(opts, source, transfer_config, pipe);
             }
             Source::CratesIo(source) => {
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
             }
             Source::Conda(config) => {
+                let compress = if config.compress_repodata {
+                    vec![compress_pipe::Compression::Bzip2]
+                } else {
+                    vec![]
+                };
                 let source = conda::Conda::new(config);
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_checksum_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
+                let pipe = |source| {
+                    let bytestream =
+                        stream_pipe::ByteStreamPipe::new(source, pipeline.buffer_path()?, false);
+                    let repodata_run =
+                        conda::RepodataRunPipe::new(bytestream, pipeline.buffer_path()?);
+                    let compressed = compress_pipe::CompressPipe::new(
+                        repodata_run,
+                        pipeline.buffer_path()?,
+                        regex::Regex::new(r"(^|/)repodata\.json$").unwrap(),
+                        compress,
+                    );
+                    pipeline
+                        .stream(compressed)
+                        .configurable(vec![Checksum, Index { max_depth: 999 }])
+                        .build()
+                };
+                transfer!(opts, source, transfer_config, pipe);
             }
             Source::Rsync(source) => {
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
+                // rsync doesn't provide checksums
+                if source.fetch_over_rsync {
+                    let fetch = rsync::RsyncFetch::new(source, pipeline.buffer_path()?);
+                    transfer!(opts, fetch, transfer_config, |source| {
+                        pipeline
+                            .stream(source)
+                            .configurable(vec![AugmentChecksum, Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .configurable(vec![AugmentChecksum, Index { max_depth: 999 }])
+                            .build()
+                    });
+                }
             }
             Source::GithubRelease(source) => {
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, true, 999)
-                );
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, true)
+                });
             }
             Source::DartPub(source) => {
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
-            }
-            Source::Gradle(source) => {
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
+                let source = opts.snapshot_cache_config.clone().into_pipe(source);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Gradle(config) => {
+                let source = gradle::Gradle::new(config);
+                let pipe = |source| {
+                    let bytestream =
+                        stream_pipe::ByteStreamPipe::new(source, pipeline.buffer_path()?, false);
+                    let versions = gradle::VersionsPipe::new(bytestream, pipeline.buffer_path()?);
+                    pipeline
+                        .stream(versions)
+                        .configurable(vec![Checksum, Index { max_depth: 999 }])
+                        .build()
+                };
+                transfer!(opts, source, transfer_config, pipe);
             }
             Source::Ghcup(source) => {
                 let target_mirror = source.target_mirror.clone();
 
-                let script_src = rewrite_pipe::RewritePipe::new(
-                    stream_pipe::ByteStreamPipe::new(
-                        source.get_script(),
-                        buffer_path.clone().expect("buffer path is not present"),
-                        false,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    utils::fn_regex_rewrite(
-                        &HASKELL_PATTERN,
-                        Path::new(&target_mirror)
-                            .join("packages")
-                            .to_str()
-                            .unwrap()
-                            .to_string(),
-                    ),
-                    999999,
-                );
-
-                let yaml_rewrite_fn = move |src: String| -> Result<String> {
-                    Ok(src
-                        .replace(
-                            HASKELL_URL,
-                            Path::new(&target_mirror).join("packages").to_str().unwrap(),
-                        )
-                        .replace(
-                            STACK_URL,
-                            Path::new(&target_mirror).join("stack").to_str().unwrap(),
-                        )
-                        .replace(
-                            HLS_URL,
-                            Path::new(&target_mirror).join("hls").to_str().unwrap(),
-                        ))
-                };
-                let yaml_legacy_src = rewrite_pipe::RewritePipe::new(
-                    stream_pipe::ByteStreamPipe::new(
-                        source.get_yaml(true),
-                        buffer_path.clone().unwrap(),
+                let script_src = pipeline
+                    .bytes(source.get_script(), false)
+                    .rewrite(
+                        utils::fn_regex_rewrite(
+                            &HASKELL_PATTERN,
+                            Path::new(&target_mirror)
+                                .join("packages")
+                                .to_str()
+                                .unwrap()
+                                .to_string(),
+                        ),
+                        999999,
+                        None,
+                    )
+                    .build()?;
+                let yaml_legacy_src = pipeline
+                    .bytes(source.get_yaml(true), true)
+                    .rewrite(ghcup_rewrite_fn(target_mirror.clone()), 999999, None)
+                    .build()?;
+                let stack_setup_src = pipeline
+                    .bytes(source.get_stack_setup(), false)
+                    .rewrite(ghcup_rewrite_fn(target_mirror), 999999, None)
+                    .build()?;
+                let yaml_src = pipeline.bytes(source.get_yaml(false), true).build()?;
+                let packages_src = pipeline.bytes(source.get_packages(), false).build()?;
+                let stack_src = pipeline
+                    .bytes(
+                        GitHubRelease::new(
+                            String::from("commercialhaskell/stack"),
+                            source.retain_stack_versions,
+                        ),
                         true,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    yaml_rewrite_fn,
-                    999999,
-                );
-
-                let yaml_src = stream_pipe::ByteStreamPipe::new(
-                    source.get_yaml(false),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-
-                let packages_src = stream_pipe::ByteStreamPipe::new(
-                    source.get_packages(),
-                    buffer_path.clone().unwrap(),
-                    false,
-                );
-                let stack_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("commercialhaskell/stack"),
-                        source.retain_stack_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-                let hls_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("haskell/haskell-language-server"),
-                        source.retain_hls_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-
-                let unified = merge_pipe! {
-                    packages: packages_src,
-                    hls: hls_src,
-                    stack: stack_src,
-                    yaml: yaml_legacy_src,
-                    yaml_v2: yaml_src,
-                    script: script_src,
-                };
-
-                let indexed = index_pipe::IndexPipe::new(
-                    unified,
-                    buffer_path.clone().unwrap(),
-                    prefix.clone().unwrap(),
-                    999,
-                );
+                    )
+                    .build()?;
+                let hls_src = pipeline
+                    .bytes(
+                        GitHubRelease::new(
+                            String::from("haskell/haskell-language-server"),
+                            source.retain_hls_versions,
+                        ),
+                        true,
+                    )
+                    .build()?;
 
-                transfer!(opts, indexed, transfer_config, id_pipe!());
+                let unified = MergePipe::from_vec(vec![
+                    ("packages".to_string(), packages_src),
+                    ("hls".to_string(), hls_src),
+                    ("stack".to_string(), stack_src),
+                    ("yaml".to_string(), yaml_legacy_src),
+                    ("yaml_v2".to_string(), yaml_src),
+                    ("script".to_string(), script_src),
+                    ("stack_setup".to_string(), stack_setup_src),
+                ])
+                .mode(opts.merge_config.merge_mode);
+                transfer!(opts, unified, transfer_config, |source| {
+                    pipeline
+                        .stream(source)
+                        .configurable(vec![Index { max_depth: 999 }])
+                        .build()
+                });
             }
             Source::Rustup(source) => {
-                transfer!(
-                    opts,
-                    source,
-                    transfer_config,
-                    index_bytes_pipe!(buffer_path, prefix, false, 999)
-                );
+                let transfer_config = if source.gc_expired {
+                    simple_diff_transfer::SimpleDiffTransferConfig {
+                        no_delete: false,
+                        delete_filter: Some(simple_diff_transfer::DeleteFilter(
+                            std::sync::Arc::new(source.expired_filter()),
+                        )),
+                        ..transfer_config
+                    }
+                } else {
+                    transfer_config
+                };
+                if let Some(target_mirror) = source.target_mirror.clone() {
+                    let target_mirror = format!("{}/", target_mirror.trim_end_matches('/'));
+                    let manifest_rewrite_fn = move |src: String| -> Result<String> {
+                        Ok(src.replace(rustup::DIST_BASE, &target_mirror))
+                    };
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .rewrite(
+                                manifest_rewrite_fn,
+                                // channel manifests are about 1 MiB, skip reading large archives
+                                16 << 20,
+                                Some(regex::Regex::new(r"\.toml$").unwrap()),
+                            )
+                            .try_pipe(|source| {
+                                Ok(rustup::ManifestChecksumPipe::new(
+                                    source,
+                                    pipeline.buffer_path()?,
+                                ))
+                            })
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_bytes(&pipeline, source, false)
+                    });
+                }
             }
             Source::Elan(source) => {
-                let elan_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("leanprover/elan"),
-                        source.retain_elan_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-                let glean_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("alissa-tung/glean"),
-                        source.retain_glean_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-                let lean_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("leanprover/lean4"),
-                        source.retain_lean_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-                let lean_nightly_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("leanprover/lean4-nightly"),
-                        source.retain_lean_nightly_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-                let proofwidgets_src = stream_pipe::ByteStreamPipe::new(
-                    GitHubRelease::new(
-                        String::from("leanprover-community/ProofWidgets4"),
-                        source.retain_proofwidgets_versions,
-                    ),
-                    buffer_path.clone().unwrap(),
-                    true,
-                );
-                let lean_org_repo_src = merge_pipe! {
-                    lean4: lean_src,
-                    lean4_nightly: lean_nightly_src,
+                let github_release = |repo: &str, retain_versions| {
+                    pipeline
+                        .bytes(GitHubRelease::new(repo.to_string(), retain_versions), true)
+                        .build()
                 };
-                let unified = merge_pipe! {
-                    elan: elan_src,
-                    leanprover: lean_org_repo_src,
-                    glean: glean_src,
-                    proofwidgets: proofwidgets_src,
+                let elan_src = github_release("leanprover/elan", source.retain_elan_versions)?;
+                let glean_src = github_release("alissa-tung/glean", source.retain_glean_versions)?;
+                let lean_src = github_release("leanprover/lean4", source.retain_lean_versions)?;
+                let lean_nightly_src = github_release(
+                    "leanprover/lean4-nightly",
+                    source.retain_lean_nightly_versions,
+                )?;
+                let proofwidgets_src = github_release(
+                    "leanprover-community/ProofWidgets4",
+                    source.retain_proofwidgets_versions,
+                )?;
+                let release_src = pipeline
+                    .bytes(source.get_release(), false)
+                    .rewrite(
+                        lean::elan::rewrite_fn(source.target_mirror.clone()),
+                        16 << 20,
+                        None,
+                    )
+                    .build()?;
+                let lean_org_repo_src = MergePipe::from_vec(vec![
+                    ("lean4".to_string(), lean_src),
+                    ("lean4_nightly".to_string(), lean_nightly_src),
+                ])
+                .mode(opts.merge_config.merge_mode);
+                let unified = MergePipe::from_vec(vec![
+                    ("elan".to_string(), elan_src),
+                    ("leanprover".to_string(), boxed(lean_org_repo_src)),
+                    ("glean".to_string(), glean_src),
+                    ("proofwidgets".to_string(), proofwidgets_src),
+                    ("release".to_string(), release_src),
+                ])
+                .mode(opts.merge_config.merge_mode);
+                transfer!(opts, unified, transfer_config, |source| {
+                    pipeline
+                        .stream(source)
+                        .configurable(vec![Index { max_depth: 999 }])
+                        .build()
+                });
+            }
+            Source::Hexpm(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Luarocks(source) => {
+                if let Some(target_mirror) = source.target_mirror.clone() {
+                    let base = source.base.clone();
+                    let manifest_rewrite_fn = move |src: String| -> Result<String> {
+                        Ok(src.replace(&base, &target_mirror))
+                    };
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .rewrite(
+                                manifest_rewrite_fn,
+                                u64::MAX,
+                                // zipped manifests are not rewritten
+                                Some(regex::Regex::new(r"^manifest(-\d\.\d)?$").unwrap()),
+                            )
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_bytes(&pipeline, source, false)
+                    });
+                }
+            }
+            Source::Julia(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Helm(config) => {
+                let source = helm::Helm::new(config.clone());
+                if let Some(target_mirror) = config.target_mirror {
+                    let base = config.base;
+                    let index_rewrite_fn = move |src: String| -> Result<String> {
+                        helm::rewrite_index(&base, &target_mirror, src)
+                    };
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .checksum()
+                            .try_pipe(|source| {
+                                Ok(rewrite_pipe::RewritePipe::new(
+                                    source,
+                                    pipeline.buffer_path()?,
+                                    index_rewrite_fn,
+                                    u64::MAX,
+                                )
+                                .key_filter(|key| key == "index.yaml"))
+                            })
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_checksum_bytes(&pipeline, source, false)
+                    });
+                }
+            }
+            Source::DistroImage(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Terraform(config) => {
+                let source = terraform::Terraform::new(config);
+                let pipe = |source| {
+                    pipeline
+                        .bytes(source, false)
+                        .checksum()
+                        .try_pipe(|source| {
+                            Ok(terraform::NetworkMirrorPipe::new(
+                                source,
+                                pipeline.buffer_path()?,
+                            ))
+                        })
+                        .configurable(vec![Index { max_depth: 999 }])
+                        .build()
                 };
-                let indexed = index_pipe::IndexPipe::new(
-                    unified,
-                    buffer_path.clone().unwrap(),
-                    prefix.clone().unwrap(),
-                    999,
-                );
-
-                transfer!(opts, indexed, transfer_config, id_pipe!());
+                transfer!(opts, source, transfer_config, pipe);
+            }
+            Source::Vsx(config) => {
+                let source = vsx::Vsx::new(config);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, true)
+                });
+            }
+            Source::Jetbrains(config) => {
+                let source = jetbrains::Jetbrains::new(config);
+                let pipe = |source| {
+                    let bytestream =
+                        stream_pipe::ByteStreamPipe::new(source, pipeline.buffer_path()?, true);
+                    let update_plugins =
+                        jetbrains::UpdatePluginsPipe::new(bytestream, pipeline.buffer_path()?);
+                    pipeline
+                        .stream(update_plugins)
+                        .configurable(vec![Index { max_depth: 999 }])
+                        .build()
+                };
+                transfer!(opts, source, transfer_config, pipe);
+            }
+            Source::HuggingFace(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Kernel(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, true)
+                });
+            }
+            Source::Gnu(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, true)
+                });
             }
+            Source::Apache(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, true)
+                });
+            }
+            Source::HttpDir(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, true)
+                });
+            }
+            Source::Filelist(source) => {
+                let source = etag_pipe::EtagPipe::new(source);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, true)
+                });
+            }
+            Source::S3(config) => {
+                // S3 and file backends snapshot both paths and metadata, so
+                // metadata is pinned for targets taking either
+                let source: BoxedSource<SnapshotMeta> =
+                    boxed(config.into_backend(buffer_path.clone())?);
+                transfer!(opts, source, transfer_config, Ok);
+            }
+            Source::Local(source) => {
+                let source: BoxedSource<SnapshotMeta> = boxed(FileBackend {
+                    buffer_path: buffer_path.clone(),
+                    ..source
+                });
+                transfer!(opts, source, transfer_config, Ok);
+            }
+            Source::P2(source) => {
+                let source = etag_pipe::EtagPipe::new(source);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::GoDist(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Zig(config) => {
+                let source = zig::Zig::new(config.clone());
+                if let Some(target_mirror) = config.target_mirror {
+                    let base = config.base;
+                    let index_rewrite_fn = move |src: String| -> Result<String> {
+                        zig::rewrite_index(&base, &target_mirror, src)
+                    };
+                    // tarballs exceed the length limit, and are passed through without reading
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .checksum()
+                            .try_pipe(|source| {
+                                Ok(rewrite_pipe::RewritePipe::new(
+                                    source,
+                                    pipeline.buffer_path()?,
+                                    index_rewrite_fn,
+                                    16 << 20,
+                                )
+                                .key_filter(|key| key == zig::INDEX_KEY))
+                            })
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_checksum_bytes(&pipeline, source, false)
+                    });
+                }
+            }
+            Source::Flutter(source) => {
+                if let Some(target_mirror) = source.target_mirror.clone() {
+                    let releases_rewrite_fn = move |src: String| -> Result<String> {
+                        flutter::rewrite_releases(&target_mirror, src)
+                    };
+                    // SDK archives exceed the length limit, and are passed through without reading
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .checksum()
+                            .rewrite(
+                                releases_rewrite_fn,
+                                16 << 20,
+                                Some(regex::Regex::new(r"^releases_\w+\.json$").unwrap()),
+                            )
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_checksum_bytes(&pipeline, source, false)
+                    });
+                }
+            }
+            Source::Msys2(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Openwrt(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Raspbian(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Ros(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Conan(config) => {
+                let source = conan::Conan::new(config);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Opam(config) => {
+                let source = etag_pipe::EtagPipe::new(opam::Opam::new(config));
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Vcpkg(config) => {
+                let source = vcpkg::Vcpkg::new(config);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Bcr(config) => {
+                let source = bcr::Bcr::new(config.clone());
+                if let Some(target_mirror) = config.target_mirror {
+                    let source_rewrite_fn = move |src: String| -> Result<String> {
+                        bcr::rewrite_source(&target_mirror, src)
+                    };
+                    // archives exceed the length limit, and are passed through without reading
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .checksum()
+                            .rewrite(
+                                source_rewrite_fn,
+                                1 << 20,
+                                Some(regex::Regex::new(r"/source\.json$").unwrap()),
+                            )
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_checksum_bytes(&pipeline, source, false)
+                    });
+                }
+            }
+            Source::Gentoo(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::FreebsdPkg(source) => {
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Quicklisp(config) => {
+                let source = quicklisp::Quicklisp::new(config.clone());
+                if let Some(target_mirror) = config.target_mirror {
+                    let base = config.base;
+                    let dist_rewrite_fn = move |src: String| -> Result<String> {
+                        Ok(src.replace(&base, &target_mirror))
+                    };
+                    // release tarballs are not valid UTF-8, and are passed through
+                    transfer!(opts, source, transfer_config, |source| {
+                        pipeline
+                            .bytes(source, false)
+                            .checksum()
+                            .rewrite(dist_rewrite_fn, 16 << 20, None)
+                            .configurable(vec![Index { max_depth: 999 }])
+                            .build()
+                    });
+                } else {
+                    transfer!(opts, source, transfer_config, |source| {
+                        index_checksum_bytes(&pipeline, source, false)
+                    });
+                }
+            }
+            Source::Chocolatey(config) => {
+                let source = chocolatey::Chocolatey::new(config);
+                transfer!(opts, source, transfer_config, |source| {
+                    index_checksum_bytes(&pipeline, source, false)
+                });
+            }
+            Source::Git(config) => {
+                git::run(config, transfer_config).await?;
+            }
+            Source::Accounting(config) => {
+                accounting::summarize(config).await?;
+            }
+            Source::SelfTest(config) => {
+                self_test::run(config, transfer_config).await?;
+            }
+            Source::Undelete(config) => match opts.target_type {
+                Target::S3 => {
+                    let target: S3Backend = opts.s3_config.clone().try_into()?;
+                    let logger = utils::create_logger(opts.verbose);
+                    target
+                        .undelete(
+                            &logger,
+                            &config.report,
+                            &config.run_id,
+                            opts.transfer_config.dry_run,
+                        )
+                        .await?;
+                }
+                _ => {
+                    return Err(Error::ConfigureError(
+                        "undelete only supports S3 target".to_string(),
+                    ))
+                }
+            },
+            Source::PurgeTrash(config) => match opts.target_type {
+                Target::File => {
+                    let target: FileBackend = opts.file_config.clone().into();
+                    let logger = utils::create_logger(opts.verbose);
+                    target
+                        .purge_trash(&logger, config.keep_days, opts.transfer_config.dry_run)
+                        .await?;
+                }
+                _ => {
+                    return Err(Error::ConfigureError(
+                        "purge-trash only supports file target".to_string(),
+                    ))
+                }
+            },
         }
+        Ok(())
     });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
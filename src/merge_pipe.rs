@@ -5,117 +5,288 @@
 //! In such case, several different sources of distinct base urls can be
 //! implemented, and they should be unified by `MergePipe` later.
 //!
+//! By default, keys of each source are prefixed with its name, so that
+//! sources are put in their own directories. In union mode, keys are kept as
+//! is, and sources share the same directory. A key found in more than one
+//! source fails the snapshot, as objects of the same key can't be told apart.
+//!
 //! # Example
 //! ```ignore
-//! merge_pipe! {
-//!     metadata: MetaSource,
-//!     pkg: PackageSource,
-//! }
+//! MergePipe::from_vec(vec![
+//!     ("metadata".to_string(), boxed(MetaSource)),
+//!     ("pkg".to_string(), boxed(PackageSource)),
+//! ])
+//! ```
+
+use std::collections::HashMap;
 
 use async_trait::async_trait;
 use slog::info;
+use structopt::StructOpt;
 
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
+use crate::pipeline::BoxedSource;
 use crate::traits::{Key, SnapshotStorage, SourceStorage};
 
-/// Generate MergePipe from a list of sources.
-macro_rules! merge_pipe {
-    ($name:ident: $source:expr, $($tt: tt)+) => {
-        crate::merge_pipe::MergePipe::new(stringify!($name), $source, merge_pipe!($($tt)+))
-    };
-    ($name:ident: $source:expr $(,)?) => {
-        crate::merge_pipe::MergePipe::new(stringify!($name), $source, crate::merge_pipe::NilPipe)
-    };
+/// How keys of merged sources are laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeMode {
+    /// keys of each source are put under its name
+    Prefix,
+    /// keys are kept as is
+    Union,
 }
 
-pub struct MergePipe<Source1, Source2> {
-    prefix: String,
-    s1: Source1,
-    s2: Source2,
+impl std::str::FromStr for MergeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "prefix" => Ok(Self::Prefix),
+            "union" => Ok(Self::Union),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported merge mode {}, expect prefix or union",
+                s
+            ))),
+        }
+    }
 }
 
-impl<Source1, Source2> MergePipe<Source1, Source2> {
-    pub fn new(prefix: &str, s1: Source1, s2: Source2) -> Self {
-        let prefix = if prefix.ends_with('/') {
-            prefix.to_string()
-        } else {
-            format!("{}/", prefix)
-        };
-        Self { prefix, s1, s2 }
+#[derive(StructOpt, Debug, Clone)]
+pub struct MergeConfig {
+    #[structopt(
+        long,
+        help = "Merge sources of ghcup and elan by `prefix` (a directory for each source) or `union` (keys kept as is, duplicated keys rejected)",
+        default_value = "prefix"
+    )]
+    pub merge_mode: MergeMode,
+}
+
+pub struct MergePipe<Snapshot, Item> {
+    /// sources along with their prefixes (ending with `/`)
+    sources: Vec<(String, BoxedSource<Snapshot, Item>)>,
+    mode: MergeMode,
+    /// index of source providing each key, only used in union mode
+    owners: HashMap<String, usize>,
+}
+
+/// Merge snapshots of sources as is, recording the source of every key.
+/// Keys found in more than one source are rejected.
+fn merge_union<Snapshot: Key>(
+    snapshots: Vec<Vec<Snapshot>>,
+    names: &[&str],
+) -> Result<(Vec<Snapshot>, HashMap<String, usize>)> {
+    let mut merged = vec![];
+    let mut owners = HashMap::new();
+    for (idx, snapshot) in snapshots.into_iter().enumerate() {
+        for item in snapshot {
+            if let Some(owner) = owners.insert(item.key().to_string(), idx) {
+                return Err(Error::PipeError(format!(
+                    "duplicated key {} in source {} and {}",
+                    item.key(),
+                    names[owner],
+                    names[idx]
+                )));
+            }
+            merged.push(item);
+        }
+    }
+    Ok((merged, owners))
+}
+
+impl<Snapshot, Item> MergePipe<Snapshot, Item> {
+    /// Merge `sources`, prefixing keys of each source with its name.
+    pub fn from_vec(sources: Vec<(String, BoxedSource<Snapshot, Item>)>) -> Self {
+        let sources = sources
+            .into_iter()
+            .map(|(name, source)| {
+                let prefix = if name.ends_with('/') {
+                    name
+                } else {
+                    format!("{}/", name)
+                };
+                (prefix, source)
+            })
+            .collect();
+        Self {
+            sources,
+            mode: MergeMode::Prefix,
+            owners: HashMap::new(),
+        }
+    }
+
+    pub fn mode(mut self, mode: MergeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Find the source of `key`, along with the key in that source.
+    fn route<'a>(&self, key: &'a str) -> Option<(usize, &'a str)> {
+        if self.mode == MergeMode::Union {
+            return self.owners.get(key).map(|idx| (*idx, key));
+        }
+        self.sources
+            .iter()
+            .enumerate()
+            .find_map(|(idx, (prefix, _))| key.strip_prefix(prefix.as_str()).map(|key| (idx, key)))
     }
 }
 
 #[async_trait]
-impl<Source1, Source2, SnapshotItem> SnapshotStorage<SnapshotItem> for MergePipe<Source1, Source2>
+impl<Snapshot, Item> SnapshotStorage<Snapshot> for MergePipe<Snapshot, Item>
 where
-    SnapshotItem: Key + Clone,
-    Source1: SnapshotStorage<SnapshotItem> + Send + 'static,
-    Source2: SnapshotStorage<SnapshotItem> + Send + 'static,
+    Snapshot: Key + Clone,
+    Item: Send + Sync + 'static,
 {
     async fn snapshot(
         &mut self,
         mission: Mission,
         config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotItem>> {
+    ) -> Result<Vec<Snapshot>> {
         let logger = mission.logger.clone();
+        let mut snapshots = vec![];
 
-        info!(logger, "merge_pipe: snapshotting {}", self.prefix);
-        let mut snapshot1 = self.s1.snapshot(mission.clone(), config).await?;
-        snapshot1.iter_mut().for_each(|item| {
-            *item.key_mut() = format!("{}{}", self.prefix, item.key());
-        });
+        for (_, source) in self.sources.iter_mut() {
+            info!(logger, "merge_pipe: snapshotting {}", source.info());
+            snapshots.push(source.snapshot(mission.clone(), config).await?);
+        }
 
-        let mut snapshot2 = self.s2.snapshot(mission.clone(), config).await?;
+        if self.mode == MergeMode::Union {
+            let names: Vec<_> = self
+                .sources
+                .iter()
+                .map(|(prefix, _)| prefix.trim_end_matches('/'))
+                .collect();
+            let (merged, owners) = merge_union(snapshots, &names)?;
+            self.owners = owners;
+            return Ok(merged);
+        }
 
-        snapshot1.append(&mut snapshot2);
+        Ok(self
+            .sources
+            .iter()
+            .zip(snapshots)
+            .flat_map(|((prefix, _), snapshot)| {
+                snapshot.into_iter().map(move |mut item| {
+                    *item.key_mut() = format!("{}{}", prefix, item.key());
+                    item
+                })
+            })
+            .collect())
+    }
 
-        Ok(snapshot1)
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        let mut total = 0;
+        for (_, source) in &self.sources {
+            total += source.estimate(mission).await?;
+        }
+        Some(total)
     }
 
     fn info(&self) -> String {
-        format!("MergePipe (<{}>, <{}>)", self.s1.info(), self.s2.info())
+        let sources: Vec<_> = self
+            .sources
+            .iter()
+            .map(|(_, source)| format!("<{}>", source.info()))
+            .collect();
+        let mode = match self.mode {
+            MergeMode::Prefix => "prefix",
+            MergeMode::Union => "union",
+        };
+        format!("MergePipe {} ({})", mode, sources.join(", "))
     }
 }
 
 #[async_trait]
-impl<Source1, Source2, Source, SnapshotItem> SourceStorage<SnapshotItem, Source>
-    for MergePipe<Source1, Source2>
+impl<Snapshot, Item> SourceStorage<Snapshot, Item> for MergePipe<Snapshot, Item>
 where
-    SnapshotItem: Key + Clone,
-    Source: Send + Sync + 'static,
-    Source1: SourceStorage<SnapshotItem, Source> + Send + 'static,
-    Source2: SourceStorage<SnapshotItem, Source> + Send + 'static,
+    Snapshot: Key + Clone,
+    Item: Send + Sync + 'static,
 {
-    async fn get_object(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<Source> {
-        let path = snapshot.key();
-
-        if let Some(key) = path.strip_prefix(&self.prefix) {
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Item> {
+        let (idx, key) = self
+            .route(snapshot.key())
+            .ok_or_else(|| Error::PipeError(format!("no source found for {}", snapshot.key())))?;
+        let source = &self.sources[idx].1;
+        if key == snapshot.key() {
+            source.get_object(snapshot, mission).await
+        } else {
             let mut snapshot = snapshot.clone();
             *snapshot.key_mut() = String::from(key);
-            self.s1.get_object(&snapshot, mission).await
-        } else {
-            self.s2.get_object(snapshot, mission).await
+            source.get_object(&snapshot, mission).await
         }
     }
 }
 
-pub struct NilPipe;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SnapshotPath;
 
-#[async_trait]
-impl<T> SnapshotStorage<T> for NilPipe {
-    async fn snapshot(&mut self, _: Mission, _: &SnapshotConfig) -> Result<Vec<T>> {
-        Ok(vec![])
+    struct Dummy;
+
+    #[async_trait]
+    impl SnapshotStorage<SnapshotPath> for Dummy {
+        async fn snapshot(&mut self, _: Mission, _: &SnapshotConfig) -> Result<Vec<SnapshotPath>> {
+            Ok(vec![])
+        }
+
+        fn info(&self) -> String {
+            String::from("dummy")
+        }
     }
 
-    fn info(&self) -> String {
-        String::from("nil")
+    #[async_trait]
+    impl SourceStorage<SnapshotPath, String> for Dummy {
+        async fn get_object(&self, snapshot: &SnapshotPath, _: &Mission) -> Result<String> {
+            Ok(snapshot.key().to_string())
+        }
     }
-}
 
-#[async_trait]
-impl<T: Sync, U> SourceStorage<T, U> for NilPipe {
-    async fn get_object(&self, _: &T, _: &Mission) -> Result<U> {
-        Err(Error::PipeError(String::from("unexpected prefix")))
+    fn sources(names: &[&str]) -> Vec<(String, BoxedSource<SnapshotPath, String>)> {
+        names
+            .iter()
+            .map(|name| {
+                let source: BoxedSource<SnapshotPath, String> = Box::new(Dummy);
+                (name.to_string(), source)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_route_prefix() {
+        let merged = MergePipe::from_vec(sources(&["packages", "hls/", "stack"]));
+        assert_eq!(merged.route("packages/ghc.tar.xz"), Some((0, "ghc.tar.xz")));
+        assert_eq!(
+            merged.route("hls/2.0/hls.tar.xz"),
+            Some((1, "2.0/hls.tar.xz"))
+        );
+        assert_eq!(merged.route("stack/"), Some((2, "")));
+        assert_eq!(merged.route("yaml/ghcup.yaml"), None);
+    }
+
+    #[test]
+    fn test_merge_union() {
+        let path = |key: &str| SnapshotPath::new(key.to_string());
+        let mut merged = MergePipe::from_vec(sources(&["a", "b"])).mode(MergeMode::Union);
+        let (snapshot, owners) = merge_union(
+            vec![vec![path("x"), path("y")], vec![path("z")]],
+            &["a", "b"],
+        )
+        .unwrap();
+        assert_eq!(snapshot.len(), 3);
+        merged.owners = owners;
+        assert_eq!(merged.route("z"), Some((1, "z")));
+        assert_eq!(merged.route("a/x"), None);
+
+        let err = merge_union(
+            vec![vec![path("x"), path("y")], vec![path("y")]],
+            &["a", "b"],
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("duplicated key y in source a and b"));
     }
 }
@@ -1,22 +1,31 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
 use crate::error::Result;
 use crate::traits::{Diff, Key, Metadata, SnapshotStorage};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SnapshotMetaFlag {
     pub force: bool,
     pub force_last: bool,
+    /// transfer after all other objects, only if none of them failed
+    #[serde(default)]
+    pub after_all: bool,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct SnapshotMeta {
     pub key: String,
     pub size: Option<u64>,
     pub last_modified: Option<u64>,
     pub checksum_method: Option<String>,
     pub checksum: Option<String>,
+    pub content_type: Option<String>,
+    /// ETag of object on S3, or expected ETag of source objects
+    pub etag: Option<String>,
+    /// priority of transfer, overriding `force_last`
+    pub priority: Option<isize>,
     pub flags: SnapshotMetaFlag,
 }
 
@@ -33,6 +42,7 @@ impl SnapshotMeta {
             flags: SnapshotMetaFlag {
                 force: true,
                 force_last: true,
+                after_all: false,
             },
             ..Default::default()
         }
@@ -59,6 +69,10 @@ impl<Source: SnapshotStorage<SnapshotMeta> + std::fmt::Debug + std::marker::Send
             .collect())
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!("as snapshot path, {:?}", self.source)
     }
@@ -81,6 +95,15 @@ fn compare_option<T: Eq>(a: &Option<T>, b: &Option<T>) -> bool {
     }
 }
 
+/// ETags of multipart uploads (ending with `-{parts}`) depend on part size,
+/// so they are only compared with each other.
+fn compare_etag(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) if a.contains('-') == b.contains('-') => a.eq_ignore_ascii_case(b),
+        _ => true,
+    }
+}
+
 impl Diff for SnapshotMeta {
     fn diff(&self, other: &Self) -> bool {
         if !compare_option(&self.size, &other.size) {
@@ -95,22 +118,61 @@ impl Diff for SnapshotMeta {
         if !compare_option(&self.checksum, &other.checksum) {
             return true;
         }
+        if !compare_etag(&self.etag, &other.etag) {
+            return true;
+        }
         if self.flags.force || other.flags.force {
             return true;
         }
         false
     }
+
+    fn diff_metadata(&self, other: &Self) -> bool {
+        if self.flags.force || other.flags.force {
+            return false;
+        }
+        // Without size on both sides, we can't tell whether content is the same.
+        if self.size.is_none() || self.size != other.size {
+            return false;
+        }
+        // Neither could we with size only, content has to match by checksum
+        // or ETag, or by modified time recorded on both sides (e.g.
+        // `clone-last-modified` of S3 objects uploaded without checksum),
+        // which is what `diff` relies on without checksum.
+        let same_checksum = self.checksum.is_some()
+            && self.checksum == other.checksum
+            && self.checksum_method == other.checksum_method;
+        let same_etag = matches!(
+            (&self.etag, &other.etag),
+            (Some(a), Some(b)) if a.eq_ignore_ascii_case(b)
+        );
+        let same_modified =
+            self.last_modified.is_some() && self.last_modified == other.last_modified;
+        if !same_checksum && !same_etag && !same_modified {
+            return false;
+        }
+        self.missing_metadata(other)
+    }
+
+    fn missing_metadata(&self, other: &Self) -> bool {
+        (self.checksum.is_some() && other.checksum.is_none())
+            || (self.last_modified.is_some() && other.last_modified.is_none())
+    }
 }
 
 impl Metadata for SnapshotMeta {
     fn priority(&self) -> isize {
-        if self.flags.force_last {
-            -1
-        } else {
-            0
+        match self.priority {
+            Some(priority) => priority,
+            None if self.flags.force_last => -1,
+            None => 0,
         }
     }
 
+    fn after_all(&self) -> bool {
+        self.flags.after_all
+    }
+
     fn last_modified(&self) -> Option<u64> {
         self.last_modified
     }
@@ -122,4 +184,109 @@ impl Metadata for SnapshotMeta {
     fn checksum_method(&self) -> Option<&str> {
         self.checksum_method.as_deref()
     }
+
+    fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_etag() {
+        let meta = |etag: &str| SnapshotMeta {
+            key: "a".to_string(),
+            size: Some(10),
+            etag: Some(etag.to_string()),
+            ..Default::default()
+        };
+        assert!(!meta("ABCD").diff(&meta("abcd")));
+        assert!(meta("abcd").diff(&meta("abce")));
+        assert!(!meta("abcd").diff(&meta("0123-2")));
+        assert!(meta("0123-2").diff(&meta("0124-2")));
+        assert!(!meta("abcd").diff(&SnapshotMeta::new("a".to_string())));
+    }
+
+    #[test]
+    fn test_diff_metadata() {
+        let source = SnapshotMeta {
+            key: "a".to_string(),
+            size: Some(10),
+            checksum_method: Some("sha256".to_string()),
+            checksum: Some("abc".to_string()),
+            ..Default::default()
+        };
+        let target = SnapshotMeta {
+            key: "a".to_string(),
+            size: Some(10),
+            ..Default::default()
+        };
+        // same size doesn't tell content is the same
+        assert!(!source.diff(&target));
+        assert!(!source.diff_metadata(&target));
+        assert!(source.missing_metadata(&target));
+
+        let source = SnapshotMeta {
+            etag: Some("0cc175b9c0f1b6a831c399e269772661".to_string()),
+            ..source
+        };
+        let target = SnapshotMeta {
+            etag: Some("0CC175B9C0F1B6A831C399E269772661".to_string()),
+            ..target
+        };
+        assert!(!source.diff(&target));
+        assert!(source.diff_metadata(&target));
+        assert!(!target.diff_metadata(&source));
+        assert!(!source.diff_metadata(&source));
+
+        let resized = SnapshotMeta {
+            size: Some(11),
+            ..target.clone()
+        };
+        assert!(!source.diff_metadata(&resized));
+
+        let unsized_target = SnapshotMeta {
+            size: None,
+            ..target
+        };
+        assert!(!source.diff_metadata(&unsized_target));
+    }
+
+    #[test]
+    fn test_diff_metadata_s3() {
+        // sha256 of source can't be compared with MD5 ETag of S3 object
+        let source = SnapshotMeta {
+            key: "a.tar.gz".to_string(),
+            size: Some(10),
+            last_modified: Some(1600000000),
+            checksum_method: Some("sha256".to_string()),
+            checksum: Some("abc".to_string()),
+            ..Default::default()
+        };
+        let target = SnapshotMeta {
+            key: "a.tar.gz".to_string(),
+            size: Some(10),
+            last_modified: Some(1600000000),
+            etag: Some("0cc175b9c0f1b6a831c399e269772661".to_string()),
+            ..Default::default()
+        };
+        // but content is the same by size and `clone-last-modified`
+        assert!(!source.diff(&target));
+        assert!(source.diff_metadata(&target));
+
+        // without `clone-last-modified`, it's only missing
+        let target = SnapshotMeta {
+            last_modified: None,
+            ..target
+        };
+        assert!(!source.diff(&target));
+        assert!(!source.diff_metadata(&target));
+        assert!(source.missing_metadata(&target));
+    }
 }
@@ -0,0 +1,229 @@
+//! mirror-intel target
+//!
+//! [mirror-intel](https://github.com/sjtug/mirror-intel) is a caching proxy
+//! in front of S3. A request to `{base}/{key}` is redirected to storage if
+//! the object is cached, otherwise it is redirected to upstream and the
+//! object is queued to be downloaded by intel. `MirrorIntel` target warms
+//! the cache by requesting every key of source.
+//!
+//! Objects are not fetched by mirror-clone. `PathPipe` only yields keys of
+//! source, so indexes and rewritten files produced by other pipes are not
+//! available with this target. Intel can't be listed, so snapshot of this
+//! target is empty, and nothing is deleted.
+//!
+//! Intel drops tasks when its queue is full. At most `queue_limit` objects
+//! are waited for at a time: an object is polled every `poll_interval`
+//! until it is redirected to `storage`, or `queue_timeout` has passed.
+
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use slog::{debug, info};
+use tokio::sync::Semaphore;
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{Key, SnapshotStorage, SourceStorage, TargetStorage};
+
+#[derive(Debug, Clone)]
+pub struct MirrorIntelConfig {
+    pub base: String,
+    /// prefix of URLs which cached objects are redirected to
+    pub storage: String,
+    pub queue_limit: usize,
+    pub poll_interval: Duration,
+    pub queue_timeout: Duration,
+}
+
+pub struct MirrorIntel {
+    config: MirrorIntelConfig,
+    client: reqwest::Client,
+    queue: Semaphore,
+}
+
+fn intel_url(base: &str, key: &str) -> String {
+    let path: Vec<_> = key
+        .split('/')
+        .map(|segment| urlencoding::encode(segment).to_string())
+        .collect();
+    format!("{}/{}", base.trim_end_matches('/'), path.join("/"))
+}
+
+impl MirrorIntel {
+    pub fn new(config: MirrorIntelConfig) -> Self {
+        Self {
+            queue: Semaphore::new(config.queue_limit),
+            config,
+            // redirects tell whether objects are cached
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// Request `url` from intel, returning whether it is cached. Objects not
+    /// cached are queued by intel on request.
+    async fn request(&self, url: &str) -> Result<bool> {
+        let resp = self.client.head(url).send().await?;
+        let status = resp.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(Error::HTTPError(status));
+        }
+        Ok(status.is_redirection()
+            && resp
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .is_some_and(|location| location.starts_with(&self.config.storage)))
+    }
+}
+
+impl std::fmt::Debug for MirrorIntel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for MirrorIntel {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        info!(
+            mission.logger,
+            "mirror-intel can't be listed, requesting all objects"
+        );
+        mission.progress.finish_with_message("done");
+        Ok(vec![])
+    }
+
+    fn info(&self) -> String {
+        format!("mirror-intel (meta), {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotPath> for MirrorIntel {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        Ok(
+            <Self as SnapshotStorage<SnapshotMeta>>::snapshot(self, mission, config)
+                .await?
+                .into_iter()
+                .map(|x| SnapshotPath::new(x.key))
+                .collect(),
+        )
+    }
+
+    fn info(&self) -> String {
+        format!("mirror-intel (path), {:?}", self.config)
+    }
+}
+
+#[async_trait]
+impl<Snapshot: Key> TargetStorage<Snapshot, TransferPath> for MirrorIntel {
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        item: TransferPath,
+        mission: &Mission,
+    ) -> Result<()> {
+        let url = intel_url(&self.config.base, &item.0);
+        let _permit = self.queue.acquire().await.unwrap();
+        let deadline = Instant::now() + self.config.queue_timeout;
+        if self.request(&url).await? {
+            debug!(mission.logger, "cached: {}", snapshot.key());
+            return Ok(());
+        }
+        debug!(mission.logger, "queued: {}", snapshot.key());
+        loop {
+            if Instant::now() >= deadline {
+                return Err(Error::StorageError(format!(
+                    "{} not cached by mirror-intel in {:?}",
+                    snapshot.key(),
+                    self.config.queue_timeout
+                )));
+            }
+            tokio::time::sleep(self.config.poll_interval).await;
+            if self.request(&url).await? {
+                debug!(mission.logger, "cached: {}", snapshot.key());
+                return Ok(());
+            }
+        }
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+        Err(Error::StorageError(format!(
+            "can't delete {} from mirror-intel",
+            snapshot.key()
+        )))
+    }
+}
+
+/// PathPipe yields keys of source items, without fetching them.
+pub struct PathPipe<Source> {
+    pub source: Source,
+}
+
+impl<Source> PathPipe<Source> {
+    pub fn new(source: Source) -> Self {
+        Self { source }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for PathPipe<Source>
+where
+    Snapshot: Send + 'static,
+    Source: SnapshotStorage<Snapshot>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("path <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SourceStorage<Snapshot, TransferPath> for PathPipe<Source>
+where
+    Snapshot: Key,
+    Source: Send + Sync + 'static,
+{
+    async fn get_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<TransferPath> {
+        Ok(TransferPath(snapshot.key().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intel_url() {
+        assert_eq!(
+            intel_url(
+                "https://mirrors.example.com/crates.io/",
+                "cr/at/crate #1.crate"
+            ),
+            "https://mirrors.example.com/crates.io/cr/at/crate%20%231.crate"
+        );
+    }
+}
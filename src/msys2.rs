@@ -0,0 +1,140 @@
+//! MSYS2 source
+//!
+//! MSYS2 source reads pacman database of each configured repository, and
+//! yields packages with sizes and sha256 checksums, together with their
+//! signatures. Repositories are laid out as upstream, i.e. `msys/x86_64` for
+//! MSYS packages, and `mingw/{env}` (e.g. `mingw/ucrt64`) for each MinGW
+//! environment, whose database is named after the environment.
+//!
+//! Database files of every repository are transferred at the end.
+
+use async_trait::async_trait;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::Result;
+use crate::fetch::{exists, fetch};
+use crate::metadata::SnapshotMeta;
+use crate::pacman::parse_db;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Msys2 {
+    #[structopt(
+        long,
+        default_value = "https://repo.msys2.org",
+        help = "Base of MSYS2 repositories"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        default_value = "msys/x86_64,mingw/mingw64,mingw/ucrt64,mingw/clang64",
+        use_delimiter = true,
+        help = "Repositories to mirror"
+    )]
+    pub repos: Vec<String>,
+}
+
+/// Suffixes of database files, which are mirrored as-is if present.
+const DB_SUFFIXES: &[&str] = &[
+    "db",
+    "db.sig",
+    "db.tar.zst",
+    "db.tar.zst.sig",
+    "files",
+    "files.sig",
+    "files.tar.zst",
+    "files.tar.zst.sig",
+];
+
+/// Database name of a repository, e.g. `msys` of `msys/x86_64`, and `ucrt64`
+/// of `mingw/ucrt64`.
+fn db_name(repo: &str) -> &str {
+    match repo.split('/').next() {
+        Some("msys") => "msys",
+        _ => repo.rsplit('/').next().unwrap_or(repo),
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Msys2 {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut snapshot = vec![];
+        let mut db_files = vec![];
+        for repo in &self.repos {
+            let repo = repo.trim_matches('/');
+            let name = db_name(repo);
+            let url = format!("{}/{}", self.base, repo);
+            info!(logger, "fetching {}/{}.db...", repo, name);
+            progress.set_message(repo);
+            let data = fetch(&client, &format!("{}/{}.db", url, name)).await?;
+            let packages = parse_db(&data)?;
+            let total_size: u64 = packages.iter().filter_map(|package| package.csize).sum();
+            info!(
+                logger,
+                "{}: {} packages ({})",
+                repo,
+                packages.len(),
+                human_size(total_size)
+            );
+
+            for package in packages {
+                let key = format!("{}/{}", repo, package.filename);
+                snapshot.push(SnapshotMeta::new(format!("{}.sig", key)));
+                snapshot.push(SnapshotMeta {
+                    key,
+                    size: package.csize,
+                    checksum_method: package.sha256.as_ref().map(|_| "sha256".to_string()),
+                    checksum: package.sha256,
+                    ..Default::default()
+                });
+            }
+
+            for suffix in DB_SUFFIXES {
+                let file = format!("{}.{}", name, suffix);
+                if exists(&client, &format!("{}/{}", url, file)).await? {
+                    db_files.push(format!("{}/{}", repo, file));
+                }
+            }
+        }
+        snapshot.extend(db_files.into_iter().map(SnapshotMeta::force));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("msys2, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Msys2 {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_name() {
+        assert_eq!(db_name("msys/x86_64"), "msys");
+        assert_eq!(db_name("msys/i686"), "msys");
+        assert_eq!(db_name("mingw/ucrt64"), "ucrt64");
+        assert_eq!(db_name("mingw/clang64"), "clang64");
+    }
+}
@@ -0,0 +1,361 @@
+//! Multi-target replication
+//!
+//! `MultiTarget` is a target storage which replicates objects to several inner
+//! targets (e.g. S3 and file), so that one source snapshot feeds all of them
+//! in a single run. It is selected by joining target types with `+`, e.g.
+//! `--target-type s3+file`.
+//!
+//! Snapshots of inner targets are merged. An object present in all targets
+//! with the same metadata is taken as is. Otherwise, it is marked as forced,
+//! so that it is transferred again to every target if it is in source, or
+//! deleted from targets having it if it isn't.
+//!
+//! Every object is put to all targets concurrently. Failures are reported per
+//! target, and `--multi-target-policy` decides the result of a transfer:
+//! `all` fails it if any target fails, while `any` only fails it if all
+//! targets fail.
+
+use std::collections::{BTreeMap, HashSet};
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use slog::warn;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Diff, Key, SnapshotStorage, TargetStorage};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailurePolicy {
+    All,
+    Any,
+}
+
+impl std::str::FromStr for FailurePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(Self::All),
+            "any" => Ok(Self::Any),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported multi-target policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct MultiTargetConfig {
+    #[structopt(
+        long,
+        help = "With multiple targets, succeed when `all` or `any` of them succeed",
+        default_value = "all"
+    )]
+    pub multi_target_policy: FailurePolicy,
+}
+
+/// Snapshot items which could be marked as forced, so that they are always
+/// considered different.
+pub trait Force {
+    fn set_force(&mut self);
+}
+
+impl Force for SnapshotMeta {
+    fn set_force(&mut self) {
+        self.flags.force = true;
+    }
+}
+
+impl Force for SnapshotPath {
+    fn set_force(&mut self) {
+        self.1 = true;
+    }
+}
+
+/// A target which could be replicated to.
+pub trait ReplicaTarget<Snapshot>:
+    TargetStorage<Snapshot, ByteStream> + SnapshotStorage<Snapshot>
+{
+}
+
+impl<Snapshot, Target> ReplicaTarget<Snapshot> for Target where
+    Target: TargetStorage<Snapshot, ByteStream> + SnapshotStorage<Snapshot>
+{
+}
+
+pub struct MultiTarget<Snapshot> {
+    targets: Vec<Box<dyn ReplicaTarget<Snapshot>>>,
+    policy: FailurePolicy,
+    /// keys in snapshot of each target
+    present: Vec<HashSet<String>>,
+}
+
+impl<Snapshot: 'static> MultiTarget<Snapshot> {
+    pub fn new(config: MultiTargetConfig) -> Self {
+        Self {
+            targets: vec![],
+            policy: config.multi_target_policy,
+            present: vec![],
+        }
+    }
+
+    pub fn push(&mut self, target: impl ReplicaTarget<Snapshot>) {
+        self.targets.push(Box::new(target));
+    }
+
+    /// Combine results of all targets by policy, logging failed ones.
+    fn check(
+        &self,
+        op: &str,
+        key: &str,
+        results: Vec<Result<()>>,
+        mission: &Mission,
+    ) -> Result<()> {
+        let total = results.len();
+        let errors: Vec<String> = results
+            .into_iter()
+            .zip(self.targets.iter())
+            .filter_map(|(result, target)| {
+                result
+                    .err()
+                    .map(|err| format!("{}: {:?}", target.info(), err))
+            })
+            .collect();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        for error in &errors {
+            warn!(mission.logger, "failed to {} {} on {}", op, key, error);
+        }
+        if self.policy == FailurePolicy::Any && errors.len() < total {
+            return Ok(());
+        }
+        Err(Error::StorageError(format!(
+            "failed to {} {} on {}/{} targets: {}",
+            op,
+            key,
+            errors.len(),
+            total,
+            errors.join("; ")
+        )))
+    }
+}
+
+/// Merge snapshots of targets. Objects missing in some targets, or different
+/// among targets, are marked as forced.
+fn merge_snapshots<Snapshot: Key + Diff + Force>(snapshots: Vec<Vec<Snapshot>>) -> Vec<Snapshot> {
+    let total = snapshots.len();
+    let mut merged: BTreeMap<String, (Snapshot, usize, bool)> = BTreeMap::new();
+    for snapshot in snapshots {
+        for item in snapshot {
+            match merged.get_mut(item.key()) {
+                Some((first, count, consistent)) => {
+                    *count += 1;
+                    if first.diff(&item) {
+                        *consistent = false;
+                    }
+                }
+                None => {
+                    merged.insert(item.key().to_string(), (item, 1, true));
+                }
+            }
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(_, (mut item, count, consistent))| {
+            if count < total || !consistent {
+                item.set_force();
+            }
+            item
+        })
+        .collect()
+}
+
+/// Remove buffer file at `path` taken over from the object of `key`.
+async fn remove_buffer(path: &std::path::Path, key: &str, mission: &Mission) {
+    if let Err(err) = tokio::fs::remove_file(path).await {
+        warn!(
+            mission.logger,
+            "failed to remove buffer of {}: {:?}", key, err
+        );
+    }
+}
+
+/// Make a replica of buffer file at `path` for the `idx`-th target.
+async fn replicate(path: &std::path::Path, idx: usize, mission: &Mission) -> Result<ByteObject> {
+    let replica = std::path::PathBuf::from(format!("{}.{}", path.display(), idx));
+    if tokio::fs::hard_link(path, &replica).await.is_err() {
+        tokio::fs::copy(path, &replica).await?;
+    }
+    // the replica is removed on errors
+    let mut object = ByteObject::LocalFile {
+        file: None,
+        path: Some(replica.clone()),
+    };
+    match tokio::fs::File::open(&replica).await {
+        Ok(file) => {
+            if let ByteObject::LocalFile {
+                file: object_file, ..
+            } = &mut object
+            {
+                *object_file = Some(file);
+            }
+            Ok(object)
+        }
+        Err(err) => {
+            object.discard(&mission.logger, &replica.display().to_string());
+            Err(err.into())
+        }
+    }
+}
+
+#[async_trait]
+impl<Snapshot> SnapshotStorage<Snapshot> for MultiTarget<Snapshot>
+where
+    Snapshot: Key + Diff + Force,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        let mut snapshots = vec![];
+        self.present.clear();
+        for target in self.targets.iter_mut() {
+            let snapshot = target.snapshot(mission.clone(), config).await?;
+            self.present
+                .push(snapshot.iter().map(|item| item.key().to_string()).collect());
+            snapshots.push(snapshot);
+        }
+        Ok(merge_snapshots(snapshots))
+    }
+
+    fn info(&self) -> String {
+        let targets: Vec<_> = self.targets.iter().map(|target| target.info()).collect();
+        format!("multi target <{}>", targets.join(", "))
+    }
+}
+
+#[async_trait]
+impl<Snapshot> TargetStorage<Snapshot, ByteStream> for MultiTarget<Snapshot>
+where
+    Snapshot: Key + Diff + Force,
+{
+    async fn put_object(
+        &self,
+        snapshot: &Snapshot,
+        byte_stream: ByteStream,
+        mission: &Mission,
+    ) -> Result<()> {
+        let ByteStream {
+            object,
+            length,
+            modified_at,
+            content_type,
+            checksum,
+            etag,
+        } = byte_stream;
+        let path = object.use_file()?;
+
+        let mut replicas = vec![];
+        for idx in 0..self.targets.len() {
+            match replicate(&path, idx, mission).await {
+                Ok(replica) => replicas.push(ByteStream {
+                    object: replica,
+                    length,
+                    modified_at,
+                    content_type: content_type.clone(),
+                    checksum: checksum.clone(),
+                    etag: etag.clone(),
+                }),
+                Err(err) => {
+                    for replica in replicas {
+                        replica.object.discard(&mission.logger, snapshot.key());
+                    }
+                    remove_buffer(&path, snapshot.key(), mission).await;
+                    return Err(err);
+                }
+            }
+        }
+        remove_buffer(&path, snapshot.key(), mission).await;
+
+        let results = join_all(
+            self.targets
+                .iter()
+                .zip(replicas)
+                .map(|(target, replica)| target.put_object(snapshot, replica, mission)),
+        )
+        .await;
+        self.check("put", snapshot.key(), results, mission)
+    }
+
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        let results = join_all(
+            self.targets
+                .iter()
+                .enumerate()
+                .map(|(idx, target)| async move {
+                    // only delete from targets having the object, if snapshot is taken
+                    match self.present.get(idx) {
+                        Some(present) if !present.contains(snapshot.key()) => Ok(()),
+                        _ => target.delete_object(snapshot, mission).await,
+                    }
+                }),
+        )
+        .await;
+        self.check("delete", snapshot.key(), results, mission)
+    }
+
+    async fn update_metadata(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        let results = join_all(
+            self.targets
+                .iter()
+                .map(|target| target.update_metadata(snapshot, mission)),
+        )
+        .await;
+        self.check("update metadata of", snapshot.key(), results, mission)
+    }
+
+    /// Space of the most limited target.
+    async fn available_space(&self) -> Result<Option<u64>> {
+        let mut available: Option<u64> = None;
+        for target in &self.targets {
+            if let Some(space) = target.available_space().await? {
+                available = Some(available.map_or(space, |available| available.min(space)));
+            }
+        }
+        Ok(available)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_snapshots() {
+        let meta = |key: &str, size: u64| SnapshotMeta {
+            key: key.to_string(),
+            size: Some(size),
+            ..Default::default()
+        };
+        let merged = merge_snapshots(vec![
+            vec![meta("a", 1), meta("b", 2), meta("c", 3)],
+            vec![meta("a", 1), meta("b", 4), meta("d", 5)],
+        ]);
+        let flags: Vec<_> = merged
+            .iter()
+            .map(|item| (item.key.as_str(), item.flags.force))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![("a", false), ("b", true), ("c", true), ("d", true)]
+        );
+    }
+}
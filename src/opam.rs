@@ -0,0 +1,230 @@
+//! opam source
+//!
+//! opam source mirrors an opam repository served over HTTP, together with its
+//! archive cache. The repository index (`index.tar.gz`) is downloaded, and
+//! checksums of package archives are collected from `url` and `extra-source`
+//! sections of every `opam` file in it.
+//!
+//! Every archive is placed in the cache at `cache/<algo>/<xx>/<hash>`, once
+//! for each of its checksums, which is the layout used by `archive-mirrors`
+//! of opam. By default, archives are fetched from the cache of upstream
+//! repository. With `--upstream-sources`, they are fetched from the URLs
+//! given in `opam` files instead.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use lazy_static::lazy_static;
+use regex::Regex;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+lazy_static! {
+    static ref SECTION: Regex = Regex::new(r#"(?:url|extra-source\s+"[^"]*")\s*\{"#).unwrap();
+    static ref SRC: Regex = Regex::new(r#"(?:src|archive):\s*"([^"]*)""#).unwrap();
+    static ref CHECKSUM: Regex = Regex::new(r#""(md5|sha256|sha512)=([0-9a-fA-F]+)""#).unwrap();
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct OpamConfig {
+    #[structopt(long, default_value = "https://opam.ocaml.org")]
+    pub base: String,
+    #[structopt(long, help = "Fetch archives from their upstream instead of the cache")]
+    pub upstream_sources: bool,
+}
+
+pub struct Opam {
+    pub config: OpamConfig,
+    /// cache key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+/// An archive of a package, with its source URL and checksums as
+/// `(algo, hash)`.
+#[derive(Debug, PartialEq)]
+struct Archive {
+    src: String,
+    checksums: Vec<(String, String)>,
+}
+
+/// Parse archives in `url` and `extra-source` sections of an `opam` file.
+fn parse_opam(opam: &str) -> Vec<Archive> {
+    let mut archives = vec![];
+    for section in SECTION.find_iter(opam) {
+        let rest = &opam[section.end()..];
+        let body = match rest.find('}') {
+            Some(end) => &rest[..end],
+            None => continue,
+        };
+        let src = match SRC.captures(body) {
+            Some(capture) => capture[1].to_string(),
+            None => continue,
+        };
+        let checksums: Vec<_> = CHECKSUM
+            .captures_iter(body)
+            .map(|capture| (capture[1].to_string(), capture[2].to_lowercase()))
+            .collect();
+        if !checksums.is_empty() {
+            archives.push(Archive { src, checksums });
+        }
+    }
+    archives
+}
+
+fn cache_key(algo: &str, hash: &str) -> String {
+    format!("cache/{}/{}/{}", algo, &hash[..2], hash)
+}
+
+impl Opam {
+    pub fn new(config: OpamConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Opam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Opam {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching repository index...");
+        progress.set_message("fetching repository index...");
+        let data = fetch(&client, &format!("{}/index.tar.gz", self.config.base)).await?;
+
+        info!(logger, "parsing...");
+        let mut packages = 0;
+        let mut archives = vec![];
+        let mut tarball = tar::Archive::new(GzDecoder::new(&data[..]));
+        for entry in tarball.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            if !path.starts_with("packages/") || !path.ends_with("/opam") {
+                continue;
+            }
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            archives.extend(parse_opam(&content));
+            packages += 1;
+            progress.set_message(&format!("{} packages", packages));
+        }
+        info!(logger, "{} packages, {} archives", packages, archives.len());
+
+        let mut snapshot = vec![];
+        for archive in archives {
+            for (algo, hash) in archive.checksums {
+                if hash.len() < 2 {
+                    continue;
+                }
+                let key = cache_key(&algo, &hash);
+                if self.urls.contains_key(&key) {
+                    continue;
+                }
+                snapshot.push(SnapshotMeta {
+                    key: key.clone(),
+                    checksum_method: Some(algo),
+                    checksum: Some(hash),
+                    ..Default::default()
+                });
+                self.urls.insert(key, archive.src.clone());
+            }
+        }
+
+        snapshot.push(SnapshotMeta::force(String::from("repo")));
+        snapshot.push(SnapshotMeta::force(String::from("index.tar.gz")));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("opam, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Opam {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        if !self.config.upstream_sources || !snapshot.key.starts_with("cache/") {
+            return Ok(TransferURL(format!(
+                "{}/{}",
+                self.config.base, snapshot.key
+            )));
+        }
+        self.urls
+            .get(&snapshot.key)
+            .map(|url| TransferURL(url.clone()))
+            .ok_or_else(|| Error::ProcessError(format!("unknown key {}", snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_opam() {
+        let opam = r#"opam-version: "2.0"
+synopsis: "Example"
+url {
+  src: "https://example.com/foo-1.0.tar.gz"
+  checksum: [
+    "md5=0123456789ABCDEF0123456789abcdef"
+    "sha256=aa"
+  ]
+}
+extra-source "fix.patch" {
+  src: "https://example.com/fix.patch"
+  checksum: "sha512=bb"
+}
+extra-source "broken.patch" {
+  src: "https://example.com/broken.patch"
+}
+"#;
+        assert_eq!(
+            parse_opam(opam),
+            vec![
+                Archive {
+                    src: "https://example.com/foo-1.0.tar.gz".to_string(),
+                    checksums: vec![
+                        (
+                            "md5".to_string(),
+                            "0123456789abcdef0123456789abcdef".to_string()
+                        ),
+                        ("sha256".to_string(), "aa".to_string()),
+                    ],
+                },
+                Archive {
+                    src: "https://example.com/fix.patch".to_string(),
+                    checksums: vec![("sha512".to_string(), "bb".to_string())],
+                },
+            ]
+        );
+        assert_eq!(
+            cache_key("md5", "0123456789abcdef0123456789abcdef"),
+            "cache/md5/01/0123456789abcdef0123456789abcdef"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! OpenWrt source
+//!
+//! OpenWrt source mirrors configured releases of OpenWrt. Targets of a
+//! release and their package architectures are read from `.targets.json`.
+//! For every target, images are listed in `targets/{target}/sha256sums`,
+//! and target packages in `targets/{target}/packages/Packages.gz`. For every
+//! architecture, packages of each feed are listed in
+//! `packages/{arch}/{feed}/Packages.gz`. All files are yielded with sha256
+//! checksums.
+//!
+//! Package indexes and checksum files are transferred at the end. Kernel
+//! module feeds (`targets/{target}/kmods`) are not mirrored.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::Client;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::apt::parse_packages;
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::distro_image::parse_sums;
+use crate::error::{Error, Result};
+use crate::fetch::{exists, fetch_json, fetch_optional};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{bar, human_size};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct OpenWrt {
+    #[structopt(
+        long,
+        default_value = "https://downloads.openwrt.org/releases",
+        help = "Base of OpenWrt releases"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "Releases to mirror, e.g. 23.05.2",
+        required = true,
+        min_values = 1
+    )]
+    pub releases: Vec<String>,
+    #[structopt(
+        long,
+        default_value = "base,luci,packages,routing,telephony",
+        use_delimiter = true,
+        help = "Package feeds to mirror"
+    )]
+    pub feeds: Vec<String>,
+}
+
+/// Files of a package index, which are transferred at the end.
+const INDEX_FILES: &[&str] = &[
+    "Packages",
+    "Packages.gz",
+    "Packages.manifest",
+    "Packages.sig",
+];
+
+/// Signatures of `sha256sums` that may be published along with it.
+const SUMS_SIGNATURES: &[&str] = &["sha256sums.asc", "sha256sums.sig"];
+
+/// A directory with an index of files.
+enum IndexDir {
+    /// image directory of a target, with `sha256sums`
+    Target(String),
+    /// package directory, with `Packages.gz`
+    Packages(String),
+}
+
+impl IndexDir {
+    fn path(&self) -> &str {
+        match self {
+            IndexDir::Target(dir) | IndexDir::Packages(dir) => dir,
+        }
+    }
+}
+
+/// Generate snapshot of a directory from its index.
+async fn index_snapshot(client: &Client, base: &str, dir: &IndexDir) -> Result<Vec<SnapshotMeta>> {
+    let mut snapshot = vec![];
+    match dir {
+        IndexDir::Target(dir) => {
+            let data = match fetch_optional(client, &format!("{}/{}/sha256sums", base, dir)).await?
+            {
+                Some(data) => data,
+                None => return Ok(snapshot),
+            };
+            for (checksum, file) in parse_sums(&String::from_utf8_lossy(&data)) {
+                snapshot.push(SnapshotMeta {
+                    key: format!("{}/{}", dir, file),
+                    checksum_method: Some("sha256".to_string()),
+                    checksum: Some(checksum),
+                    ..Default::default()
+                });
+            }
+            for signature in SUMS_SIGNATURES {
+                if exists(client, &format!("{}/{}/{}", base, dir, signature)).await? {
+                    snapshot.push(SnapshotMeta::force(format!("{}/{}", dir, signature)));
+                }
+            }
+            snapshot.push(SnapshotMeta::force(format!("{}/sha256sums", dir)));
+        }
+        IndexDir::Packages(dir) => {
+            let data =
+                match fetch_optional(client, &format!("{}/{}/Packages.gz", base, dir)).await? {
+                    Some(data) => data,
+                    None => return Ok(snapshot),
+                };
+            let mut content = String::new();
+            GzDecoder::new(&data[..]).read_to_string(&mut content)?;
+            for mut package in parse_packages(&content) {
+                package.key = format!("{}/{}", dir, package.key);
+                snapshot.push(package);
+            }
+            snapshot.extend(
+                INDEX_FILES
+                    .iter()
+                    .map(|file| SnapshotMeta::force(format!("{}/{}", dir, file))),
+            );
+        }
+    }
+    Ok(snapshot)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for OpenWrt {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let mut dirs = vec![];
+        let mut metadata = vec![];
+        for release in &self.releases {
+            info!(logger, "fetching targets of {}...", release);
+            progress.set_message(release);
+            // target (e.g. `x86/64`) -> package architecture (e.g. `x86_64`)
+            let targets: BTreeMap<String, String> =
+                fetch_json(&client, &format!("{}/{}/.targets.json", self.base, release)).await?;
+            let archs: BTreeSet<&String> = targets.values().collect();
+            info!(
+                logger,
+                "{}: {} targets, {} architectures",
+                release,
+                targets.len(),
+                archs.len()
+            );
+            for arch in archs {
+                for feed in &self.feeds {
+                    dirs.push(IndexDir::Packages(format!(
+                        "{}/packages/{}/{}",
+                        release, arch, feed
+                    )));
+                }
+            }
+            for target in targets.keys() {
+                let dir = format!("{}/targets/{}", release, target);
+                dirs.push(IndexDir::Packages(format!("{}/packages", dir)));
+                dirs.push(IndexDir::Target(dir));
+            }
+            metadata.push(SnapshotMeta::force(format!("{}/.targets.json", release)));
+        }
+
+        info!(logger, "fetching {} indexes...", dirs.len());
+        progress.set_length(dirs.len() as u64);
+        progress.set_style(bar());
+        let snapshots: Vec<Vec<SnapshotMeta>> = stream::iter(dirs.into_iter().map(|dir| {
+            let client = client.clone();
+            let base = self.base.clone();
+            let logger = logger.clone();
+            let progress = progress.clone();
+            async move {
+                let result = match index_snapshot(&client, &base, &dir).await {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => {
+                        warn!(logger, "failed to fetch index of {}: {:?}", dir.path(), err);
+                        vec![]
+                    }
+                };
+                progress.inc(1);
+                Ok::<_, Error>(result)
+            }
+        }))
+        .buffer_unordered(config.concurrent_resolve)
+        .try_collect()
+        .await?;
+
+        // images of a target may also be listed as target packages
+        let mut keys = BTreeSet::new();
+        let mut snapshot: Vec<SnapshotMeta> = snapshots
+            .into_iter()
+            .flatten()
+            .filter(|meta| keys.insert(meta.key.clone()))
+            .collect();
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} files (at least {})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+        snapshot.extend(metadata);
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("openwrt, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for OpenWrt {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
@@ -1,19 +1,69 @@
+use crate::accounting::AccountingConfig;
+use crate::apache::Apache as ApacheConfig;
+use crate::bcr::BcrConfig;
+use crate::cas::{CasBackend, CasConfig, LinkMode};
+use crate::chocolatey::ChocolateyConfig;
+use crate::conan::ConanConfig;
 use crate::conda::CondaConfig;
+use crate::content_type::ContentTypes;
 use crate::crates_io::CratesIo as CratesIoConfig;
 use crate::dart::Dart;
-use crate::file_backend::FileBackend;
+use crate::dedup::DedupConfig;
+use crate::distro_image::DistroImage;
+use crate::file_backend::{CloneMode, FileBackend, LinkCache, Permissions};
+use crate::filelist::FileList;
+use crate::filter_pipe::FilterConfig;
+use crate::flutter::Flutter as FlutterConfig;
+use crate::freebsd_pkg::FreeBsdPkg as FreeBsdPkgConfig;
+use crate::gentoo::Gentoo as GentooConfig;
 use crate::ghcup::Ghcup as GhcupConfig;
+use crate::git::GitConfig;
 use crate::github_release::GitHubRelease;
-use crate::gradle::Gradle;
+use crate::gnu::Gnu as GnuConfig;
+use crate::godist::GoDist as GoDistConfig;
+use crate::gradle::GradleConfig;
+use crate::helm::HelmConfig;
+use crate::hexpm::Hexpm as HexpmConfig;
 use crate::homebrew::HomebrewConfig;
+use crate::html_scanner::HttpDir;
+use crate::http_put::{HttpPutBackend, HttpPutConfig, Listing};
+use crate::huggingface::HuggingFace;
+use crate::index_pipe::IndexConfig;
+use crate::ipfs::{IpfsBackend, IpfsConfig};
+use crate::jetbrains::JetbrainsConfig;
+use crate::julia::Julia as JuliaConfig;
+use crate::kernel::Kernel as KernelConfig;
 use crate::lean::elan::ElanConfig;
-use crate::pypi::Pypi as PypiConfig;
+use crate::luarocks::Luarocks as LuarocksConfig;
+use crate::merge_pipe::MergeConfig;
+use crate::mirror_intel::{MirrorIntel, MirrorIntelConfig};
+use crate::msys2::Msys2 as Msys2Config;
+use crate::multi_target::MultiTargetConfig;
+use crate::opam::OpamConfig;
+use crate::openwrt::OpenWrt as OpenWrtConfig;
+use crate::p2::P2 as P2Config;
+use crate::pipeline::PipelineConfig;
+use crate::priority_pipe::PriorityConfig;
+use crate::pypi::PypiConfig;
+use crate::quicklisp::QuicklispConfig;
+use crate::raspbian::Raspbian as RaspbianConfig;
+use crate::retry_pipe::RetryConfig;
+use crate::ros::Ros as RosConfig;
 use crate::rsync::Rsync as RsyncConfig;
 use crate::rustup::Rustup as RustupConfig;
+use crate::self_test::SelfTest as SelfTestConfig;
+use crate::snapshot_cache_pipe::SnapshotCacheConfig;
+use crate::terraform::TerraformConfig;
+use crate::vcpkg::VcpkgConfig;
+use crate::vsx::VsxConfig;
+use crate::zig::ZigConfig;
 use crate::{
     error::{Error, Result},
-    s3::S3Backend,
+    s3::{AddressingStyle, PrefixHint, S3Backend, S3Credentials},
+    s3_retry::RetryPolicy,
 };
+use std::convert::TryFrom;
+
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -35,23 +85,130 @@ pub enum Source {
     #[structopt(about = "ghcup")]
     Ghcup(GhcupConfig),
     #[structopt(about = "gradle")]
-    Gradle(Gradle),
+    Gradle(GradleConfig),
     #[structopt(about = "rustup")]
     Rustup(RustupConfig),
     #[structopt(about = "elan")]
     Elan(ElanConfig),
+    #[structopt(about = "hex.pm")]
+    Hexpm(HexpmConfig),
+    #[structopt(about = "LuaRocks")]
+    Luarocks(LuarocksConfig),
+    #[structopt(about = "Julia PkgServer")]
+    Julia(JuliaConfig),
+    #[structopt(about = "Helm chart repository")]
+    Helm(HelmConfig),
+    #[structopt(about = "Distribution images with SHA256SUMS (cloud images, ISOs)")]
+    DistroImage(DistroImage),
+    #[structopt(about = "Terraform provider registry")]
+    Terraform(TerraformConfig),
+    #[structopt(about = "Open VSX extension registry")]
+    Vsx(VsxConfig),
+    #[structopt(about = "JetBrains plugin repository")]
+    Jetbrains(JetbrainsConfig),
+    #[structopt(about = "Hugging Face Hub models and datasets")]
+    HuggingFace(HuggingFace),
+    #[structopt(about = "Linux kernel releases on kernel.org")]
+    Kernel(KernelConfig),
+    #[structopt(about = "GNU FTP mirror")]
+    Gnu(GnuConfig),
+    #[structopt(about = "Apache dist tree")]
+    Apache(ApacheConfig),
+    #[structopt(about = "Plain HTTP directory tree (nginx or Apache autoindex)")]
+    HttpDir(HttpDir),
+    #[structopt(about = "Files listed in a manifest")]
+    Filelist(FileList),
+    #[structopt(about = "S3-compatible bucket")]
+    S3(S3SourceConfig),
+    #[structopt(about = "Local directory")]
+    Local(FileBackend),
+    #[structopt(about = "Eclipse p2 update site")]
+    P2(P2Config),
+    #[structopt(about = "Zig downloads")]
+    Zig(ZigConfig),
+    #[structopt(about = "Go toolchain releases")]
+    GoDist(GoDistConfig),
+    #[structopt(about = "Flutter SDK releases")]
+    Flutter(FlutterConfig),
+    #[structopt(about = "MSYS2 repositories")]
+    Msys2(Msys2Config),
+    #[structopt(about = "OpenWrt releases")]
+    Openwrt(OpenWrtConfig),
+    #[structopt(about = "Raspberry Pi OS APT repository and images")]
+    Raspbian(RaspbianConfig),
+    #[structopt(about = "ROS APT repositories")]
+    Ros(RosConfig),
+    #[structopt(about = "Conan remote (e.g. ConanCenter)")]
+    Conan(ConanConfig),
+    #[structopt(about = "vcpkg asset cache")]
+    Vcpkg(VcpkgConfig),
+    #[structopt(about = "Bazel Central Registry")]
+    Bcr(BcrConfig),
+    #[structopt(about = "Gentoo distfiles")]
+    Gentoo(GentooConfig),
+    #[structopt(about = "FreeBSD pkg repositories")]
+    FreebsdPkg(FreeBsdPkgConfig),
+    #[structopt(about = "Quicklisp dist")]
+    Quicklisp(QuicklispConfig),
+    #[structopt(about = "Chocolatey repository")]
+    Chocolatey(ChocolateyConfig),
+    #[structopt(about = "opam repository and archive cache")]
+    Opam(OpamConfig),
+    #[structopt(about = "Maintain bare mirrors of git repositories in a local directory")]
+    Git(GitConfig),
+    #[structopt(about = "Print monthly summary of bandwidth accounting report")]
+    Accounting(AccountingConfig),
+    #[structopt(
+        about = "Mirror a bundled fixture repository to a temporary directory and verify it"
+    )]
+    SelfTest(SelfTestConfig),
+    #[structopt(about = "Restore objects deleted by a run (S3 with versioning)")]
+    Undelete(UndeleteConfig),
+    #[structopt(about = "Remove old files in trash of file backend")]
+    PurgeTrash(PurgeTrashConfig),
 }
 
 #[derive(Debug)]
 pub enum Target {
     S3,
     File,
+    Ipfs,
+    HttpPut,
+    Cas,
+    MirrorIntel,
+    /// replicate to all targets, e.g. `s3+file`
+    Multi(Vec<Target>),
+}
+
+/// Pick credentials of S3 requests from command line options.
+fn s3_credentials(
+    access_key: Option<String>,
+    secret_key: Option<String>,
+    profile: Option<String>,
+    anonymous: bool,
+) -> Result<S3Credentials> {
+    match (access_key, secret_key, profile) {
+        _ if anonymous => Ok(S3Credentials::Anonymous),
+        (Some(access_key), Some(secret_key), _) => Ok(S3Credentials::Static {
+            access_key,
+            secret_key,
+        }),
+        (None, None, Some(profile)) => Ok(S3Credentials::Profile(profile)),
+        (None, None, None) => Ok(S3Credentials::Default),
+        _ => Err(Error::ConfigureError(
+            "both access key and secret key should be given".to_string(),
+        )),
+    }
 }
 
-impl From<S3CliConfig> for S3Backend {
-    fn from(config: S3CliConfig) -> Self {
-        let mut s3_config =
-            crate::s3::S3Config::new_jcloud(config.s3_prefix.unwrap(), config.s3_scan_metadata);
+impl TryFrom<S3CliConfig> for S3Backend {
+    type Error = Error;
+
+    fn try_from(config: S3CliConfig) -> Result<Self> {
+        let prefix = config.s3_prefix.ok_or_else(|| {
+            Error::ConfigureError("--s3-prefix is required by S3 target".to_string())
+        })?;
+        let mut s3_config = crate::s3::S3Config::new_jcloud(prefix, config.s3_scan_metadata);
         if let Some(endpoint) = config.s3_endpoint {
             s3_config.endpoint = endpoint;
         }
@@ -59,14 +216,138 @@ impl From<S3CliConfig> for S3Backend {
             s3_config.bucket = bucket;
         }
         s3_config.max_keys = config.s3_max_keys;
-        s3_config.prefix_hint_mode = config.s3_prefix_hint_mode;
+        s3_config.prefix_hint = config.s3_prefix_hint;
+        // objects are read back by dedup
+        s3_config.buffer_path = config.s3_buffer_path;
+        s3_config.deletion_report = config.s3_deletion_report;
+        s3_config.storage_class = config.s3_storage_class;
+        s3_config.acl = config.s3_acl;
+        s3_config.sse = config.s3_sse;
+        s3_config.sse_kms_key_id = config.s3_sse_kms_key_id;
+        s3_config.content_types = ContentTypes::new(&config.s3_content_type)?;
+        s3_config.inventory = config.s3_inventory;
+        s3_config.scan_metadata_concurrency = config.s3_scan_metadata_concurrency;
+        s3_config.delete_batch_size = config.s3_delete_batch_size;
+        s3_config.retry = RetryPolicy {
+            retries: config.s3_retries,
+            base_delay: std::time::Duration::from_millis(config.s3_retry_delay),
+            max_delay: std::time::Duration::from_secs(config.s3_retry_max_delay),
+        };
+        s3_config.region = config.s3_region;
+        s3_config.addressing_style = config.s3_addressing_style;
+        s3_config.credentials = s3_credentials(
+            config.s3_access_key,
+            config.s3_secret_key,
+            config.s3_profile,
+            config.s3_anonymous,
+        )?;
+        S3Backend::new(s3_config)
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct S3SourceConfig {
+    #[structopt(long, help = "Endpoint of source bucket")]
+    pub endpoint: Option<String>,
+    #[structopt(long, help = "Source bucket")]
+    pub bucket: Option<String>,
+    #[structopt(long, help = "Prefix of objects in source bucket")]
+    pub prefix: String,
+    #[structopt(
+        long,
+        alias = "prefix-hint-mode",
+        help = "List sub-prefixes in parallel to accelerate scanning, among hex2, hex1, alnum, or a comma-separated list"
+    )]
+    pub prefix_hint: Option<PrefixHint>,
+    #[structopt(long, help = "Max keys to list at a time", default_value = "1000")]
+    pub max_keys: u64,
+    #[structopt(long, help = "Scan metadata (Greatly increase requests)")]
+    pub scan_metadata: bool,
+    #[structopt(
+        long,
+        env = "S3_SOURCE_REGION",
+        help = "Region name used for signing requests"
+    )]
+    pub region: Option<String>,
+    #[structopt(
+        long,
+        env = "S3_SOURCE_ADDRESSING_STYLE",
+        help = "Address bucket by `path` (endpoint/bucket/key) or `virtual-hosted` (bucket.endpoint/key)",
+        default_value = "path"
+    )]
+    pub addressing_style: AddressingStyle,
+    #[structopt(
+        long,
+        env = "S3_SOURCE_ACCESS_KEY",
+        help = "Access key of source bucket"
+    )]
+    pub access_key: Option<String>,
+    #[structopt(
+        long,
+        env = "S3_SOURCE_SECRET_KEY",
+        hide_env_values = true,
+        help = "Secret key of source bucket"
+    )]
+    pub secret_key: Option<String>,
+    #[structopt(
+        long,
+        env = "S3_SOURCE_PROFILE",
+        help = "Read credentials from this profile"
+    )]
+    pub profile: Option<String>,
+    #[structopt(long, help = "Access source bucket anonymously")]
+    pub anonymous: bool,
+    #[structopt(
+        long,
+        help = "Take snapshot from S3 Inventory, given as `bucket/key` of manifest or its prefix"
+    )]
+    pub inventory: Option<String>,
+}
+
+impl S3SourceConfig {
+    /// Create S3 backend of source bucket, buffering objects to `buffer_path`.
+    pub fn into_backend(self, buffer_path: Option<String>) -> Result<S3Backend> {
+        let mut s3_config = crate::s3::S3Config::new_jcloud(self.prefix, self.scan_metadata);
+        if let Some(endpoint) = self.endpoint {
+            s3_config.endpoint = endpoint;
+        }
+        if let Some(bucket) = self.bucket {
+            s3_config.bucket = bucket;
+        }
+        s3_config.max_keys = self.max_keys;
+        s3_config.prefix_hint = self.prefix_hint;
+        s3_config.buffer_path = buffer_path;
+        s3_config.inventory = self.inventory;
+        s3_config.region = self.region;
+        s3_config.addressing_style = self.addressing_style;
+        s3_config.credentials = s3_credentials(
+            self.access_key,
+            self.secret_key,
+            self.profile,
+            self.anonymous,
+        )?;
         S3Backend::new(s3_config)
     }
 }
 
 impl From<FileBackendConfig> for FileBackend {
     fn from(config: FileBackendConfig) -> Self {
-        FileBackend::new(config.file_base_path.unwrap())
+        let mut backend = FileBackend::new(config.file_base_path.unwrap());
+        let mode = config.file_link_mode;
+        backend.link_cache = config.file_link_cache.map(|path| LinkCache { path, mode });
+        backend.buffer_path = config.file_buffer_path;
+        backend.durable = config.file_durable;
+        backend.trash_path = config.file_trash_path;
+        backend.max_disk_usage = config.max_disk_usage;
+        backend.sidecar = config.file_sidecar_checksum;
+        let (uid, gid) = config.file_owner.unwrap_or_default();
+        backend.permissions = Permissions {
+            file_mode: config.file_mode,
+            dir_mode: config.file_dir_mode,
+            uid,
+            gid,
+        };
+        backend
     }
 }
 
@@ -80,12 +361,224 @@ pub struct S3CliConfig {
     pub s3_prefix: Option<String>,
     #[structopt(long, help = "Buffer data to this temporary directory")]
     pub s3_buffer_path: Option<String>,
-    #[structopt(long, help = "Prefix hint mode, to accelerate scanning")]
-    pub s3_prefix_hint_mode: Option<String>,
+    #[structopt(
+        long,
+        alias = "s3-prefix-hint-mode",
+        help = "List sub-prefixes in parallel to accelerate scanning, among hex2, hex1, alnum, or a comma-separated list"
+    )]
+    pub s3_prefix_hint: Option<PrefixHint>,
     #[structopt(long, help = "Max keys to list at a time", default_value = "1000")]
     pub s3_max_keys: u64,
     #[structopt(long, help = "Scan metadata (Greatly increase requests)")]
     pub s3_scan_metadata: bool,
+    #[structopt(
+        long,
+        help = "Record delete markers created on versioned bucket to this file"
+    )]
+    pub s3_deletion_report: Option<String>,
+    #[structopt(
+        long,
+        env = "S3_REGION",
+        help = "Region name used for signing requests"
+    )]
+    pub s3_region: Option<String>,
+    #[structopt(
+        long,
+        env = "S3_ADDRESSING_STYLE",
+        help = "Address bucket by `path` (endpoint/bucket/key) or `virtual-hosted` (bucket.endpoint/key)",
+        default_value = "path"
+    )]
+    pub s3_addressing_style: AddressingStyle,
+    #[structopt(long, env = "S3_ACCESS_KEY", help = "Access key of S3 backend")]
+    pub s3_access_key: Option<String>,
+    #[structopt(
+        long,
+        env = "S3_SECRET_KEY",
+        hide_env_values = true,
+        help = "Secret key of S3 backend"
+    )]
+    pub s3_secret_key: Option<String>,
+    #[structopt(long, env = "S3_PROFILE", help = "Read credentials from this profile")]
+    pub s3_profile: Option<String>,
+    #[structopt(long, help = "Access S3 backend anonymously")]
+    pub s3_anonymous: bool,
+    #[structopt(long, help = "Storage class of uploaded objects, e.g. STANDARD_IA")]
+    pub s3_storage_class: Option<String>,
+    #[structopt(long, help = "Canned ACL of uploaded objects, e.g. public-read")]
+    pub s3_acl: Option<String>,
+    #[structopt(
+        long,
+        help = "Server-side encryption of uploaded objects, AES256 or aws:kms"
+    )]
+    pub s3_sse: Option<String>,
+    #[structopt(long, help = "KMS key id for aws:kms server-side encryption")]
+    pub s3_sse_kms_key_id: Option<String>,
+    #[structopt(
+        long,
+        help = "Override content type of uploaded objects by extension, e.g. `log=text/plain`"
+    )]
+    pub s3_content_type: Vec<String>,
+    #[structopt(
+        long,
+        help = "Take snapshot from S3 Inventory, given as `bucket/key` of manifest or its prefix"
+    )]
+    pub s3_inventory: Option<String>,
+    #[structopt(
+        long,
+        help = "Concurrent requests when scanning metadata",
+        default_value = "64"
+    )]
+    pub s3_scan_metadata_concurrency: usize,
+    #[structopt(
+        long,
+        help = "Delete up to this many keys in one DeleteObjects request, 1 to delete one by one",
+        default_value = "1000"
+    )]
+    pub s3_delete_batch_size: usize,
+    #[structopt(
+        long,
+        help = "Part size of multipart uploads in bucket (e.g. 8388608 of aws-cli), to compute ETags of larger objects"
+    )]
+    pub s3_etag_part_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Retries of S3 requests failed with transient errors",
+        default_value = "5"
+    )]
+    pub s3_retries: u32,
+    #[structopt(
+        long,
+        help = "Milliseconds to wait before the first retry of S3 requests, doubled on every retry",
+        default_value = "500"
+    )]
+    pub s3_retry_delay: u64,
+    #[structopt(
+        long,
+        help = "Max seconds to wait between retries of S3 requests",
+        default_value = "30"
+    )]
+    pub s3_retry_max_delay: u64,
+    #[structopt(
+        long,
+        help = "Stream objects not smaller than this size (in bytes) to S3 without buffering on disk"
+    )]
+    pub s3_direct_stream: Option<u64>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct IpfsCliConfig {
+    #[structopt(
+        long,
+        help = "HTTP API of IPFS node",
+        default_value = "http://127.0.0.1:5001"
+    )]
+    pub ipfs_api: String,
+    #[structopt(
+        long,
+        help = "Root directory in MFS of IPFS node",
+        default_value = "/mirror"
+    )]
+    pub ipfs_root: String,
+    #[structopt(
+        long,
+        help = "Key to publish root directory with",
+        default_value = "self"
+    )]
+    pub ipfs_key: String,
+}
+
+impl From<IpfsCliConfig> for IpfsBackend {
+    fn from(config: IpfsCliConfig) -> Self {
+        IpfsBackend::new(IpfsConfig {
+            api: config.ipfs_api,
+            root: config.ipfs_root,
+            key: config.ipfs_key,
+        })
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct HttpPutCliConfig {
+    #[structopt(
+        long,
+        help = "Base URL to upload objects to",
+        required_if("target_type", "http-put")
+    )]
+    pub http_put_base: Option<String>,
+    #[structopt(
+        long,
+        help = "Header sent with every request, e.g. `Authorization: Bearer xxx`",
+        parse(try_from_str = crate::http_put::parse_header)
+    )]
+    pub http_put_header: Option<(String, String)>,
+    #[structopt(
+        long,
+        help = "Listing API of target, `artifactory` or `nexus`",
+        default_value = "artifactory"
+    )]
+    pub http_put_listing: Listing,
+    #[structopt(
+        long,
+        help = "URL of listing API",
+        required_if("target_type", "http-put")
+    )]
+    pub http_put_listing_url: Option<String>,
+}
+
+impl From<HttpPutCliConfig> for HttpPutBackend {
+    fn from(config: HttpPutCliConfig) -> Self {
+        HttpPutBackend::new(HttpPutConfig {
+            base: config.http_put_base.unwrap(),
+            header: config.http_put_header,
+            listing: config.http_put_listing,
+            listing_url: config.http_put_listing_url.unwrap(),
+        })
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct CasCliConfig {
+    #[structopt(
+        long,
+        help = "Content-addressed store of cas backend, defaults to `.cas` in base path"
+    )]
+    pub cas_path: Option<String>,
+    #[structopt(
+        long,
+        help = "Link keys to store with `hardlink` or `symlink`",
+        default_value = "hardlink"
+    )]
+    pub cas_link: LinkMode,
+    #[structopt(long, help = "Remove content no longer linked from store")]
+    pub cas_gc: bool,
+}
+
+impl CasCliConfig {
+    pub fn into_backend(self, file_config: FileBackendConfig) -> CasBackend {
+        let base_path = file_config.file_base_path.unwrap();
+        CasBackend::new(CasConfig {
+            cas_path: self
+                .cas_path
+                .unwrap_or_else(|| format!("{}/.cas", base_path.trim_end_matches('/'))),
+            base_path,
+            link: self.cas_link,
+            gc: self.cas_gc,
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct UndeleteConfig {
+    #[structopt(long, help = "Deletion report recorded by S3 backend")]
+    pub report: String,
+    #[structopt(long, help = "Run id of deletions to restore")]
+    pub run_id: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct PurgeTrashConfig {
+    #[structopt(long, help = "Keep trash of this many days", default_value = "7")]
+    pub keep_days: i64,
 }
 
 #[derive(StructOpt, Debug, Clone)]
@@ -93,29 +586,133 @@ pub struct FileBackendConfig {
     #[structopt(
         long,
         help = "Base path for file backend",
-        required_if("target_type", "file")
+        required_ifs(&[("target_type", "file"), ("target_type", "cas")])
     )]
     pub file_base_path: Option<String>,
     #[structopt(
         long,
         help = "Buffer path for file backend, should not be within base path",
-        required_if("target_type", "file")
+        required_ifs(&[("target_type", "file"), ("target_type", "cas")])
     )]
     pub file_buffer_path: Option<String>,
+    #[structopt(
+        long,
+        help = "Keep content of files in this cache (outside base path), and clone files with the same content from it"
+    )]
+    pub file_link_cache: Option<String>,
+    #[structopt(
+        long,
+        help = "Clone files from cache with `reflink` or `hardlink`",
+        default_value = "reflink"
+    )]
+    pub file_link_mode: CloneMode,
+    #[structopt(
+        long,
+        help = "Sync files to disk before renaming them into place, so that a crash never leaves truncated files"
+    )]
+    pub file_durable: bool,
+    #[structopt(
+        long,
+        help = "Move deleted files into dated directories here (outside base path) instead of removing them"
+    )]
+    pub file_trash_path: Option<String>,
+    #[structopt(
+        long,
+        help = "Mode of files put, in octal, e.g. 644",
+        parse(try_from_str = crate::file_backend::parse_mode)
+    )]
+    pub file_mode: Option<u32>,
+    #[structopt(
+        long,
+        help = "Mode of directories created, in octal, e.g. 755",
+        parse(try_from_str = crate::file_backend::parse_mode)
+    )]
+    pub file_dir_mode: Option<u32>,
+    #[structopt(
+        long,
+        help = "Owner of files and directories, as `user`, `user:group` or `:group`",
+        parse(try_from_str = crate::file_backend::parse_owner)
+    )]
+    pub file_owner: Option<(Option<u32>, Option<u32>)>,
+    #[structopt(
+        long,
+        help = "Max usage of file system of file backend, in percent, e.g. 95"
+    )]
+    pub max_disk_usage: Option<u64>,
+    #[structopt(
+        long,
+        help = "Write `.mirror-clone.sha256` checksum file next to every file"
+    )]
+    pub file_sidecar_checksum: bool,
 }
 
 impl std::str::FromStr for Target {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
+        if s.contains('+') {
+            return Ok(Self::Multi(
+                s.split('+').map(str::parse).collect::<Result<_>>()?,
+            ));
+        }
         match s {
             "s3" => Ok(Self::S3),
             "file" => Ok(Self::File),
+            "ipfs" => Ok(Self::Ipfs),
+            "http-put" => Ok(Self::HttpPut),
+            "cas" => Ok(Self::Cas),
+            "mirror-intel" => Ok(Self::MirrorIntel),
             _ => Err(Error::ConfigureError("unsupported target".to_string())),
         }
     }
 }
 
+#[derive(StructOpt, Debug, Clone)]
+pub struct MirrorIntelCliConfig {
+    #[structopt(
+        long,
+        help = "Base URL of repository on mirror-intel",
+        required_if("target_type", "mirror-intel")
+    )]
+    pub intel_base: Option<String>,
+    #[structopt(
+        long,
+        help = "Prefix of URLs which mirror-intel redirects cached objects to",
+        required_if("target_type", "mirror-intel")
+    )]
+    pub intel_storage: Option<String>,
+    #[structopt(
+        long,
+        help = "Objects queued on mirror-intel to wait for at a time",
+        default_value = "64"
+    )]
+    pub intel_queue_limit: usize,
+    #[structopt(
+        long,
+        help = "Seconds between checks of queued objects",
+        default_value = "10"
+    )]
+    pub intel_poll_interval: u64,
+    #[structopt(
+        long,
+        help = "Seconds to wait for a queued object to be cached",
+        default_value = "600"
+    )]
+    pub intel_queue_timeout: u64,
+}
+
+impl From<MirrorIntelCliConfig> for MirrorIntel {
+    fn from(config: MirrorIntelCliConfig) -> Self {
+        MirrorIntel::new(MirrorIntelConfig {
+            base: config.intel_base.unwrap(),
+            storage: config.intel_storage.unwrap(),
+            queue_limit: config.intel_queue_limit,
+            poll_interval: std::time::Duration::from_secs(config.intel_poll_interval),
+            queue_timeout: std::time::Duration::from_secs(config.intel_queue_timeout),
+        })
+    }
+}
+
 #[derive(StructOpt, Debug)]
 pub struct TransferConfig {
     #[structopt(long, help = "Concurrent transfer tasks", default_value = "8")]
@@ -132,6 +729,67 @@ pub struct TransferConfig {
     pub print_plan: usize,
     #[structopt(long, help = "Force transfer all objects")]
     pub force_all: bool,
+    #[structopt(
+        long,
+        help = "Update metadata in place when only metadata differs (target snapshot should contain metadata)"
+    )]
+    pub update_metadata: bool,
+    #[structopt(
+        long,
+        help = "Only backfill metadata of objects identical in source and target, without transferring or deleting objects"
+    )]
+    pub backfill_metadata: bool,
+    #[structopt(
+        long,
+        help = "Trim transfer plan to fit available space of target, instead of aborting"
+    )]
+    pub trim_to_space: bool,
+    #[structopt(
+        long,
+        help = "Append bytes downloaded per upstream host and uploaded per target to this file"
+    )]
+    pub accounting_report: Option<String>,
+    #[structopt(
+        long,
+        help = "Pause requests to a host after this many consecutive hard failures (0 to disable)",
+        default_value = "20"
+    )]
+    pub circuit_breaker_threshold: usize,
+    #[structopt(
+        long,
+        help = "Seconds to pause requests to a host when its circuit breaker trips",
+        default_value = "300"
+    )]
+    pub circuit_breaker_cooldown: u64,
+    #[structopt(
+        long,
+        help = "Limit total bandwidth of downloads and uploads, e.g. `200MiB/s`",
+        parse(try_from_str = crate::throttle::parse_bandwidth)
+    )]
+    pub max_bandwidth: Option<u64>,
+    #[structopt(
+        long,
+        help = "Times to resume an interrupted download with range requests",
+        default_value = "3"
+    )]
+    pub range_resume: u32,
+    #[structopt(
+        long,
+        help = "Download large objects as this many ranges in parallel",
+        default_value = "1"
+    )]
+    pub range_parallel: u64,
+    #[structopt(
+        long,
+        help = "Only download objects not smaller than this size (in bytes) in parallel",
+        default_value = "67108864"
+    )]
+    pub range_parallel_min_size: u64,
+    #[structopt(
+        long,
+        help = "Keep ETag and Last-Modified of downloaded objects in this file, and skip objects not modified since then"
+    )]
+    pub validator_cache: Option<String>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -145,12 +803,45 @@ pub struct Opts {
     pub s3_config: S3CliConfig,
     #[structopt(flatten)]
     pub file_config: FileBackendConfig,
+    #[structopt(flatten)]
+    pub ipfs_config: IpfsCliConfig,
+    #[structopt(flatten)]
+    pub http_put_config: HttpPutCliConfig,
+    #[structopt(flatten)]
+    pub cas_config: CasCliConfig,
+    #[structopt(flatten)]
+    pub mirror_intel_config: MirrorIntelCliConfig,
     #[structopt(long, help = "Enable progress bar")]
     pub progress: bool,
+    #[structopt(
+        short,
+        long,
+        parse(from_occurrences),
+        help = "Verbose logging, -v for debug and -vv for trace"
+    )]
+    pub verbose: u8,
     #[structopt(long, help = "Worker threads")]
     pub workers: Option<usize>,
     #[structopt(long, help = "Concurrent resolve tasks", default_value = "64")]
     pub concurrent_resolve: usize,
     #[structopt(flatten)]
     pub transfer_config: TransferConfig,
+    #[structopt(flatten)]
+    pub dedup_config: DedupConfig,
+    #[structopt(flatten)]
+    pub filter_config: FilterConfig,
+    #[structopt(flatten)]
+    pub priority_config: PriorityConfig,
+    #[structopt(flatten)]
+    pub pipeline_config: PipelineConfig,
+    #[structopt(flatten)]
+    pub index_config: IndexConfig,
+    #[structopt(flatten)]
+    pub retry_config: RetryConfig,
+    #[structopt(flatten)]
+    pub snapshot_cache_config: SnapshotCacheConfig,
+    #[structopt(flatten)]
+    pub multi_target_config: MultiTargetConfig,
+    #[structopt(flatten)]
+    pub merge_config: MergeConfig,
 }
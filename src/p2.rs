@@ -0,0 +1,340 @@
+//! Eclipse p2 source
+//!
+//! P2 source mirrors an Eclipse p2 update site. It reads artifact repository
+//! metadata (`artifacts.jar`, or `artifacts.xml.xz`) to enumerate plugins,
+//! features and binaries with sizes and checksums (sha256 if present,
+//! otherwise md5). Paths of artifacts are resolved with mapping rules in
+//! metadata. Composite repositories (`compositeArtifacts.jar`) are followed
+//! to their children under base.
+//!
+//! Packed artifacts (`.pack.gz`) are not mirrored. Metadata files of every
+//! repository are transferred at the end.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{Cursor, Read};
+
+use async_trait::async_trait;
+use regex::Regex;
+use reqwest::Client;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::{exists, fetch_optional};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct P2 {
+    #[structopt(
+        long,
+        help = "Base of p2 update site, e.g. https://download.eclipse.org/releases/2023-12"
+    )]
+    pub base: String,
+}
+
+/// Metadata files which are mirrored as-is if present.
+const METADATA_FILES: &[&str] = &[
+    "p2.index",
+    "content.jar",
+    "content.xml.xz",
+    "compositeContent.jar",
+    "compositeContent.xml.xz",
+];
+
+/// An artifact in artifact repository, with path relative to the repository.
+#[derive(Debug, PartialEq)]
+struct Artifact {
+    path: String,
+    size: Option<u64>,
+    checksum_method: Option<String>,
+    checksum: Option<String>,
+}
+
+fn parse_xml(content: &str) -> Result<roxmltree::Document<'_>> {
+    roxmltree::Document::parse(content)
+        .map_err(|err| Error::ProcessError(format!("invalid p2 metadata: {:?}", err)))
+}
+
+/// Parse `artifacts.xml` of a simple artifact repository.
+fn parse_artifacts(content: &str) -> Result<Vec<Artifact>> {
+    let document = parse_xml(content)?;
+    let classifier_matcher = Regex::new(r"\(classifier=([^)]+)\)").unwrap();
+
+    // classifier -> output path template, ignoring rules of packed artifacts
+    let rules: Vec<(String, String)> = document
+        .descendants()
+        .filter(|node| node.has_tag_name("rule"))
+        .filter_map(|node| {
+            let filter = node.attribute("filter")?;
+            if filter.contains("(format=") {
+                return None;
+            }
+            let classifier = classifier_matcher.captures(filter)?[1].to_string();
+            let output = node.attribute("output")?.strip_prefix("${repoUrl}/")?;
+            Some((classifier, output.to_string()))
+        })
+        .collect();
+
+    Ok(document
+        .descendants()
+        .filter(|node| node.has_tag_name("artifact"))
+        .filter_map(|node| {
+            let classifier = node.attribute("classifier")?;
+            let id = node.attribute("id")?;
+            let version = node.attribute("version")?;
+            let property = |name: &str| {
+                node.descendants()
+                    .filter(|child| child.has_tag_name("property"))
+                    .find(|child| child.attribute("name") == Some(name))
+                    .and_then(|child| child.attribute("value"))
+            };
+            if property("format").is_some() {
+                return None;
+            }
+            let (_, output) = rules.iter().find(|(x, _)| x == classifier)?;
+            let (checksum_method, checksum) = match (
+                property("download.checksum.sha-256"),
+                property("download.md5"),
+            ) {
+                (Some(sha256), _) => (Some("sha256"), Some(sha256)),
+                (None, Some(md5)) => (Some("md5"), Some(md5)),
+                (None, None) => (None, None),
+            };
+            Some(Artifact {
+                path: output.replace("${id}", id).replace("${version}", version),
+                size: property("download.size").and_then(|size| size.parse().ok()),
+                checksum_method: checksum_method.map(|x| x.to_string()),
+                checksum: checksum.map(|x| x.to_string()),
+            })
+        })
+        .collect())
+}
+
+/// Parse children locations of a composite repository.
+fn parse_children(content: &str) -> Result<Vec<String>> {
+    let document = parse_xml(content)?;
+    Ok(document
+        .descendants()
+        .filter(|node| node.has_tag_name("child"))
+        .filter_map(|node| node.attribute("location"))
+        .map(|location| location.to_string())
+        .collect())
+}
+
+/// Fetch metadata `{name}.jar` or `{name}.xml.xz` of a repository, returning
+/// the file found and XML content in it.
+async fn fetch_metadata(
+    client: &Client,
+    url: &str,
+    name: &str,
+) -> Result<Option<(String, String)>> {
+    let file = format!("{}.jar", name);
+    if let Some(data) = fetch_optional(client, &format!("{}/{}", url, file)).await? {
+        let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+        let mut content = String::new();
+        archive
+            .by_name(&format!("{}.xml", name))?
+            .read_to_string(&mut content)?;
+        return Ok(Some((file, content)));
+    }
+    let file = format!("{}.xml.xz", name);
+    if let Some(data) = fetch_optional(client, &format!("{}/{}", url, file)).await? {
+        let mut content = vec![];
+        lzma_rs::xz_decompress(&mut Cursor::new(data), &mut content)
+            .map_err(|err| Error::ProcessError(format!("invalid xz of {}: {:?}", file, err)))?;
+        return Ok(Some((file, String::from_utf8_lossy(&content).into_owned())));
+    }
+    Ok(None)
+}
+
+/// Join repository path with a file name or child path.
+fn join_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for P2 {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let base = self.base.trim_end_matches('/').to_string();
+
+        let mut snapshot = vec![];
+        let mut metadata = vec![];
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(String::new());
+
+        while let Some(path) = queue.pop_front() {
+            if !visited.insert(path.clone()) {
+                continue;
+            }
+            let url = if path.is_empty() {
+                base.clone()
+            } else {
+                format!("{}/{}", base, path)
+            };
+            progress.set_message(&url);
+
+            if let Some((file, content)) = fetch_metadata(&client, &url, "artifacts").await? {
+                let artifacts = parse_artifacts(&content)?;
+                info!(logger, "{}: {} artifacts", url, artifacts.len());
+                snapshot.extend(artifacts.into_iter().map(|artifact| SnapshotMeta {
+                    key: join_path(&path, &artifact.path),
+                    size: artifact.size,
+                    checksum_method: artifact.checksum_method,
+                    checksum: artifact.checksum,
+                    ..Default::default()
+                }));
+                metadata.push(join_path(&path, &file));
+            } else if let Some((file, content)) =
+                fetch_metadata(&client, &url, "compositeArtifacts").await?
+            {
+                let repo_url = url::Url::parse(&format!("{}/", url))
+                    .map_err(|err| Error::ProcessError(format!("invalid url: {:?}", err)))?;
+                for child in parse_children(&content)? {
+                    let child_url = match repo_url.join(&child) {
+                        Ok(child_url) => child_url.to_string(),
+                        Err(err) => {
+                            warn!(logger, "invalid child {} of {}: {:?}", child, url, err);
+                            continue;
+                        }
+                    };
+                    match child_url.strip_prefix(&format!("{}/", base)) {
+                        Some(child_path) => {
+                            queue.push_back(child_path.trim_end_matches('/').to_string())
+                        }
+                        None => warn!(logger, "skip child {} outside of base", child_url),
+                    }
+                }
+                metadata.push(join_path(&path, &file));
+            } else {
+                warn!(logger, "no p2 repository found at {}", url);
+                continue;
+            }
+
+            for file in METADATA_FILES {
+                if exists(&client, &format!("{}/{}", url, file)).await? {
+                    metadata.push(join_path(&path, file));
+                }
+            }
+        }
+
+        info!(
+            logger,
+            "{} artifacts, {} metadata files",
+            snapshot.len(),
+            metadata.len()
+        );
+        snapshot.extend(metadata.into_iter().map(SnapshotMeta::force));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("p2, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for P2 {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!(
+            "{}/{}",
+            self.base.trim_end_matches('/'),
+            snapshot.key
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_artifacts() {
+        let artifacts = r#"<?xml version='1.0' encoding='UTF-8'?>
+<?artifactRepository version='1.1.0'?>
+<repository name='Eclipse' type='org.eclipse.equinox.p2.artifact.repository.simpleRepository' version='1'>
+  <mappings size='3'>
+    <rule filter='(&amp; (classifier=osgi.bundle) (format=packed))' output='${repoUrl}/plugins/${id}_${version}.jar.pack.gz'/>
+    <rule filter='(&amp; (classifier=osgi.bundle))' output='${repoUrl}/plugins/${id}_${version}.jar'/>
+    <rule filter='(&amp; (classifier=org.eclipse.update.feature))' output='${repoUrl}/features/${id}_${version}.jar'/>
+  </mappings>
+  <artifacts size='3'>
+    <artifact classifier='osgi.bundle' id='org.eclipse.core.runtime' version='3.30.0.v20231102-0719'>
+      <properties size='3'>
+        <property name='download.size' value='79318'/>
+        <property name='download.md5' value='0ec2d5bd5dbb6d0e4ec9aa4a1e2b4e4b'/>
+        <property name='download.checksum.sha-256' value='6c8fd3a6a3b0f3a1d7e4c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1'/>
+      </properties>
+    </artifact>
+    <artifact classifier='osgi.bundle' id='org.eclipse.core.runtime' version='3.30.0.v20231102-0719'>
+      <processing size='1'>
+        <step id='org.eclipse.equinox.p2.processing.Pack200Unpacker' required='true'/>
+      </processing>
+      <properties size='2'>
+        <property name='download.size' value='31337'/>
+        <property name='format' value='packed'/>
+      </properties>
+    </artifact>
+    <artifact classifier='org.eclipse.update.feature' id='org.eclipse.platform' version='4.30.0.v20231201-0110'>
+      <properties size='2'>
+        <property name='download.size' value='1234'/>
+        <property name='download.md5' value='d41d8cd98f00b204e9800998ecf8427e'/>
+      </properties>
+    </artifact>
+  </artifacts>
+</repository>"#;
+        let artifacts = parse_artifacts(artifacts).unwrap();
+        assert_eq!(
+            artifacts,
+            vec![
+                Artifact {
+                    path: "plugins/org.eclipse.core.runtime_3.30.0.v20231102-0719.jar".to_string(),
+                    size: Some(79318),
+                    checksum_method: Some("sha256".to_string()),
+                    checksum: Some(
+                        "6c8fd3a6a3b0f3a1d7e4c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1"
+                            .to_string()
+                    ),
+                },
+                Artifact {
+                    path: "features/org.eclipse.platform_4.30.0.v20231201-0110.jar".to_string(),
+                    size: Some(1234),
+                    checksum_method: Some("md5".to_string()),
+                    checksum: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+                },
+            ]
+        );
+
+        let composite = r#"<?xml version='1.0' encoding='UTF-8'?>
+<?compositeArtifactRepository version='1.0.0'?>
+<repository name='Eclipse Repository' type='org.eclipse.equinox.internal.p2.artifact.repository.CompositeArtifactRepository' version='1.0.0'>
+  <children size='2'>
+    <child location='202312061001'/>
+    <child location='https://download.eclipse.org/technology/epp/packages/2023-12/'/>
+  </children>
+</repository>"#;
+        assert_eq!(
+            parse_children(composite).unwrap(),
+            vec![
+                "202312061001",
+                "https://download.eclipse.org/technology/epp/packages/2023-12/"
+            ]
+        );
+    }
+}
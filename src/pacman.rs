@@ -0,0 +1,114 @@
+//! Pacman repository database
+//!
+//! A pacman repository database (`{repo}.db`) is a tarball, compressed with
+//! gzip, xz or zstd, containing a `desc` file for each package. `desc` files
+//! consist of sections like `%FILENAME%` followed by values, one per line.
+
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+
+use crate::error::{Error, Result};
+
+/// A package in repository database.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Package {
+    pub filename: String,
+    /// size of package file
+    pub csize: Option<u64>,
+    pub sha256: Option<String>,
+}
+
+/// Parse a `desc` file, returning `None` if it has no `%FILENAME%`.
+fn parse_desc(content: &str) -> Option<Package> {
+    let mut package = Package::default();
+    let mut section = "";
+    for line in content.lines() {
+        if line.starts_with('%') && line.ends_with('%') {
+            section = line;
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        match section {
+            "%FILENAME%" => package.filename = line.to_string(),
+            "%CSIZE%" => package.csize = line.parse().ok(),
+            "%SHA256SUM%" => package.sha256 = Some(line.to_string()),
+            _ => {}
+        }
+    }
+    if package.filename.is_empty() {
+        None
+    } else {
+        Some(package)
+    }
+}
+
+/// Decompress gzip, xz or zstd data by its magic number. Data without a
+/// known magic number is returned as is.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    if data.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(data).read_to_end(&mut buf)?;
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        buf = zstd::stream::decode_all(data)?;
+    } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        lzma_rs::xz_decompress(&mut Cursor::new(data), &mut buf)
+            .map_err(|err| Error::ProcessError(format!("invalid xz data: {:?}", err)))?;
+    } else {
+        buf = data.to_vec();
+    }
+    Ok(buf)
+}
+
+/// Parse packages in a repository database.
+pub fn parse_db(data: &[u8]) -> Result<Vec<Package>> {
+    let data = decompress(data)?;
+    let mut archive = tar::Archive::new(&data[..]);
+    let mut packages = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.path()?.ends_with("desc") {
+            continue;
+        }
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        packages.extend(parse_desc(&content));
+    }
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_db() {
+        let desc = "%FILENAME%\nmingw-w64-ucrt-x86_64-zlib-1.3-1-any.pkg.tar.zst\n\n%NAME%\nmingw-w64-ucrt-x86_64-zlib\n\n%CSIZE%\n105328\n\n%SHA256SUM%\nabcd\n\n%BUILDDATE%\n1692021234\n\n";
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, content) in [
+            ("mingw-w64-ucrt-x86_64-zlib-1.3-1/desc", desc),
+            (
+                "mingw-w64-ucrt-x86_64-zlib-1.3-1/files",
+                "%FILES%\nucrt64/\n",
+            ),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let data = zstd::stream::encode_all(&builder.into_inner().unwrap()[..], 0).unwrap();
+        assert_eq!(
+            parse_db(&data).unwrap(),
+            vec![Package {
+                filename: "mingw-w64-ucrt-x86_64-zlib-1.3-1-any.pkg.tar.zst".to_string(),
+                csize: Some(105328),
+                sha256: Some("abcd".to_string()),
+            }]
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! Pipelines of pipes composed at runtime.
+//!
+//! Pipes are wrapped in `BoxedSource`, a trait object of source storage, so
+//! that a pipeline could be extended by pipes chosen at runtime instead of
+//! by types written in `main.rs`. `Pipeline` builds such a chain on top of a
+//! source yielding `ByteStream`.
+//!
+//! Most sources end their pipeline with a few generic pipes (e.g. checksum
+//! and index), given by `configurable`. These can be replaced by pipes in a
+//! YAML (or JSON) file given with `--pipeline`, which lists them in order:
+//!
+//! ```yaml
+//! pipes:
+//!   - checksum
+//!   - rewrite:
+//!       keys: '\.json$'
+//!       pattern: 'https://example\.com/'
+//!       replace: 'https://mirror.example.org/'
+//!   - filter:
+//!       exclude: ['\.iso$']
+//!   - index:
+//!       max_depth: 999
+//! ```
+//!
+//! Source-specific pipes (e.g. `ApiPipe` of Homebrew) are still applied
+//! before the configurable ones.
+
+use async_trait::async_trait;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Deserializer};
+use structopt::StructOpt;
+
+use crate::checksum_pipe::{AugmentChecksumPipe, ChecksumPipe};
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::filter_pipe::FilterPipe;
+use crate::index_pipe::IndexPipe;
+use crate::rewrite_pipe::RewritePipe;
+use crate::stream_pipe::{ByteStream, ByteStreamPipe};
+use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct PipelineConfig {
+    #[structopt(
+        long,
+        help = "Replace generic pipes of source (e.g. checksum and index) with pipes listed in this YAML file"
+    )]
+    pub pipeline: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PipelineFile {
+    pipes: Vec<PipeSpec>,
+}
+
+impl PipelineConfig {
+    /// Load pipes from file, or `None` if it is not given.
+    pub fn load(&self) -> Result<Option<Vec<PipeSpec>>> {
+        let path = match &self.pipeline {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let data = std::fs::read(path)?;
+        let file: PipelineFile = serde_yaml::from_slice(&data)
+            .map_err(|err| Error::ConfigureError(format!("invalid pipeline {}: {}", path, err)))?;
+        Ok(Some(file.pipes))
+    }
+}
+
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn optional_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Regex>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn regex_set<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<RegexSet, D::Error> {
+    let patterns = Vec::<String>::deserialize(deserializer)?;
+    RegexSet::new(patterns).map_err(serde::de::Error::custom)
+}
+
+fn default_max_length() -> u64 {
+    16 << 20
+}
+
+fn default_max_depth() -> usize {
+    999
+}
+
+/// A generic pipe, which could be configured at runtime.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PipeSpec {
+    /// verify checksum of objects in snapshot
+    Checksum,
+    /// compute checksum of local files missing one
+    AugmentChecksum,
+    /// replace `pattern` in objects with `replace`
+    Rewrite {
+        /// only rewrite objects whose key matches
+        #[serde(default, deserialize_with = "optional_regex")]
+        keys: Option<Regex>,
+        #[serde(deserialize_with = "regex")]
+        pattern: Regex,
+        replace: String,
+        /// objects larger than this are passed through
+        #[serde(default = "default_max_length")]
+        max_length: u64,
+    },
+    Filter {
+        #[serde(default = "RegexSet::empty", deserialize_with = "regex_set")]
+        include: RegexSet,
+        #[serde(default = "RegexSet::empty", deserialize_with = "regex_set")]
+        exclude: RegexSet,
+    },
+    /// generate index pages for each directory
+    Index {
+        #[serde(default = "default_max_depth")]
+        max_depth: usize,
+    },
+}
+
+/// Source storage of any pipe.
+pub trait Pipe<Snapshot, Item>: SnapshotStorage<Snapshot> + SourceStorage<Snapshot, Item> {}
+
+impl<Snapshot, Item, Source> Pipe<Snapshot, Item> for Source where
+    Source: SnapshotStorage<Snapshot> + SourceStorage<Snapshot, Item>
+{
+}
+
+pub type BoxedSource<Snapshot, Item = ByteStream> = Box<dyn Pipe<Snapshot, Item>>;
+
+/// Box `source`, so that it could be composed with other boxed sources.
+pub fn boxed<Snapshot, Item, Source>(source: Source) -> BoxedSource<Snapshot, Item>
+where
+    Source: Pipe<Snapshot, Item>,
+{
+    Box::new(source)
+}
+
+#[async_trait]
+impl<Snapshot, Item> SnapshotStorage<Snapshot> for BoxedSource<Snapshot, Item>
+where
+    Snapshot: Send + 'static,
+    Item: 'static,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        SnapshotStorage::snapshot(&mut **self, mission, config).await
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        SnapshotStorage::estimate(&**self, mission).await
+    }
+
+    fn info(&self) -> String {
+        SnapshotStorage::info(&**self)
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Item> SourceStorage<Snapshot, Item> for BoxedSource<Snapshot, Item>
+where
+    Snapshot: Send + Sync + 'static,
+    Item: 'static,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<Item> {
+        SourceStorage::get_object(&**self, snapshot, mission).await
+    }
+}
+
+/// Paths and configured pipes shared by pipelines of a task.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineBuilder {
+    pub buffer_path: Option<String>,
+    /// base path of generated indexes
+    pub prefix: Option<String>,
+    /// pipes replacing the generic ones, see `PipelineConfig`
+    pub pipes: Option<Vec<PipeSpec>>,
+    /// file names of generated indexes, see `IndexConfig`
+    pub index_filenames: Vec<String>,
+}
+
+impl PipelineBuilder {
+    /// Start a pipeline from `source` yielding `ByteStream`.
+    pub fn stream<Snapshot, Source>(&self, source: Source) -> Pipeline<Snapshot>
+    where
+        Source: Pipe<Snapshot, ByteStream>,
+    {
+        Pipeline {
+            source: Ok(boxed(source)),
+            builder: self.clone(),
+        }
+    }
+
+    /// Start a pipeline from `source` yielding `TransferURL`, which is
+    /// downloaded by `ByteStreamPipe`.
+    pub fn bytes<Snapshot, Source>(
+        &self,
+        source: Source,
+        use_snapshot_last_modified: bool,
+    ) -> Pipeline<Snapshot>
+    where
+        Snapshot: Key + Metadata,
+        Source: Pipe<Snapshot, TransferURL>,
+    {
+        Pipeline {
+            source: self.buffer_path().map(|buffer_path| {
+                boxed(ByteStreamPipe::new(
+                    source,
+                    buffer_path,
+                    use_snapshot_last_modified,
+                ))
+            }),
+            builder: self.clone(),
+        }
+    }
+
+    /// Buffer path of pipes writing objects to local files.
+    pub fn buffer_path(&self) -> Result<String> {
+        self.buffer_path.clone().ok_or_else(|| {
+            Error::ConfigureError(
+                "pipes of this source need --s3-buffer-path or --file-buffer-path".to_string(),
+            )
+        })
+    }
+
+    fn prefix(&self) -> Result<String> {
+        self.prefix
+            .clone()
+            .ok_or_else(|| Error::ConfigureError("index pipe needs a prefix".to_string()))
+    }
+}
+
+/// Chain of pipes, built by `PipelineBuilder`. Errors of adding pipes (e.g.
+/// a missing buffer path) are kept until `build`.
+pub struct Pipeline<Snapshot> {
+    source: Result<BoxedSource<Snapshot>>,
+    builder: PipelineBuilder,
+}
+
+impl<Snapshot> Pipeline<Snapshot>
+where
+    Snapshot: Key + Metadata,
+{
+    /// Wrap the pipeline with `pipe`.
+    pub fn pipe<Source>(self, pipe: impl FnOnce(BoxedSource<Snapshot>) -> Source) -> Self
+    where
+        Source: Pipe<Snapshot, ByteStream>,
+    {
+        self.try_pipe(|source| Ok(pipe(source)))
+    }
+
+    /// Wrap the pipeline with `pipe`, which may fail to be built.
+    pub fn try_pipe<Source>(
+        self,
+        pipe: impl FnOnce(BoxedSource<Snapshot>) -> Result<Source>,
+    ) -> Self
+    where
+        Source: Pipe<Snapshot, ByteStream>,
+    {
+        Self {
+            source: self.source.and_then(|source| Ok(boxed(pipe(source)?))),
+            builder: self.builder,
+        }
+    }
+
+    pub fn checksum(self) -> Self {
+        self.pipe(ChecksumPipe::new)
+    }
+
+    pub fn augment_checksum(self) -> Self {
+        self.pipe(AugmentChecksumPipe::new)
+    }
+
+    /// Rewrite objects not larger than `max_length` with `rewrite_fn`, only
+    /// those whose key matches `keys` if given.
+    pub fn rewrite<F>(self, rewrite_fn: F, max_length: u64, keys: Option<Regex>) -> Self
+    where
+        F: Fn(String) -> Result<String> + Send + Sync + 'static,
+    {
+        let buffer_path = self.builder.buffer_path();
+        self.try_pipe(|source| {
+            let pipe = RewritePipe::new(source, buffer_path?, rewrite_fn, max_length);
+            Ok(match keys {
+                Some(keys) => pipe.key_pattern(keys),
+                None => pipe,
+            })
+        })
+    }
+
+    pub fn filter(self, include: RegexSet, exclude: RegexSet) -> Self {
+        self.pipe(|source| FilterPipe::new(source, include, exclude))
+    }
+
+    pub fn index(self, max_depth: usize) -> Self
+    where
+        IndexPipe<BoxedSource<Snapshot>>: Pipe<Snapshot, ByteStream>,
+    {
+        let buffer_path = self.builder.buffer_path();
+        let prefix = self.builder.prefix();
+        let filenames = self.builder.index_filenames.clone();
+        self.try_pipe(|source| {
+            Ok(IndexPipe::new(source, buffer_path?, prefix?, max_depth).filenames(filenames))
+        })
+    }
+
+    pub fn apply(self, spec: PipeSpec) -> Self
+    where
+        IndexPipe<BoxedSource<Snapshot>>: Pipe<Snapshot, ByteStream>,
+    {
+        match spec {
+            PipeSpec::Checksum => self.checksum(),
+            PipeSpec::AugmentChecksum => self.augment_checksum(),
+            PipeSpec::Rewrite {
+                keys,
+                pattern,
+                replace,
+                max_length,
+            } => self.rewrite(
+                move |src: String| Ok(pattern.replace_all(&src, replace.as_str()).into_owned()),
+                max_length,
+                keys,
+            ),
+            PipeSpec::Filter { include, exclude } => self.filter(include, exclude),
+            PipeSpec::Index { max_depth } => self.index(max_depth),
+        }
+    }
+
+    /// Apply generic pipes of source, unless they're replaced by configured
+    /// ones.
+    pub fn configurable(self, defaults: Vec<PipeSpec>) -> Self
+    where
+        IndexPipe<BoxedSource<Snapshot>>: Pipe<Snapshot, ByteStream>,
+    {
+        let pipes = self.builder.pipes.clone().unwrap_or(defaults);
+        pipes.into_iter().fold(self, Self::apply)
+    }
+
+    pub fn build(self) -> Result<BoxedSource<Snapshot>> {
+        self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipeline_file() {
+        let file: PipelineFile = serde_yaml::from_str(
+            r#"
+pipes:
+  - checksum
+  - rewrite:
+      keys: '\.json$'
+      pattern: 'https://example\.com/'
+      replace: 'https://mirror.example.org/'
+  - filter:
+      exclude: ['\.iso$']
+  - index: {}
+"#,
+        )
+        .unwrap();
+        assert_eq!(file.pipes.len(), 4);
+        assert!(matches!(file.pipes[0], PipeSpec::Checksum));
+        match &file.pipes[1] {
+            PipeSpec::Rewrite {
+                keys,
+                pattern,
+                max_length,
+                ..
+            } => {
+                assert!(keys.as_ref().unwrap().is_match("index.json"));
+                assert!(pattern.is_match("https://example.com/a.tar.gz"));
+                assert_eq!(*max_length, 16 << 20);
+            }
+            spec => panic!("unexpected pipe {:?}", spec),
+        }
+        match &file.pipes[2] {
+            PipeSpec::Filter { include, exclude } => {
+                assert!(include.is_empty());
+                assert!(exclude.is_match("ubuntu.iso"));
+            }
+            spec => panic!("unexpected pipe {:?}", spec),
+        }
+        assert!(matches!(file.pipes[3], PipeSpec::Index { max_depth: 999 }));
+
+        assert!(serde_yaml::from_str::<PipelineFile>("pipes: [compress]").is_err());
+        assert!(serde_yaml::from_str::<PipelineFile>(
+            "pipes: [{rewrite: {pattern: '(', replace: ''}}]"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! PriorityPipe sets priority of source items by regex pattern.
+//!
+//! Objects are transferred from highest priority to lowest. Rules are given
+//! in the form of `regex=priority`, and the first rule matching key of an
+//! item decides its priority, overriding `force_last` of snapshot. Items
+//! matching no rule are left as is. For example, `--priority
+//! 'repodata\.json$=-10'` transfers indexes after all packages.
+//!
+//! `SnapshotPath` has no priority, so rules don't apply to sources yielding
+//! paths only.
+
+use async_trait::async_trait;
+use regex::Regex;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct PriorityConfig {
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Set priority of objects whose key matches a regex, in the form of `regex=priority` (higher first, default 0), can be given multiple times"
+    )]
+    pub priority: Vec<String>,
+}
+
+/// Parse a rule in the form of `regex=priority`. The regex may contain `=`.
+fn parse_rule(rule: &str) -> Result<(Regex, isize)> {
+    let invalid = |reason: String| {
+        Error::ConfigureError(format!("invalid priority rule {}: {}", rule, reason))
+    };
+    let (pattern, priority) = rule
+        .rsplit_once('=')
+        .ok_or_else(|| invalid("missing priority".to_string()))?;
+    let priority = priority
+        .trim()
+        .parse()
+        .map_err(|err: std::num::ParseIntError| invalid(err.to_string()))?;
+    let pattern = Regex::new(pattern).map_err(|err| invalid(err.to_string()))?;
+    Ok((pattern, priority))
+}
+
+impl PriorityConfig {
+    pub fn into_pipe<Source>(self, source: Source) -> Result<PriorityPipe<Source>> {
+        let rules = self
+            .priority
+            .iter()
+            .map(|rule| parse_rule(rule))
+            .collect::<Result<_>>()?;
+        Ok(PriorityPipe::new(source, rules))
+    }
+}
+
+/// Snapshot items whose priority could be set.
+pub trait Prioritize {
+    fn set_priority(&mut self, priority: isize);
+}
+
+impl Prioritize for SnapshotMeta {
+    fn set_priority(&mut self, priority: isize) {
+        self.priority = Some(priority);
+    }
+}
+
+impl Prioritize for SnapshotPath {
+    fn set_priority(&mut self, _priority: isize) {}
+}
+
+pub struct PriorityPipe<Source> {
+    pub source: Source,
+    pub rules: Vec<(Regex, isize)>,
+}
+
+impl<Source> PriorityPipe<Source> {
+    pub fn new(source: Source, rules: Vec<(Regex, isize)>) -> Self {
+        PriorityPipe { source, rules }
+    }
+
+    fn priority(&self, key: &str) -> Option<isize> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(key))
+            .map(|(_, priority)| *priority)
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for PriorityPipe<Source>
+where
+    Snapshot: Key + Prioritize,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        if !self.rules.is_empty() {
+            for item in snapshot.iter_mut() {
+                if let Some(priority) = self.priority(item.key()) {
+                    item.set_priority(priority);
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        let rules: Vec<_> = self
+            .rules
+            .iter()
+            .map(|(pattern, priority)| format!("{}={}", pattern, priority))
+            .collect();
+        format!("priority {:?} <{}>", rules, self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source, SourceItem> SourceStorage<Snapshot, SourceItem> for PriorityPipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SourceStorage<Snapshot, SourceItem>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<SourceItem> {
+        self.source.get_object(snapshot, mission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::Metadata;
+
+    #[test]
+    fn test_parse_rule() {
+        let (pattern, priority) = parse_rule(r"(^|/)repodata\.json$=-10").unwrap();
+        assert!(pattern.is_match("noarch/repodata.json"));
+        assert_eq!(priority, -10);
+        let (pattern, priority) = parse_rule("a=b=1").unwrap();
+        assert_eq!(pattern.as_str(), "a=b");
+        assert_eq!(priority, 1);
+        assert!(parse_rule("repodata.json").is_err());
+        assert!(parse_rule("repodata.json=last").is_err());
+        assert!(parse_rule("(=1").is_err());
+    }
+
+    #[test]
+    fn test_priority() {
+        let pipe = PriorityPipe::new(
+            (),
+            vec![
+                parse_rule(r"\.toml$=-1").unwrap(),
+                parse_rule(r"\.sha256$=-2").unwrap(),
+                parse_rule("^dist/=5").unwrap(),
+            ],
+        );
+        assert_eq!(pipe.priority("dist/channel-rust-stable.toml"), Some(-1));
+        assert_eq!(
+            pipe.priority("dist/channel-rust-stable.toml.sha256"),
+            Some(-2)
+        );
+        assert_eq!(pipe.priority("dist/rust.tar.gz"), Some(5));
+        assert_eq!(pipe.priority("rustup/rustup-init"), None);
+
+        let mut meta = SnapshotMeta::force("index.json".to_string());
+        assert_eq!(meta.priority(), -1);
+        meta.set_priority(10);
+        assert_eq!(meta.priority(), 10);
+    }
+}
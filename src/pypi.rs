@@ -3,15 +3,36 @@
 //! Pypi is a source storage which scans PyPI. The snapshot is generated by first
 //! scanning the package index, then scanning index of every package. This only takes
 //! about 5 minutes on SJTUG server, where we fetch data from TUNA mirrors.
-//! A PyPI link may contain checksum in its URL, and when taking snapshot, this source
-//! will remove checksums from URL.
+//! Indexes are fetched in the JSON form of simple API (PEP 691), where each file
+//! comes with sha256 digest, and optionally size and upload time (PEP 700), so
+//! that files can be verified by checksum pipe and diffed by metadata.
 //!
-//! Pypi supports path snapshot, and TransferURL source object.
+//! When `state_file` is set, the last serial of the index (`X-PyPI-Last-Serial`)
+//! and resolved files of all projects are saved after each snapshot. Following
+//! snapshots only re-resolve projects changed since that serial, which are
+//! queried with `changelog_since_serial` of the XML-RPC API, and new projects in
+//! the index. A full scan is done if the state file is missing or unreadable.
+//!
+//! Projects can be limited to `only_projects` and projects in a requirements
+//! file, and excluded with `exclude_projects`. They are filtered by normalized
+//! names before resolving project pages.
+//!
+//! With `simple_index`, packages are placed under `packages/`, and simple
+//! index pages of all projects (HTML and JSON, as `index.html` and
+//! `index.v1_json`) are generated under `simple/` by `SimpleIndexPipe`, with
+//! links pointing to packages on the mirror, so that the mirror can be used by
+//! pip directly. The web server should serve `index.v1_json` to clients
+//! accepting `application/vnd.pypi.simple.v1+json`.
+//!
+//! Pypi supports meta snapshot, and TransferURL source object.
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::path::Path;
 
 use async_trait::async_trait;
-use futures_util::{stream, StreamExt, TryStreamExt};
+use chrono::DateTime;
+use futures_util::{stream, StreamExt};
 use google_bigquery2::api::QueryRequest;
 use google_bigquery2::hyper::client::HttpConnector;
 use google_bigquery2::hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
@@ -23,15 +44,24 @@ use google_bigquery2::{hyper, Bigquery};
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
 use regex::Regex;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::Digest;
 use slog::{info, warn, Logger};
 use structopt::StructOpt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
 
-use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
+use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
+use crate::fetch::{fetch_with, fetch_with_headers, FetchOptions};
+use crate::filter_pipe::Filtered;
+use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
 use crate::python_version::Version;
-use crate::traits::{SnapshotStorage, SourceStorage};
-use crate::utils::bar;
+use crate::snapshot_cache_pipe::SnapshotCachePipe;
+use crate::stream_pipe::{ByteObject, ByteStream, ByteStreamPipe};
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::{bar, hash_string, unix_time};
 
 const BQ_QUERY: &str = r#"
     SELECT file.project, COUNT(*) AS num_downloads
@@ -48,7 +78,7 @@ const BQ_QUERY: &str = r#"
     "#;
 
 #[derive(Debug, Clone, StructOpt)]
-pub struct Pypi {
+pub struct PypiConfig {
     /// Base of simple index
     #[structopt(
         long,
@@ -74,11 +104,116 @@ pub struct Pypi {
     /// previous cache.
     #[structopt(long)]
     pub keep_recent: Option<usize>,
+    /// Drop pre-releases and dev releases. When used with `keep_recent`, only
+    /// recent N stable versions are kept.
+    #[structopt(long)]
+    pub stable_only: bool,
     /// When debug mode is enabled, only first 1000 packages will be selected.
     /// Please add `--no-delete` parameter on simple diff transfer when enabling
     /// debug mode on a production endpoint.
     #[structopt(long)]
     pub debug: bool,
+    /// File to save the last processed serial and resolved projects in. When set,
+    /// only projects changed since the last snapshot are re-resolved. Incremental
+    /// snapshot is not supported in bigquery mode. Remove the file after changing
+    /// `keep_recent` or `stable_only`, so that they apply to all projects.
+    #[structopt(long)]
+    pub state_file: Option<String>,
+    /// XML-RPC endpoint to query changelog from, which should be the upstream of
+    /// simple index.
+    #[structopt(long, default_value = "https://pypi.org/pypi")]
+    pub xmlrpc: String,
+    /// Only mirror these projects (and projects in `requirements`).
+    #[structopt(long, use_delimiter = true)]
+    pub only_projects: Vec<String>,
+    /// Do not mirror these projects.
+    #[structopt(long, use_delimiter = true)]
+    pub exclude_projects: Vec<String>,
+    /// Only mirror projects in this requirements file (and `only_projects`).
+    #[structopt(long)]
+    pub requirements: Option<String>,
+    /// Generate simple index pages of projects under `simple/`, and place
+    /// packages under `packages/`.
+    #[structopt(long)]
+    pub simple_index: bool,
+}
+
+pub struct Pypi {
+    pub config: PypiConfig,
+    /// normalized project name -> files, kept for `SimpleIndexPipe`
+    projects: BTreeMap<String, Vec<File>>,
+}
+
+/// Content type of JSON simple API.
+const SIMPLE_JSON: &str = "application/vnd.pypi.simple.v1+json";
+
+/// Project list in JSON simple API.
+#[derive(Deserialize)]
+struct ProjectList {
+    projects: Vec<ProjectEntry>,
+}
+
+#[derive(Deserialize)]
+struct ProjectEntry {
+    name: String,
+}
+
+/// Project page in JSON simple API.
+#[derive(Deserialize)]
+struct ProjectPage {
+    files: Vec<File>,
+}
+
+/// A file in project page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct File {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+    size: Option<u64>,
+    #[serde(rename = "upload-time")]
+    upload_time: Option<String>,
+    #[serde(rename = "requires-python")]
+    requires_python: Option<String>,
+    /// `true`, or reason of yanking
+    yanked: Option<Value>,
+}
+
+/// State of incremental snapshot.
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    serial: u64,
+    /// normalized project name -> files
+    projects: BTreeMap<String, Vec<File>>,
+}
+
+/// Normalize a project name as in PEP 503.
+fn normalize(name: &str) -> String {
+    static RE_SEPARATOR: once_cell::sync::Lazy<Regex> =
+        once_cell::sync::Lazy::new(|| Regex::new(r"[-_.]+").unwrap());
+    RE_SEPARATOR.replace_all(name, "-").to_lowercase()
+}
+
+fn simple_options() -> FetchOptions {
+    FetchOptions::default()
+        .accept(SIMPLE_JSON)
+        .content_type("json")
+}
+
+/// Parse project names in a requirements file. Options (e.g. `-r`, `-e`) and
+/// URLs are skipped.
+fn parse_requirements(content: &str) -> Vec<String> {
+    static RE_NAME: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
+        Regex::new(r"^([A-Za-z0-9][A-Za-z0-9._-]*)\s*($|[\[(;<>=!~@ ])").unwrap()
+    });
+    content
+        .lines()
+        .map(|line| line.split(" #").next().unwrap_or_default().trim())
+        .filter(|line| !line.starts_with('#') && !line.starts_with('-'))
+        .filter_map(|line| RE_NAME.captures(line))
+        .map(|cap| normalize(&cap[1]))
+        .collect()
 }
 
 async fn pypi_index(
@@ -86,26 +221,116 @@ async fn pypi_index(
     client: &Client,
     simple_base: &str,
     debug: bool,
-) -> Result<Vec<String>> {
+) -> Result<(Vec<String>, Option<u64>)> {
     info!(logger, "downloading pypi index...");
-    let mut index = client
-        .get(&format!("{}/", simple_base))
-        .send()
-        .await?
-        .text()
-        .await?;
+    let (headers, index) =
+        fetch_with_headers(client, &format!("{}/", simple_base), &simple_options()).await?;
+    let serial = headers
+        .get("X-PyPI-Last-Serial")
+        .and_then(|serial| serial.to_str().ok())
+        .and_then(|serial| serial.parse().ok());
 
     info!(logger, "parsing index...");
-    let matcher = Regex::new(r#"<a.*href=".*?".*>(.*?)</a>"#).unwrap();
+    let index: ProjectList = serde_json::from_slice(&index)?;
+    let mut projects: Vec<String> = index
+        .projects
+        .into_iter()
+        .map(|project| project.name)
+        .collect();
     if debug {
-        index = index[..1000].to_string();
+        projects.truncate(1000);
+    }
+    Ok((projects, serial))
+}
+
+/// Query names of projects changed since `serial` with XML-RPC API.
+async fn changelog_since(client: &Client, xmlrpc: &str, serial: u64) -> Result<Vec<String>> {
+    let request = format!(
+        "<?xml version=\"1.0\"?><methodCall><methodName>changelog_since_serial</methodName>\
+         <params><param><value><int>{}</int></value></param></params></methodCall>",
+        serial
+    );
+    let response = client
+        .post(xmlrpc)
+        .header(reqwest::header::CONTENT_TYPE, "text/xml")
+        .body(request)
+        .send()
+        .timeout(FetchOptions::default().timeout)
+        .await
+        .into_result()?;
+    if !response.status().is_success() {
+        return Err(Error::HTTPError(response.status()));
+    }
+    parse_changelog(&response.text().await?)
+}
+
+/// Parse project names in response of `changelog_since_serial`, which is an
+/// array of `[name, version, timestamp, action, serial]`.
+fn parse_changelog(content: &str) -> Result<Vec<String>> {
+    let document = roxmltree::Document::parse(content)
+        .map_err(|err| Error::ProcessError(format!("invalid XML-RPC response: {:?}", err)))?;
+    if document
+        .descendants()
+        .any(|node| node.has_tag_name("fault"))
+    {
+        return Err(Error::ProcessError(format!("XML-RPC fault: {}", content)));
     }
-    Ok(matcher
-        .captures_iter(&index)
-        .map(|cap| cap[1].to_string())
+    let entries = document
+        .descendants()
+        .find(|node| node.has_tag_name("data"))
+        .ok_or_else(|| Error::ProcessError("no data in XML-RPC response".to_string()))?;
+    Ok(entries
+        .children()
+        .filter(|node| node.has_tag_name("value"))
+        .filter_map(|entry| {
+            let name = entry
+                .descendants()
+                .find(|node| node.has_tag_name("data"))?
+                .children()
+                .find(|node| node.has_tag_name("value"))?;
+            let name: String = name
+                .descendants()
+                .filter(|node| node.is_text())
+                .filter_map(|node| node.text())
+                .collect();
+            Some(name.trim().to_string())
+        })
         .collect())
 }
 
+async fn load_state(path: &str) -> Result<State> {
+    let data = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Save state to a temporary file and rename it, so that an interrupted save
+/// won't leave a broken state.
+async fn save_state(path: &str, state: &State) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, serde_json::to_vec(state)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Parse a project page, resolving file URLs against `page_url`, and removing
+/// checksums in URL fragments.
+fn parse_project(page_url: &str, content: &[u8]) -> Result<Vec<File>> {
+    let page_url = url::Url::parse(page_url)
+        .map_err(|err| Error::ProcessError(format!("invalid URL {}: {:?}", page_url, err)))?;
+    let page: ProjectPage = serde_json::from_slice(content)?;
+    page.files
+        .into_iter()
+        .map(|mut file| {
+            let mut url = page_url.join(&file.url).map_err(|err| {
+                Error::ProcessError(format!("invalid URL {}: {:?}", file.url, err))
+            })?;
+            url.set_fragment(None);
+            file.url = url.to_string();
+            Ok(file)
+        })
+        .collect()
+}
+
 macro_rules! append_proxy_from_env {
     ($proxies:expr, $env_name:expr, $intercept:expr) => {
         if let Ok(proxy) = env::var($env_name) {
@@ -191,44 +416,94 @@ async fn bigquery_index(logger: &Logger) -> Result<Vec<String>> {
         .collect())
 }
 
-fn version_from_filename(filename: &str) -> Option<Version> {
-    static RE_VERSION: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
-        Regex::new(r"^\w+-([\w.-_+]+).*(.tar.gz|tar.bz2|.zip|.whl|.exe|.egg)$").unwrap()
-    });
-    RE_VERSION
-        .captures(filename)
-        .and_then(|cap| cap.get(1))
-        .and_then(|cap| Version::parse(cap.as_str()).ok())
+/// Extensions of distributions other than wheels and eggs.
+const DIST_EXTENSIONS: &[&str] = &[
+    ".tar.gz", ".tar.bz2", ".tar.xz", ".tgz", ".zip", ".exe", ".msi", ".rpm", ".dmg",
+];
+
+/// Find version in file name of a distribution of (normalized) `project`,
+/// e.g. `foo_bar-1.0-py3-none-any.whl` or `foo-bar-1.0.tar.gz`, returning the
+/// version as written and parsed.
+fn find_version<'a>(project: &str, filename: &'a str) -> Option<(&'a str, Version)> {
+    // `-` in project name is escaped in wheels and eggs, so version is the second part
+    for extension in [".whl", ".egg"] {
+        if let Some(stem) = filename.strip_suffix(extension) {
+            let version = stem.split('-').nth(1)?;
+            return Version::parse(version).ok().map(|parsed| (version, parsed));
+        }
+    }
+    let stem = DIST_EXTENSIONS
+        .iter()
+        .find_map(|extension| filename.strip_suffix(extension))?;
+    let version = stem
+        .match_indices('-')
+        .find(|(pos, _)| normalize(&stem[..*pos]) == project)
+        .map(|(pos, _)| &stem[pos + 1..])?;
+    // platform may follow version, e.g. `1.0.win32` or `1.0.linux-x86_64`
+    let mut end = version.len();
+    loop {
+        if let Ok(parsed) = Version::parse(&version[..end]) {
+            return Some((&version[..end], parsed));
+        }
+        end = version[..end].rfind(['.', '-'])?;
+    }
+}
+
+fn version_from_filename(project: &str, filename: &str) -> Option<Version> {
+    find_version(project, filename).map(|(_, version)| version)
+}
+
+/// Drop files of pre-releases and dev releases. Files of unknown versions are
+/// kept.
+fn drop_unstable(logger: &Logger, package: &str, entries: Vec<File>) -> Vec<File> {
+    entries
+        .into_iter()
+        .filter(
+            |file| match version_from_filename(package, &file.filename) {
+                Some(version) => version.is_stable(),
+                None => {
+                    warn!(
+                        logger,
+                        "failed to parse version from filename: {}", file.filename
+                    );
+                    true
+                }
+            },
+        )
+        .collect()
 }
 
 fn truncate_to_recent(
     logger: &Logger,
     package: &str,
-    entries: Vec<(String, String)>,
+    entries: Vec<File>,
     keep_recent: usize,
-) -> Vec<(String, String)> {
+) -> Vec<File> {
     let candidates: Option<Vec<_>> = entries
         .iter()
-        .map(|(url, name)| {
-            if let Some(version) = version_from_filename(name) {
-                Some((url, name, version))
+        .map(|file| {
+            if let Some(version) = version_from_filename(package, &file.filename) {
+                Some((file, version))
             } else {
-                warn!(logger, "failed to parse version from filename: {}", name);
+                warn!(
+                    logger,
+                    "failed to parse version from filename: {}", file.filename
+                );
                 None
             }
         })
         .collect();
     if let Some(mut candidates) = candidates {
-        candidates.sort_by_key(|(_, _, version)| version.clone());
+        candidates.sort_by_key(|(_, version)| version.clone());
         let mut result = vec![];
         let at_most_unstable = keep_recent / 2;
         let mut selected_count = 0;
         let mut selected_unstable_count = 0;
         let mut prev = None;
-        for (url, name, version) in candidates.into_iter().rev() {
+        for (file, version) in candidates.into_iter().rev() {
             if prev.as_ref() == Some(&version) {
                 // Another file of this version is already selected. Select this too.
-                result.push((url.clone(), name.clone()));
+                result.push(file.clone());
                 continue;
             }
             if selected_count >= keep_recent {
@@ -239,13 +514,13 @@ fn truncate_to_recent(
             // A new version is encountered.
             if version.is_stable() {
                 // We'd like to pick stable versions first.
-                result.push((url.clone(), name.clone()));
+                result.push(file.clone());
             } else {
                 // If it's not an unstable version, pick it only if we haven't selected enough.
                 if selected_unstable_count >= at_most_unstable {
                     continue;
                 }
-                result.push((url.clone(), name.clone()));
+                result.push(file.clone());
                 selected_unstable_count += 1;
             }
             prev = Some(version);
@@ -258,104 +533,261 @@ fn truncate_to_recent(
     }
 }
 
+impl Pypi {
+    pub fn new(config: PypiConfig) -> Self {
+        Self {
+            config,
+            projects: BTreeMap::new(),
+        }
+    }
+
+    /// Prefix of package keys.
+    fn package_prefix(&self) -> &'static str {
+        if self.config.simple_index {
+            "packages/"
+        } else {
+            ""
+        }
+    }
+}
+
+impl std::fmt::Debug for Pypi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
 #[async_trait]
-impl SnapshotStorage<SnapshotPath> for Pypi {
+impl SnapshotStorage<SnapshotMeta> for Pypi {
     async fn snapshot(
         &mut self,
         mission: Mission,
         config: &SnapshotConfig,
-    ) -> Result<Vec<SnapshotPath>> {
+    ) -> Result<Vec<SnapshotMeta>> {
         let logger = mission.logger;
         let progress = mission.progress;
         let client = mission.client;
 
-        let projects = if self.bq_query {
-            if self.debug {
+        let (projects, serial) = if self.config.bq_query {
+            if self.config.debug {
                 warn!(logger, "debug mode is ignored in bigquery mode");
             }
-            bigquery_index(&logger).await?
+            if self.config.state_file.is_some() {
+                warn!(logger, "state file is ignored in bigquery mode");
+            }
+            (bigquery_index(&logger).await?, None)
         } else {
-            pypi_index(&logger, &client, &self.simple_base, self.debug).await?
+            pypi_index(
+                &logger,
+                &client,
+                &self.config.simple_base,
+                self.config.debug,
+            )
+            .await?
+        };
+        let mut projects: Vec<String> = projects.iter().map(|name| normalize(name)).collect();
+
+        let mut only: HashSet<String> = self
+            .config
+            .only_projects
+            .iter()
+            .map(|name| normalize(name))
+            .collect();
+        if let Some(path) = &self.config.requirements {
+            only.extend(parse_requirements(&tokio::fs::read_to_string(path).await?));
+        }
+        let exclude: HashSet<String> = self
+            .config
+            .exclude_projects
+            .iter()
+            .map(|name| normalize(name))
+            .collect();
+        if !only.is_empty() || !exclude.is_empty() {
+            let total = projects.len();
+            projects
+                .retain(|name| (only.is_empty() || only.contains(name)) && !exclude.contains(name));
+            info!(logger, "{} of {} projects selected", projects.len(), total);
+        }
+
+        let state_file = self
+            .config
+            .state_file
+            .as_ref()
+            .filter(|_| !self.config.bq_query);
+        let mut previous = match state_file {
+            Some(path) => match load_state(path).await {
+                Ok(state) => Some(state),
+                Err(err) => {
+                    info!(logger, "no previous state, doing full scan: {:?}", err);
+                    None
+                }
+            },
+            None => None,
         };
+        let mut changed = HashSet::new();
+        if let (Some(state), Some(serial)) = (&previous, serial) {
+            match changelog_since(&client, &self.config.xmlrpc, state.serial).await {
+                Ok(names) => {
+                    changed = names.iter().map(|name| normalize(name)).collect();
+                    info!(
+                        logger,
+                        "serial {} -> {}, {} projects changed",
+                        state.serial,
+                        serial,
+                        changed.len()
+                    );
+                }
+                Err(err) => {
+                    warn!(
+                        logger,
+                        "failed to query changelog, doing full scan: {:?}", err
+                    );
+                    previous = None;
+                }
+            }
+        }
+        let mut previous = previous.map(|state| state.projects).unwrap_or_default();
+
+        // Projects removed from index are dropped. Changed projects and new
+        // projects are resolved, where previous files of changed projects are
+        // kept in case of failure.
+        let mut resolved = BTreeMap::new();
+        let mut fallback = HashMap::new();
+        let mut to_resolve = vec![];
+        for name in projects {
+            match previous.remove(&name) {
+                Some(files) if !changed.contains(&name) => {
+                    resolved.insert(name, files);
+                }
+                Some(files) => {
+                    fallback.insert(name.clone(), files);
+                    to_resolve.push(name);
+                }
+                None => to_resolve.push(name),
+            }
+        }
 
         info!(logger, "downloading package index...");
-        progress.set_length(projects.len() as u64);
+        progress.set_length(to_resolve.len() as u64);
         progress.set_style(bar());
 
-        let matcher = Regex::new(r#"<a.*href="(.*?)".*>(.*?)</a>"#).unwrap();
-        let packages: Result<Vec<Vec<(String, String)>>> =
-            stream::iter(projects.into_iter().map(|name| {
+        let results: Vec<(String, Result<Vec<File>>)> =
+            stream::iter(to_resolve.into_iter().map(|name| {
                 let client = client.clone();
-                let simple_base = self.simple_base.clone();
-                let keep_recent = self.keep_recent;
+                let simple_base = self.config.simple_base.clone();
+                let keep_recent = self.config.keep_recent;
+                let stable_only = self.config.stable_only;
                 let progress = progress.clone();
-                let matcher = matcher.clone();
                 let logger = logger.clone();
 
-                let func = {
-                    let logger = logger.clone();
-                    async move {
-                        progress.set_message(&name);
-                        let package = client
-                            .get(&format!("{}/{}/", simple_base, name))
-                            .send()
-                            .await?
-                            .text()
-                            .await?;
-                        let caps: Vec<(String, String)> = matcher
-                            .captures_iter(&package)
-                            .map(|cap| {
-                                let url = format!("{}/{}/{}", simple_base, name, &cap[1]);
-                                let parsed = url::Url::parse(&url).unwrap();
-                                let cleaned: &str = &parsed[..url::Position::AfterPath];
-                                (cleaned.to_string(), cap[2].to_string())
-                            })
-                            .collect();
-                        let caps = if let Some(keep_recent) = keep_recent {
-                            truncate_to_recent(&logger, &name, caps, keep_recent)
+                async move {
+                    progress.set_message(&name);
+                    let page_url = format!("{}/{}/", simple_base, name);
+                    let files = match fetch_with(&client, &page_url, &simple_options()).await {
+                        Ok(page) => parse_project(&page_url, &page),
+                        Err(err) => Err(err),
+                    };
+                    let files = files.map(|files| {
+                        let files = if stable_only {
+                            drop_unstable(&logger, &name, files)
                         } else {
-                            caps
+                            files
                         };
-                        progress.inc(1);
-                        Ok::<Vec<(String, String)>, Error>(caps)
-                    }
-                };
-                async move {
-                    match func.await {
-                        Ok(x) => Ok(x),
-                        Err(err) => {
-                            warn!(logger, "failed to fetch index {:?}", err);
-                            Ok(vec![])
+                        if let Some(keep_recent) = keep_recent {
+                            truncate_to_recent(&logger, &name, files, keep_recent)
+                        } else {
+                            files
                         }
-                    }
+                    });
+                    progress.inc(1);
+                    (name, files)
                 }
             }))
             .buffer_unordered(config.concurrent_resolve)
-            .try_collect()
+            .collect()
             .await;
 
-        let package_base = if self.package_base.ends_with('/') {
-            self.package_base.clone()
+        let mut failed = vec![];
+        for (name, files) in results {
+            match files {
+                Ok(files) => {
+                    resolved.insert(name, files);
+                }
+                Err(err) => {
+                    warn!(logger, "failed to fetch index {:?}", err);
+                    if let Some(files) = fallback.remove(&name) {
+                        resolved.insert(name.clone(), files);
+                    }
+                    failed.push(name);
+                }
+            }
+        }
+
+        let package_base = if self.config.package_base.ends_with('/') {
+            self.config.package_base.clone()
         } else {
-            format!("{}/", self.package_base)
+            format!("{}/", self.config.package_base)
         };
 
-        let snapshot = packages?
-            .into_iter()
+        let snapshot = resolved
+            .values()
             .flatten()
-            .filter_map(|(url, _)| {
-                if url.starts_with(&package_base) {
-                    Some(url[package_base.len()..].to_string())
-                } else {
-                    warn!(logger, "PyPI package isn't stored on base: {:?}", url);
-                    None
-                }
+            .filter_map(|file| {
+                let key = match file.url.strip_prefix(&package_base) {
+                    Some(key) => format!("{}{}", self.package_prefix(), key),
+                    None => {
+                        warn!(logger, "PyPI package isn't stored on base: {:?}", file.url);
+                        return None;
+                    }
+                };
+                let sha256 = file.hashes.get("sha256").cloned();
+                Some(SnapshotMeta {
+                    key,
+                    size: file.size,
+                    last_modified: file
+                        .upload_time
+                        .as_deref()
+                        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                        .map(|time| time.timestamp() as u64),
+                    checksum_method: sha256.as_ref().map(|_| "sha256".to_string()),
+                    checksum: sha256,
+                    ..Default::default()
+                })
             })
             .collect();
 
+        match (state_file, serial) {
+            (Some(path), Some(serial)) => {
+                // failed projects are not saved, so that they are resolved again
+                // next time
+                let failed_projects: Vec<_> = failed
+                    .iter()
+                    .filter_map(|name| resolved.remove_entry(name))
+                    .collect();
+                let state = State {
+                    serial,
+                    projects: resolved,
+                };
+                save_state(path, &state).await?;
+                resolved = state.projects;
+                resolved.extend(failed_projects);
+                info!(
+                    logger,
+                    "saved state of serial {}, {} projects failed",
+                    serial,
+                    failed.len()
+                );
+            }
+            (Some(_), None) => warn!(logger, "no X-PyPI-Last-Serial in index, state not saved"),
+            _ => {}
+        }
+        if self.config.simple_index {
+            self.projects = resolved;
+        }
+
         progress.finish_with_message("done");
 
-        Ok(crate::utils::snapshot_string_to_path(snapshot))
+        Ok(snapshot)
     }
 
     fn info(&self) -> String {
@@ -364,8 +796,481 @@ impl SnapshotStorage<SnapshotPath> for Pypi {
 }
 
 #[async_trait]
-impl SourceStorage<SnapshotPath, TransferURL> for Pypi {
-    async fn get_object(&self, snapshot: &SnapshotPath, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.package_base, snapshot.0)))
+impl SourceStorage<SnapshotMeta, TransferURL> for Pypi {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        let key = snapshot
+            .key
+            .strip_prefix(self.package_prefix())
+            .unwrap_or(&snapshot.key);
+        Ok(TransferURL(format!("{}/{}", self.config.package_base, key)))
+    }
+}
+
+/// Generate the simple index page of a project in HTML (PEP 503).
+fn project_html(name: &str, links: &[(String, &File)]) -> String {
+    let mut anchors = String::new();
+    for (url, file) in links {
+        let mut attributes = String::new();
+        if let Some(requires_python) = &file.requires_python {
+            attributes += &format!(
+                r#" data-requires-python="{}""#,
+                html_escape::encode_double_quoted_attribute(requires_python)
+            );
+        }
+        match &file.yanked {
+            Some(Value::String(reason)) => {
+                attributes += &format!(
+                    r#" data-yanked="{}""#,
+                    html_escape::encode_double_quoted_attribute(reason)
+                )
+            }
+            Some(Value::Bool(true)) => attributes += r#" data-yanked="""#,
+            _ => {}
+        }
+        anchors += &format!(
+            "    <a href=\"{}\"{}>{}</a><br/>\n",
+            html_escape::encode_double_quoted_attribute(url),
+            attributes,
+            html_escape::encode_text(&file.filename)
+        );
+    }
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta name="pypi:repository-version" content="1.1">
+    <title>Links for {name}</title>
+  </head>
+  <body>
+    <h1>Links for {name}</h1>
+{anchors}  </body>
+</html>
+"#,
+        name = html_escape::encode_text(name),
+        anchors = anchors
+    )
+}
+
+/// Generate the simple index page of a project in JSON (PEP 691 and PEP 700).
+fn project_json(name: &str, links: &[(String, &File)]) -> String {
+    let mut versions = BTreeSet::new();
+    let files: Vec<Value> = links
+        .iter()
+        .map(|(url, file)| {
+            if let Some((version, _)) = find_version(name, &file.filename) {
+                versions.insert(version.to_string());
+            }
+            let mut entry = serde_json::json!({
+                "filename": file.filename,
+                "url": url,
+                "hashes": file.hashes,
+                "yanked": file.yanked.clone().unwrap_or(Value::Bool(false)),
+            });
+            if let Some(requires_python) = &file.requires_python {
+                entry["requires-python"] = requires_python.clone().into();
+            }
+            if let Some(size) = file.size {
+                entry["size"] = size.into();
+            }
+            if let Some(upload_time) = &file.upload_time {
+                entry["upload-time"] = upload_time.clone().into();
+            }
+            entry
+        })
+        .collect();
+    serde_json::json!({
+        "meta": { "api-version": "1.1" },
+        "name": name,
+        "versions": versions,
+        "files": files,
+    })
+    .to_string()
+}
+
+/// Generate the root page of simple index in HTML.
+fn root_html<'a>(projects: impl Iterator<Item = &'a String>) -> String {
+    let anchors: String = projects
+        .map(|name| {
+            let name = html_escape::encode_text(name);
+            format!("    <a href=\"{}/\">{}</a><br/>\n", name, name)
+        })
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta name="pypi:repository-version" content="1.1">
+    <title>Simple index</title>
+  </head>
+  <body>
+{}  </body>
+</html>
+"#,
+        anchors
+    )
+}
+
+/// Generate the root page of simple index in JSON.
+fn root_json<'a>(projects: impl Iterator<Item = &'a String>) -> String {
+    let projects: Vec<Value> = projects
+        .map(|name| serde_json::json!({ "name": name }))
+        .collect();
+    serde_json::json!({
+        "meta": { "api-version": "1.1" },
+        "projects": projects,
+    })
+    .to_string()
+}
+
+/// `SimpleIndexPipe` adds simple index pages of projects resolved by `Pypi`
+/// source, if `simple_index` is enabled. Pages are generated on demand, and
+/// only their sizes and checksums are kept after snapshot. Pages only link
+/// files left by filters, and projects with all files filtered out are
+/// omitted.
+pub struct SimpleIndexPipe {
+    source: ByteStreamPipe<Filtered<SnapshotCachePipe<Pypi>>>,
+    buffer_path: String,
+    projects: BTreeMap<String, Vec<File>>,
+    package_base: String,
+}
+
+impl SimpleIndexPipe {
+    pub fn new(
+        source: ByteStreamPipe<Filtered<SnapshotCachePipe<Pypi>>>,
+        buffer_path: String,
+    ) -> Self {
+        Self {
+            source,
+            buffer_path,
+            projects: BTreeMap::new(),
+            package_base: String::new(),
+        }
+    }
+
+    /// Links to files of a project on the mirror, relative to its pages.
+    fn links(&self, name: &str, with_fragment: bool) -> Option<Vec<(String, &File)>> {
+        Some(
+            self.projects
+                .get(name)?
+                .iter()
+                .filter_map(|file| {
+                    let key = file.url.strip_prefix(&self.package_base)?;
+                    let url = format!("../../packages/{}", key);
+                    match file.hashes.get("sha256") {
+                        Some(sha256) if with_fragment => {
+                            Some((format!("{}#sha256={}", url, sha256), file))
+                        }
+                        _ => Some((url, file)),
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Generate a page, returning its content and content type.
+    fn page(&self, key: &str) -> Option<(String, &'static str)> {
+        if !self.source.source.inner().source.config.simple_index {
+            return None;
+        }
+        let path = key.strip_prefix("simple/")?;
+        match path.rsplit_once('/') {
+            None if path == "index.html" => Some((root_html(self.projects.keys()), "text/html")),
+            None if path == "index.v1_json" => Some((root_json(self.projects.keys()), SIMPLE_JSON)),
+            Some((name, "index.html")) => {
+                Some((project_html(name, &self.links(name, true)?), "text/html"))
+            }
+            // hashes are given in JSON instead of URL fragments
+            Some((name, "index.v1_json")) => {
+                Some((project_json(name, &self.links(name, false)?), SIMPLE_JSON))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for SimpleIndexPipe {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        let pypi = &mut self.source.source.inner_mut().source;
+        if !pypi.config.simple_index {
+            return Ok(snapshot);
+        }
+        self.projects = std::mem::take(&mut pypi.projects);
+        self.package_base = format!("{}/", pypi.config.package_base.trim_end_matches('/'));
+
+        let selected: HashSet<&str> = snapshot.iter().map(|meta| meta.key.as_str()).collect();
+        let package_base = &self.package_base;
+        self.projects.retain(|_, files| {
+            if files.is_empty() {
+                return true;
+            }
+            files.retain(|file| {
+                file.url
+                    .strip_prefix(package_base.as_str())
+                    .is_some_and(|key| selected.contains(format!("packages/{}", key).as_str()))
+            });
+            !files.is_empty()
+        });
+
+        let mut keys = vec![
+            "simple/index.html".to_string(),
+            "simple/index.v1_json".to_string(),
+        ];
+        for name in self.projects.keys() {
+            keys.push(format!("simple/{}/index.html", name));
+            keys.push(format!("simple/{}/index.v1_json", name));
+        }
+        // Pages are compared by size and checksum instead of being forced, so
+        // that only pages of changed projects are transferred. They are
+        // transferred after packages.
+        for key in keys {
+            let (content, _) = self.page(&key).unwrap();
+            snapshot.push(SnapshotMeta {
+                key,
+                size: Some(content.len() as u64),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some(format!("{:x}", sha2::Sha256::digest(content.as_bytes()))),
+                flags: SnapshotMetaFlag {
+                    force: false,
+                    force_last: true,
+                    after_all: false,
+                },
+                ..Default::default()
+            });
+        }
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("SimpleIndexPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for SimpleIndexPipe {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        if let Some((content, content_type)) = self.page(key) {
+            let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+            let path = Path::new(&self.buffer_path).join(pipe_file);
+            let mut f = BufWriter::new(
+                tokio::fs::OpenOptions::default()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .read(true)
+                    .open(&path)
+                    .await?,
+            );
+            f.write_all(content.as_bytes()).await?;
+            f.flush().await?;
+            let mut f = f.into_inner();
+            f.seek(std::io::SeekFrom::Start(0)).await?;
+            Ok(ByteStream {
+                object: ByteObject::LocalFile {
+                    file: Some(f),
+                    path: Some(path),
+                },
+                length: content.len() as u64,
+                modified_at: unix_time(),
+                content_type: Some(content_type.to_string()),
+                checksum: None,
+                etag: None,
+            })
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_project() {
+        let page = r#"{
+  "meta": {"api-version": "1.1"},
+  "name": "six",
+  "files": [
+    {
+      "filename": "six-1.16.0-py2.py3-none-any.whl",
+      "url": "../../packages/d9/5a/six-1.16.0-py2.py3-none-any.whl#sha256=8abb",
+      "hashes": {"sha256": "8abb"},
+      "requires-python": ">=2.7",
+      "size": 11053,
+      "upload-time": "2021-05-05T14:18:17.237055Z",
+      "yanked": false
+    },
+    {
+      "filename": "six-1.0.0.tar.gz",
+      "url": "https://files.pythonhosted.org/packages/ab/cd/six-1.0.0.tar.gz",
+      "hashes": {}
+    }
+  ]
+}"#;
+        let files = parse_project("https://pypi.org/simple/six/", page.as_bytes()).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].url,
+            "https://pypi.org/packages/d9/5a/six-1.16.0-py2.py3-none-any.whl"
+        );
+        assert_eq!(files[0].size, Some(11053));
+        assert_eq!(files[0].hashes["sha256"], "8abb");
+        assert_eq!(
+            files[0].upload_time.as_deref(),
+            Some("2021-05-05T14:18:17.237055Z")
+        );
+        assert_eq!(
+            files[1].url,
+            "https://files.pythonhosted.org/packages/ab/cd/six-1.0.0.tar.gz"
+        );
+        assert_eq!(files[1].size, None);
+    }
+
+    #[test]
+    fn test_retention() {
+        assert_eq!(
+            version_from_filename("foo-bar", "foo_bar-1.0rc1-py3-none-any.whl"),
+            Version::parse("1.0rc1").ok()
+        );
+        assert_eq!(
+            version_from_filename("foo-bar", "Foo.Bar-2.0.tar.gz"),
+            Version::parse("2.0").ok()
+        );
+        assert_eq!(
+            version_from_filename("foo-bar", "foo-bar-0.9.win-amd64-py2.7.exe"),
+            Version::parse("0.9").ok()
+        );
+        assert_eq!(version_from_filename("foo-bar", "other-1.0.tar.gz"), None);
+
+        let logger = crate::utils::create_logger(0);
+        let files: Vec<File> = [
+            "foo_bar-2.0-py3-none-any.whl",
+            "foo-bar-2.0.tar.gz",
+            "foo-bar-2.1.dev1.tar.gz",
+            "foo-bar-1.0.tar.gz",
+            "foo-bar-0.9.tar.gz",
+            "foo-bar-3.0a1.tar.gz",
+        ]
+        .iter()
+        .map(|filename| File {
+            filename: filename.to_string(),
+            url: format!("https://files.pythonhosted.org/packages/{}", filename),
+            hashes: HashMap::new(),
+            size: None,
+            upload_time: None,
+            requires_python: None,
+            yanked: None,
+        })
+        .collect();
+        let stable = drop_unstable(&logger, "foo-bar", files.clone());
+        assert_eq!(stable.len(), 4);
+        let mut recent: Vec<String> = truncate_to_recent(&logger, "foo-bar", stable, 2)
+            .into_iter()
+            .map(|file| file.filename)
+            .collect();
+        recent.sort();
+        assert_eq!(
+            recent,
+            vec![
+                "foo-bar-1.0.tar.gz",
+                "foo-bar-2.0.tar.gz",
+                "foo_bar-2.0-py3-none-any.whl"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simple_index_pages() {
+        let file = File {
+            filename: "foo_bar-1.0-py3-none-any.whl".to_string(),
+            url: "https://files.pythonhosted.org/packages/ab/cd/foo_bar-1.0-py3-none-any.whl"
+                .to_string(),
+            hashes: vec![("sha256".to_string(), "abcd".to_string())]
+                .into_iter()
+                .collect(),
+            size: Some(42),
+            upload_time: None,
+            requires_python: Some(">=3.8".to_string()),
+            yanked: Some(Value::String("broken".to_string())),
+        };
+        let links = vec![(
+            "../../packages/ab/cd/foo_bar-1.0-py3-none-any.whl#sha256=abcd".to_string(),
+            &file,
+        )];
+        let html = project_html("foo-bar", &links);
+        assert!(html.contains(
+            r#"<a href="../../packages/ab/cd/foo_bar-1.0-py3-none-any.whl#sha256=abcd" data-requires-python="&gt;=3.8" data-yanked="broken">foo_bar-1.0-py3-none-any.whl</a><br/>"#
+        ));
+
+        let json: Value = serde_json::from_str(&project_json("foo-bar", &links)).unwrap();
+        assert_eq!(json["meta"]["api-version"], "1.1");
+        assert_eq!(json["versions"], serde_json::json!(["1.0"]));
+        assert_eq!(json["files"][0]["hashes"]["sha256"], "abcd");
+        assert_eq!(json["files"][0]["requires-python"], ">=3.8");
+        assert_eq!(json["files"][0]["yanked"], "broken");
+        assert_eq!(json["files"][0]["size"], 42);
+    }
+
+    #[test]
+    fn test_parse_requirements() {
+        let requirements = "# comment
+-r base.txt
+--index-url https://pypi.org/simple
+Django>=4.2,<5  # web
+requests[socks]==2.31.0
+zope.interface
+numpy; python_version >= \"3.9\"
+pkg @ https://example.com/pkg.tar.gz
+https://example.com/other.tar.gz
+";
+        assert_eq!(
+            parse_requirements(requirements),
+            vec!["django", "requests", "zope-interface", "numpy", "pkg"]
+        );
+    }
+
+    #[test]
+    fn test_parse_changelog() {
+        let response = r#"<?xml version='1.0'?>
+<methodResponse>
+<params>
+<param>
+<value><array><data>
+<value><array><data>
+<value><string>Foo_Bar</string></value>
+<value><string>1.0</string></value>
+<value><int>1700000000</int></value>
+<value><string>new release</string></value>
+<value><int>20000001</int></value>
+</data></array></value>
+<value><array><data>
+<value>six</value>
+<value><nil/></value>
+<value><int>1700000001</int></value>
+<value><string>remove project</string></value>
+<value><int>20000002</int></value>
+</data></array></value>
+</data></array></value>
+</param>
+</params>
+</methodResponse>"#;
+        let names = parse_changelog(response).unwrap();
+        assert_eq!(names, vec!["Foo_Bar", "six"]);
+        assert_eq!(normalize(&names[0]), "foo-bar");
+        assert_eq!(normalize("zope.interface"), "zope-interface");
+
+        let fault = "<?xml version='1.0'?><methodResponse><fault><value><struct></struct></value></fault></methodResponse>";
+        assert!(parse_changelog(fault).is_err());
     }
 }
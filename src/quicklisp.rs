@@ -0,0 +1,245 @@
+//! Quicklisp source
+//!
+//! Quicklisp source mirrors the latest version of a Quicklisp dist. The dist
+//! file (`dist/quicklisp.txt`) points to `distinfo.txt`, `systems.txt` and
+//! `releases.txt` of the latest version, where `releases.txt` lists release
+//! tarballs with sizes and md5 checksums. All files are placed at the same
+//! paths as upstream, e.g. `archive/alexandria/2023-10-21/...tgz`.
+//!
+//! Index files are transferred at the end, followed by the dist file. If
+//! `target_mirror` is set, they should be piped through `RewritePipe` which
+//! replaces `base` with `target_mirror`, so that the Quicklisp client fetches
+//! everything from the mirror.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct QuicklispConfig {
+    #[structopt(
+        long,
+        default_value = "http://beta.quicklisp.org",
+        help = "Base of Quicklisp"
+    )]
+    pub base: String,
+    #[structopt(long, default_value = "quicklisp", help = "Name of dist to mirror")]
+    pub dist: String,
+    #[structopt(long, help = "Mirror URL to rewrite dist metadata to")]
+    pub target_mirror: Option<String>,
+}
+
+pub struct Quicklisp {
+    pub config: QuicklispConfig,
+    /// key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+/// Index files referred by a dist file.
+const INDEX_FIELDS: &[&str] = &[
+    "system-index-url",
+    "release-index-url",
+    "canonical-distinfo-url",
+];
+
+/// A release in `releases.txt`.
+#[derive(Debug, PartialEq)]
+struct Release {
+    url: String,
+    size: Option<u64>,
+    md5: String,
+}
+
+/// Parse `key: value` lines of a dist file.
+fn parse_dist(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Parse `releases.txt`, whose lines are
+/// `project url size file-md5 content-sha1 prefix [system-file1..system-fileN]`.
+fn parse_releases(content: &str) -> Vec<Release> {
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 4 {
+                return None;
+            }
+            Some(Release {
+                url: parts[1].to_string(),
+                size: parts[2].parse().ok(),
+                md5: parts[3].to_string(),
+            })
+        })
+        .collect()
+}
+
+impl Quicklisp {
+    pub fn new(config: QuicklispConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+
+    /// Resolve upstream URL to the mirror key, which is its path relative to
+    /// `base`. URLs elsewhere are keyed by their path.
+    fn key(&self, url: &str) -> Result<String> {
+        let key = match url.strip_prefix(&format!("{}/", self.config.base)) {
+            Some(key) => key.to_string(),
+            None => url::Url::parse(url)
+                .map_err(|err| Error::ProcessError(format!("invalid URL {}: {:?}", url, err)))?
+                .path()
+                .trim_start_matches('/')
+                .to_string(),
+        };
+        if key.is_empty() {
+            return Err(Error::ProcessError(format!("invalid URL {}", url)));
+        }
+        Ok(key)
+    }
+}
+
+impl std::fmt::Debug for Quicklisp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Quicklisp {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let dist_key = format!("dist/{}.txt", self.config.dist);
+        info!(logger, "fetching {}...", dist_key);
+        progress.set_message(&dist_key);
+        let dist =
+            parse_dist(&fetch_text(&client, &format!("{}/{}", self.config.base, dist_key)).await?);
+        info!(
+            logger,
+            "version: {}",
+            dist.get("version").map(|v| v.as_str()).unwrap_or("unknown")
+        );
+
+        let release_index = dist
+            .get("release-index-url")
+            .ok_or_else(|| Error::ProcessError("no release-index-url in dist".to_string()))?;
+        info!(logger, "fetching releases...");
+        let releases = parse_releases(&fetch_text(&client, release_index).await?);
+
+        let mut snapshot = vec![];
+        for release in releases {
+            let key = self.key(&release.url)?;
+            if self.urls.contains_key(&key) {
+                continue;
+            }
+            snapshot.push(SnapshotMeta {
+                key: key.clone(),
+                size: release.size,
+                checksum_method: Some("md5".to_string()),
+                checksum: Some(release.md5),
+                ..Default::default()
+            });
+            self.urls.insert(key, release.url);
+        }
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} releases ({})",
+            snapshot.len(),
+            human_size(total_size)
+        );
+
+        for field in INDEX_FIELDS {
+            if let Some(url) = dist.get(*field) {
+                let key = self.key(url)?;
+                snapshot.push(SnapshotMeta::force(key.clone()));
+                self.urls.insert(key, url.clone());
+            }
+        }
+        snapshot.push(SnapshotMeta::force(dist_key));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("quicklisp, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Quicklisp {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(match self.urls.get(&snapshot.key) {
+            Some(url) => url.clone(),
+            None => format!("{}/{}", self.config.base, snapshot.key),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dist_and_releases() {
+        let dist = parse_dist(
+            "name: quicklisp
+version: 2023-10-21
+release-index-url: http://beta.quicklisp.org/dist/quicklisp/2023-10-21/releases.txt
+",
+        );
+        assert_eq!(
+            dist["release-index-url"],
+            "http://beta.quicklisp.org/dist/quicklisp/2023-10-21/releases.txt"
+        );
+
+        let releases = parse_releases(
+            "# project url size file-md5 content-sha1 prefix [system-file1..system-fileN]
+1am http://beta.quicklisp.org/archive/1am/2014-11-06/1am-20141106-git.tgz 3133 c5e83c329157518e3ebfeef63e4ac269 0d2b3f4cf7fd4d9b2f4ab3f8e4d6a0a8d9b0b1c2 1am-20141106-git 1am.asd
+",
+        );
+        assert_eq!(
+            releases,
+            vec![Release {
+                url: "http://beta.quicklisp.org/archive/1am/2014-11-06/1am-20141106-git.tgz"
+                    .to_string(),
+                size: Some(3133),
+                md5: "c5e83c329157518e3ebfeef63e4ac269".to_string(),
+            }]
+        );
+
+        let source = Quicklisp::new(QuicklispConfig {
+            base: "http://beta.quicklisp.org".to_string(),
+            dist: "quicklisp".to_string(),
+            target_mirror: None,
+        });
+        assert_eq!(
+            source.key(&releases[0].url).unwrap(),
+            "archive/1am/2014-11-06/1am-20141106-git.tgz"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Raspberry Pi source
+//!
+//! Raspberry Pi source mirrors an APT repository of Raspberry Pi OS, which
+//! is either the Raspberry Pi archive (`archive.raspberrypi.org/debian`) or
+//! Raspbian (`raspbian.raspberrypi.org/raspbian`), together with OS images.
+//!
+//! Images are scanned from HTML index of each image directory (e.g.
+//! `raspios_lite_arm64/images`), where only the latest `images_to_retain`
+//! image sets (e.g. `raspios_lite_arm64-2023-12-11`) are kept. Images are
+//! yielded with sha256 checksums from their `.sha256` sidecar files, and
+//! placed under `images/` of the mirror.
+
+use async_trait::async_trait;
+use regex::Regex;
+use reqwest::Client;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::apt::Apt;
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::distro_image::{latest_sets, parse_sums};
+use crate::error::Result;
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Raspbian {
+    #[structopt(
+        long,
+        default_value = "https://archive.raspberrypi.org/debian",
+        help = "Base of APT repository"
+    )]
+    pub base: String,
+    #[structopt(long, default_value = "bookworm,bullseye", use_delimiter = true)]
+    pub suites: Vec<String>,
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Components to mirror (all if not set)"
+    )]
+    pub components: Vec<String>,
+    #[structopt(long, default_value = "arm64,armhf", use_delimiter = true)]
+    pub architectures: Vec<String>,
+    #[structopt(
+        long,
+        default_value = "https://downloads.raspberrypi.org",
+        help = "Base of image directories"
+    )]
+    pub images_base: String,
+    #[structopt(
+        long,
+        use_delimiter = true,
+        help = "Image directories to mirror, e.g. raspios_lite_arm64"
+    )]
+    pub images: Vec<String>,
+    #[structopt(long, default_value = "1", help = "Image sets to retain")]
+    pub images_to_retain: usize,
+}
+
+/// Prefix of image keys in the mirror.
+const IMAGES_PREFIX: &str = "images/";
+
+/// Files in an HTML index, excluding directories and links to other pages.
+fn listed_files(index: &str) -> Vec<String> {
+    let href = Regex::new(r#"<a[^>]*href="([^"/?#]+)""#).unwrap();
+    href.captures_iter(index)
+        .map(|cap| cap[1].to_string())
+        .collect()
+}
+
+/// Generate snapshot of an image set `{images_base}/{dir}`.
+async fn image_set_snapshot(
+    client: &Client,
+    images_base: &str,
+    dir: &str,
+) -> Result<Vec<SnapshotMeta>> {
+    let files = listed_files(&fetch_text(client, &format!("{}/{}/", images_base, dir)).await?);
+    let mut snapshot = vec![];
+    for file in &files {
+        let mut meta = SnapshotMeta::new(format!("{}{}/{}", IMAGES_PREFIX, dir, file));
+        let sidecar = format!("{}.sha256", file);
+        if files.contains(&sidecar) {
+            let sums = fetch_text(client, &format!("{}/{}/{}", images_base, dir, sidecar)).await?;
+            if let Some((checksum, _)) = parse_sums(&sums).into_iter().next() {
+                meta.checksum_method = Some("sha256".to_string());
+                meta.checksum = Some(checksum);
+            }
+        }
+        snapshot.push(meta);
+    }
+    Ok(snapshot)
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Raspbian {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let apt = Apt {
+            base: self.base.clone(),
+            suites: self.suites.clone(),
+            components: self.components.clone(),
+            architectures: self.architectures.clone(),
+            exclude: None,
+        };
+        let mut snapshot = apt.snapshot(&mission).await?;
+
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let href = Regex::new(r#"<a[^>]*href="([^"]*)""#).unwrap();
+        for image in &self.images {
+            let dir = format!("{}/images", image.trim_matches('/'));
+            info!(logger, "listing image sets of {}...", dir);
+            progress.set_message(&dir);
+            let index = fetch_text(&client, &format!("{}/{}/", self.images_base, dir)).await?;
+            let hrefs: Vec<String> = href
+                .captures_iter(&index)
+                .map(|cap| cap[1].to_string())
+                .collect();
+            let pattern = Regex::new(&format!(
+                r"^{}-\d{{4}}-\d{{2}}-\d{{2}}$",
+                regex::escape(image.trim_matches('/'))
+            ))
+            .unwrap();
+            let sets = latest_sets(&hrefs, &pattern, self.images_to_retain);
+            if sets.is_empty() {
+                warn!(logger, "no image set found in {}", dir);
+            }
+            for set in sets {
+                let set_dir = format!("{}/{}", dir, set);
+                snapshot.extend(image_set_snapshot(&client, &self.images_base, &set_dir).await?);
+            }
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("raspbian, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Raspbian {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(
+            match snapshot.key.strip_prefix(IMAGES_PREFIX) {
+                Some(path) => format!("{}/{}", self.images_base, path),
+                None => format!("{}/{}", self.base, snapshot.key),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listed_files() {
+        let index = r#"<tr><td><a href="/raspios_lite_arm64/images/">Parent Directory</a></td></tr>
+<tr><td><a href="?C=M;O=A">Last modified</a></td></tr>
+<tr><td><a href="2023-12-11-raspios-bookworm-arm64-lite.img.xz">2023-12-11-raspios-bookworm-arm64-lite.img.xz</a></td></tr>
+<tr><td><a href="2023-12-11-raspios-bookworm-arm64-lite.img.xz.sha256">2023-12-11-raspios-bookworm-arm64-lite.img.xz.sha256</a></td></tr>
+<tr><td><a href="subdir/">subdir/</a></td></tr>"#;
+        assert_eq!(
+            listed_files(index),
+            vec![
+                "2023-12-11-raspios-bookworm-arm64-lite.img.xz",
+                "2023-12-11-raspios-bookworm-arm64-lite.img.xz.sha256"
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! RetryPipe retries `get_object` of source on transient errors.
+//!
+//! Without retry, an object whose download fails once (e.g. on a dropped
+//! connection or a 502 of upstream) is skipped for the whole run. This pipe
+//! retries such failures with exponential backoff: timeouts, connection
+//! errors, 5xx and 429 responses. `Retry-After` of 429 and 503 responses is
+//! respected, capped at `max_delay`.
+//!
+//! Objects not modified since previous download, or whose host is paused by
+//! circuit breaker, are not retried.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use slog::warn;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::{Error, Result};
+use crate::fetch::is_transient;
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct RetryConfig {
+    #[structopt(
+        long,
+        help = "Retries of downloading an object on transient errors",
+        default_value = "3"
+    )]
+    pub get_retries: u32,
+    #[structopt(
+        long,
+        help = "Milliseconds to wait before the first retry of downloading an object, doubled on each retry",
+        default_value = "1000"
+    )]
+    pub get_retry_delay: u64,
+}
+
+impl RetryConfig {
+    pub fn into_pipe<Source>(self, source: Source) -> RetryPipe<Source> {
+        RetryPipe::new(
+            source,
+            self.get_retries,
+            Duration::from_millis(self.get_retry_delay),
+        )
+    }
+}
+
+pub struct RetryPipe<Source> {
+    pub source: Source,
+    /// retries after the first attempt
+    pub retries: u32,
+    /// delay before the first retry
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl<Source> RetryPipe<Source> {
+    pub fn new(source: Source, retries: u32, base_delay: Duration) -> Self {
+        Self {
+            source,
+            retries,
+            base_delay,
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    /// Delay before the `attempt`-th retry (from 0) after `err`.
+    fn delay(&self, attempt: u32, err: &Error) -> Duration {
+        match err {
+            Error::RetryAfter(_, retry_after) => *retry_after,
+            _ => self.base_delay.saturating_mul(1 << attempt.min(16)),
+        }
+        .min(self.max_delay)
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for RetryPipe<Source>
+where
+    Snapshot: Send + 'static,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!(
+            "retry {} times from {:?} <{}>",
+            self.retries,
+            self.base_delay,
+            self.source.info()
+        )
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source, SourceItem> SourceStorage<Snapshot, SourceItem> for RetryPipe<Source>
+where
+    Snapshot: Key,
+    Source: SourceStorage<Snapshot, SourceItem>,
+    SourceItem: Send + 'static,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<SourceItem> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.source.get_object(snapshot, mission).await {
+                Ok(item) => return Ok(item),
+                Err(err) => err,
+            };
+            if attempt >= self.retries || !is_transient(&err) {
+                return Err(err);
+            }
+            let delay = self.delay(attempt, &err);
+            warn!(
+                mission.logger,
+                "failed to get {}, retry in {:?}: {:?}",
+                snapshot.key(),
+                delay,
+                err
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_delay() {
+        let pipe = RetryPipe::new((), 3, Duration::from_millis(500));
+        let err = Error::HTTPError(StatusCode::BAD_GATEWAY);
+        assert_eq!(pipe.delay(0, &err), Duration::from_millis(500));
+        assert_eq!(pipe.delay(2, &err), Duration::from_secs(2));
+        assert_eq!(pipe.delay(20, &err), pipe.max_delay);
+
+        let err = Error::RetryAfter(StatusCode::TOO_MANY_REQUESTS, Duration::from_secs(7));
+        assert_eq!(pipe.delay(0, &err), Duration::from_secs(7));
+        let err = Error::RetryAfter(StatusCode::TOO_MANY_REQUESTS, Duration::from_secs(3600));
+        assert_eq!(pipe.delay(0, &err), pipe.max_delay);
+    }
+
+    #[test]
+    fn test_transient() {
+        assert!(is_transient(&Error::HTTPError(StatusCode::BAD_GATEWAY)));
+        assert!(is_transient(&Error::RetryAfter(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Duration::from_secs(1)
+        )));
+        assert!(is_transient(&Error::TimeoutError(())));
+        assert!(!is_transient(&Error::HTTPError(StatusCode::NOT_FOUND)));
+        assert!(!is_transient(&Error::NotModified("index.json".to_string())));
+        assert!(!is_transient(&Error::CircuitOpen(
+            "example.com".to_string()
+        )));
+    }
+}
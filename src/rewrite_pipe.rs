@@ -8,17 +8,24 @@
 //! The rewriting process relies on `ByteStream` which only supports
 //! `LocalFile` currently.
 //! So a new file will be created when rewriting and deleted when dropped.
+//!
+//! Objects larger than `max_length` are passed through without reading. If
+//! `key_filter` is set, only objects of matching keys are rewritten, so that
+//! small text files in packages are never touched by accident.
 
 use async_trait::async_trait;
+use regex::Regex;
 
-use slog::warn;
+use slog::{debug, warn};
 
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
 use crate::stream_pipe::{ByteObject, ByteStream};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
+type KeyFilter = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
 pub struct RewritePipe<Source, RewriteItem, F>
 where
     F: Fn(RewriteItem) -> Result<RewriteItem> + Send + Sync,
@@ -27,6 +34,7 @@ where
     pub buffer_path: String,
     pub rewrite_fn: F,
     pub max_length: u64,
+    pub key_filter: Option<KeyFilter>,
     _phantom: std::marker::PhantomData<RewriteItem>,
 }
 
@@ -40,9 +48,21 @@ where
             buffer_path,
             rewrite_fn,
             max_length,
+            key_filter: None,
             _phantom: Default::default(),
         }
     }
+
+    /// Only rewrite objects whose key satisfies `filter`.
+    pub fn key_filter(mut self, filter: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.key_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Only rewrite objects whose key matches `pattern`.
+    pub fn key_pattern(self, pattern: Regex) -> Self {
+        self.key_filter(move |key| pattern.is_match(key))
+    }
 }
 
 #[async_trait]
@@ -62,6 +82,10 @@ where
         self.source.snapshot(mission, config).await
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!("rewrite <{}>", self.source.info())
     }
@@ -71,7 +95,7 @@ where
 #[async_trait]
 impl<Snapshot, Source, F> SourceStorage<Snapshot, ByteStream> for RewritePipe<Source, String, F>
 where
-    Snapshot: Send + Sync + 'static,
+    Snapshot: Key,
     Source: SourceStorage<Snapshot, ByteStream>,
     F: Fn(String) -> Result<String> + Send + Sync + 'static,
 {
@@ -80,9 +104,34 @@ where
 
         let mut byte_stream = self.source.get_object(snapshot, mission).await?;
 
-        if byte_stream.length > self.max_length {
+        let selected = self
+            .key_filter
+            .as_ref()
+            .map_or(true, |filter| filter(snapshot.key()));
+        if !selected || byte_stream.length > self.max_length {
             Ok(byte_stream)
         } else {
+            if byte_stream.object.is_stream() {
+                // streamed objects are buffered to be rewritten
+                let ByteStream {
+                    object,
+                    length,
+                    modified_at,
+                    content_type,
+                    checksum,
+                    etag,
+                } = byte_stream;
+                byte_stream = ByteStream {
+                    object: object
+                        .into_local(&self.buffer_path, &mission.logger)
+                        .await?,
+                    length,
+                    modified_at,
+                    content_type,
+                    checksum,
+                    etag,
+                };
+            }
             match byte_stream.object {
                 ByteObject::LocalFile {
                     ref mut file,
@@ -91,12 +140,15 @@ where
                     if let Some(ref mut file) = file {
                         let mut buffer = String::new();
                         if file.read_to_string(&mut buffer).await.is_err() {
-                            warn!(logger, "rewrite_pipe: not a valid UTF-8 file, ignored");
+                            // binary files may pass through this pipe, so this is not a warning
+                            debug!(logger, "rewrite_pipe: not a valid UTF-8 file, ignored");
+                            file.seek(std::io::SeekFrom::Start(0)).await?;
                             Ok(byte_stream)
                         } else {
                             match (self.rewrite_fn)(buffer) {
                                 Err(e) => {
                                     warn!(logger, "rewrite_pipe: {:?}, ignored", e);
+                                    file.seek(std::io::SeekFrom::Start(0)).await?;
                                     Ok(byte_stream)
                                 }
                                 Ok(content) => {
@@ -110,6 +162,8 @@ where
                                     file.seek(std::io::SeekFrom::Start(0)).await?;
 
                                     byte_stream.length = content_length;
+                                    byte_stream.checksum = None;
+                                    byte_stream.etag = None;
                                     Ok(byte_stream)
                                 }
                             }
@@ -120,6 +174,7 @@ where
                         )))
                     }
                 }
+                ByteObject::Stream(_) => unreachable!("streamed objects are buffered"),
             }
         }
     }
@@ -0,0 +1,146 @@
+//! ROS source
+//!
+//! ROS source mirrors APT repositories on packages.ros.org for configured
+//! ROS distributions. Each distribution is built for an Ubuntu release in
+//! either the ROS 1 (`ros/ubuntu`) or the ROS 2 (`ros2/ubuntu`) repository,
+//! e.g. `humble` for `jammy` in `ros2/ubuntu`. Suites of the distributions
+//! are mirrored with `Apt`, where packages of other distributions in the
+//! same suite (`ros-{distro}-*`) are skipped. Index files are mirrored as-is,
+//! so they still list packages of other distributions.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use regex::Regex;
+use structopt::StructOpt;
+
+use crate::apt::Apt;
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct Ros {
+    #[structopt(
+        long,
+        default_value = "https://packages.ros.org",
+        help = "Base of ROS repositories"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "ROS distributions to mirror, e.g. noetic,humble",
+        required = true,
+        use_delimiter = true
+    )]
+    pub distros: Vec<String>,
+    #[structopt(long, default_value = "amd64,arm64", use_delimiter = true)]
+    pub architectures: Vec<String>,
+}
+
+/// Known distributions, with their repository and Ubuntu suite.
+const DISTROS: &[(&str, &str, &str)] = &[
+    ("melodic", "ros", "bionic"),
+    ("noetic", "ros", "focal"),
+    ("foxy", "ros2", "focal"),
+    ("galactic", "ros2", "focal"),
+    ("humble", "ros2", "jammy"),
+    ("iron", "ros2", "jammy"),
+    ("jazzy", "ros2", "noble"),
+    ("rolling", "ros2", "noble"),
+];
+
+/// Group distributions by repository and suite, e.g. `ros2/ubuntu` ->
+/// `["jammy"]` for `humble`.
+fn distro_suites(distros: &[String]) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut repos: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for distro in distros {
+        let (_, repo, suite) = DISTROS
+            .iter()
+            .find(|(name, _, _)| name == distro)
+            .ok_or_else(|| Error::ConfigureError(format!("unknown ROS distro {}", distro)))?;
+        let suites = repos.entry(format!("{}/ubuntu", repo)).or_default();
+        if !suites.iter().any(|x| x == suite) {
+            suites.push(suite.to_string());
+        }
+    }
+    Ok(repos)
+}
+
+/// Pattern of package files of distributions not to mirror.
+fn exclude_pattern(distros: &[String]) -> Option<Regex> {
+    let others: Vec<&str> = DISTROS
+        .iter()
+        .map(|(name, _, _)| *name)
+        .filter(|name| !distros.iter().any(|distro| distro == name))
+        .collect();
+    if others.is_empty() {
+        None
+    } else {
+        Some(Regex::new(&format!(r"(^|/)ros-({})-[^/]*$", others.join("|"))).unwrap())
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Ros {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let exclude = exclude_pattern(&self.distros);
+        let mut snapshot = vec![];
+        for (repo, suites) in distro_suites(&self.distros)? {
+            let apt = Apt {
+                base: format!("{}/{}", self.base, repo),
+                suites,
+                components: vec![],
+                architectures: self.architectures.clone(),
+                exclude: exclude.clone(),
+            };
+            snapshot.extend(apt.snapshot(&mission).await?.into_iter().map(|mut meta| {
+                meta.key = format!("{}/{}", repo, meta.key);
+                meta
+            }));
+        }
+
+        mission.progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("ros, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Ros {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distros() {
+        let distros = vec![
+            "noetic".to_string(),
+            "humble".to_string(),
+            "iron".to_string(),
+        ];
+        let repos = distro_suites(&distros).unwrap();
+        assert_eq!(repos["ros/ubuntu"], vec!["focal"]);
+        assert_eq!(repos["ros2/ubuntu"], vec!["jammy"]);
+        assert!(distro_suites(&["hydro".to_string()]).is_err());
+
+        let exclude = exclude_pattern(&distros).unwrap();
+        assert!(exclude.is_match("pool/main/r/ros-rolling-rclcpp/ros-rolling-rclcpp_1.0_amd64.deb"));
+        assert!(!exclude.is_match("pool/main/r/ros-humble-rclcpp/ros-humble-rclcpp_1.0_amd64.deb"));
+        assert!(!exclude.is_match("pool/main/p/python3-rosdep/python3-rosdep_0.22_all.deb"));
+    }
+}
@@ -2,15 +2,25 @@
 //!
 //! Rsync endpoint helps synchronize files on rsync daemon to other targets.
 //! This is done by first running rsync program to get a file list, then
-//! downlaod them over HTTP. Currently, symbolic links are not supported.
+//! downlaod them over HTTP.
 //!
 //! Rsync snapshot provides a snapshot with metadata, which includes path, size,
 //! and file modified time.
 //!
+//! Symbolic links are skipped by default. With `--resolve-symlinks`, links
+//! inside the rsync module are resolved to their targets: a link to a file
+//! becomes a copy of that file, and a link to a directory becomes copies of
+//! all files under it. Hard links are listed as regular files by rsync, so
+//! they are always transferred as separate copies.
+//!
 //! Note that we do not ensure consistency between Rsync snapshot and HTTP downloads.
 //! Some servers serve different files under Rsync and HTTP. For example, mirrors.tuna
 //! has two servers, and HTTP contents may be not exactly the same as rsync. Users
 //! must ensure what's served on HTTP is really what's inside rsync.
+//!
+//! To avoid such inconsistency, `RsyncFetch` downloads files over rsync
+//! protocol instead. Requested files are collected into batches, and each
+//! batch is fetched by one rsync process with `--files-from`.
 
 use crate::error::Result;
 use crate::traits::{SnapshotStorage, SourceStorage};
@@ -18,14 +28,22 @@ use crate::traits::{SnapshotStorage, SourceStorage};
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::Error;
 use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::utils::{hash_string, unix_time};
 
 use async_trait::async_trait;
 use chrono::TimeZone;
+use filetime::FileTime;
 use slog::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug, Clone, StructOpt)]
 pub struct Rsync {
@@ -42,6 +60,19 @@ pub struct Rsync {
     /// Prefix to ignore. If this is an empty string, all objects are transferred.
     #[structopt(long, help = "Prefix to ignore", default_value = "")]
     pub ignore_prefix: String,
+    #[structopt(long, help = "Fetch file content over rsync instead of HTTP")]
+    pub fetch_over_rsync: bool,
+    #[structopt(
+        long,
+        help = "Max files to fetch in one rsync process",
+        default_value = "64"
+    )]
+    pub rsync_batch_size: usize,
+    #[structopt(long, help = "Resolve symbolic links to their targets")]
+    pub resolve_symlinks: bool,
+    /// key of resolved symbolic link -> key of target file
+    #[structopt(skip)]
+    links: HashMap<String, String>,
 }
 
 fn parse_rsync_output(line: &str) -> Result<(&str, &str, &str, &str, &str)> {
@@ -90,6 +121,7 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
         });
 
         let mut snapshot = vec![];
+        let mut symlinks = HashMap::new();
         let mut idx: usize = 0;
 
         let timezone = chrono::Local::now().timezone();
@@ -119,7 +151,12 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
                     snapshot.push(meta);
                 }
                 if permission.starts_with('l') {
-                    warn!(logger, "symbolic link is not supported: {}", file);
+                    match file.split_once(" -> ") {
+                        Some((link, target)) if self.resolve_symlinks => {
+                            symlinks.insert(link.to_string(), target.to_string());
+                        }
+                        _ => warn!(logger, "symbolic link is not supported: {}", file),
+                    }
                 }
             }
         }
@@ -131,6 +168,34 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
             return Err(Error::ProcessError(format!("exit code: {:?}", status)));
         }
 
+        if !symlinks.is_empty() {
+            progress.set_message("resolving symbolic links");
+            let files: BTreeMap<String, SnapshotMeta> = snapshot
+                .iter()
+                .map(|meta| (meta.key.clone(), meta.clone()))
+                .collect();
+            let mut unresolved = 0;
+            for (link, target) in resolve_symlinks(&files, &symlinks) {
+                match target {
+                    Some(target) => {
+                        let meta = &files[&target];
+                        snapshot.push(SnapshotMeta {
+                            key: link.clone(),
+                            ..meta.clone()
+                        });
+                        self.links.insert(link, target);
+                    }
+                    None => unresolved += 1,
+                }
+            }
+            info!(
+                logger,
+                "{} symbolic links resolved, {} unresolved",
+                self.links.len(),
+                unresolved
+            );
+        }
+
         progress.finish_with_message("done");
 
         Ok(snapshot)
@@ -141,9 +206,379 @@ impl SnapshotStorage<SnapshotMeta> for Rsync {
     }
 }
 
+/// Join `target` of symbolic link `link` to the directory of link. Returns
+/// `None` if the target is outside rsync module.
+fn join_link(link: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut path: Vec<&str> = link.split('/').collect();
+    path.pop();
+    for segment in target.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                path.pop()?;
+            }
+            segment => path.push(segment),
+        }
+    }
+    Some(path.join("/"))
+}
+
+/// Follow symbolic links in `key`, including those of its parent directories.
+fn follow_links(key: &str, symlinks: &HashMap<String, String>) -> Option<String> {
+    let mut key = key.to_string();
+    // guard against loops of links
+    for _ in 0..32 {
+        let link = std::iter::once(key.as_str())
+            .chain(key.match_indices('/').map(|(idx, _)| &key[..idx]))
+            .find(|prefix| symlinks.contains_key(*prefix));
+        match link {
+            Some(link) => {
+                let target = join_link(link, &symlinks[link])?;
+                key = format!("{}{}", target, &key[link.len()..]);
+            }
+            None => return Some(key),
+        }
+    }
+    None
+}
+
+/// Resolve symbolic links to files in `files`. Links to directories are
+/// expanded to every file under them. Returns keys of links with their
+/// target files, or `None` if the target doesn't exist.
+fn resolve_symlinks(
+    files: &BTreeMap<String, SnapshotMeta>,
+    symlinks: &HashMap<String, String>,
+) -> Vec<(String, Option<String>)> {
+    let mut resolved = vec![];
+    for link in symlinks.keys() {
+        let target = match follow_links(link, symlinks) {
+            Some(target) => target,
+            None => {
+                resolved.push((link.clone(), None));
+                continue;
+            }
+        };
+        if files.contains_key(&target) {
+            resolved.push((link.clone(), Some(target)));
+            continue;
+        }
+        let dir = format!("{}/", target);
+        let children: Vec<_> = files
+            .range(dir.clone()..)
+            .take_while(|(key, _)| key.starts_with(&dir))
+            .map(|(key, _)| (format!("{}/{}", link, &key[dir.len()..]), Some(key.clone())))
+            .collect();
+        if children.is_empty() {
+            resolved.push((link.clone(), None));
+        }
+        resolved.extend(children);
+    }
+    resolved
+}
+
+impl Rsync {
+    /// Key of file to download for `key`, which is the target of resolved
+    /// symbolic links.
+    fn source_key<'a>(&'a self, key: &'a str) -> &'a str {
+        self.links.get(key).map(String::as_str).unwrap_or(key)
+    }
+}
+
 #[async_trait]
 impl SourceStorage<SnapshotMeta, TransferURL> for Rsync {
     async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
-        Ok(TransferURL(format!("{}/{}", self.http_base, snapshot.key)))
+        Ok(TransferURL(format!(
+            "{}/{}",
+            self.http_base,
+            self.source_key(&snapshot.key)
+        )))
+    }
+}
+
+/// sequence number of batches and buffer files, to avoid conflicts of names
+static BATCH_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+struct FetchRequest {
+    key: String,
+    reply: oneshot::Sender<Result<PathBuf>>,
+}
+
+/// Directory of rsync endpoint which keys in snapshot are relative to. Without
+/// trailing slash, rsync lists the directory itself instead of its content.
+fn rsync_root(rsync_base: &str) -> &str {
+    if rsync_base.ends_with('/') {
+        rsync_base
+    } else {
+        match rsync_base.rfind('/') {
+            Some(idx) => &rsync_base[..=idx],
+            None => rsync_base,
+        }
+    }
+}
+
+/// Fetch files of a batch into a temporary directory with one rsync process,
+/// and move them to buffer files.
+async fn fetch_batch(
+    rsync_base: &str,
+    buffer_path: &str,
+    batch: Vec<FetchRequest>,
+    logger: &slog::Logger,
+) {
+    let batch_dir = format!(
+        "{}/rsync.{}.{}",
+        buffer_path,
+        unix_time(),
+        BATCH_SEQ.fetch_add(1, Ordering::Relaxed)
+    );
+    let list_path = format!("{}.list", batch_dir);
+    let mut list = String::new();
+    for request in &batch {
+        list.push_str(&request.key);
+        list.push('\n');
+    }
+
+    let status = async {
+        tokio::fs::create_dir_all(&batch_dir).await?;
+        tokio::fs::write(&list_path, list).await?;
+        let status = Command::new("rsync")
+            .kill_on_drop(true)
+            .arg("-t")
+            .arg("--no-motd")
+            .arg(format!("--files-from={}", list_path))
+            .arg(rsync_root(rsync_base))
+            .arg(format!("{}/", batch_dir))
+            .stdout(Stdio::null())
+            .status()
+            .await?;
+        Ok::<_, Error>(status)
+    }
+    .await;
+    match &status {
+        // some files may be missing on partial transfer (exit code 23 or 24)
+        Ok(status) if !status.success() => {
+            warn!(logger, "rsync exited with {:?} when fetching batch", status)
+        }
+        Err(err) => warn!(logger, "failed to run rsync: {:?}", err),
+        _ => {}
+    }
+
+    for request in batch {
+        let result = async {
+            let path = format!(
+                "{}/{}.{}.{}.buffer",
+                buffer_path,
+                hash_string(&request.key),
+                unix_time(),
+                BATCH_SEQ.fetch_add(1, Ordering::Relaxed)
+            );
+            // links of the same file may be requested in one batch, so the
+            // fetched file is not moved
+            tokio::fs::hard_link(format!("{}/{}", batch_dir, request.key), &path)
+                .await
+                .map_err(|err| {
+                    Error::ProcessError(format!("rsync didn't fetch {}: {:?}", request.key, err))
+                })?;
+            Ok(PathBuf::from(path))
+        }
+        .await;
+        if let Err(Ok(path)) = request.reply.send(result) {
+            // the request is cancelled
+            tokio::fs::remove_file(path).await.ok();
+        }
+    }
+
+    tokio::fs::remove_dir_all(&batch_dir).await.ok();
+    tokio::fs::remove_file(&list_path).await.ok();
+}
+
+async fn fetch_worker(
+    rsync_base: String,
+    buffer_path: String,
+    batch_size: usize,
+    logger: slog::Logger,
+    mut requests: mpsc::Receiver<FetchRequest>,
+) {
+    while let Some(request) = requests.recv().await {
+        let mut batch = vec![request];
+        // collect requests arriving in a short time into the same batch
+        let deadline = tokio::time::sleep(Duration::from_millis(200));
+        tokio::pin!(deadline);
+        while batch.len() < batch_size {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => batch.push(request),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        fetch_batch(&rsync_base, &buffer_path, batch, &logger).await;
+    }
+}
+
+/// `RsyncFetch` is a wrapper on `Rsync` source, which fetches files over rsync
+/// into `buffer_path` and yields `ByteStream`.
+pub struct RsyncFetch {
+    source: Rsync,
+    buffer_path: String,
+    requests: std::sync::Mutex<Option<mpsc::Sender<FetchRequest>>>,
+}
+
+impl RsyncFetch {
+    pub fn new(source: Rsync, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+            requests: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Get sender of requests, starting the worker on first use.
+    fn requests(&self, logger: &slog::Logger) -> mpsc::Sender<FetchRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| {
+                let batch_size = self.source.rsync_batch_size.max(1);
+                let (tx, rx) = mpsc::channel(batch_size);
+                tokio::spawn(fetch_worker(
+                    self.source.rsync_base.clone(),
+                    self.buffer_path.clone(),
+                    batch_size,
+                    logger.clone(),
+                    rx,
+                ));
+                tx
+            })
+            .clone()
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for RsyncFetch {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    fn info(&self) -> String {
+        format!("rsync fetch <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for RsyncFetch {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let (reply, response) = oneshot::channel();
+        self.requests(&mission.logger)
+            .send(FetchRequest {
+                key: self.source.source_key(&snapshot.key).to_string(),
+                reply,
+            })
+            .await
+            .map_err(|_| Error::ProcessError("rsync fetcher exited".to_string()))?;
+        let path = response
+            .await
+            .map_err(|_| Error::ProcessError("rsync fetcher exited".to_string()))??;
+
+        // the buffer file is removed when returning early on errors
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(path.clone()),
+        };
+        let file = tokio::fs::File::open(&path).await?;
+        let metadata = file.metadata().await?;
+        if let ByteObject::LocalFile {
+            file: object_file, ..
+        } = &mut object
+        {
+            *object_file = Some(file);
+        }
+
+        if let Some(size) = snapshot.size {
+            if size != metadata.len() {
+                return Err(Error::PipeError(format!(
+                    "size mismatch: {}/{}",
+                    metadata.len(),
+                    size
+                )));
+            }
+        }
+        mission
+            .accounting
+            .record_download(&self.source.rsync_base, metadata.len());
+
+        Ok(ByteStream {
+            object,
+            length: metadata.len(),
+            modified_at: snapshot.last_modified.unwrap_or_else(|| {
+                FileTime::from_last_modification_time(&metadata).unix_seconds() as u64
+            }),
+            content_type: None,
+            checksum: None,
+            etag: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rsync_root() {
+        assert_eq!(
+            rsync_root("rsync://example.com/ubuntu/"),
+            "rsync://example.com/ubuntu/"
+        );
+        assert_eq!(
+            rsync_root("rsync://example.com/ubuntu"),
+            "rsync://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_resolve_symlinks() {
+        let files: BTreeMap<String, SnapshotMeta> = ["dists/jammy/Release", "pool/a.deb"]
+            .iter()
+            .map(|key| (key.to_string(), SnapshotMeta::force(key.to_string())))
+            .collect();
+        let symlinks: HashMap<String, String> = [
+            ("dists/stable", "jammy"),
+            ("dists/latest", "stable"),
+            ("current.deb", "./pool/../pool/a.deb"),
+            ("outside", "../etc/passwd"),
+            ("absolute", "/etc/passwd"),
+            ("loop", "loop"),
+        ]
+        .iter()
+        .map(|(link, target)| (link.to_string(), target.to_string()))
+        .collect();
+        let mut resolved = resolve_symlinks(&files, &symlinks);
+        resolved.sort();
+        let some = |key: &str| Some(key.to_string());
+        assert_eq!(
+            resolved,
+            vec![
+                ("absolute".to_string(), None),
+                ("current.deb".to_string(), some("pool/a.deb")),
+                (
+                    "dists/latest/Release".to_string(),
+                    some("dists/jammy/Release")
+                ),
+                (
+                    "dists/stable/Release".to_string(),
+                    some("dists/jammy/Release")
+                ),
+                ("loop".to_string(), None),
+                ("outside".to_string(), None),
+            ]
+        );
     }
 }
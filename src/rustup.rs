@@ -3,16 +3,35 @@
 //! Rustup source provides a file list of recent rustup toolchains.
 //! It is recommended to use it with `--no-delete` flag. This source
 //! yields path snapshots.
+//!
+//! If `target_mirror` is set, URLs in channel manifests are rewritten to the
+//! mirror with `RewritePipe`, and `.sha256` files of manifests are generated
+//! from rewritten manifests by `ManifestChecksumPipe`, so that the mirror can
+//! be used as `RUSTUP_DIST_SERVER` directly.
+//!
+//! If `gc_expired` is set, objects in dist days older than `days_to_retain`
+//! which are no longer in snapshot are deleted from target, even if
+//! `--no-delete` is given. Use it with `--dry-run` to review them first.
+
+use std::path::Path;
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath, TransferURL};
 use crate::error::{Error, Result};
-use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::fetch::fetch_text;
+use crate::stream_pipe::{ByteObject, ByteStream};
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::{hash_string, unix_time};
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use futures_util::{stream, StreamExt, TryStreamExt};
 use regex::Regex;
+use sha2::{Digest, Sha256};
 use slog::{info, warn};
 use structopt::StructOpt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+
+/// Base of URLs in channel manifests
+pub const DIST_BASE: &str = "https://static.rust-lang.org/";
 
 #[derive(Debug, StructOpt)]
 pub struct Rustup {
@@ -20,12 +39,52 @@ pub struct Rustup {
     pub base: String,
     #[structopt(long, default_value = "120")]
     pub days_to_retain: usize,
+    #[structopt(long, help = "Rewrite URLs in channel manifests to this mirror")]
+    pub target_mirror: Option<String>,
+    #[structopt(
+        long,
+        help = "Delete objects of dist days older than days_to_retain from target"
+    )]
+    pub gc_expired: bool,
 }
 
 fn day_earlier(date_time: DateTime<Utc>, days: i64) -> Option<DateTime<Utc>> {
     date_time.checked_sub_signed(Duration::days(days))
 }
 
+/// Whether `key` is in a dist day not later than `cutoff` (as `%Y-%m-%d`).
+fn is_expired(key: &str, cutoff: &str) -> bool {
+    let day = match key
+        .strip_prefix("dist/")
+        .and_then(|key| key.split_once('/'))
+    {
+        Some((day, _)) => day,
+        None => return false,
+    };
+    let is_date = day.len() == 10
+        && day.chars().enumerate().all(|(idx, c)| {
+            if idx == 4 || idx == 7 {
+                c == '-'
+            } else {
+                c.is_ascii_digit()
+            }
+        });
+    is_date && day <= cutoff
+}
+
+impl Rustup {
+    /// Filter of target objects which could be deleted, i.e. those in dist
+    /// days out of `days_to_retain`. Stable toolchains retained for a longer
+    /// time are still in snapshot, so they won't be deleted.
+    pub fn expired_filter(&self) -> impl Fn(&str) -> bool + Send + Sync + 'static {
+        let cutoff = day_earlier(Utc::now(), self.days_to_retain as i64)
+            .unwrap()
+            .format("%Y-%m-%d")
+            .to_string();
+        move |key: &str| is_expired(key, &cutoff)
+    }
+}
+
 #[async_trait]
 impl SnapshotStorage<SnapshotPath> for Rustup {
     async fn snapshot(
@@ -42,6 +101,9 @@ impl SnapshotStorage<SnapshotPath> for Rustup {
         info!(logger, "fetching channels...");
 
         let matcher = Regex::new(r#"url = "(.*)""#).unwrap();
+        // `.sha256` files of dated manifests are only needed when they are
+        // rewritten, as rustup checks them before updating a toolchain
+        let rewrite = self.target_mirror.is_some();
 
         let mut targets = vec![];
         for day_back in 0..self.days_to_retain {
@@ -72,19 +134,17 @@ impl SnapshotStorage<SnapshotPath> for Rustup {
                     let mut caps = vec![];
                     let target = format!("dist/{}/channel-rust-{}.toml", day_string, channel);
                     progress.set_message(&target);
-                    let data = client
-                        .get(&format!("{}/{}", base, target))
-                        .send()
-                        .await?
-                        .text()
-                        .await?;
+                    let data = fetch_text(&client, &format!("{}/{}", base, target)).await?;
 
                     for capture in matcher.captures_iter(&data) {
                         let url = &capture[1];
-                        let url = url.replace("https://static.rust-lang.org/", "");
+                        let url = url.replace(DIST_BASE, "");
                         caps.push(SnapshotPath::new(url));
                     }
 
+                    if rewrite {
+                        caps.push(SnapshotPath::force(format!("{}.sha256", target)));
+                    }
                     caps.push(SnapshotPath::force(target));
                     progress.inc(1);
                     Ok::<_, Error>(caps)
@@ -132,3 +192,119 @@ impl SourceStorage<SnapshotPath, TransferURL> for Rustup {
         Ok(TransferURL(format!("{}/{}", self.base, snapshot.0)))
     }
 }
+
+/// `ManifestChecksumPipe` generates `.sha256` files of channel manifests from
+/// manifests given by its source, which are rewritten.
+pub struct ManifestChecksumPipe<Source> {
+    source: Source,
+    buffer_path: String,
+}
+
+impl<Source> ManifestChecksumPipe<Source> {
+    pub fn new(source: Source, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+        }
+    }
+}
+
+#[async_trait]
+impl<Source> SnapshotStorage<SnapshotPath> for ManifestChecksumPipe<Source>
+where
+    Source: SnapshotStorage<SnapshotPath>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotPath>> {
+        self.source.snapshot(mission, config).await
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("ManifestChecksumPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Source> SourceStorage<SnapshotPath, ByteStream> for ManifestChecksumPipe<Source>
+where
+    Source: SourceStorage<SnapshotPath, ByteStream>,
+{
+    async fn get_object(&self, snapshot: &SnapshotPath, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        let manifest = match key.strip_suffix(".sha256") {
+            Some(manifest) if manifest.ends_with(".toml") => manifest,
+            _ => return self.source.get_object(snapshot, mission).await,
+        };
+
+        let mut manifest_stream = self
+            .source
+            .get_object(&SnapshotPath::new(manifest.to_string()), mission)
+            .await?;
+        let mut hasher = Sha256::new();
+        let mut stream = manifest_stream.object.as_stream();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+        let filename = manifest.rsplit('/').next().unwrap_or(manifest);
+        let content = format!("{:x}  {}\n", hasher.finalize(), filename);
+
+        let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+        let path = Path::new(&self.buffer_path).join(pipe_file);
+        let mut f = BufWriter::new(
+            tokio::fs::OpenOptions::default()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .await?,
+        );
+        f.write_all(content.as_bytes()).await?;
+        f.flush().await?;
+        let mut f = f.into_inner();
+        f.seek(std::io::SeekFrom::Start(0)).await?;
+        Ok(ByteStream {
+            object: ByteObject::LocalFile {
+                file: Some(f),
+                path: Some(path),
+            },
+            length: content.len() as u64,
+            modified_at: manifest_stream.modified_at,
+            content_type: None,
+            checksum: None,
+            etag: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_expired() {
+        let cutoff = "2023-01-10";
+        assert!(is_expired(
+            "dist/2023-01-10/channel-rust-nightly.toml",
+            cutoff
+        ));
+        assert!(is_expired(
+            "dist/2022-12-31/rustc-nightly-x86_64-unknown-linux-gnu.tar.xz",
+            cutoff
+        ));
+        assert!(!is_expired(
+            "dist/2023-01-11/channel-rust-beta.toml",
+            cutoff
+        ));
+        assert!(!is_expired("dist/channel-rust-stable.toml", cutoff));
+        assert!(!is_expired("dist/rustc-1.0.0-src.tar.gz", cutoff));
+        assert!(!is_expired("rustup/dist/2019-01-01/rustup-init", cutoff));
+    }
+}
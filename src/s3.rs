@@ -1,55 +1,240 @@
 //! S3 backend
 //!
-//! S3 backend is a target storage, which enables taking snapshot of an S3
-//! storage, and uploading objects to it. For snapshot, this storage by default
-//! only has size and path. We could enable modify time and other metadata
-//! in snapshot later. This storage only accepts `ByteStream`.
+//! S3 backend is a target storage, which uploads `ByteStream` objects under
+//! `prefix` of a bucket. It could also be used as a source storage, which
+//! downloads objects with `GetObject` into `buffer_path`, so that a bucket
+//! could be replicated to another bucket or local files.
 //!
-//! This backend has only been tested with SJTU S3 service, which is
-//! (possibly) set up with Ceph. Unlike official S3 protocol, SJTU
-//! S3 service supports special characters in key. For example, if
-//! we put `go@1.10-1.10.8.catalina.bottle.2.tar.gz` into SJTU S3,
-//! the `@` character won't be ignored. You may access it either at
-//! `go@...` or `go%40...` on HTTP.
+//! Snapshot has path, size and ETag of objects, and their modified time and
+//! checksum if metadata is scanned. Requests are retried on transient errors
+//! (e.g. `503 SlowDown` of Ceph), see `s3_retry` module. Options of listing,
+//! uploading, deleting and credentials are documented on `S3Config`.
 //!
-//! This backend will automatically add a MIME type for object, based on
-//! suffix.
+//! This backend was first written for SJTU S3 service, which is (possibly)
+//! set up with Ceph. Unlike official S3 protocol, it keeps special characters
+//! in key, e.g. `@` of `go@1.10-1.10.8.catalina.bottle.2.tar.gz` could be
+//! accessed either at `go@...` or `go%40...` on HTTP.
 
+use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::HashMap, sync::atomic::AtomicU64};
 
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
+use crate::content_type::ContentTypes;
 use crate::error::{Error, Result};
 use crate::metadata::SnapshotMeta;
-use crate::stream_pipe::ByteStream;
-use crate::traits::{Key, SnapshotStorage, TargetStorage};
+use crate::s3_retry::{retry, RetryPolicy};
+use crate::stream_pipe::{ByteBody, ByteObject, ByteStream};
+use crate::throttle::throttled;
+use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage, TargetStorage};
+use crate::utils::{hash_string, human_size, unix_time};
 
 use async_trait::async_trait;
-use futures_util::{stream, StreamExt};
-use rusoto_core::Region;
+use flate2::read::GzDecoder;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use rusoto_core::credential::{
+    Anonymous, AwsCredentials, CredentialsError, DefaultCredentialsProvider, ProfileProvider,
+    ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::request::{DispatchSignedRequestFuture, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{DispatchSignedRequest, HttpClient, Region};
 use rusoto_s3::{
-    DeleteObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3,
+    CopyObjectRequest, Delete, DeleteObjectRequest, DeleteObjectsRequest,
+    GetBucketVersioningRequest, GetObjectAclRequest, GetObjectRequest, Grant, HeadObjectRequest,
+    ListObjectsV2Request, ObjectIdentifier, PutObjectRequest, S3Client, S3,
 };
-use slog::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use slog::{debug, info, warn, Logger};
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+/// How to obtain credentials of S3 requests.
+pub enum S3Credentials {
+    /// default credential chain of rusoto
+    Default,
+    Static {
+        access_key: String,
+        secret_key: String,
+    },
+    Profile(String),
+    Anonymous,
+}
+
+impl std::fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't print secret key
+        match self {
+            S3Credentials::Default => write!(f, "Default"),
+            S3Credentials::Static { access_key, .. } => write!(f, "Static({})", access_key),
+            S3Credentials::Profile(profile) => write!(f, "Profile({})", profile),
+            S3Credentials::Anonymous => write!(f, "Anonymous"),
+        }
+    }
+}
+
+/// How buckets are addressed in requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressingStyle {
+    /// `endpoint/bucket/key`, supported by MinIO, AWS and Backblaze B2
+    Path,
+    /// `bucket.endpoint/key`, required by some AWS regions and services
+    VirtualHosted,
+}
+
+impl std::str::FromStr for AddressingStyle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "path" => Ok(Self::Path),
+            "virtual" | "virtual-hosted" => Ok(Self::VirtualHosted),
+            _ => Err(Error::ConfigureError(format!(
+                "unsupported addressing style {}, expect path or virtual-hosted",
+                s
+            ))),
+        }
+    }
+}
+
+/// Sub-prefixes listed in parallel when scanning bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrefixHint {
+    /// two hex digits, `00` to `ff`
+    Hex2,
+    /// one hex digit, `0` to `f`
+    Hex1,
+    /// one digit or ASCII letter
+    Alnum,
+    /// comma-separated list of prefixes
+    List(Vec<String>),
+}
+
+impl std::str::FromStr for PrefixHint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            // `packages/xx/` of PyPI
+            "hex2" | "pypi" => Ok(Self::Hex2),
+            "hex1" => Ok(Self::Hex1),
+            "alnum" => Ok(Self::Alnum),
+            _ => {
+                let list: Vec<String> = s
+                    .split(',')
+                    .map(|prefix| prefix.trim().trim_matches('/').to_string())
+                    .filter(|prefix| !prefix.is_empty())
+                    .collect();
+                if list.is_empty() {
+                    return Err(Error::ConfigureError(format!(
+                        "unsupported prefix hint {}",
+                        s
+                    )));
+                }
+                Ok(Self::List(list))
+            }
+        }
+    }
+}
+
+impl PrefixHint {
+    /// Sub-prefixes to be listed, relative to `prefix/`.
+    pub fn prefixes(&self) -> Vec<String> {
+        match self {
+            Self::Hex2 => (0..256).map(|i| format!("{:02x}", i)).collect(),
+            Self::Hex1 => (0..16).map(|i| format!("{:x}", i)).collect(),
+            Self::Alnum => ('0'..='9')
+                .chain('a'..='z')
+                .chain('A'..='Z')
+                .map(String::from)
+                .collect(),
+            Self::List(list) => list.clone(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct S3Config {
     pub endpoint: String,
+    /// region name used for signing requests
+    pub region: Option<String>,
+    /// default credential chain of rusoto (environment variables, profile
+    /// file, instance metadata), explicit keys, a named profile, or none for
+    /// public buckets
+    pub credentials: S3Credentials,
+    pub addressing_style: AddressingStyle,
     pub bucket: String,
     pub prefix: String,
-    pub prefix_hint_mode: Option<String>,
+    /// sub-prefixes listed in parallel. Keys not under any of them are not
+    /// listed, so the hint should cover the key space of the bucket.
+    pub prefix_hint: Option<PrefixHint>,
+    /// read modified time and checksum recorded in `clone-*` metadata with
+    /// `HeadObject`, so that objects could be compared by checksum
     pub scan_metadata: bool,
     pub max_keys: u64,
+    /// file recording delete markers created on a versioned bucket together
+    /// with a run id, so that objects deleted by a run could be restored by
+    /// `undelete`
+    pub deletion_report: Option<String>,
+    pub buffer_path: Option<String>,
+    /// storage class of uploaded objects, e.g. `STANDARD_IA`. It's kept when
+    /// updating metadata, as is every header below.
+    pub storage_class: Option<String>,
+    /// canned ACL of uploaded objects, e.g. `public-read`
+    pub acl: Option<String>,
+    /// server-side encryption, `AES256` or `aws:kms`
+    pub sse: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    /// MIME type of uploaded objects by suffix, see `content_type` module
+    pub content_types: ContentTypes,
+    /// `bucket/key` of an S3 Inventory `manifest.json`, or a prefix under
+    /// which the latest manifest is used, to take snapshot of buckets too
+    /// large to be listed. Only CSV inventories are supported, and listing is
+    /// used if the inventory can't be read.
+    pub inventory: Option<String>,
+    /// concurrent `HeadObject` requests when scanning metadata
+    pub scan_metadata_concurrency: usize,
+    /// keys in one `DeleteObjects` request, or 1 to delete objects one by
+    /// one with `DeleteObject`. Deletions requested in a short time are
+    /// collected into one request.
+    pub delete_batch_size: usize,
+    pub retry: RetryPolicy,
 }
 
 impl S3Config {
+    /// Whether ETags of uploaded objects are MD5 of their content, which is
+    /// not the case with KMS encryption.
+    fn md5_etag(&self) -> bool {
+        !self
+            .sse
+            .as_deref()
+            .is_some_and(|sse| sse.starts_with("aws:kms"))
+    }
+
     pub fn new_jcloud(prefix: String, scan_metadata: bool) -> Self {
         Self {
             endpoint: "https://s3.jcloud.sjtu.edu.cn".to_string(),
+            region: None,
+            credentials: S3Credentials::Default,
+            addressing_style: AddressingStyle::Path,
             bucket: "899a892efef34b1b944a19981040f55b-oss01".to_string(),
             prefix,
             max_keys: 1000,
-            prefix_hint_mode: None,
+            prefix_hint: None,
             scan_metadata,
+            deletion_report: None,
+            buffer_path: None,
+            storage_class: None,
+            acl: None,
+            sse: None,
+            sse_kms_key_id: None,
+            content_types: ContentTypes::default(),
+            inventory: None,
+            scan_metadata_concurrency: 64,
+            delete_batch_size: MAX_DELETE_BATCH,
+            retry: RetryPolicy::default(),
         }
     }
 }
@@ -57,20 +242,524 @@ impl S3Config {
 pub struct S3Backend {
     config: S3Config,
     client: S3Client,
+    run_id: String,
+    deletion_report: Option<Arc<Mutex<tokio::fs::File>>>,
+    deletions: std::sync::Mutex<Option<mpsc::Sender<DeleteRequest>>>,
+    /// ETags of objects in snapshot, so that unchanged content isn't
+    /// uploaded again
+    etags: std::sync::Mutex<HashMap<String, String>>,
+}
+
+/// A delete marker created by mirror-clone on a versioned bucket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletionRecord {
+    pub run_id: String,
+    pub bucket: String,
+    pub key: String,
+    pub version_id: String,
+}
+
+/// Manifest of S3 Inventory.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InventoryManifest {
+    file_format: String,
+    file_schema: String,
+    files: Vec<InventoryFile>,
+}
+
+#[derive(Deserialize)]
+struct InventoryFile {
+    key: String,
+}
+
+/// Split a line of inventory CSV, in which every field is quoted.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Columns of inventory CSV used in snapshot.
+struct InventorySchema {
+    key: usize,
+    size: Option<usize>,
+    is_latest: Option<usize>,
+    is_delete_marker: Option<usize>,
+    etag: Option<usize>,
+}
+
+impl InventorySchema {
+    fn parse(schema: &str) -> Result<Self> {
+        let columns: Vec<_> = schema.split(',').map(str::trim).collect();
+        let find = |name: &str| columns.iter().position(|column| *column == name);
+        Ok(Self {
+            key: find("Key")
+                .ok_or_else(|| Error::StorageError("no key column in inventory".to_string()))?,
+            size: find("Size"),
+            is_latest: find("IsLatest"),
+            is_delete_marker: find("IsDeleteMarker"),
+            etag: find("ETag"),
+        })
+    }
+
+    /// Parse a line of inventory, returning key (with `prefix_base`
+    /// stripped) and size of current objects.
+    fn parse_line(&self, line: &str, prefix_base: &str) -> Option<SnapshotMeta> {
+        let fields = split_csv_line(line);
+        let flag = |idx: Option<usize>| idx.and_then(|idx| fields.get(idx)).map(|x| x == "true");
+        if flag(self.is_latest) == Some(false) || flag(self.is_delete_marker) == Some(true) {
+            return None;
+        }
+        // keys are url-encoded in inventory, with spaces as `+`
+        let key = fields.get(self.key)?.replace('+', " ");
+        let key = urlencoding::decode(&key).ok()?;
+        let key = key.strip_prefix(prefix_base)?;
+        Some(SnapshotMeta {
+            key: key.to_string(),
+            size: self
+                .size
+                .and_then(|idx| fields.get(idx))
+                .and_then(|size| size.parse().ok()),
+            etag: self
+                .etag
+                .and_then(|idx| fields.get(idx))
+                .map(|etag| etag.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+/// max keys in one `DeleteObjects` request allowed by S3
+const MAX_DELETE_BATCH: usize = 1000;
+
+struct DeleteRequest {
+    key: String,
+    reply: oneshot::Sender<Result<()>>,
+}
+
+/// Append a delete marker to deletion report.
+async fn record_deletion(report: &Mutex<tokio::fs::File>, record: &DeletionRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    let mut report = report.lock().await;
+    report.write_all(line.as_bytes()).await?;
+    report.flush().await?;
+    Ok(())
+}
+
+/// Worker collecting deletions requested in a short time into
+/// `DeleteObjects` requests.
+struct DeleteWorker {
+    client: S3Client,
+    policy: RetryPolicy,
+    bucket: String,
+    report: Option<Arc<Mutex<tokio::fs::File>>>,
+    run_id: String,
+    batch_size: usize,
+    logger: Logger,
+}
+
+impl DeleteWorker {
+    /// Delete keys of a batch with one `DeleteObjects` request, and reply
+    /// result of every key.
+    async fn delete_batch(&self, batch: Vec<DeleteRequest>) {
+        let req = DeleteObjectsRequest {
+            bucket: self.bucket.clone(),
+            delete: Delete {
+                objects: batch
+                    .iter()
+                    .map(|request| ObjectIdentifier {
+                        key: request.key.clone(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                quiet: Some(false),
+            },
+            ..Default::default()
+        };
+        let output = match retry(&self.policy, &self.logger, "DeleteObjects", || {
+            self.client.delete_objects(req.clone())
+        })
+        .await
+        {
+            Ok(output) => output,
+            Err(err) => {
+                let message = format!("DeleteObjects failed: {:?}", err);
+                for request in batch {
+                    request
+                        .reply
+                        .send(Err(Error::StorageError(message.clone())))
+                        .ok();
+                }
+                return;
+            }
+        };
+
+        // the same key may be requested more than once in a batch
+        let mut results: HashMap<String, std::result::Result<(), String>> = HashMap::new();
+        for deleted in output.deleted.unwrap_or_default() {
+            let key = match deleted.key {
+                Some(key) => key,
+                None => continue,
+            };
+            let mut result = Ok(());
+            if let (Some(report), Some(true), Some(version_id)) = (
+                &self.report,
+                deleted.delete_marker,
+                deleted.delete_marker_version_id,
+            ) {
+                let record = DeletionRecord {
+                    run_id: self.run_id.clone(),
+                    bucket: self.bucket.clone(),
+                    key: key.clone(),
+                    version_id,
+                };
+                result = record_deletion(report, &record)
+                    .await
+                    .map_err(|err| format!("failed to record deletion: {:?}", err));
+            }
+            results.insert(key, result);
+        }
+        for error in output.errors.unwrap_or_default() {
+            if let Some(key) = error.key {
+                results.insert(
+                    key,
+                    Err(format!(
+                        "{}: {}",
+                        error.code.unwrap_or_default(),
+                        error.message.unwrap_or_default()
+                    )),
+                );
+            }
+        }
+
+        for request in batch {
+            let result = match results.get(&request.key) {
+                Some(Ok(())) => Ok(()),
+                Some(Err(err)) => Err(Error::StorageError(err.clone())),
+                None => Err(Error::StorageError(
+                    "not reported by DeleteObjects".to_string(),
+                )),
+            };
+            request.reply.send(result).ok();
+        }
+    }
+
+    async fn run(self, mut requests: mpsc::Receiver<DeleteRequest>) {
+        while let Some(request) = requests.recv().await {
+            let mut batch = vec![request];
+            // collect deletions requested in a short time into the same batch
+            let deadline = tokio::time::sleep(Duration::from_millis(200));
+            tokio::pin!(deadline);
+            while batch.len() < self.batch_size {
+                tokio::select! {
+                    request = requests.recv() => match request {
+                        Some(request) => batch.push(request),
+                        None => break,
+                    },
+                    _ = &mut deadline => break,
+                }
+            }
+            self.delete_batch(batch).await;
+        }
+    }
 }
 
-fn jcloud_region(name: String, endpoint: String) -> Region {
-    Region::Custom { name, endpoint }
+/// Stream content of file at `path`, which is opened when polled, so that
+/// body of a request could be built again on retry.
+fn file_stream(
+    path: std::path::PathBuf,
+) -> impl futures_util::Stream<Item = std::io::Result<bytes::Bytes>> + Send + 'static {
+    stream::once(tokio::fs::File::open(path))
+        .map_ok(|file| {
+            FramedRead::new(BufReader::new(file), BytesCodec::new()).map_ok(|bytes| bytes.freeze())
+        })
+        .try_flatten()
 }
 
-fn get_s3_client(name: String, endpoint: String) -> S3Client {
-    S3Client::new(jcloud_region(name, endpoint))
+/// Dispatcher of virtual-hosted style requests. Requests are built by rusoto
+/// in path style, so bucket is moved to host name here, and requests are
+/// signed afterwards.
+struct VirtualHostedDispatcher<P> {
+    dispatcher: Arc<HttpClient>,
+    provider: Arc<P>,
+}
+
+/// Move bucket in path of `request` to its host name.
+fn virtual_hosted(request: &mut SignedRequest) {
+    let path = request.path.trim_start_matches('/');
+    let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+    if bucket.is_empty() {
+        return;
+    }
+    let hostname = format!("{}.{}", bucket, request.hostname());
+    request.path = format!("/{}", key);
+    request.set_hostname(Some(hostname));
+}
+
+impl<P> DispatchSignedRequest for VirtualHostedDispatcher<P>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let dispatcher = self.dispatcher.clone();
+        let provider = self.provider.clone();
+        Box::pin(async move {
+            virtual_hosted(&mut request);
+            let credentials = provider
+                .credentials()
+                .await
+                .map_err(|err| HttpDispatchError::new(err.to_string()))?;
+            if credentials.is_anonymous() {
+                request.complement();
+            } else {
+                request.sign(&credentials);
+            }
+            dispatcher.dispatch(request, timeout).await
+        })
+    }
+}
+
+fn new_s3_client<P>(
+    dispatcher: HttpClient,
+    provider: P,
+    region: Region,
+    addressing_style: AddressingStyle,
+) -> S3Client
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    match addressing_style {
+        AddressingStyle::Path => S3Client::new_with(dispatcher, provider, region),
+        // requests are left unsigned by client, and signed by dispatcher
+        AddressingStyle::VirtualHosted => S3Client::new_with(
+            VirtualHostedDispatcher {
+                dispatcher: Arc::new(dispatcher),
+                provider: Arc::new(provider),
+            },
+            StaticProvider::from(AwsCredentials::default()),
+            region,
+        ),
+    }
+}
+
+fn get_s3_client(config: &S3Config) -> Result<S3Client> {
+    let region = Region::Custom {
+        name: config
+            .region
+            .clone()
+            .unwrap_or_else(|| "jCloud S3".to_string()),
+        endpoint: config.endpoint.clone(),
+    };
+    let dispatcher = HttpClient::new().map_err(|err| {
+        Error::ConfigureError(format!("failed to create request dispatcher: {}", err))
+    })?;
+    let style = config.addressing_style;
+    let credentials_error = |err: CredentialsError| {
+        Error::ConfigureError(format!("failed to load S3 credentials: {}", err))
+    };
+    Ok(match &config.credentials {
+        S3Credentials::Default => {
+            let provider = DefaultCredentialsProvider::new().map_err(credentials_error)?;
+            new_s3_client(dispatcher, provider, region, style)
+        }
+        S3Credentials::Static {
+            access_key,
+            secret_key,
+        } => {
+            let provider = StaticProvider::new_minimal(access_key.clone(), secret_key.clone());
+            new_s3_client(dispatcher, provider, region, style)
+        }
+        S3Credentials::Profile(profile) => {
+            let mut provider = ProfileProvider::new().map_err(credentials_error)?;
+            provider.set_profile(profile.clone());
+            new_s3_client(dispatcher, provider, region, style)
+        }
+        // requests are not signed with anonymous credentials
+        S3Credentials::Anonymous => {
+            let provider = StaticProvider::from(AwsCredentials::default());
+            new_s3_client(dispatcher, provider, region, style)
+        }
+    })
 }
 
 impl S3Backend {
-    pub fn new(config: S3Config) -> Self {
-        let client = get_s3_client("jCloud S3".to_string(), config.endpoint.clone());
-        Self { config, client }
+    pub fn new(config: S3Config) -> Result<Self> {
+        let client = get_s3_client(&config)?;
+        let deletion_report = match &config.deletion_report {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| {
+                        Error::ConfigureError(format!(
+                            "failed to open deletion report {}: {}",
+                            path, err
+                        ))
+                    })?;
+                Some(Arc::new(Mutex::new(tokio::fs::File::from_std(file))))
+            }
+            None => None,
+        };
+        Ok(Self {
+            config,
+            client,
+            run_id: chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string(),
+            deletion_report,
+            deletions: std::sync::Mutex::new(None),
+            etags: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get sender of deletions, starting the worker on first use.
+    fn deletions(&self, logger: &Logger) -> mpsc::Sender<DeleteRequest> {
+        self.deletions
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| {
+                let batch_size = self.config.delete_batch_size.clamp(1, MAX_DELETE_BATCH);
+                let (tx, rx) = mpsc::channel(batch_size);
+                let worker = DeleteWorker {
+                    client: self.client.clone(),
+                    policy: self.config.retry.clone(),
+                    bucket: self.config.bucket.clone(),
+                    report: self.deletion_report.clone(),
+                    run_id: self.run_id.clone(),
+                    batch_size,
+                    logger: logger.clone(),
+                };
+                tokio::spawn(worker.run(rx));
+                tx
+            })
+            .clone()
+    }
+
+    async fn get_bytes(&self, bucket: &str, key: &str, logger: &Logger) -> Result<Vec<u8>> {
+        let req = GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        let resp = retry(&self.config.retry, logger, "GetObject", || {
+            self.client.get_object(req.clone())
+        })
+        .await?;
+        let mut data = vec![];
+        if let Some(mut body) = resp.body {
+            while let Some(content) = body.next().await {
+                data.extend_from_slice(&content?);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Find key of the latest inventory manifest under `prefix`.
+    async fn latest_manifest(&self, bucket: &str, prefix: &str, logger: &Logger) -> Result<String> {
+        let mut latest: Option<String> = None;
+        let mut continuation_token = None;
+        loop {
+            let req = ListObjectsV2Request {
+                bucket: bucket.to_string(),
+                prefix: Some(prefix.to_string()),
+                continuation_token,
+                ..Default::default()
+            };
+            let resp = retry(&self.config.retry, logger, "ListObjectsV2", || {
+                self.client.list_objects_v2(req.clone())
+            })
+            .await?;
+            for item in resp.contents.unwrap_or_default() {
+                let key = item.key.unwrap_or_default();
+                // manifests are placed in directories named by time
+                if key.ends_with("/manifest.json") && latest.as_deref() < Some(key.as_str()) {
+                    latest = Some(key);
+                }
+            }
+            continuation_token = resp.next_continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+        latest.ok_or_else(|| Error::StorageError(format!("no manifest under {}", prefix)))
+    }
+
+    /// Take snapshot from S3 Inventory at `location`.
+    async fn read_inventory(&self, location: &str, logger: &Logger) -> Result<Vec<SnapshotMeta>> {
+        let (bucket, key) = location.split_once('/').ok_or_else(|| {
+            Error::ConfigureError(format!("invalid inventory location {}", location))
+        })?;
+        let key = if key.ends_with("manifest.json") {
+            key.to_string()
+        } else {
+            self.latest_manifest(bucket, key, logger).await?
+        };
+        info!(logger, "reading inventory {}/{}", bucket, key);
+
+        let manifest: InventoryManifest =
+            serde_json::from_slice(&self.get_bytes(bucket, &key, logger).await?)?;
+        if manifest.file_format != "CSV" {
+            return Err(Error::ConfigureError(format!(
+                "unsupported inventory format {}, only CSV is supported",
+                manifest.file_format
+            )));
+        }
+        let schema = Arc::new(InventorySchema::parse(&manifest.file_schema)?);
+        let prefix_base = format!("{}/", self.config.prefix);
+
+        let mut snapshot = vec![];
+        for file in manifest.files {
+            let req = GetObjectRequest {
+                bucket: bucket.to_string(),
+                key: file.key.clone(),
+                ..Default::default()
+            };
+            let resp = retry(&self.config.retry, logger, "GetObject", || {
+                self.client.get_object(req.clone())
+            })
+            .await?;
+            let body = match resp.body {
+                Some(body) => body,
+                None => continue,
+            };
+            // inventory files are decompressed and parsed as they're downloaded
+            let reader = SyncIoBridge::new(StreamReader::new(body));
+            let schema = schema.clone();
+            let prefix_base = prefix_base.clone();
+            let mut metas = tokio::task::spawn_blocking(move || {
+                let reader = std::io::BufReader::new(GzDecoder::new(reader));
+                let mut metas = vec![];
+                for line in std::io::BufRead::lines(reader) {
+                    if let Some(meta) = schema.parse_line(&line?, &prefix_base) {
+                        metas.push(meta);
+                    }
+                }
+                Ok::<_, Error>(metas)
+            })
+            .await
+            .expect("task panicked")?;
+            snapshot.append(&mut metas);
+        }
+        Ok(snapshot)
     }
 
     pub fn gen_metadata(&self) -> HashMap<String, String> {
@@ -78,6 +767,168 @@ impl S3Backend {
         map.insert("clone-backend".to_string(), "s3-v1".to_string());
         map
     }
+
+    /// Update metadata of object `key` in place with `updates`, by copying it
+    /// to itself. Nothing is copied if metadata are already the same.
+    async fn replace_metadata(
+        &self,
+        key: String,
+        updates: HashMap<String, String>,
+        content_type: Option<String>,
+        mission: &Mission,
+    ) -> Result<()> {
+        let logger = &mission.logger;
+        let req = HeadObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: key.clone(),
+            ..Default::default()
+        };
+        let resp = retry(&self.config.retry, logger, "HeadObject", || {
+            self.client.head_object(req.clone())
+        })
+        .await?;
+
+        // metadata are replaced as a whole, so existing ones should be kept
+        let mut metadata = resp.metadata.unwrap_or_default();
+        let changed = merge_metadata(&mut metadata, updates);
+        if !changed && (content_type.is_none() || content_type == resp.content_type) {
+            debug!(logger, "same metadata, skip copy: {}", key);
+            return Ok(());
+        }
+
+        // objects uploaded with canned ACL keep it, otherwise grants are
+        // copied, as `CopyObject` doesn't keep ACL of source
+        let mut grants = match &self.config.acl {
+            Some(_) => HashMap::new(),
+            None => {
+                let req = GetObjectAclRequest {
+                    bucket: self.config.bucket.clone(),
+                    key: key.clone(),
+                    ..Default::default()
+                };
+                let acl = retry(&self.config.retry, logger, "GetObjectAcl", || {
+                    self.client.get_object_acl(req.clone())
+                })
+                .await?;
+                grant_headers(
+                    &acl.grants.unwrap_or_default(),
+                    acl.owner.as_ref().and_then(|owner| owner.id.as_deref()),
+                )
+            }
+        };
+
+        let copy_source = key
+            .split('/')
+            .map(|segment| urlencoding::encode(segment).into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+        // headers other than metadata are replaced as well, so those of the
+        // existing object are carried
+        let req = CopyObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key,
+            copy_source: format!("{}/{}", self.config.bucket, copy_source),
+            metadata_directive: Some("REPLACE".to_string()),
+            metadata: Some(metadata),
+            content_type: content_type.or(resp.content_type),
+            cache_control: resp.cache_control,
+            content_disposition: resp.content_disposition,
+            content_encoding: resp.content_encoding,
+            content_language: resp.content_language,
+            expires: resp.expires,
+            website_redirect_location: resp.website_redirect_location,
+            storage_class: resp.storage_class,
+            acl: self.config.acl.clone(),
+            grant_full_control: grants.remove("FULL_CONTROL"),
+            grant_read: grants.remove("READ"),
+            grant_read_acp: grants.remove("READ_ACP"),
+            grant_write_acp: grants.remove("WRITE_ACP"),
+            server_side_encryption: resp.server_side_encryption,
+            ssekms_key_id: resp.ssekms_key_id,
+            bucket_key_enabled: resp.bucket_key_enabled,
+            ..Default::default()
+        };
+        retry(&self.config.retry, logger, "CopyObject", || {
+            self.client.copy_object(req.clone())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Restore objects deleted by run `run_id`, by removing delete markers
+    /// recorded in `report`. If `dry_run` is set, only print what would be restored.
+    pub async fn undelete(
+        &self,
+        logger: &Logger,
+        report: &str,
+        run_id: &str,
+        dry_run: bool,
+    ) -> Result<()> {
+        let file = tokio::fs::File::open(report).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut records = vec![];
+        while let Some(line) = lines.next_line().await? {
+            let record: DeletionRecord = serde_json::from_str(&line)?;
+            if record.run_id == run_id {
+                records.push(record);
+            }
+        }
+
+        info!(
+            logger,
+            "{} objects deleted by run {}",
+            records.len(),
+            run_id
+        );
+
+        if dry_run {
+            for record in &records {
+                info!(logger, "restore {}/{}", record.bucket, record.key);
+            }
+            return Ok(());
+        }
+
+        let mut results = stream::iter(records)
+            .map(|record| {
+                let client = self.client.clone();
+                let policy = self.config.retry.clone();
+                let logger = logger.clone();
+                async move {
+                    let req = DeleteObjectRequest {
+                        bucket: record.bucket.clone(),
+                        key: record.key.clone(),
+                        version_id: Some(record.version_id.clone()),
+                        ..Default::default()
+                    };
+                    match retry(&policy, &logger, "DeleteObject", || {
+                        client.delete_object(req.clone())
+                    })
+                    .await
+                    {
+                        Ok(_) => {
+                            debug!(logger, "restored {}", record.key);
+                            true
+                        }
+                        Err(err) => {
+                            warn!(logger, "error while restore {}: {:?}", record.key, err);
+                            false
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(16);
+
+        let mut restored = 0;
+        while let Some(success) = results.next().await {
+            if success {
+                restored += 1;
+            }
+        }
+
+        info!(logger, "restored {} objects", restored);
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -90,96 +941,154 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
         let logger = mission.logger;
         let progress = mission.progress;
 
+        if let Some(path) = &self.config.deletion_report {
+            let req = GetBucketVersioningRequest {
+                bucket: self.config.bucket.clone(),
+                ..Default::default()
+            };
+            let status = retry(&self.config.retry, &logger, "GetBucketVersioning", || {
+                self.client.get_bucket_versioning(req.clone())
+            })
+            .await?
+            .status;
+            if status.as_deref() == Some("Enabled") {
+                info!(
+                    logger,
+                    "recording deletions to {} with run id {}", path, self.run_id
+                );
+            } else {
+                warn!(
+                    logger,
+                    "bucket versioning is {:?}, deleted objects can't be restored",
+                    status.as_deref().unwrap_or("disabled")
+                );
+            }
+        }
+
         info!(logger, "fetching data from S3 storage...");
 
         let s3_prefix_base = format!("{}/", self.config.prefix);
         let total_size = std::sync::Arc::new(AtomicU64::new(0));
 
-        let prefix = match self.config.prefix_hint_mode.as_deref() {
-            Some("pypi") => {
-                let mut prefix = vec![];
-                for i in 0..256 {
-                    prefix.push(format!("/{:02x}", i));
-                }
-                prefix
-            }
+        let prefix = match &self.config.prefix_hint {
+            Some(hint) => hint
+                .prefixes()
+                .into_iter()
+                .map(|prefix| format!("/{}", prefix))
+                .collect(),
             None => vec!["".to_string()],
-            Some(other) => {
-                panic!("unsupported prefix hint mode {}", other);
-            }
         };
 
-        // List bucket
-        let mut futures = stream::iter(prefix)
-            .map(|additional_prefix| {
-                let bucket = self.config.bucket.clone();
-                let prefix = Some(format!("{}{}", self.config.prefix, additional_prefix));
-                let client = self.client.clone();
-                let total_size = total_size.clone();
-                let progress = progress.clone();
-                let logger = logger.clone();
-                let s3_prefix_base = s3_prefix_base.clone();
-                let max_keys = self.config.max_keys;
+        let inventory = match &self.config.inventory {
+            Some(location) => match self.read_inventory(location, &logger).await {
+                Ok(snapshot) => Some(snapshot),
+                Err(err @ Error::ConfigureError(_)) => return Err(err),
+                Err(err) => {
+                    warn!(
+                        logger,
+                        "failed to read inventory, listing bucket: {:?}", err
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
 
-                async move {
-                    let mut snapshot = vec![];
-                    let mut continuation_token = None;
-
-                    loop {
-                        let req = ListObjectsV2Request {
-                            bucket: bucket.clone(),
-                            prefix: prefix.clone(),
-                            max_keys: Some(max_keys as i64),
-                            continuation_token,
-                            ..Default::default()
-                        };
+        let snapshots = if let Some(snapshot) = inventory {
+            let size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+            total_size.fetch_add(size, std::sync::atomic::Ordering::SeqCst);
+            snapshot
+        } else {
+            // List bucket
+            let mut futures = stream::iter(prefix)
+                .map(|additional_prefix| {
+                    let bucket = self.config.bucket.clone();
+                    let prefix = Some(format!("{}{}", self.config.prefix, additional_prefix));
+                    let client = self.client.clone();
+                    let policy = self.config.retry.clone();
+                    let total_size = total_size.clone();
+                    let progress = progress.clone();
+                    let logger = logger.clone();
+                    let s3_prefix_base = s3_prefix_base.clone();
+                    let max_keys = self.config.max_keys;
 
-                        let resp = client.list_objects_v2(req).await?;
+                    async move {
+                        let mut snapshot = vec![];
+                        let mut continuation_token = None;
 
-                        let mut first_key = true;
+                        loop {
+                            let req = ListObjectsV2Request {
+                                bucket: bucket.clone(),
+                                prefix: prefix.clone(),
+                                max_keys: Some(max_keys as i64),
+                                continuation_token,
+                                ..Default::default()
+                            };
 
-                        if let Some(contents) = resp.contents {
-                            for item in contents {
-                                if let Some(size) = item.size {
-                                    total_size.fetch_add(
-                                        size as u64,
-                                        std::sync::atomic::Ordering::SeqCst,
-                                    );
-                                }
-                                let key = item.key.unwrap();
-                                if key.starts_with(&s3_prefix_base) {
-                                    let key = key[s3_prefix_base.len()..].to_string();
-                                    // let key = crate::utils::rewrite_url_string(&gen_map, &key);
-                                    if first_key {
-                                        first_key = false;
-                                        progress.set_message(&key);
+                            let resp = retry(&policy, &logger, "ListObjectsV2", || {
+                                client.list_objects_v2(req.clone())
+                            })
+                            .await?;
+
+                            let mut first_key = true;
+
+                            if let Some(contents) = resp.contents {
+                                for item in contents {
+                                    if let Some(size) = item.size {
+                                        total_size.fetch_add(
+                                            size as u64,
+                                            std::sync::atomic::Ordering::SeqCst,
+                                        );
+                                    }
+                                    let key = item.key.unwrap();
+                                    if key.starts_with(&s3_prefix_base) {
+                                        let key = key[s3_prefix_base.len()..].to_string();
+                                        // let key = crate::utils::rewrite_url_string(&gen_map, &key);
+                                        if first_key {
+                                            first_key = false;
+                                            progress.set_message(&key);
+                                        }
+                                        snapshot.push(SnapshotMeta {
+                                            key,
+                                            size: item.size.map(|x| x as u64),
+                                            etag: item
+                                                .e_tag
+                                                .map(|x| x.trim_matches('"').to_string()),
+                                            ..Default::default()
+                                        });
+                                    } else {
+                                        warn!(logger, "prefix not match {}", key);
                                     }
-                                    snapshot.push(SnapshotMeta {
-                                        key,
-                                        size: item.size.map(|x| x as u64),
-                                        ..Default::default()
-                                    });
-                                } else {
-                                    warn!(logger, "prefix not match {}", key);
                                 }
                             }
-                        }
 
-                        if let Some(next_continuation_token) = resp.next_continuation_token {
-                            continuation_token = Some(next_continuation_token);
-                        } else {
-                            break;
+                            if let Some(next_continuation_token) = resp.next_continuation_token {
+                                continuation_token = Some(next_continuation_token);
+                            } else {
+                                break;
+                            }
                         }
+                        Ok::<_, Error>(snapshot)
                     }
-                    Ok::<_, Error>(snapshot)
-                }
-            })
-            .buffer_unordered(256);
+                })
+                .buffer_unordered(256);
+
+            let mut snapshots = vec![];
 
-        let mut snapshots = vec![];
+            while let Some(snapshot) = futures.next().await {
+                snapshots.append(&mut snapshot?);
+            }
+
+            snapshots
+        };
 
-        while let Some(snapshot) = futures.next().await {
-            snapshots.append(&mut snapshot?);
+        // ETags of objects encrypted with KMS are not MD5 of their content,
+        // and can't be compared with expected ETags
+        let mut snapshots = snapshots;
+        if !self.config.md5_etag() {
+            for snapshot in snapshots.iter_mut() {
+                snapshot.etag = None;
+            }
         }
 
         // Get metadata
@@ -188,7 +1097,9 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
                 .map(|snapshot| {
                     let bucket = self.config.bucket.clone();
                     let client = self.client.clone();
+                    let policy = self.config.retry.clone();
                     let progress = progress.clone();
+                    let logger = logger.clone();
                     let prefix = self.config.prefix.clone();
 
                     async move {
@@ -198,21 +1109,33 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
                             key: format!("{}/{}", prefix, snapshot.key),
                             ..Default::default()
                         };
-                        let resp = client.head_object(req).await?;
-                        let last_modified = if let Some(metadata) = resp.metadata {
-                            metadata
-                                .get("clone-last-modified")
-                                .and_then(|x| x.parse::<u64>().ok())
-                        } else {
-                            None
+                        let resp = retry(&policy, &logger, "HeadObject", || {
+                            client.head_object(req.clone())
+                        })
+                        .await?;
+                        let metadata = resp.metadata.unwrap_or_default();
+                        let last_modified = metadata
+                            .get("clone-last-modified")
+                            .and_then(|x| x.parse::<u64>().ok());
+                        // checksum is only meaningful with its method
+                        let (checksum_method, checksum) = match (
+                            metadata.get("clone-checksum-method"),
+                            metadata.get("clone-checksum"),
+                        ) {
+                            (Some(method), Some(checksum)) => {
+                                (Some(method.clone()), Some(checksum.clone()))
+                            }
+                            _ => (None, None),
                         };
                         Ok::<_, Error>(SnapshotMeta {
                             last_modified,
+                            checksum_method,
+                            checksum,
                             ..snapshot
                         })
                     }
                 })
-                .buffer_unordered(64);
+                .buffer_unordered(self.config.scan_metadata_concurrency.max(1));
 
             let mut snapshots = vec![];
 
@@ -228,12 +1151,12 @@ impl SnapshotStorage<SnapshotMeta> for S3Backend {
         progress.finish_with_message("done");
 
         let total_size = total_size.load(std::sync::atomic::Ordering::SeqCst);
-        info!(
-            logger,
-            "total size: {}B or {}G",
-            total_size,
-            total_size as f64 / 1000.0 / 1000.0 / 1000.0
-        );
+        info!(logger, "total size: {}", human_size(total_size));
+
+        *self.etags.lock().unwrap() = snapshots
+            .iter()
+            .filter_map(|snapshot| Some((snapshot.key.clone(), snapshot.etag.clone()?)))
+            .collect();
 
         Ok(snapshots)
     }
@@ -263,6 +1186,157 @@ impl SnapshotStorage<SnapshotPath> for S3Backend {
         format!("s3 (path), {:?}", self.config)
     }
 }
+#[async_trait]
+impl SourceStorage<SnapshotMeta, ByteStream> for S3Backend {
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let buffer_path = self.config.buffer_path.as_ref().ok_or_else(|| {
+            Error::ConfigureError("buffer path is required to use S3 as source".to_string())
+        })?;
+        let key = format!("{}/{}", self.config.prefix, snapshot.key);
+        debug!(mission.logger, "download: {}", key);
+
+        let req = GetObjectRequest {
+            bucket: self.config.bucket.clone(),
+            key: key.clone(),
+            ..Default::default()
+        };
+        let resp = retry(&self.config.retry, &mission.logger, "GetObject", || {
+            self.client.get_object(req.clone())
+        })
+        .await?;
+
+        // prefer modified time recorded by mirror-clone, as S3 only keeps
+        // the time of uploading
+        let modified_at = snapshot
+            .last_modified
+            .or_else(|| {
+                resp.metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get("clone-last-modified"))
+                    .and_then(|x| x.parse().ok())
+            })
+            .or_else(|| {
+                resp.last_modified
+                    .as_deref()
+                    .and_then(|x| chrono::DateTime::parse_from_rfc2822(x).ok())
+                    .map(|x| x.timestamp() as u64)
+            })
+            .ok_or_else(|| Error::PipeError("no modified time".to_string()))?;
+
+        let path = format!(
+            "{}/{}.{}.buffer",
+            buffer_path,
+            hash_string(&key),
+            unix_time()
+        );
+        let mut f = BufWriter::new(
+            tokio::fs::OpenOptions::default()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .await?,
+        );
+        // the buffer file is removed when returning early on errors
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(path.into()),
+        };
+
+        let mut total_bytes: u64 = 0;
+        if let Some(mut body) = resp.body {
+            while let Some(content) = body.next().await {
+                let content = content?;
+                f.write_all(&content).await?;
+                total_bytes += content.len() as u64;
+                mission.throttle.consume(content.len() as u64).await;
+            }
+        }
+        mission.accounting.record_download(
+            &format!("{}/{}", self.config.endpoint, self.config.bucket),
+            total_bytes,
+        );
+
+        if let Some(content_length) = resp.content_length {
+            if total_bytes != content_length as u64 {
+                return Err(Error::PipeError(format!(
+                    "content length mismatch: {}/{}",
+                    total_bytes, content_length
+                )));
+            }
+        }
+
+        f.flush().await?;
+        let mut f = f.into_inner();
+        f.seek(std::io::SeekFrom::Start(0)).await?;
+
+        if let ByteObject::LocalFile { file, .. } = &mut object {
+            *file = Some(f);
+        }
+
+        Ok(ByteStream {
+            object,
+            length: total_bytes,
+            modified_at,
+            content_type: snapshot.content_type.clone().or(resp.content_type),
+            checksum: None,
+            etag: None,
+        })
+    }
+}
+
+/// Grant headers (permission -> grantees) of `CopyObject` carrying `grants`
+/// of an object. Nothing is given if the owner is the only grantee, as new
+/// objects are private by default, and ACLs may be disabled on bucket.
+fn grant_headers(grants: &[Grant], owner: Option<&str>) -> HashMap<String, String> {
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+    let mut owner_only = true;
+    for grant in grants {
+        let (grantee, permission) = match (&grant.grantee, &grant.permission) {
+            (Some(grantee), Some(permission)) => (grantee, permission),
+            _ => continue,
+        };
+        let grantee = if let Some(id) = &grantee.id {
+            if owner != Some(id.as_str()) || permission != "FULL_CONTROL" {
+                owner_only = false;
+            }
+            format!("id=\"{}\"", id)
+        } else if let Some(uri) = &grantee.uri {
+            owner_only = false;
+            format!("uri=\"{}\"", uri)
+        } else if let Some(email) = &grantee.email_address {
+            owner_only = false;
+            format!("emailAddress=\"{}\"", email)
+        } else {
+            continue;
+        };
+        headers.entry(permission.clone()).or_default().push(grantee);
+    }
+    if owner_only {
+        return HashMap::new();
+    }
+    headers
+        .into_iter()
+        .map(|(permission, grantees)| (permission, grantees.join(", ")))
+        .collect()
+}
+
+/// Merge `updates` into `metadata`, returning whether any of them changed.
+fn merge_metadata(
+    metadata: &mut HashMap<String, String>,
+    updates: HashMap<String, String>,
+) -> bool {
+    let mut changed = false;
+    for (key, value) in updates {
+        if metadata.get(&key) != Some(&value) {
+            metadata.insert(key, value);
+            changed = true;
+        }
+    }
+    changed
+}
+
 pub trait S3Metadata {
     fn s3_meta(&self) -> HashMap<String, String>;
 }
@@ -289,20 +1363,10 @@ impl S3Metadata for SnapshotMeta {
     }
 }
 
-fn get_mime(key: &str) -> Option<String> {
-    // TODO: add more types from https://github.com/nginx/nginx/blob/master/conf/mime.types
-    // TODO: the correct way is to mirror content-type from remote as-is, or to read MIME type
-    if key.ends_with(".htm") || key.ends_with(".html") || key.ends_with(".shtml") {
-        Some("text/html; charset=utf-8".to_string())
-    } else {
-        None
-    }
-}
-
 #[async_trait]
 impl<Snapshot> TargetStorage<Snapshot, ByteStream> for S3Backend
 where
-    Snapshot: Key + S3Metadata,
+    Snapshot: Key + Metadata + S3Metadata,
 {
     async fn put_object(
         &self,
@@ -314,40 +1378,264 @@ where
         debug!(logger, "upload: {}", snapshot.key());
 
         let ByteStream {
-            mut object,
+            object,
             length,
             modified_at,
             content_type,
+            checksum,
+            etag,
         } = byte_stream;
 
-        let body = object.as_stream();
-
         let mut metadata = self.gen_metadata();
         metadata.insert("clone-last-modified".to_string(), modified_at.to_string());
         metadata.extend(snapshot.s3_meta());
+        // checksum computed by pipes, if source doesn't provide one
+        if let Some((method, checksum)) = checksum {
+            if !metadata.contains_key("clone-checksum") {
+                metadata.insert("clone-checksum-method".to_string(), method);
+                metadata.insert("clone-checksum".to_string(), checksum);
+            }
+        }
 
-        let req = PutObjectRequest {
+        let key = format!("{}/{}", self.config.prefix, snapshot.key());
+        let content_type = self
+            .config
+            .content_types
+            .resolve(snapshot.key(), content_type);
+
+        // content is the same (e.g. only modified time changed upstream), so
+        // only metadata is updated, with those of the downloaded object
+        if let Some(etag) = &etag {
+            let unchanged = self
+                .etags
+                .lock()
+                .unwrap()
+                .get(snapshot.key())
+                .is_some_and(|stored| stored.eq_ignore_ascii_case(etag));
+            if unchanged {
+                debug!(logger, "same etag, skip upload: {}", snapshot.key());
+                drop(object);
+                return self
+                    .replace_metadata(key, metadata, content_type, mission)
+                    .await;
+            }
+        }
+
+        let request = |body: ByteBody| PutObjectRequest {
             bucket: self.config.bucket.clone(),
-            key: format!("{}/{}", self.config.prefix, snapshot.key()),
-            body: Some(rusoto_s3::StreamingBody::new(body)),
-            metadata: Some(metadata),
+            key: key.clone(),
+            body: Some(rusoto_s3::StreamingBody::new(throttled(
+                body,
+                mission.throttle.clone(),
+            ))),
+            metadata: Some(metadata.clone()),
             content_length: Some(length as i64),
-            content_type: content_type.or_else(|| get_mime(snapshot.key())),
+            content_type: content_type.clone(),
+            storage_class: self.config.storage_class.clone(),
+            acl: self.config.acl.clone(),
+            server_side_encryption: self.config.sse.clone(),
+            ssekms_key_id: self.config.sse_kms_key_id.clone(),
             ..Default::default()
         };
 
-        self.client.put_object(req).await?;
+        let result = if object.is_stream() {
+            // streamed objects can't be sent again, so they're not retried
+            let mut object = object;
+            self.client.put_object(request(object.as_stream())).await
+        } else {
+            // buffer file is read again on every retry
+            let path = object.use_file()?;
+            let result = retry(&self.config.retry, logger, "PutObject", || {
+                self.client
+                    .put_object(request(ByteBody::new(file_stream(path.clone()))))
+            })
+            .await;
+            tokio::fs::remove_file(&path).await.ok();
+            result
+        };
+        result?;
+        mission.accounting.record_upload(
+            &format!("s3:{}/{}", self.config.bucket, self.config.prefix),
+            length,
+        );
 
         Ok(())
     }
 
-    async fn delete_object(&self, snapshot: &Snapshot, _mission: &Mission) -> Result<()> {
+    async fn delete_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        let key = format!("{}/{}", self.config.prefix, snapshot.key());
+        if self.config.delete_batch_size > 1 {
+            let (reply, response) = oneshot::channel();
+            self.deletions(&mission.logger)
+                .send(DeleteRequest { key, reply })
+                .await
+                .map_err(|_| Error::ProcessError("deletion worker exited".to_string()))?;
+            return response
+                .await
+                .map_err(|_| Error::ProcessError("deletion worker exited".to_string()))?;
+        }
+
         let req = DeleteObjectRequest {
             bucket: self.config.bucket.clone(),
-            key: format!("{}/{}", self.config.prefix, snapshot.key()),
+            key: key.clone(),
             ..Default::default()
         };
-        self.client.delete_object(req).await?;
+        let resp = retry(&self.config.retry, &mission.logger, "DeleteObject", || {
+            self.client.delete_object(req.clone())
+        })
+        .await?;
+        if let (Some(report), Some(true), Some(version_id)) =
+            (&self.deletion_report, resp.delete_marker, resp.version_id)
+        {
+            let record = DeletionRecord {
+                run_id: self.run_id.clone(),
+                bucket: self.config.bucket.clone(),
+                key,
+                version_id,
+            };
+            record_deletion(report, &record).await?;
+        }
         Ok(())
     }
+
+    /// Update metadata by copying the object onto itself, which won't
+    /// transfer the content again.
+    async fn update_metadata(&self, snapshot: &Snapshot, mission: &Mission) -> Result<()> {
+        debug!(mission.logger, "update metadata: {}", snapshot.key());
+
+        let key = format!("{}/{}", self.config.prefix, snapshot.key());
+        let mut metadata = self.gen_metadata();
+        if let Some(last_modified) = snapshot.last_modified() {
+            metadata.insert("clone-last-modified".to_string(), last_modified.to_string());
+        }
+        metadata.extend(snapshot.s3_meta());
+        let content_type = snapshot
+            .content_type()
+            .map(|content_type| content_type.to_string());
+        self.replace_metadata(key, metadata, content_type, mission)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_metadata() {
+        let mut metadata = HashMap::new();
+        metadata.insert("clone-backend".to_string(), "s3-v1".to_string());
+        metadata.insert("clone-last-modified".to_string(), "1600000000".to_string());
+
+        let mut updates = metadata.clone();
+        assert!(!merge_metadata(&mut metadata, updates.clone()));
+
+        updates.insert("clone-last-modified".to_string(), "1700000000".to_string());
+        updates.insert("clone-checksum".to_string(), "abc".to_string());
+        assert!(merge_metadata(&mut metadata, updates));
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["clone-last-modified"], "1700000000");
+    }
+
+    #[test]
+    fn test_prefix_hint() {
+        let hex2: PrefixHint = "hex2".parse().unwrap();
+        let prefixes = hex2.prefixes();
+        assert_eq!(prefixes.len(), 256);
+        assert_eq!(prefixes[0], "00");
+        assert_eq!(prefixes[255], "ff");
+        assert_eq!("pypi".parse::<PrefixHint>().unwrap(), PrefixHint::Hex2);
+        assert_eq!("hex1".parse::<PrefixHint>().unwrap().prefixes().len(), 16);
+        assert_eq!("alnum".parse::<PrefixHint>().unwrap().prefixes().len(), 62);
+        assert_eq!(
+            "crates/, index ,".parse::<PrefixHint>().unwrap().prefixes(),
+            vec!["crates".to_string(), "index".to_string()]
+        );
+        assert!(",".parse::<PrefixHint>().is_err());
+    }
+
+    #[test]
+    fn test_virtual_hosted() {
+        let region = Region::Custom {
+            name: "us-east-1".to_string(),
+            endpoint: "https://s3.example.com".to_string(),
+        };
+        let mut request = SignedRequest::new("GET", "s3", &region, "/bucket/pypi/a.whl");
+        virtual_hosted(&mut request);
+        assert_eq!(request.hostname(), "bucket.s3.example.com");
+        assert_eq!(request.path(), "/pypi/a.whl");
+
+        let mut request = SignedRequest::new("GET", "s3", &region, "/bucket");
+        virtual_hosted(&mut request);
+        assert_eq!(request.hostname(), "bucket.s3.example.com");
+        assert_eq!(request.path(), "/");
+
+        assert_eq!(
+            "virtual-hosted".parse::<AddressingStyle>().unwrap(),
+            AddressingStyle::VirtualHosted
+        );
+        assert!("host".parse::<AddressingStyle>().is_err());
+    }
+
+    #[test]
+    fn test_parse_inventory_line() {
+        let schema =
+            InventorySchema::parse("Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size")
+                .unwrap();
+        let meta = schema
+            .parse_line(
+                r#""bucket","mirror/a+b%2Bc.tar.gz","v1","true","false","1024""#,
+                "mirror/",
+            )
+            .unwrap();
+        assert_eq!(meta.key, "a b+c.tar.gz");
+        assert_eq!(meta.size, Some(1024));
+        assert!(schema
+            .parse_line(r#""bucket","mirror/a","v0","false","false","1""#, "mirror/")
+            .is_none());
+        assert!(schema
+            .parse_line(r#""bucket","other/a","v1","true","false","1""#, "mirror/")
+            .is_none());
+        assert_eq!(
+            split_csv_line(r#""a","b ""c"", d",""#),
+            vec!["a", r#"b "c", d"#, ""]
+        );
+    }
+
+    #[test]
+    fn test_md5_etag() {
+        let mut config = S3Config::new_jcloud("mirror".to_string(), false);
+        assert!(config.md5_etag());
+        config.sse = Some("AES256".to_string());
+        assert!(config.md5_etag());
+        config.sse = Some("aws:kms".to_string());
+        assert!(!config.md5_etag());
+    }
+
+    #[test]
+    fn test_grant_headers() {
+        let grant = |id: Option<&str>, uri: Option<&str>, permission: &str| Grant {
+            grantee: Some(rusoto_s3::Grantee {
+                id: id.map(String::from),
+                uri: uri.map(String::from),
+                type_: "CanonicalUser".to_string(),
+                ..Default::default()
+            }),
+            permission: Some(permission.to_string()),
+        };
+        let owner = grant(Some("owner"), None, "FULL_CONTROL");
+        assert!(grant_headers(std::slice::from_ref(&owner), Some("owner")).is_empty());
+
+        let public = grant(
+            None,
+            Some("http://acs.amazonaws.com/groups/global/AllUsers"),
+            "READ",
+        );
+        let headers = grant_headers(&[owner, public], Some("owner"));
+        assert_eq!(headers["FULL_CONTROL"], r#"id="owner""#);
+        assert_eq!(
+            headers["READ"],
+            r#"uri="http://acs.amazonaws.com/groups/global/AllUsers""#
+        );
+    }
 }
@@ -0,0 +1,150 @@
+//! Retry of S3 requests
+//!
+//! Ceph and other S3 services occasionally fail requests under load, with
+//! `503 SlowDown`, other 5xx responses, or dropped connections. Requests of
+//! S3 backend are sent with `retry`, which retries such failures with
+//! exponential backoff and jitter. `SlowDown` responses back off four times
+//! longer, as they ask clients to reduce request rate.
+//!
+//! Errors returned by the service (e.g. `NoSuchKey`) and invalid requests are
+//! not retried.
+
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use rusoto_core::RusotoError;
+use slog::{warn, Logger};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// retries after the first attempt
+    pub retries: u32,
+    /// delay before the first retry
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Transient {
+    Retry,
+    SlowDown,
+}
+
+fn classify<E>(err: &RusotoError<E>) -> Option<Transient> {
+    match err {
+        RusotoError::HttpDispatch(_) => Some(Transient::Retry),
+        RusotoError::Unknown(resp) => {
+            let body = resp.body_as_str();
+            let status = resp.status.as_u16();
+            if body.contains("SlowDown") {
+                Some(Transient::SlowDown)
+            } else if status >= 500 || status == 429 || body.contains("RequestTimeout") {
+                Some(Transient::Retry)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the `attempt`-th retry (from 0), with jitter between half
+    /// and the whole of exponential backoff.
+    fn delay(&self, attempt: u32, transient: &Transient) -> Duration {
+        let exp = match transient {
+            Transient::Retry => attempt,
+            Transient::SlowDown => attempt + 2,
+        };
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << exp.min(16))
+            .min(self.max_delay);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Send an S3 request built by `request`, retrying on transient errors.
+pub async fn retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    logger: &Logger,
+    op: &str,
+    mut request: F,
+) -> Result<T, RusotoError<E>>
+where
+    E: std::fmt::Debug,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RusotoError<E>>>,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match request().await {
+            Ok(resp) => return Ok(resp),
+            Err(err) => err,
+        };
+        match classify(&err) {
+            Some(transient) if attempt < policy.retries => {
+                let delay = policy.delay(attempt, &transient);
+                warn!(
+                    logger,
+                    "{} failed ({:?}), retry in {:?}: {:?}", op, transient, delay, err
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::request::BufferedHttpResponse;
+
+    fn response(status: u16, body: &'static str) -> RusotoError<()> {
+        RusotoError::Unknown(BufferedHttpResponse {
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+            body: bytes::Bytes::from(body),
+            headers: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            classify(&response(503, "<Error><Code>SlowDown</Code></Error>")),
+            Some(Transient::SlowDown)
+        );
+        assert_eq!(classify(&response(500, "")), Some(Transient::Retry));
+        assert_eq!(classify(&response(429, "")), Some(Transient::Retry));
+        assert_eq!(
+            classify(&response(400, "<Error><Code>RequestTimeout</Code></Error>")),
+            Some(Transient::Retry)
+        );
+        assert_eq!(classify(&response(403, "AccessDenied")), None);
+        assert_eq!(classify(&RusotoError::<()>::Service(())), None);
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..10 {
+            let delay = policy.delay(attempt, &Transient::Retry);
+            assert!(delay <= policy.max_delay);
+            assert!(delay >= (policy.base_delay * 2u32.pow(attempt)).min(policy.max_delay) / 2);
+        }
+        assert!(policy.delay(0, &Transient::SlowDown) >= policy.base_delay * 2);
+    }
+}
@@ -0,0 +1,262 @@
+//! Self test
+//!
+//! Self test mirrors a miniature repository end-to-end, so as to validate
+//! a deployment before wiring real sources. It serves bundled fixture files
+//! over a local HTTP server, and runs the full pipeline (`ByteStreamPipe`,
+//! `ChecksumPipe`, `IndexPipe`) against a file backend in a temporary
+//! directory. Then it verifies contents and modified time of mirrored files.
+//!
+//! The fixture also contains an object with a wrong checksum, which should
+//! never reach the target.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use filetime::FileTime;
+use sha2::Digest;
+use slog::{info, warn};
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::checksum_pipe::ChecksumPipe;
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::file_backend::FileBackend;
+use crate::index_pipe::IndexPipe;
+use crate::metadata::SnapshotMeta;
+use crate::simple_diff_transfer::{SimpleDiffTransfer, SimpleDiffTransferConfig};
+use crate::stream_pipe::ByteStreamPipe;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::create_logger;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct SelfTest {
+    #[structopt(long, help = "Keep mirrored files for inspection")]
+    pub keep: bool,
+}
+
+/// Files of the fixture repository.
+const FIXTURE: &[(&str, &str)] = &[
+    ("README", "mirror-clone self test fixture\n"),
+    ("packages/a/a-1.0.tar", "package a, version 1.0\n"),
+    ("packages/a/a-1.1.tar", "package a, version 1.1\n"),
+    ("packages/b/b-0.1.tar", "package b, version 0.1\n"),
+];
+
+/// An object whose checksum in `SHA256SUMS` is wrong.
+const BROKEN: (&str, &str) = ("packages/b/b-broken.tar", "corrupted content\n");
+
+/// Modified time of all fixture files.
+const FIXTURE_MTIME: u64 = 1_600_000_000;
+
+const INDEX: &str = "mirror_clone_list.html";
+
+fn sha256(content: &str) -> String {
+    format!("{:x}", sha2::Sha256::digest(content.as_bytes()))
+}
+
+/// Generate `SHA256SUMS` of fixture repository.
+fn checksums() -> String {
+    let mut result = String::new();
+    for (key, content) in FIXTURE {
+        result += &format!("{}  {}\n", sha256(content), key);
+    }
+    result += &format!("{}  {}\n", sha256("not the content"), BROKEN.0);
+    result
+}
+
+/// Respond to a single HTTP request with fixture files.
+async fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .trim_start_matches('/');
+
+    let body = match path {
+        "SHA256SUMS" => Some(checksums()),
+        _ => FIXTURE
+            .iter()
+            .chain(std::iter::once(&BROKEN))
+            .find(|(key, _)| *key == path)
+            .map(|(_, content)| content.to_string()),
+    };
+
+    let response = match body {
+        Some(body) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        None => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Source of fixture repository, which reads `SHA256SUMS` from server.
+#[derive(Debug)]
+struct Fixture {
+    base: String,
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Fixture {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let response = mission
+            .client
+            .get(format!("{}/SHA256SUMS", self.base))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Error::HTTPError(status));
+        }
+        let checksums = response.text().await?;
+        let snapshot = checksums
+            .lines()
+            .filter_map(|line| line.split_once("  "))
+            .map(|(checksum, key)| SnapshotMeta {
+                key: key.to_string(),
+                last_modified: Some(FIXTURE_MTIME),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some(checksum.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        mission.progress.finish_with_message("done");
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("fixture, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Fixture {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(format!("{}/{}", self.base, snapshot.key)))
+    }
+}
+
+/// Check mirrored files in `base`, returning all problems found.
+fn verify(base: &Path) -> Vec<String> {
+    let mut problems = vec![];
+    for (key, content) in FIXTURE {
+        let path = base.join(key);
+        match std::fs::read_to_string(&path) {
+            Ok(data) if data == *content => {}
+            Ok(_) => problems.push(format!("{}: content mismatch", key)),
+            Err(err) => problems.push(format!("{}: {}", key, err)),
+        }
+        match std::fs::metadata(&path) {
+            Ok(metadata)
+                if FileTime::from_last_modification_time(&metadata).unix_seconds()
+                    == FIXTURE_MTIME as i64 => {}
+            Ok(_) => problems.push(format!("{}: modified time mismatch", key)),
+            Err(_) => {}
+        }
+    }
+    for key in [
+        INDEX.to_string(),
+        format!("packages/{}", INDEX),
+        format!("packages/a/{}", INDEX),
+    ] {
+        if !base.join(&key).is_file() {
+            problems.push(format!("{}: index not generated", key));
+        }
+    }
+    if base.join(BROKEN.0).exists() {
+        problems.push(format!("{}: object with wrong checksum mirrored", BROKEN.0));
+    }
+    problems
+}
+
+pub async fn run(config: SelfTest, transfer_config: SimpleDiffTransferConfig) -> Result<()> {
+    let logger = create_logger(transfer_config.verbose);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    info!(logger, "serving fixture at {}", base);
+    let server = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream));
+        }
+    });
+
+    let work_dir: PathBuf =
+        std::env::temp_dir().join(format!("mirror-clone-self-test-{}", std::process::id()));
+    let buffer_path = work_dir.join("buffer");
+    let base_path = work_dir.join("target");
+    tokio::fs::create_dir_all(&buffer_path).await?;
+    tokio::fs::create_dir_all(&base_path).await?;
+    info!(logger, "mirroring to {}", base_path.display());
+    info!(
+        logger,
+        "{} has a wrong checksum on purpose, and is expected to fail", BROKEN.0
+    );
+
+    let source = Fixture { base };
+    let source = ByteStreamPipe::new(source, buffer_path.display().to_string(), true);
+    let source = ChecksumPipe::new(source);
+    let source = IndexPipe::new(
+        source,
+        buffer_path.display().to_string(),
+        "self-test".to_string(),
+        999,
+    );
+    let target = FileBackend::new(base_path.display().to_string());
+    let transfer_config = SimpleDiffTransferConfig {
+        dry_run: false,
+        force_all: false,
+        no_delete: false,
+        backfill_metadata: false,
+        ..transfer_config
+    };
+    let result = SimpleDiffTransfer::new(source, target, transfer_config)
+        .transfer()
+        .await;
+    server.abort();
+
+    let problems = match result {
+        Ok(()) => verify(&base_path),
+        Err(err) => vec![format!("transfer failed: {:?}", err)],
+    };
+
+    if config.keep {
+        info!(logger, "mirrored files are kept in {}", base_path.display());
+    } else {
+        tokio::fs::remove_dir_all(&work_dir).await?;
+    }
+
+    if problems.is_empty() {
+        info!(logger, "self test passed");
+        Ok(())
+    } else {
+        for problem in &problems {
+            warn!(logger, "{}", problem);
+        }
+        Err(Error::ProcessError(format!(
+            "self test failed with {} problems",
+            problems.len()
+        )))
+    }
+}
@@ -7,11 +7,46 @@
 //! 1. Snapshot object not in source but in target, delete
 //! 2. Snapshot object not in target but in source, add
 //! 3. Snapshot object in both source and target but different, update
+//! 4. Snapshot object in both source and target, but only metadata differs,
+//!    update metadata in place (when `update_metadata` is enabled). Content
+//!    must be known to be the same by checksum, ETag or modified time
+//!    recorded on both sides, not only by size.
 //!
 //! Then, it will concurrently transfer the objects between two endpoints.
 //! The snapshot object should support `Metadata` trait, and simple diff
 //! transfer will transfer them from highest priority to lowest priority.
 //!
+//! Updating an object is split into two pipelined stages: getting it from
+//! source (which downloads and verifies it in pipes), and putting it to
+//! target. Each stage runs `concurrent_transfer` workers, so that the next
+//! object could be fetched while the previous one is being uploaded.
+//!
+//! With `backfill_metadata`, only metadata of objects identical on both sides
+//! are updated, which upgrades old targets (e.g. S3 buckets uploaded before
+//! `clone-checksum` was recorded) without transferring anything. Objects
+//! to be transferred or deleted are left as is, and failed metadata updates
+//! are not retried with full updates. Objects missing metadata, whose
+//! content can't be verified to be the same, are counted and left as is.
+//!
+//! If target has limited space (e.g. local file system), bytes to be added by
+//! the plan are checked against it before transferring. The transfer is
+//! aborted if they don't fit, or with `trim_to_space`, objects with lower
+//! priority are left out until they fit.
+//!
+//! Objects transferred `after_all` (e.g. manifests recording a whole run) are
+//! held back until all other updates and metadata updates are done, and are
+//! left out if any of them failed or was skipped, including metadata updates
+//! failed even with full updates.
+//!
+//! If `delete_filter` is set, only objects accepted by it are deleted, so that
+//! sources could expire their own objects while leaving others untouched.
+//! Objects accepted by `keep_filter` (e.g. those left out by source filters)
+//! are never deleted.
+//!
+//! Sources may report optional objects missing upstream (e.g. documentation
+//! never published) with `Error::Absent`. They are skipped, and not counted
+//! as failures.
+//!
 //! If transfer of an object fails, it will be simply ignored. We could
 //! later implement some kind of retry logic.
 
@@ -19,33 +54,145 @@ use futures_util::{stream, StreamExt};
 use indicatif::{MultiProgress, ProgressBar};
 use reqwest::ClientBuilder;
 
+use crate::accounting::{append_report, Accounting};
+use crate::circuit_breaker::CircuitBreaker;
 use crate::common::{Mission, SnapshotConfig};
 use crate::error::{Error, Result};
+use crate::stream_pipe::RangeConfig;
+use crate::throttle::Throttle;
 use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
 use crate::traits::{Diff, Key, Metadata, SnapshotStorage, SourceStorage, TargetStorage};
-use crate::utils::{create_logger, spinner};
+use crate::utils::{create_logger, human_duration, human_size, spinner};
+use crate::validator_cache::ValidatorCache;
 
 use iter_set::{classify_by, Inclusion};
 use rand::prelude::*;
 use slog::{debug, info, o, warn};
 
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+/// Progress bars of transfer workers. Each running task borrows a lane,
+/// so that keys being transferred are shown live.
+struct WorkerLanes {
+    all: Vec<ProgressBar>,
+    idle: std::sync::Mutex<Vec<ProgressBar>>,
+}
+
+impl WorkerLanes {
+    fn new(lanes: Vec<ProgressBar>) -> Self {
+        Self {
+            idle: std::sync::Mutex::new(lanes.iter().rev().cloned().collect()),
+            all: lanes,
+        }
+    }
+
+    fn acquire(&self) -> ProgressBar {
+        self.idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(ProgressBar::hidden)
+    }
+
+    fn release(&self, lane: ProgressBar) {
+        lane.set_message("idle");
+        self.idle.lock().unwrap().push(lane);
+    }
+
+    fn finish(&self) {
+        for lane in &self.all {
+            lane.finish_and_clear();
+        }
+    }
+}
 
 enum PlanType {
-    Update,
+    UpdateMetadata,
     Delete,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Predicate on keys of target objects, deciding whether they may be deleted.
+#[derive(Clone)]
+pub struct DeleteFilter(pub Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl fmt::Debug for DeleteFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeleteFilter")
+    }
+}
+
+/// Predicate on keys and sizes of target objects.
+pub type KeepFn = dyn Fn(&str, Option<u64>) -> bool + Send + Sync;
+
+/// Predicate on keys and sizes of target objects, deciding whether they are
+/// kept out of deletions.
+#[derive(Clone)]
+pub struct KeepFilter(pub Arc<KeepFn>);
+
+impl fmt::Debug for KeepFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeepFilter")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SimpleDiffTransferConfig {
     pub progress: bool,
+    pub verbose: u8,
     pub concurrent_transfer: usize,
+    /// concurrent deletions, so that targets deleting objects in batches
+    /// could fill them
+    pub concurrent_delete: usize,
     pub no_delete: bool,
     pub dry_run: bool,
     pub snapshot_config: SnapshotConfig,
     pub print_plan: usize,
     pub force_all: bool,
+    pub update_metadata: bool,
+    pub backfill_metadata: bool,
+    pub trim_to_space: bool,
+    pub accounting_report: Option<String>,
+    pub circuit_breaker_threshold: usize,
+    pub circuit_breaker_cooldown: Duration,
+    /// bytes per second of all transfer tasks
+    pub max_bandwidth: Option<u64>,
+    /// stream objects not smaller than this to target without buffering
+    pub direct_stream: Option<u64>,
+    /// range requests of buffered downloads
+    pub range: RangeConfig,
+    /// file to keep validators of downloaded objects in, for conditional
+    /// requests
+    pub validator_cache: Option<String>,
+    pub delete_filter: Option<DeleteFilter>,
+    pub keep_filter: Option<KeepFilter>,
+}
+
+impl fmt::Display for SimpleDiffTransferConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "concurrent_transfer={} concurrent_delete={} concurrent_resolve={} ",
+            self.concurrent_transfer,
+            self.concurrent_delete,
+            self.snapshot_config.concurrent_resolve,
+        )?;
+        write!(
+            f,
+            "no_delete={} dry_run={} force_all={} update_metadata={} backfill_metadata={} delete_filter={} keep_filter={}",
+            self.no_delete,
+            self.dry_run,
+            self.force_all,
+            self.update_metadata,
+            self.backfill_metadata,
+            self.delete_filter.is_some(),
+            self.keep_filter.is_some()
+        )
+    }
 }
 
 pub struct SimpleDiffTransfer<Snapshot, Source, Target, Item>
@@ -85,15 +232,50 @@ where
         }
     }
 
+    async fn update_object(
+        source: &Source,
+        target: &Target,
+        snapshot: &Snapshot,
+        source_mission: &Mission,
+        target_mission: &Mission,
+    ) -> Result<()> {
+        let source_object = source.get_object(snapshot, source_mission).await?;
+        target
+            .put_object(snapshot, source_object, target_mission)
+            .await
+    }
+
     pub async fn transfer(mut self) -> Result<()> {
-        let logger = create_logger();
+        let logger = create_logger(self.config.verbose);
+        let start = Instant::now();
         let client = ClientBuilder::new()
             .user_agent(crate::utils::user_agent())
             .connect_timeout(Duration::from_secs(10))
             .build()?;
-        info!(logger, "using simple diff transfer"; "config" => format!("{:?}", self.config));
+        info!(logger, "using simple diff transfer"; "config" => self.config.to_string());
         info!(logger, "begin transfer"; "source" => self.source.info(), "target" => self.target.info());
 
+        let accounting = Arc::new(Accounting::default());
+        let breaker = Arc::new(CircuitBreaker::new(
+            self.config.circuit_breaker_threshold,
+            self.config.circuit_breaker_cooldown,
+        ));
+        let throttle = Arc::new(Throttle::new(self.config.max_bandwidth));
+        let direct_stream = self.config.direct_stream;
+        let range = self.config.range;
+        let validators = match &self.config.validator_cache {
+            Some(path) => Some(Arc::new(
+                ValidatorCache::load(path.clone())
+                    .await
+                    .unwrap_or_else(|err| {
+                        info!(logger, "no previous validator cache: {:?}", err);
+                        ValidatorCache::new(path.clone())
+                    }),
+            )),
+            None => None,
+        };
+        let source_info = self.source.info();
+
         info!(logger, "taking snapshot...");
 
         let all_progress = MultiProgress::new();
@@ -108,12 +290,24 @@ where
             client: client.clone(),
             progress: source_progress,
             logger: logger.new(o!("task" => "snapshot.source")),
+            accounting: accounting.clone(),
+            breaker: breaker.clone(),
+            throttle: throttle.clone(),
+            direct_stream,
+            range,
+            validators: None,
         };
 
         let target_mission = Mission {
             client: client.clone(),
             progress: target_progress,
             logger: logger.new(o!("task" => "snapshot.target")),
+            accounting: accounting.clone(),
+            breaker: breaker.clone(),
+            throttle: throttle.clone(),
+            direct_stream,
+            range,
+            validators: None,
         };
 
         let config_progress = self.config.progress;
@@ -124,6 +318,13 @@ where
             }
         });
 
+        let estimate = self.source.estimate(&source_mission).await;
+        if let Some(estimate) = estimate {
+            info!(logger, "source: about {} objects", estimate);
+            source_mission.progress.set_length(estimate as u64);
+            source_mission.progress.set_style(crate::utils::bar());
+        }
+
         let source_snapshot = self
             .source
             .snapshot(source_mission, &self.config.snapshot_config)
@@ -136,31 +337,17 @@ where
 
         handle.await.ok();
 
+        info!(
+            logger,
+            "snapshot taken in {}",
+            human_duration(start.elapsed())
+        );
+
         Self::debug_snapshot(logger.clone(), &source_snapshot);
         Self::debug_snapshot(logger.clone(), &target_snapshot);
 
         info!(logger, "mirror in progress...");
 
-        let progress = if self.config.progress {
-            ProgressBar::new(source_snapshot.len() as u64)
-        } else {
-            ProgressBar::hidden()
-        };
-        progress.set_style(crate::utils::bar());
-        progress.set_prefix("mirror");
-
-        let source_mission = Arc::new(Mission {
-            client: client.clone(),
-            progress: ProgressBar::hidden(),
-            logger: logger.new(o!("task" => "mirror.source")),
-        });
-
-        let target_mission = Arc::new(Mission {
-            client: client.clone(),
-            progress: ProgressBar::hidden(),
-            logger: logger.new(o!("task" => "mirror.target")),
-        });
-
         info!(logger, "generating transfer plan...");
 
         let source_count = source_snapshot.len();
@@ -216,19 +403,37 @@ where
             target_snapshot.len()
         );
 
-        let mut updates = vec![];
-        let mut deletions = vec![];
+        // at least objects more than target are added, and vice versa
+        let mut updates = Vec::with_capacity(
+            estimate
+                .unwrap_or(source_snapshot.len())
+                .saturating_sub(target_snapshot.len()),
+        );
+        let mut metadata_updates = vec![];
+        let mut deletions =
+            Vec::with_capacity(target_snapshot.len().saturating_sub(source_snapshot.len()));
+
+        // bytes added to target by updates, as replaced objects are freed
+        let mut added_bytes: i64 = 0;
 
         let mut max_info = 0;
+        let mut kept = 0;
+        // objects missing metadata, but not known to be identical to source
+        let mut unverified = 0;
         for result in classify_by(source_snapshot, target_snapshot, |a, b| {
             a.key().cmp(b.key())
         }) {
             match result {
                 Inclusion::Left(source) => {
+                    // objects missing in target are always downloaded
+                    if let Some(cache) = &validators {
+                        cache.forget(source.key());
+                    }
                     if max_info < self.config.print_plan {
                         info!(logger, "+ {:?}", source.key());
                         max_info += 1;
                     }
+                    added_bytes += source.size().unwrap_or(0) as i64;
                     updates.push(source);
                 }
                 Inclusion::Both(l, r) => {
@@ -237,10 +442,42 @@ where
                             info!(logger, "= {:?}", l.key());
                             max_info += 1;
                         }
+                        added_bytes += l.size().unwrap_or(0) as i64 - r.size().unwrap_or(0) as i64;
                         updates.push(l);
+                    } else if (self.config.update_metadata || self.config.backfill_metadata)
+                        && l.diff_metadata(&r)
+                    {
+                        if max_info < self.config.print_plan {
+                            info!(logger, "~ {:?}", l.key());
+                            max_info += 1;
+                        }
+                        metadata_updates.push(l);
+                    } else if self.config.backfill_metadata && l.missing_metadata(&r) {
+                        unverified += 1;
                     }
                 }
                 Inclusion::Right(target) => {
+                    if let Some(cache) = &validators {
+                        cache.forget(target.key());
+                    }
+                    if let Some(KeepFilter(keep)) = &self.config.keep_filter {
+                        if keep(target.key(), target.size()) {
+                            kept += 1;
+                            continue;
+                        }
+                    }
+                    if let Some(DeleteFilter(filter)) = &self.config.delete_filter {
+                        if !filter(target.key()) {
+                            continue;
+                        }
+                        // filtered deletions are always shown in dry run, so
+                        // that they could be reviewed before taking effect
+                        if self.config.dry_run {
+                            info!(logger, "- {:?}", target.key());
+                            deletions.push(target);
+                            continue;
+                        }
+                    }
                     if max_info < self.config.print_plan {
                         info!(logger, "- {:?}", target.key());
                         max_info += 1;
@@ -250,14 +487,84 @@ where
             }
         }
 
+        if kept > 0 {
+            info!(
+                logger,
+                "{} objects left out by filters are kept in target", kept
+            );
+        }
+
+        if unverified > 0 {
+            warn!(
+                logger,
+                "{} objects missing metadata can't be verified to be identical to source, not backfilled",
+                unverified
+            );
+        }
+
+        if self.config.backfill_metadata {
+            info!(
+                logger,
+                "backfilling metadata, skip updating {} objects and deleting {} objects",
+                updates.len(),
+                deletions.len()
+            );
+            updates.clear();
+            deletions.clear();
+            added_bytes = 0;
+        }
+
         // sort plan by priority
         updates.sort_by_key(|snapshot| -snapshot.priority());
+        metadata_updates.sort_by_key(|snapshot| -snapshot.priority());
         deletions.sort_by_key(|snapshot| -snapshot.priority());
 
+        if let Some(available) = self.target.available_space().await? {
+            let added_bytes = added_bytes.max(0) as u64;
+            info!(
+                logger,
+                "{} to be added, {} available in target",
+                human_size(added_bytes),
+                human_size(available)
+            );
+            if added_bytes > available {
+                if !self.config.trim_to_space {
+                    return Err(Error::StorageError(format!(
+                        "{} to be added exceeds {} available in target",
+                        human_size(added_bytes),
+                        human_size(available)
+                    )));
+                }
+                // keep objects by priority, without counting space freed
+                let total = updates.len();
+                let mut budget = available;
+                updates.retain(|snapshot| {
+                    let size = snapshot.size().unwrap_or(0);
+                    if size <= budget {
+                        budget -= size;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                warn!(
+                    logger,
+                    "trimmed {} objects to fit available space",
+                    total - updates.len()
+                );
+            }
+        }
+
+        let (finals, updates): (Vec<_>, Vec<_>) = updates
+            .into_iter()
+            .partition(|snapshot| snapshot.after_all());
+
         info!(
             logger,
-            "update {} objects, delete {} objects",
+            "update {} objects (and {} after all), update metadata of {} objects, delete {} objects",
             updates.len(),
+            finals.len(),
+            metadata_updates.len(),
             deletions.len()
         );
 
@@ -265,47 +572,163 @@ where
             return Ok(());
         }
 
+        let all_progress = MultiProgress::new();
+        let add_progress = |bar: ProgressBar| {
+            if self.config.progress {
+                all_progress.add(bar)
+            } else {
+                ProgressBar::hidden()
+            }
+        };
+
+        let progress = add_progress(ProgressBar::new(updates.len() as u64));
+        progress.set_style(crate::utils::bar());
+        progress.set_prefix("mirror");
+        progress.set_message("updating objects");
+
+        let lanes = Arc::new(WorkerLanes::new(
+            (0..self.config.concurrent_transfer)
+                .map(|idx| {
+                    let lane = add_progress(ProgressBar::new(0));
+                    lane.set_style(spinner());
+                    lane.set_prefix(&format!("[worker {}]", idx));
+                    lane.set_message("idle");
+                    lane
+                })
+                .collect(),
+        ));
+        let upload_lanes = Arc::new(WorkerLanes::new(
+            (0..self.config.concurrent_transfer)
+                .map(|idx| {
+                    let lane = add_progress(ProgressBar::new(0));
+                    lane.set_style(spinner());
+                    lane.set_prefix(&format!("[upload {}]", idx));
+                    lane.set_message("idle");
+                    lane
+                })
+                .collect(),
+        ));
+
+        let handle = tokio::task::spawn_blocking(move || {
+            if config_progress {
+                all_progress.join().unwrap()
+            }
+        });
+
         info!(logger, "updating objects");
 
         let source = Arc::new(self.source);
         let target = Arc::new(self.target);
+        let backfill_metadata = self.config.backfill_metadata;
+        let source_logger = logger.new(o!("task" => "mirror.source"));
+        let target_logger = logger.new(o!("task" => "mirror.target"));
 
-        progress.set_length(updates.len() as u64);
-        progress.set_position(0);
+        // objects failed to update, skipped as their upstream is paused, not
+        // modified since previous download, and optional ones absent upstream
+        let failed = Arc::new(AtomicUsize::new(0));
+        let skipped = Arc::new(AtomicUsize::new(0));
+        let unchanged = Arc::new(AtomicUsize::new(0));
+        let absent = Arc::new(AtomicUsize::new(0));
 
         let map_snapshot = |snapshot: Snapshot, plan: PlanType| {
-            progress.set_message(snapshot.key());
             let source = source.clone();
             let target = target.clone();
-            let source_mission = source_mission.clone();
-            let target_mission = target_mission.clone();
+            let lanes = lanes.clone();
+            let client = client.clone();
+            let accounting = accounting.clone();
+            let breaker = breaker.clone();
+            let throttle = throttle.clone();
+            let source_logger = source_logger.clone();
+            let target_logger = target_logger.clone();
             let logger = logger.clone();
+            let failed = failed.clone();
+            let skipped = skipped.clone();
 
             let func = async move {
+                let lane = lanes.acquire();
+                lane.set_message(snapshot.key());
+                let source_mission = Mission {
+                    client: client.clone(),
+                    progress: lane.clone(),
+                    logger: source_logger,
+                    accounting: accounting.clone(),
+                    breaker: breaker.clone(),
+                    throttle: throttle.clone(),
+                    direct_stream,
+                    range,
+                    validators: None,
+                };
+                let target_mission = Mission {
+                    client,
+                    progress: lane.clone(),
+                    logger: target_logger,
+                    accounting,
+                    breaker,
+                    throttle,
+                    direct_stream,
+                    range,
+                    validators: None,
+                };
+
                 match plan {
-                    PlanType::Update => match source.get_object(&snapshot, &source_mission).await {
-                        Ok(source_object) => {
-                            if let Err(err) = target
-                                .put_object(&snapshot, source_object, &target_mission)
-                                .await
-                            {
+                    PlanType::UpdateMetadata => {
+                        let result = target
+                            .update_metadata(&snapshot, &target_mission)
+                            .timeout(Duration::from_secs(60))
+                            .await
+                            .into_result();
+                        match result {
+                            Err(err) if backfill_metadata => {
                                 warn!(
                                     target_mission.logger,
-                                    "error while put {}: {:?}",
+                                    "error while backfill metadata {}: {:?}",
                                     snapshot.key(),
                                     err
                                 );
+                                failed.fetch_add(1, Ordering::Relaxed);
                             }
+                            Err(err) => {
+                                warn!(
+                                    target_mission.logger,
+                                    "error while update metadata {}, fallback to full update: {:?}",
+                                    snapshot.key(),
+                                    err
+                                );
+                                let result = Self::update_object(
+                                    &source,
+                                    &target,
+                                    &snapshot,
+                                    &source_mission,
+                                    &target_mission,
+                                )
+                                .timeout(Duration::from_secs(60))
+                                .await
+                                .into_result();
+                                match result {
+                                    Err(Error::CircuitOpen(host)) => {
+                                        debug!(
+                                            target_mission.logger,
+                                            "skip {}: requests to {} are paused",
+                                            snapshot.key(),
+                                            host
+                                        );
+                                        skipped.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    Err(err) => {
+                                        warn!(
+                                            target_mission.logger,
+                                            "error while update {}: {:?}",
+                                            snapshot.key(),
+                                            err
+                                        );
+                                        failed.fetch_add(1, Ordering::Relaxed);
+                                    }
+                                    Ok(()) => {}
+                                }
+                            }
+                            Ok(()) => {}
                         }
-                        Err(err) => {
-                            warn!(
-                                target_mission.logger,
-                                "error while get {}: {:?}",
-                                snapshot.key(),
-                                err
-                            );
-                        }
-                    },
+                    }
                     PlanType::Delete => {
                         if let Err(err) = target
                             .delete_object(&snapshot, &target_mission)
@@ -323,6 +746,8 @@ where
                     }
                 }
 
+                lanes.release(lane);
+
                 Ok::<(), Error>(())
             };
 
@@ -333,19 +758,202 @@ where
             }
         };
 
-        let mut results = stream::iter(
-            updates
-                .into_iter()
-                .map(|plan| map_snapshot(plan, PlanType::Update)),
-        )
-        .buffer_unordered(self.config.concurrent_transfer);
+        let fetch_snapshot = |snapshot: Snapshot, fetched_tx: mpsc::Sender<(Snapshot, Item)>| {
+            let source = source.clone();
+            let lanes = lanes.clone();
+            let client = client.clone();
+            let accounting = accounting.clone();
+            let breaker = breaker.clone();
+            let throttle = throttle.clone();
+            let source_logger = source_logger.clone();
+            let progress = progress.clone();
+            let failed = failed.clone();
+            let skipped = skipped.clone();
+            let unchanged = unchanged.clone();
+            let absent = absent.clone();
+            let validators = validators.clone();
+
+            async move {
+                let lane = lanes.acquire();
+                lane.set_message(snapshot.key());
+                let source_mission = Mission {
+                    client,
+                    progress: lane.clone(),
+                    logger: source_logger,
+                    accounting,
+                    breaker,
+                    throttle,
+                    direct_stream,
+                    range,
+                    validators: validators.as_ref().map(|cache| cache.scope(snapshot.key())),
+                };
+                let result = source.get_object(&snapshot, &source_mission).await;
+                lanes.release(lane);
+
+                match result {
+                    Ok(source_object) => {
+                        // receiver only goes away when uploading stage is done
+                        fetched_tx.send((snapshot, source_object)).await.ok();
+                    }
+                    Err(Error::CircuitOpen(host)) => {
+                        debug!(
+                            source_mission.logger,
+                            "skip {}: requests to {} are paused",
+                            snapshot.key(),
+                            host
+                        );
+                        skipped.fetch_add(1, Ordering::Relaxed);
+                        progress.inc(1);
+                    }
+                    Err(Error::NotModified(url)) => {
+                        debug!(
+                            source_mission.logger,
+                            "skip {}: {} not modified",
+                            snapshot.key(),
+                            url
+                        );
+                        unchanged.fetch_add(1, Ordering::Relaxed);
+                        progress.inc(1);
+                    }
+                    Err(Error::Absent(url)) => {
+                        debug!(
+                            source_mission.logger,
+                            "skip {}: {} absent upstream",
+                            snapshot.key(),
+                            url
+                        );
+                        absent.fetch_add(1, Ordering::Relaxed);
+                        progress.inc(1);
+                    }
+                    Err(err) => {
+                        warn!(
+                            source_mission.logger,
+                            "error while get {}: {:?}",
+                            snapshot.key(),
+                            err
+                        );
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        progress.inc(1);
+                    }
+                }
+            }
+        };
 
-        while let Some(_x) = results.next().await {
-            progress.inc(1);
+        let put_snapshot = |(snapshot, source_object): (Snapshot, Item)| {
+            let target = target.clone();
+            let upload_lanes = upload_lanes.clone();
+            let client = client.clone();
+            let accounting = accounting.clone();
+            let breaker = breaker.clone();
+            let throttle = throttle.clone();
+            let target_logger = target_logger.clone();
+            let failed = failed.clone();
+            let validators = validators.clone();
+
+            async move {
+                let lane = upload_lanes.acquire();
+                lane.set_message(snapshot.key());
+                let target_mission = Mission {
+                    client,
+                    progress: lane.clone(),
+                    logger: target_logger,
+                    accounting,
+                    breaker,
+                    throttle,
+                    direct_stream,
+                    range,
+                    validators: None,
+                };
+                if let Err(err) = target
+                    .put_object(&snapshot, source_object, &target_mission)
+                    .await
+                {
+                    warn!(
+                        target_mission.logger,
+                        "error while put {}: {:?}",
+                        snapshot.key(),
+                        err
+                    );
+                    failed.fetch_add(1, Ordering::Relaxed);
+                } else if let Some(cache) = &validators {
+                    cache.commit(snapshot.key());
+                }
+                upload_lanes.release(lane);
+            }
+        };
+
+        // Fetched objects are handed over to uploading stage through a bounded
+        // channel, so that at most `concurrent_transfer` objects are waiting
+        // in buffer.
+        let concurrent_transfer = self.config.concurrent_transfer;
+        let (fetch_snapshot, put_snapshot, progress) = (&fetch_snapshot, &put_snapshot, &progress);
+        let update_objects = |updates: Vec<Snapshot>| async move {
+            let (fetched_tx, mut fetched_rx) = mpsc::channel(concurrent_transfer);
+            let fetch = async {
+                let fetched_tx = fetched_tx;
+                stream::iter(
+                    updates
+                        .into_iter()
+                        .map(|snapshot| fetch_snapshot(snapshot, fetched_tx.clone())),
+                )
+                .buffer_unordered(concurrent_transfer)
+                .for_each(|_| async {})
+                .await;
+            };
+            let upload = async {
+                let mut results = stream::poll_fn(|cx| fetched_rx.poll_recv(cx))
+                    .map(put_snapshot)
+                    .buffer_unordered(concurrent_transfer);
+                while let Some(_x) = results.next().await {
+                    progress.inc(1);
+                }
+            };
+            tokio::join!(fetch, upload);
+        };
+        update_objects(updates).await;
+
+        if !metadata_updates.is_empty() {
+            info!(logger, "updating metadata");
+            progress.set_message("updating metadata");
+
+            progress.set_length(metadata_updates.len() as u64);
+            progress.set_position(0);
+
+            let mut results = stream::iter(
+                metadata_updates
+                    .into_iter()
+                    .map(|plan| map_snapshot(plan, PlanType::UpdateMetadata)),
+            )
+            .buffer_unordered(self.config.concurrent_transfer);
+
+            while let Some(_x) = results.next().await {
+                progress.inc(1);
+            }
+        }
+
+        if !finals.is_empty() {
+            let failed = failed.load(Ordering::Relaxed) + skipped.load(Ordering::Relaxed);
+            if failed > 0 {
+                warn!(
+                    logger,
+                    "skip {} objects to be updated after all, as {} objects failed or skipped",
+                    finals.len(),
+                    failed
+                );
+            } else {
+                info!(logger, "updating objects after all");
+                progress.set_message("updating objects after all");
+
+                progress.set_length(finals.len() as u64);
+                progress.set_position(0);
+
+                update_objects(finals).await;
+            }
         }
 
         if !self.config.no_delete {
             info!(logger, "deleting objects");
+            progress.set_message("deleting objects");
 
             progress.set_length(deletions.len() as u64);
             progress.set_position(0);
@@ -355,15 +963,199 @@ where
                     .into_iter()
                     .map(|plan| map_snapshot(plan, PlanType::Delete)),
             )
-            .buffer_unordered(self.config.concurrent_transfer);
+            .buffer_unordered(self.config.concurrent_delete.max(1));
 
             while let Some(_x) = results.next().await {
                 progress.inc(1);
             }
         }
 
-        info!(logger, "transfer complete");
+        lanes.finish();
+        upload_lanes.finish();
+        progress.finish_with_message("done");
+        handle.await.ok();
+
+        info!(
+            logger,
+            "transfer complete in {}",
+            human_duration(start.elapsed())
+        );
+
+        let failed = failed.load(Ordering::Relaxed);
+        let skipped = skipped.load(Ordering::Relaxed);
+        if failed > 0 || skipped > 0 {
+            warn!(
+                logger,
+                "{} objects failed, {} skipped as their upstream is paused", failed, skipped
+            );
+        }
+        let unchanged = unchanged.load(Ordering::Relaxed);
+        if unchanged > 0 {
+            info!(logger, "{} objects not modified upstream", unchanged);
+        }
+        let absent = absent.load(Ordering::Relaxed);
+        if absent > 0 {
+            info!(logger, "{} optional objects absent upstream", absent);
+        }
+
+        if let Some(cache) = &validators {
+            if let Err(err) = cache.save().await {
+                warn!(logger, "failed to save validator cache: {:?}", err);
+            }
+        }
+
+        let record = accounting.record(source_info);
+        info!(
+            logger,
+            "downloaded {}, uploaded {}",
+            human_size(record.total_downloads()),
+            human_size(record.total_uploads())
+        );
+        if let Some(path) = &self.config.accounting_report {
+            if let Err(err) = append_report(path, &record).await {
+                warn!(logger, "failed to write accounting report: {:?}", err);
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{SnapshotMeta, SnapshotMetaFlag};
+
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    struct Source;
+
+    #[async_trait]
+    impl SnapshotStorage<SnapshotMeta> for Source {
+        async fn snapshot(&mut self, _: Mission, _: &SnapshotConfig) -> Result<Vec<SnapshotMeta>> {
+            Ok(vec![
+                SnapshotMeta {
+                    key: "a".to_string(),
+                    size: Some(1),
+                    last_modified: Some(1600000000),
+                    checksum_method: Some("sha256".to_string()),
+                    checksum: Some("abc".to_string()),
+                    ..Default::default()
+                },
+                SnapshotMeta {
+                    key: "final".to_string(),
+                    flags: SnapshotMetaFlag {
+                        after_all: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            ])
+        }
+
+        fn info(&self) -> String {
+            String::from("source")
+        }
+    }
+
+    #[async_trait]
+    impl SourceStorage<SnapshotMeta, String> for Source {
+        async fn get_object(&self, snapshot: &SnapshotMeta, _: &Mission) -> Result<String> {
+            Ok(snapshot.key.clone())
+        }
+    }
+
+    /// Target with `a` missing checksum, failing all metadata updates, and
+    /// failing full updates too if `fail_put` is set.
+    struct Target {
+        fail_put: bool,
+        puts: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl SnapshotStorage<SnapshotMeta> for Target {
+        async fn snapshot(&mut self, _: Mission, _: &SnapshotConfig) -> Result<Vec<SnapshotMeta>> {
+            Ok(vec![SnapshotMeta {
+                key: "a".to_string(),
+                size: Some(1),
+                last_modified: Some(1600000000),
+                ..Default::default()
+            }])
+        }
+
+        fn info(&self) -> String {
+            String::from("target")
+        }
+    }
+
+    #[async_trait]
+    impl TargetStorage<SnapshotMeta, String> for Target {
+        async fn put_object(&self, _: &SnapshotMeta, item: String, _: &Mission) -> Result<()> {
+            if self.fail_put && item == "a" {
+                return Err(Error::StorageError(String::from("put failed")));
+            }
+            self.puts.lock().unwrap().push(item);
+            Ok(())
+        }
+
+        async fn delete_object(&self, _: &SnapshotMeta, _: &Mission) -> Result<()> {
+            Ok(())
+        }
+
+        async fn update_metadata(&self, _: &SnapshotMeta, _: &Mission) -> Result<()> {
+            Err(Error::StorageError(String::from("update metadata failed")))
+        }
+    }
+
+    fn config() -> SimpleDiffTransferConfig {
+        SimpleDiffTransferConfig {
+            progress: false,
+            verbose: 0,
+            concurrent_transfer: 1,
+            concurrent_delete: 1,
+            no_delete: true,
+            dry_run: false,
+            snapshot_config: SnapshotConfig {
+                concurrent_resolve: 1,
+            },
+            print_plan: 0,
+            force_all: false,
+            update_metadata: true,
+            backfill_metadata: false,
+            trim_to_space: false,
+            accounting_report: None,
+            circuit_breaker_threshold: 0,
+            circuit_breaker_cooldown: Duration::from_secs(0),
+            max_bandwidth: None,
+            direct_stream: None,
+            range: RangeConfig::default(),
+            validator_cache: None,
+            delete_filter: None,
+            keep_filter: None,
+        }
+    }
+
+    async fn transfer(fail_put: bool) -> Vec<String> {
+        std::env::set_var("MIRROR_CLONE_SITE", "test");
+        let puts = Arc::new(Mutex::new(vec![]));
+        let target = Target {
+            fail_put,
+            puts: puts.clone(),
+        };
+        SimpleDiffTransfer::new(Source, target, config())
+            .transfer()
+            .await
+            .unwrap();
+        let puts = puts.lock().unwrap().clone();
+        puts
+    }
+
+    #[tokio::test]
+    async fn test_failed_metadata_update_skips_finals() {
+        // metadata update falls back to full update
+        assert_eq!(transfer(false).await, vec!["a", "final"]);
+        // and if that fails too, finals are left out
+        assert!(transfer(true).await.is_empty());
+    }
+}
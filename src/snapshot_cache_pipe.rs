@@ -0,0 +1,211 @@
+//! SnapshotCachePipe caches snapshot of source on disk.
+//!
+//! Enumerating some sources (e.g. PyPI and pub.dev) takes hours. When a
+//! cache file is given, snapshot of source is saved to it along with the time
+//! it is taken, and is reused by later runs until it is older than `ttl`, so
+//! that repeated runs (e.g. when debugging pipes or targets) don't crawl
+//! upstream again.
+//!
+//! Only sources whose `get_object` doesn't depend on state built when taking
+//! snapshot could be cached. Without a cache file, this pipe passes snapshot
+//! through.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig};
+use crate::error::Result;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::{human_duration, unix_time};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct SnapshotCacheConfig {
+    #[structopt(
+        long,
+        help = "Cache snapshot of source in this file, and reuse it in later runs (only for some sources)"
+    )]
+    pub snapshot_cache: Option<String>,
+    #[structopt(
+        long,
+        help = "Seconds to reuse cached snapshot for",
+        default_value = "3600"
+    )]
+    pub snapshot_cache_ttl: u64,
+}
+
+impl SnapshotCacheConfig {
+    pub fn into_pipe<Source>(self, source: Source) -> SnapshotCachePipe<Source> {
+        SnapshotCachePipe::new(
+            source,
+            self.snapshot_cache,
+            Duration::from_secs(self.snapshot_cache_ttl),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedSnapshot<Snapshot> {
+    /// unix time when snapshot is taken
+    created_at: u64,
+    snapshot: Vec<Snapshot>,
+}
+
+/// Load snapshot cached in `path`, returning `None` if it's older than `ttl`.
+async fn load_cache<Snapshot: DeserializeOwned>(
+    path: &str,
+    ttl: Duration,
+    now: u64,
+) -> Result<Option<CachedSnapshot<Snapshot>>> {
+    let data = tokio::fs::read(path).await?;
+    let cache: CachedSnapshot<Snapshot> = serde_json::from_slice(&data)?;
+    if now.saturating_sub(cache.created_at) < ttl.as_secs() {
+        Ok(Some(cache))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Save snapshot to a temporary file and rename it, so that an interrupted
+/// save won't leave a broken cache.
+async fn save_cache<Snapshot: Serialize>(
+    path: &str,
+    cache: &CachedSnapshot<Snapshot>,
+) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, serde_json::to_vec(cache)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+pub struct SnapshotCachePipe<Source> {
+    pub source: Source,
+    pub path: Option<String>,
+    pub ttl: Duration,
+}
+
+impl<Source> SnapshotCachePipe<Source> {
+    pub fn new(source: Source, path: Option<String>, ttl: Duration) -> Self {
+        Self { source, path, ttl }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source> SnapshotStorage<Snapshot> for SnapshotCachePipe<Source>
+where
+    Snapshot: Serialize + DeserializeOwned + Send + Sync + 'static,
+    Source: SnapshotStorage<Snapshot> + Send,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<Snapshot>> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return self.source.snapshot(mission, config).await,
+        };
+        let logger = mission.logger.clone();
+        let progress = mission.progress.clone();
+
+        let now = unix_time();
+        match load_cache::<Snapshot>(&path, self.ttl, now).await {
+            Ok(Some(cache)) => {
+                info!(
+                    logger,
+                    "using snapshot cached {} ago in {}, {} objects",
+                    human_duration(Duration::from_secs(now.saturating_sub(cache.created_at))),
+                    path,
+                    cache.snapshot.len()
+                );
+                progress.finish_with_message("cached");
+                return Ok(cache.snapshot);
+            }
+            Ok(None) => info!(logger, "snapshot cached in {} expired", path),
+            Err(err) => info!(logger, "no snapshot cached in {}: {:?}", path, err),
+        }
+
+        let cache = CachedSnapshot {
+            created_at: now,
+            snapshot: self.source.snapshot(mission, config).await?,
+        };
+        if let Err(err) = save_cache(&path, &cache).await {
+            warn!(logger, "failed to cache snapshot in {}: {:?}", path, err);
+        }
+        Ok(cache.snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        match &self.path {
+            Some(path) => format!(
+                "snapshot cached in {} for {:?} <{}>",
+                path,
+                self.ttl,
+                self.source.info()
+            ),
+            None => self.source.info(),
+        }
+    }
+}
+
+#[async_trait]
+impl<Snapshot, Source, SourceItem> SourceStorage<Snapshot, SourceItem> for SnapshotCachePipe<Source>
+where
+    Snapshot: Send + Sync + 'static,
+    Source: SourceStorage<Snapshot, SourceItem>,
+{
+    async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<SourceItem> {
+        self.source.get_object(snapshot, mission).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::SnapshotMeta;
+
+    #[tokio::test]
+    async fn test_cache() {
+        let path = std::env::temp_dir()
+            .join(format!("snapshot-cache-{}.json", unix_time()))
+            .display()
+            .to_string();
+        let cache = CachedSnapshot {
+            created_at: 1000,
+            snapshot: vec![
+                SnapshotMeta {
+                    key: "a.tar.gz".to_string(),
+                    size: Some(10),
+                    checksum_method: Some("sha256".to_string()),
+                    checksum: Some("abc".to_string()),
+                    ..Default::default()
+                },
+                SnapshotMeta::force("index.json".to_string()),
+            ],
+        };
+        save_cache(&path, &cache).await.unwrap();
+
+        let ttl = Duration::from_secs(60);
+        let loaded = load_cache::<SnapshotMeta>(&path, ttl, 1059)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.snapshot.len(), 2);
+        assert_eq!(loaded.snapshot[0].checksum.as_deref(), Some("abc"));
+        assert!(loaded.snapshot[1].flags.force_last);
+        assert!(load_cache::<SnapshotMeta>(&path, ttl, 1060)
+            .await
+            .unwrap()
+            .is_none());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
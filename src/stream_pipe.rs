@@ -6,46 +6,178 @@
 //!
 //! Currently, this is done by downloading files to local file system,
 //! provide it to target storage, and delete it on dropping file object.
-//! We may later refactor it to use in-memory stream or direct reqwest stream.
+//!
+//! If `direct_stream` of mission is set, objects of known length not smaller
+//! than it are not buffered. The response body is handed over to target as
+//! `ByteObject::Stream`, and fails at the end if its length doesn't match,
+//! or if nothing is received for `DIRECT_READ_TIMEOUT`. Only S3 target
+//! accepts such objects, and they're not retried on failure.
+//!
+//! Buffered downloads of known length are resumed with range requests when
+//! interrupted, if server accepts ranges. Objects not smaller than
+//! `parallel_min_size` of `RangeConfig` may be downloaded as several ranges
+//! in parallel, each written to its offset in the buffer file.
+//!
+//! Requests are conditional if mission has validators of previous download,
+//! see `validator_cache`.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
 use async_trait::async_trait;
 use chrono::DateTime;
 
 use crate::common::{Mission, SnapshotConfig, TransferURL};
 use crate::error::{Error, Result};
+use crate::fetch::{status_error, Validators};
+use crate::throttle::throttled;
 use crate::traits::{Key, Metadata, SnapshotStorage, SourceStorage};
-use crate::utils::{hash_string, unix_time};
+use crate::utils::{hash_string, human_duration, human_size, human_time, unix_time};
 use futures_core::Stream;
-use futures_util::{StreamExt, TryStreamExt};
+use futures_util::{stream, StreamExt, TryStreamExt};
 use slog::{debug, warn};
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio_util::codec;
 
+/// sequence number of buffer files, to avoid conflicts of their names
+static BUFFER_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// How buffered downloads use range requests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RangeConfig {
+    /// times to resume an interrupted download
+    pub resume: u32,
+    /// number of ranges downloaded in parallel
+    pub parallel: u64,
+    pub parallel_min_size: u64,
+}
+
+/// Body of a streamed object. The stream is only polled through `&mut`, so
+/// wrapping it in a mutex makes it `Sync` without locking.
+pub struct ByteBody(Mutex<Pin<Box<dyn Stream<Item = std::io::Result<bytes::Bytes>> + Send>>>);
+
+impl ByteBody {
+    pub fn new(stream: impl Stream<Item = std::io::Result<bytes::Bytes>> + Send + 'static) -> Self {
+        Self(Mutex::new(Box::pin(stream)))
+    }
+}
+
+impl Stream for ByteBody {
+    type Item = std::io::Result<bytes::Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.get_mut().unwrap().as_mut().poll_next(cx)
+    }
+}
+
 pub enum ByteObject {
     LocalFile {
         file: Option<tokio::fs::File>,
         path: Option<std::path::PathBuf>,
     },
+    Stream(Option<ByteBody>),
 }
 
 impl ByteObject {
-    pub fn as_stream(&mut self) -> impl Stream<Item = std::io::Result<bytes::Bytes>> {
+    pub fn as_stream(&mut self) -> ByteBody {
         match self {
-            ByteObject::LocalFile { file, .. } => codec::FramedRead::new(
-                BufReader::new(file.take().unwrap()),
-                codec::BytesCodec::new(),
-            )
-            .map_ok(|bytes| bytes.freeze()),
+            ByteObject::LocalFile { file, .. } => ByteBody::new(
+                codec::FramedRead::new(
+                    BufReader::new(file.take().unwrap()),
+                    codec::BytesCodec::new(),
+                )
+                .map_ok(|bytes| bytes.freeze()),
+            ),
+            ByteObject::Stream(body) => body.take().unwrap(),
         }
     }
 
-    pub fn use_file(mut self) -> std::path::PathBuf {
+    pub fn is_stream(&self) -> bool {
+        matches!(self, ByteObject::Stream(_))
+    }
+
+    /// Write a streamed object to a buffer file in `buffer_path`, so that it
+    /// could be read as a local file. Local files are returned as is.
+    /// The buffer file is removed on errors, which are logged to `logger`.
+    pub async fn into_local(mut self, buffer_path: &str, logger: &slog::Logger) -> Result<Self> {
+        let mut body = match &mut self {
+            ByteObject::Stream(body) => body.take().unwrap(),
+            ByteObject::LocalFile { .. } => return Ok(self),
+        };
+        let path = std::path::PathBuf::from(format!(
+            "{}/stream.{}.{}.buffer",
+            buffer_path,
+            unix_time(),
+            BUFFER_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut f = BufWriter::new(tokio::fs::File::create(&path).await?);
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(path.clone()),
+        };
+        let written: Result<tokio::fs::File> = async {
+            while let Some(content) = body.next().await {
+                f.write_all(&content?).await?;
+            }
+            f.flush().await?;
+            drop(f);
+            Ok(tokio::fs::File::open(&path).await?)
+        }
+        .await;
+        match written {
+            Ok(f) => {
+                if let ByteObject::LocalFile { file, .. } = &mut object {
+                    *file = Some(f);
+                }
+                Ok(object)
+            }
+            Err(err) => {
+                object.discard(logger, &path.display().to_string());
+                Err(err)
+            }
+        }
+    }
+
+    /// Remove the local file, returning error if it fails. Unlike dropping
+    /// the object, which only logs such errors.
+    pub fn abort(mut self) -> std::io::Result<()> {
+        match &mut self {
+            ByteObject::LocalFile { file, path } => {
+                drop(file.take());
+                match path.take() {
+                    Some(path) => std::fs::remove_file(path),
+                    None => Ok(()),
+                }
+            }
+            ByteObject::Stream(_) => Ok(()),
+        }
+    }
+
+    /// Remove the local file on errors, logging failures to remove it. The
+    /// file may not be created yet, which is not taken as failure.
+    pub fn discard(self, logger: &slog::Logger, key: &str) {
+        match self.abort() {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                warn!(logger, "failed to remove buffer of {}: {:?}", key, err);
+            }
+            _ => {}
+        }
+    }
+
+    /// Take over the local file. Streamed objects are only given to targets
+    /// which handle them, so this fails on them.
+    pub fn use_file(mut self) -> Result<std::path::PathBuf> {
         match &mut self {
             ByteObject::LocalFile { file, path } => {
                 drop(file.take().unwrap());
-                path.take().unwrap()
+                Ok(path.take().unwrap())
             }
+            ByteObject::Stream(_) => Err(Error::StorageError(
+                "streamed object can't be used as file".to_string(),
+            )),
         }
     }
 }
@@ -62,8 +194,164 @@ impl Drop for ByteObject {
                     }
                 }
             }
+            ByteObject::Stream(_) => {}
+        }
+    }
+}
+
+/// Streamed bodies fail if nothing is received for this long, so that a
+/// stalled upstream won't hold a worker and an upload forever.
+const DIRECT_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Fail `body` if none of its chunks arrives within `timeout`.
+fn read_timeout<S>(body: S, timeout: std::time::Duration) -> impl Stream<Item = S::Item>
+where
+    S: Stream<Item = std::io::Result<bytes::Bytes>> + Unpin,
+{
+    stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout(timeout, body.next()).await {
+            Ok(Some(item)) => Some((item, Some(body))),
+            Ok(None) => None,
+            Err(_) => Some((
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("nothing received in {}", human_duration(timeout)),
+                )),
+                None,
+            )),
+        }
+    })
+}
+
+/// Stream body of `response` directly, which fails at the end if `length`
+/// bytes are not received, and is accounted when finished.
+fn direct_body(
+    response: reqwest::Response,
+    length: u64,
+    url: String,
+    mission: &Mission,
+) -> ByteBody {
+    let received = Arc::new(AtomicU64::new(0));
+    let counter = received.clone();
+    let accounting = mission.accounting.clone();
+    let body = throttled(
+        read_timeout(
+            response.bytes_stream().map_err(std::io::Error::other),
+            DIRECT_READ_TIMEOUT,
+        ),
+        mission.throttle.clone(),
+    )
+    .inspect_ok(move |bytes| {
+        counter.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    });
+    let end = stream::once(async move {
+        let received = received.load(Ordering::Relaxed);
+        accounting.record_download(&url, received);
+        if received != length {
+            Some(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("content length mismatch: {}/{}", received, length),
+            )))
+        } else {
+            None
+        }
+    })
+    .filter_map(futures_util::future::ready);
+    ByteBody::new(body.chain(end))
+}
+
+/// Request `start..end` of `url`. If object has been modified since
+/// `validator` (ETag or Last-Modified), server responds with the whole object,
+/// which is an error.
+async fn get_range(
+    mission: &Mission,
+    url: &str,
+    validator: Option<&reqwest::header::HeaderValue>,
+    start: u64,
+    end: u64,
+) -> Result<reqwest::Response> {
+    let mut request = mission.client.get(url).header(
+        reqwest::header::RANGE,
+        format!("bytes={}-{}", start, end - 1),
+    );
+    if let Some(validator) = validator {
+        request = request.header(reqwest::header::IF_RANGE, validator.clone());
+    }
+    let response = request.send().await?;
+    match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => Ok(response),
+        status if status.is_success() => Err(Error::PipeError(format!(
+            "range not served by {} (status {})",
+            url, status
+        ))),
+        status => Err(status_error(status, response.headers())),
+    }
+}
+
+/// Download `start..end` of `url` to the same range of file at `path`, from
+/// `response` if given. The download is resumed at most `resume` times.
+#[allow(clippy::too_many_arguments)]
+async fn download_part(
+    mission: &Mission,
+    url: &str,
+    validator: Option<&reqwest::header::HeaderValue>,
+    path: &str,
+    start: u64,
+    end: u64,
+    mut response: Option<reqwest::Response>,
+    resume: u32,
+) -> Result<()> {
+    let mut file = OpenOptions::default().write(true).open(path).await?;
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let mut file = BufWriter::new(file);
+    let mut offset = start;
+    let mut resumed = 0;
+    while offset < end {
+        let current = match response.take() {
+            Some(response) => response,
+            None => get_range(mission, url, validator, offset, end).await?,
+        };
+        let mut stream = current.bytes_stream();
+        let mut failure = None;
+        while let Some(content) = stream.next().await {
+            let content = match content {
+                Ok(content) => content,
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                }
+            };
+            // the first response may contain more than this part
+            let content = content.slice(..content.len().min((end - offset) as usize));
+            file.write_all(&content).await?;
+            offset += content.len() as u64;
+            mission.throttle.consume(content.len() as u64).await;
+            if offset == end {
+                break;
+            }
         }
+        if offset == end {
+            break;
+        }
+        if resumed >= resume {
+            return Err(match failure {
+                Some(err) => err.into(),
+                None => Error::PipeError(format!(
+                    "content length mismatch: {}/{}",
+                    offset - start,
+                    end - start
+                )),
+            });
+        }
+        resumed += 1;
+        warn!(
+            mission.logger,
+            "resume {} from {} ({}/{}): {:?}", url, offset, resumed, resume, failure
+        );
     }
+    file.flush().await?;
+    Ok(())
 }
 
 pub struct ByteStream {
@@ -71,6 +359,48 @@ pub struct ByteStream {
     pub length: u64,
     pub modified_at: u64,
     pub content_type: Option<String>,
+    /// checksum method and checksum of content, if computed by pipes
+    pub checksum: Option<(String, String)>,
+    /// expected S3 ETag of content, if computed by pipes
+    pub etag: Option<String>,
+}
+
+impl ByteStream {
+    /// Write `content` generated by pipes for `key` to a buffer file in
+    /// `buffer_path`, so that it is served like downloaded objects.
+    pub async fn from_content(
+        buffer_path: &str,
+        key: &str,
+        content: &[u8],
+        content_type: Option<&str>,
+    ) -> Result<Self> {
+        let pipe_file = format!("{}.{}.buffer", hash_string(key), unix_time());
+        let path = std::path::Path::new(buffer_path).join(pipe_file);
+        let mut f = BufWriter::new(
+            OpenOptions::default()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .await?,
+        );
+        f.write_all(content).await?;
+        f.flush().await?;
+        let mut f = f.into_inner();
+        f.seek(std::io::SeekFrom::Start(0)).await?;
+        Ok(ByteStream {
+            object: ByteObject::LocalFile {
+                file: Some(f),
+                path: Some(path),
+            },
+            length: content.len() as u64,
+            modified_at: unix_time(),
+            content_type: content_type.map(|content_type| content_type.to_string()),
+            checksum: None,
+            etag: None,
+        })
+    }
 }
 
 pub struct ByteStreamPipe<Source> {
@@ -103,6 +433,10 @@ where
         self.source.snapshot(mission, config).await
     }
 
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
     fn info(&self) -> String {
         format!(
             "StreamPipe buffered to {} <{}>",
@@ -120,31 +454,53 @@ where
 {
     async fn get_object(&self, snapshot: &Snapshot, mission: &Mission) -> Result<ByteStream> {
         let transfer_url = self.source.get_object(snapshot, mission).await?;
+        mission.breaker.check(&transfer_url.0)?;
 
+        // a URL may be downloaded concurrently, e.g. by pipes generating
+        // files from other files
         let path = format!(
-            "{}/{}.{}.buffer",
+            "{}/{}.{}.{}.buffer",
             self.buffer_path,
             hash_string(&transfer_url.0),
-            unix_time()
+            unix_time(),
+            BUFFER_SEQ.fetch_add(1, Ordering::Relaxed)
         );
         let logger = &mission.logger;
-        let mut f = BufWriter::new(
-            OpenOptions::default()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .read(true)
-                .open(&path)
-                .await?,
-        );
 
-        let response = mission.client.get(&transfer_url.0).send().await?;
-        let status = response.status();
-        if !status.is_success() {
-            return Err(Error::HTTPError(status));
+        let validators = mission
+            .validators
+            .as_ref()
+            .and_then(|scope| scope.get(&transfer_url.0));
+        let mut request = mission.client.get(&transfer_url.0);
+        if let Some(validators) = &validators {
+            request = validators.apply(request);
         }
+        let response = match request.send().await {
+            Ok(response) if response.status().is_success() => Ok(response),
+            Ok(response)
+                if response.status() == reqwest::StatusCode::NOT_MODIFIED
+                    && validators.is_some() =>
+            {
+                Err(Error::NotModified(transfer_url.0.clone()))
+            }
+            Ok(response) => Err(status_error(response.status(), response.headers())),
+            Err(err) => Err(err.into()),
+        };
+        match &response {
+            Ok(_) | Err(Error::NotModified(_)) => mission.breaker.success(&transfer_url.0),
+            Err(err) => {
+                if mission.breaker.failure(&transfer_url.0, err) {
+                    warn!(
+                        logger,
+                        "too many failures from {}, pausing requests for {}",
+                        transfer_url.0,
+                        human_duration(mission.breaker.cooldown())
+                    );
+                }
+            }
+        }
+        let response = response?;
 
-        let mut total_bytes: u64 = 0;
         let content_length = response.content_length();
         let snapshot_modified_at = snapshot.last_modified();
         let http_modified_at = response
@@ -156,7 +512,8 @@ where
             .map(|x| x.timestamp() as u64);
 
         let modified_at = if self.use_snapshot_last_modified {
-            snapshot_modified_at
+            // fall back to HTTP header if snapshot doesn't have modified time
+            snapshot_modified_at.or(http_modified_at)
         } else {
             http_modified_at
         };
@@ -170,52 +527,154 @@ where
                     warn!(
                         mission.logger,
                         "mismatch modified time: http={}, snapshot={}",
-                        http_modified_at,
-                        snapshot_modified_at
+                        human_time(http_modified_at),
+                        human_time(snapshot_modified_at)
                     );
                 }
             }
         }
 
-        let content_type = response
-            .headers()
-            .get(reqwest::header::CONTENT_TYPE)
-            .map(|x| x.as_bytes())
-            .and_then(|x| std::str::from_utf8(x).ok())
-            .map(|x| x.to_string());
+        // validators are staged when the object is downloaded
+        let new_validators = Validators::from_headers(response.headers());
 
-        debug!(logger, "download: {} {:?}", transfer_url.0, content_length);
+        let content_type = match snapshot.content_type() {
+            Some(content_type) => Some(content_type.to_string()),
+            None => response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .map(|x| x.as_bytes())
+                .and_then(|x| std::str::from_utf8(x).ok())
+                .map(|x| x.to_string()),
+        };
 
-        let mut stream = response.bytes_stream();
-        while let Some(content) = stream.next().await {
-            let content = content?;
-            f.write_all(&content).await?;
-            total_bytes += content.len() as u64;
-        }
+        debug!(
+            logger,
+            "download: {} ({})",
+            transfer_url.0,
+            content_length.map_or_else(|| "unknown size".to_string(), human_size)
+        );
 
-        if let Some(content_length) = content_length {
-            if total_bytes != content_length {
-                return Err(Error::PipeError(format!(
-                    "content length mismatch: {}/{}",
-                    total_bytes, content_length
-                )));
+        let direct_length = content_length.or_else(|| snapshot.size());
+        if let (Some(min_size), Some(length)) = (mission.direct_stream, direct_length) {
+            if length >= min_size {
+                debug!(logger, "stream: {}", transfer_url.0);
+                // a stream failing later fails the upload, so validators
+                // won't be committed
+                if let Some(scope) = &mission.validators {
+                    scope.stage(&transfer_url.0, new_validators);
+                }
+                return Ok(ByteStream {
+                    object: ByteObject::Stream(Some(direct_body(
+                        response,
+                        length,
+                        transfer_url.0.clone(),
+                        mission,
+                    ))),
+                    length,
+                    modified_at,
+                    content_type,
+                    checksum: None,
+                    etag: None,
+                });
             }
         }
 
-        f.flush().await?;
-        let mut f = f.into_inner();
+        let mut f = BufWriter::new(
+            OpenOptions::default()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .read(true)
+                .open(&path)
+                .await?,
+        );
+        // the buffer file is removed on errors
+        let mut object = ByteObject::LocalFile {
+            file: None,
+            path: Some(path.clone().into()),
+        };
+        let downloaded: Result<(u64, tokio::fs::File)> = async {
+            let total_bytes = if let Some(content_length) = content_length {
+                let ranges = response
+                    .headers()
+                    .get(reqwest::header::ACCEPT_RANGES)
+                    .is_some_and(|x| x.as_bytes() == b"bytes");
+                let validator = response
+                    .headers()
+                    .get(reqwest::header::ETAG)
+                    .or_else(|| response.headers().get(reqwest::header::LAST_MODIFIED))
+                    .cloned();
+                let (resume, parallel) = match ranges {
+                    true if content_length >= mission.range.parallel_min_size => {
+                        (mission.range.resume, mission.range.parallel.max(1))
+                    }
+                    true => (mission.range.resume, 1),
+                    false => (0, 1),
+                };
+                let part_size = content_length.div_ceil(parallel);
+                // the first part is downloaded from the response
+                let mut response = Some(response);
+                let parts = (0..parallel).map(|i| {
+                    download_part(
+                        mission,
+                        &transfer_url.0,
+                        validator.as_ref(),
+                        &path,
+                        (i * part_size).min(content_length),
+                        ((i + 1) * part_size).min(content_length),
+                        response.take(),
+                        resume,
+                    )
+                });
+                futures_util::future::try_join_all(parts).await?;
+                content_length
+            } else {
+                let mut total_bytes = 0;
+                let mut stream = response.bytes_stream();
+                while let Some(content) = stream.next().await {
+                    let content = content?;
+                    f.write_all(&content).await?;
+                    total_bytes += content.len() as u64;
+                    mission.throttle.consume(content.len() as u64).await;
+                }
+                total_bytes
+            };
 
-        f.seek(std::io::SeekFrom::Start(0)).await?;
+            f.flush().await?;
+            let mut f = f.into_inner();
+            f.seek(std::io::SeekFrom::Start(0)).await?;
+            Ok((total_bytes, f))
+        }
+        .await;
+        let total_bytes = match downloaded {
+            Ok((total_bytes, f)) => {
+                if let ByteObject::LocalFile { file, .. } = &mut object {
+                    *file = Some(f);
+                }
+                total_bytes
+            }
+            Err(err) => {
+                object.discard(logger, snapshot.key());
+                return Err(err);
+            }
+        };
+
+        mission
+            .accounting
+            .record_download(&transfer_url.0, total_bytes);
+
+        if let Some(scope) = &mission.validators {
+            scope.stage(&transfer_url.0, new_validators);
+        }
 
         // TODO: check snapshot http modified_at consistency
         Ok(ByteStream {
-            object: ByteObject::LocalFile {
-                file: Some(f),
-                path: Some(path.into()),
-            },
+            object,
             length: total_bytes,
             modified_at,
             content_type,
+            checksum: None,
+            etag: None,
         })
     }
 }
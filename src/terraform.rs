@@ -0,0 +1,400 @@
+//! Terraform source
+//!
+//! Terraform source walks the provider registry API (v1) for a configured
+//! list of providers. For every version and platform, it queries the download
+//! endpoint, and yields provider zips with sha256 checksums, together with
+//! `SHA256SUMS` and signature files.
+//!
+//! Files are laid out as a provider network mirror, i.e.
+//! `<hostname>/<namespace>/<type>/<file>`. The JSON API of network mirror
+//! (`index.json` and `<version>.json`) is not served by upstream registries,
+//! so it is generated by `NetworkMirrorPipe` from the snapshot.
+
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_json;
+use crate::metadata::SnapshotMeta;
+use crate::stream_pipe::ByteStream;
+use crate::traits::{Key, SnapshotStorage, SourceStorage};
+use crate::utils::bar;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct TerraformConfig {
+    #[structopt(
+        long,
+        default_value = "https://registry.terraform.io",
+        help = "Base of provider registry"
+    )]
+    pub registry: String,
+    #[structopt(
+        long,
+        help = "Providers to mirror, e.g. hashicorp/aws",
+        required = true,
+        min_values = 1
+    )]
+    pub providers: Vec<String>,
+    #[structopt(long, help = "Platforms to mirror, e.g. linux_amd64 (all if not set)")]
+    pub platforms: Vec<String>,
+    #[structopt(long, help = "Version numbers to retain of each provider")]
+    pub version_to_retain: Option<usize>,
+}
+
+pub struct Terraform {
+    pub config: TerraformConfig,
+    /// key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ProviderVersions {
+    versions: Vec<ProviderVersion>,
+}
+
+#[derive(Deserialize)]
+struct ProviderVersion {
+    version: String,
+    #[serde(default)]
+    platforms: Vec<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    arch: String,
+}
+
+#[derive(Deserialize)]
+struct Download {
+    filename: String,
+    download_url: String,
+    shasums_url: String,
+    shasums_signature_url: String,
+    shasum: String,
+}
+
+lazy_static! {
+    static ref PROVIDER_ZIP: Regex = Regex::new(
+        r"^(?P<dir>.+)/terraform-provider-[^_/]+_(?P<version>[^_/]+)_(?P<platform>[^_/]+_[^_/]+)\.zip$"
+    )
+    .unwrap();
+}
+
+/// Key for sorting versions, which compares numbers in version strings.
+fn version_order(version: &str) -> Vec<u64> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|x| x.parse().unwrap_or(0))
+        .collect()
+}
+
+fn file_name(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or(url)
+}
+
+impl Terraform {
+    pub fn new(config: TerraformConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Terraform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Terraform {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        let hostname = url::Url::parse(&self.config.registry)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .ok_or_else(|| Error::ConfigureError("invalid registry".to_string()))?;
+
+        let mut downloads = vec![];
+        for provider in &self.config.providers {
+            info!(logger, "fetching versions of {}...", provider);
+            progress.set_message(provider);
+            let mut versions: ProviderVersions = fetch_json(
+                &client,
+                &format!(
+                    "{}/v1/providers/{}/versions",
+                    self.config.registry, provider
+                ),
+            )
+            .await?;
+            versions
+                .versions
+                .sort_by_key(|version| version_order(&version.version));
+            if let Some(version_to_retain) = self.config.version_to_retain {
+                let skip = versions.versions.len().saturating_sub(version_to_retain);
+                versions.versions.drain(..skip);
+            }
+            for version in versions.versions {
+                for platform in version.platforms {
+                    let name = format!("{}_{}", platform.os, platform.arch);
+                    if self.config.platforms.is_empty() || self.config.platforms.contains(&name) {
+                        downloads.push((
+                            provider.clone(),
+                            format!(
+                                "{}/v1/providers/{}/{}/download/{}/{}",
+                                self.config.registry,
+                                provider,
+                                version.version,
+                                platform.os,
+                                platform.arch
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+
+        info!(logger, "fetching {} downloads...", downloads.len());
+        progress.set_length(downloads.len() as u64);
+        progress.set_style(bar());
+
+        let results: Vec<Option<(String, Download)>> =
+            stream::iter(downloads.into_iter().map(|(provider, url)| {
+                let client = client.clone();
+                let progress = progress.clone();
+                let logger = logger.clone();
+                async move {
+                    progress.set_message(&url);
+                    let result = match fetch_json::<Download>(&client, &url).await {
+                        Ok(download) => Some((provider, download)),
+                        Err(err) => {
+                            warn!(logger, "failed to fetch {}: {:?}", url, err);
+                            None
+                        }
+                    };
+                    progress.inc(1);
+                    Ok::<_, Error>(result)
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        let mut snapshot = vec![];
+        for (provider, download) in results.into_iter().flatten() {
+            let dir = format!("{}/{}", hostname, provider);
+            let key = format!("{}/{}", dir, download.filename);
+            snapshot.push(SnapshotMeta {
+                key: key.clone(),
+                checksum_method: Some("sha256".to_string()),
+                checksum: Some(download.shasum),
+                ..Default::default()
+            });
+            self.urls.insert(key, download.download_url);
+            for url in [download.shasums_url, download.shasums_signature_url] {
+                let key = format!("{}/{}", dir, file_name(&url));
+                if let std::collections::hash_map::Entry::Vacant(entry) =
+                    self.urls.entry(key.clone())
+                {
+                    snapshot.push(SnapshotMeta::new(key));
+                    entry.insert(url);
+                }
+            }
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("terraform, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Terraform {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        self.urls
+            .get(&snapshot.key)
+            .map(|url| TransferURL(url.clone()))
+            .ok_or_else(|| Error::ProcessError(format!("unknown key {}", snapshot.key)))
+    }
+}
+
+/// Generate JSON API of provider network mirror from provider zips in snapshot.
+fn generate_mirror_json(snapshot: &[SnapshotMeta]) -> BTreeMap<String, String> {
+    // dir -> version -> platform -> (file, hashes)
+    let mut providers: BTreeMap<&str, BTreeMap<&str, BTreeMap<&str, serde_json::Value>>> =
+        BTreeMap::new();
+    for item in snapshot {
+        if let Some(captures) = PROVIDER_ZIP.captures(&item.key) {
+            let hashes: Vec<String> = item
+                .checksum
+                .iter()
+                .map(|checksum| format!("zh:{}", checksum))
+                .collect();
+            providers
+                .entry(captures.name("dir").unwrap().as_str())
+                .or_default()
+                .entry(captures.name("version").unwrap().as_str())
+                .or_default()
+                .insert(
+                    captures.name("platform").unwrap().as_str(),
+                    serde_json::json!({
+                        "url": file_name(&item.key),
+                        "hashes": hashes,
+                    }),
+                );
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    for (dir, versions) in providers {
+        let index: BTreeMap<_, _> = versions
+            .keys()
+            .map(|version| (*version, serde_json::json!({})))
+            .collect();
+        files.insert(
+            format!("{}/index.json", dir),
+            serde_json::json!({ "versions": index }).to_string(),
+        );
+        for (version, archives) in versions {
+            files.insert(
+                format!("{}/{}.json", dir, version),
+                serde_json::json!({ "archives": archives }).to_string(),
+            );
+        }
+    }
+    files
+}
+
+/// `NetworkMirrorPipe` adds JSON API of provider network mirror to source.
+pub struct NetworkMirrorPipe<Source> {
+    source: Source,
+    buffer_path: String,
+    files: BTreeMap<String, String>,
+}
+
+impl<Source> NetworkMirrorPipe<Source> {
+    pub fn new(source: Source, buffer_path: String) -> Self {
+        Self {
+            source,
+            buffer_path,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl<Source> SnapshotStorage<SnapshotMeta> for NetworkMirrorPipe<Source>
+where
+    Source: SnapshotStorage<SnapshotMeta>,
+{
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let mut snapshot = self.source.snapshot(mission, config).await?;
+        self.files = generate_mirror_json(&snapshot);
+        snapshot.extend(self.files.keys().cloned().map(SnapshotMeta::force));
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        self.source.estimate(mission).await
+    }
+
+    fn info(&self) -> String {
+        format!("NetworkMirrorPipe <{}>", self.source.info())
+    }
+}
+
+#[async_trait]
+impl<Source> SourceStorage<SnapshotMeta, ByteStream> for NetworkMirrorPipe<Source>
+where
+    Source: SourceStorage<SnapshotMeta, ByteStream>,
+{
+    async fn get_object(&self, snapshot: &SnapshotMeta, mission: &Mission) -> Result<ByteStream> {
+        let key = snapshot.key();
+        if let Some(content) = self.files.get(key) {
+            ByteStream::from_content(
+                &self.buffer_path,
+                key,
+                content.as_bytes(),
+                Some("application/json"),
+            )
+            .await
+        } else {
+            self.source.get_object(snapshot, mission).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_mirror_json() {
+        let dir = "registry.terraform.io/hashicorp/random";
+        let snapshot = vec![
+            SnapshotMeta {
+                key: format!("{}/terraform-provider-random_3.5.1_linux_amd64.zip", dir),
+                checksum: Some("abcd".to_string()),
+                ..Default::default()
+            },
+            SnapshotMeta {
+                key: format!("{}/terraform-provider-random_3.5.1_darwin_arm64.zip", dir),
+                checksum: Some("ef01".to_string()),
+                ..Default::default()
+            },
+            SnapshotMeta::new(format!(
+                "{}/terraform-provider-random_3.5.1_SHA256SUMS",
+                dir
+            )),
+        ];
+        let files = generate_mirror_json(&snapshot);
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            vec![
+                &format!("{}/3.5.1.json", dir),
+                &format!("{}/index.json", dir)
+            ]
+        );
+        assert_eq!(
+            files[&format!("{}/index.json", dir)],
+            r#"{"versions":{"3.5.1":{}}}"#
+        );
+        let archives: serde_json::Value =
+            serde_json::from_str(&files[&format!("{}/3.5.1.json", dir)]).unwrap();
+        assert_eq!(
+            archives["archives"]["linux_amd64"],
+            serde_json::json!({
+                "url": "terraform-provider-random_3.5.1_linux_amd64.zip",
+                "hashes": ["zh:abcd"]
+            })
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Bandwidth throttling
+//!
+//! `Throttle` is a token bucket shared by all transfer tasks, limiting total
+//! bandwidth of downloads in `ByteStreamPipe` and uploads of targets, so
+//! that mirrors don't saturate the uplink during peak hours. Bytes are
+//! consumed after they are transferred: a task taking more than available
+//! waits until the debt is paid, while the bucket holds at most one second
+//! of bandwidth.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::{Stream, StreamExt};
+
+use crate::error::{Error, Result};
+
+struct Bucket {
+    /// may be negative, if tasks took more than available
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct Throttle {
+    /// bytes per second, `None` for unlimited
+    rate: Option<u64>,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate.unwrap_or(0) as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Time to wait before transferring more, after `bytes` are transferred.
+    fn take(&self, bytes: u64, now: Instant) -> Option<Duration> {
+        let rate = self.rate? as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+        bucket.last = now;
+        if bucket.tokens < 0.0 {
+            Some(Duration::from_secs_f64(-bucket.tokens / rate))
+        } else {
+            None
+        }
+    }
+
+    /// Account `bytes` transferred, waiting if bandwidth is used up.
+    pub async fn consume(&self, bytes: u64) {
+        if let Some(wait) = self.take(bytes, Instant::now()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Throttle reads of `stream` with `throttle`.
+pub fn throttled<S>(
+    stream: S,
+    throttle: Arc<Throttle>,
+) -> impl Stream<Item = std::io::Result<bytes::Bytes>> + Send + 'static
+where
+    S: Stream<Item = std::io::Result<bytes::Bytes>> + Send + 'static,
+{
+    stream.then(move |chunk| {
+        let throttle = throttle.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                throttle.consume(bytes.len() as u64).await;
+            }
+            chunk
+        }
+    })
+}
+
+/// Parse bandwidth like `200MiB/s`, `10MB/s` or `1G`, returning bytes per
+/// second. `K`, `M` and `G` are binary units.
+pub fn parse_bandwidth(s: &str) -> Result<u64> {
+    let invalid = || Error::ConfigureError(format!("invalid bandwidth {}", s));
+    let value = s.trim().trim_end_matches("/s");
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let unit = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        _ => return Err(invalid()),
+    };
+    // a rate of 0 would never pay the debt
+    match (number * unit as f64) as u64 {
+        0 => Err(Error::ConfigureError(format!(
+            "bandwidth {} is below 1 B/s",
+            s
+        ))),
+        bandwidth => Ok(bandwidth),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bandwidth() {
+        assert_eq!(parse_bandwidth("200MiB/s").unwrap(), 200 << 20);
+        assert_eq!(parse_bandwidth("10MB/s").unwrap(), 10_000_000);
+        assert_eq!(parse_bandwidth("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_bandwidth("4096").unwrap(), 4096);
+        assert!(parse_bandwidth("fast").is_err());
+        assert!(parse_bandwidth("10TB/s").is_err());
+        assert!(parse_bandwidth("0").is_err());
+        assert!(parse_bandwidth("0.5B/s").is_err());
+    }
+
+    #[test]
+    fn test_take() {
+        let throttle = Throttle::new(Some(1000));
+        let start = Instant::now();
+        assert_eq!(throttle.take(500, start), None);
+        assert_eq!(throttle.take(1000, start), Some(Duration::from_millis(500)));
+        // debt is paid after 500ms, and another second fills the bucket
+        assert_eq!(
+            throttle.take(1000, start + Duration::from_millis(1500)),
+            None
+        );
+        assert_eq!(Throttle::new(None).take(u64::MAX, start), None);
+    }
+}
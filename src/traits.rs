@@ -1,5 +1,5 @@
 use crate::common::{Mission, SnapshotConfig, SnapshotPath};
-use crate::error::Result;
+use crate::error::{Error, Result};
 use async_trait::async_trait;
 
 #[async_trait]
@@ -9,6 +9,12 @@ pub trait SnapshotStorage<SnapshotItem>: Send + Sync + 'static {
         mission: Mission,
         config: &SnapshotConfig,
     ) -> Result<Vec<SnapshotItem>>;
+    /// Estimated number of items in snapshot, known before taking it (e.g. from
+    /// counts reported by upstream API). It is only a hint for progress bars
+    /// and preallocation, and may differ from the actual snapshot.
+    async fn estimate(&self, _mission: &Mission) -> Option<usize> {
+        None
+    }
     fn info(&self) -> String;
 }
 
@@ -26,6 +32,17 @@ pub trait TargetStorage<SnapshotItem, TargetItem>: Send + Sync + 'static {
         mission: &Mission,
     ) -> Result<()>;
     async fn delete_object(&self, snapshot: &SnapshotItem, mission: &Mission) -> Result<()>;
+    /// Update metadata of an existing object without transferring its content.
+    async fn update_metadata(&self, _snapshot: &SnapshotItem, _mission: &Mission) -> Result<()> {
+        Err(Error::StorageError(String::from(
+            "metadata-only update is not supported",
+        )))
+    }
+    /// Bytes which could still be put to target, if it is limited (e.g. by
+    /// free space of local file system).
+    async fn available_space(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
 }
 
 pub trait Key: Send + Sync + 'static {
@@ -39,6 +56,12 @@ pub trait Metadata {
         0
     }
 
+    /// Whether object is transferred after all other objects, and only if
+    /// none of them failed, e.g. manifests recording a whole run.
+    fn after_all(&self) -> bool {
+        false
+    }
+
     fn last_modified(&self) -> Option<u64> {
         None
     }
@@ -50,10 +73,31 @@ pub trait Metadata {
     fn checksum_method(&self) -> Option<&str> {
         None
     }
+
+    /// content type of object, which overrides the one given by upstream
+    fn content_type(&self) -> Option<&str> {
+        None
+    }
+
+    fn size(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub trait Diff {
     fn diff(&self, other: &Self) -> bool;
+
+    /// Whether `self` (from source) has metadata missing in `other` (from target),
+    /// while content of them is known to be the same (e.g. by checksum or ETag).
+    fn diff_metadata(&self, _other: &Self) -> bool {
+        false
+    }
+
+    /// Whether `self` (from source) has metadata missing in `other` (from
+    /// target), whether or not content of them is known to be the same.
+    fn missing_metadata(&self, _other: &Self) -> bool {
+        false
+    }
 }
 
 impl Key for SnapshotPath {
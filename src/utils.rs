@@ -1,11 +1,11 @@
 use std::convert::Infallible;
 use std::str::FromStr;
 
+use chrono::{Datelike, Duration, NaiveDate};
 use indicatif::ProgressStyle;
 use regex::Regex;
-use slog::{o, Drain};
+use slog::{o, Drain, Level};
 
-use crate::common::SnapshotPath;
 use crate::error::Result;
 use crate::metadata::SnapshotMeta;
 
@@ -31,15 +31,57 @@ impl From<CommaSplitVecString> for Vec<String> {
     }
 }
 
-pub fn create_logger() -> slog::Logger {
+/// Create the root logger.
+///
+/// With `verbose == 0`, log level is decided by `RUST_LOG` on release builds.
+/// Otherwise, `-v` enables debug logs and `-vv` enables trace logs.
+pub fn create_logger(verbose: u8) -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    #[cfg(not(debug_assertions))]
-    let drain = slog_envlogger::new(drain);
+    let drain: Box<dyn Drain<Ok = (), Err = slog::Never> + Send> = match verbose {
+        0 => {
+            #[cfg(not(debug_assertions))]
+            let drain = slog_envlogger::new(drain).fuse();
+            Box::new(drain)
+        }
+        1 => Box::new(drain.filter_level(Level::Debug).fuse()),
+        _ => Box::new(drain.filter_level(Level::Trace).fuse()),
+    };
     let drain = slog_async::Async::new(drain).chan_size(1024).build().fuse();
     slog::Logger::root(drain, o!())
 }
 
+/// Format a byte count with binary units, e.g. `1.50 GiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+    format!("{:.2} {}", size, unit)
+}
+
+/// Format a duration, e.g. `1h 2m 3s`. Sub-second parts are dropped.
+pub fn human_duration(duration: std::time::Duration) -> String {
+    humantime::format_duration(std::time::Duration::from_secs(duration.as_secs())).to_string()
+}
+
+/// Format a unix timestamp as RFC 3339.
+pub fn human_time(timestamp: u64) -> String {
+    humantime::format_rfc3339_seconds(
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(timestamp),
+    )
+    .to_string()
+}
+
 pub fn spinner() -> ProgressStyle {
     ProgressStyle::default_spinner()
         .template("{prefix:.bold.dim} {spinner} {msg}")
@@ -54,10 +96,6 @@ pub fn bar() -> ProgressStyle {
         .progress_chars("=> ")
 }
 
-pub fn snapshot_string_to_path(snapshot: Vec<String>) -> Vec<SnapshotPath> {
-    snapshot.into_iter().map(SnapshotPath::new).collect()
-}
-
 pub fn snapshot_string_to_meta(snapshot: Vec<String>) -> Vec<SnapshotMeta> {
     snapshot.into_iter().map(SnapshotMeta::new).collect()
 }
@@ -134,3 +172,46 @@ pub fn unix_time() -> u64 {
         .expect("Time went backwards")
         .as_secs()
 }
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Parse date in `ls -l` output, e.g. `Nov  8 11:02` or `Jan 12  2023`, into
+/// unix time of the date. Year of recent files is inferred from `today`.
+pub fn parse_ls_date(month: &str, day: &str, time_or_year: &str, today: NaiveDate) -> Option<u64> {
+    let month = MONTHS.iter().position(|x| *x == month)? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    let date = if time_or_year.contains(':') {
+        let date = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+        if date > today + Duration::days(1) {
+            NaiveDate::from_ymd_opt(today.year() - 1, month, day)?
+        } else {
+            date
+        }
+    } else {
+        NaiveDate::from_ymd_opt(time_or_year.parse().ok()?, month, day)?
+    };
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.50 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.00 GiB");
+    }
+
+    #[test]
+    fn test_human_duration() {
+        assert_eq!(
+            human_duration(std::time::Duration::from_millis(3_723_500)),
+            "1h 2m 3s"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Conditional downloads
+//!
+//! Sources forcing transfer of objects (e.g. indexes without size or
+//! modified time) download them again in every run. With a validator cache,
+//! `ByteStreamPipe` keeps `ETag` and `Last-Modified` of downloaded objects,
+//! persisted in a JSON file between runs, and sends them with
+//! `If-None-Match` and `If-Modified-Since`. Objects not modified upstream
+//! fail with `Error::NotModified`, and are skipped by transfer.
+//!
+//! Validators are kept by key of objects in transfer, along with the URL they
+//! are downloaded from. Validators of an object are committed after it is
+//! uploaded to target, and are forgotten if it's missing in target, so that
+//! skipped objects are always present in target.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::fetch::Validators;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    #[serde(flatten)]
+    validators: Validators,
+}
+
+pub struct ValidatorCache {
+    path: String,
+    saved: Mutex<BTreeMap<String, Entry>>,
+    /// validators of objects downloaded but not yet uploaded
+    staged: Mutex<HashMap<String, Entry>>,
+}
+
+impl ValidatorCache {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            saved: Mutex::new(BTreeMap::new()),
+            staged: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn load(path: String) -> Result<Self> {
+        let data = tokio::fs::read(&path).await?;
+        let cache = Self::new(path);
+        *cache.saved.lock().unwrap() = serde_json::from_slice(&data)?;
+        Ok(cache)
+    }
+
+    /// Save cache to a temporary file and rename it, so that an interrupted
+    /// save won't leave a broken cache.
+    pub async fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec(&*self.saved.lock().unwrap())?;
+        let tmp = format!("{}.tmp", self.path);
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+
+    /// Scope cache to object of `key`, which is passed to sources.
+    pub fn scope(self: &Arc<Self>, key: &str) -> ValidatorScope {
+        ValidatorScope {
+            cache: self.clone(),
+            key: key.to_string(),
+        }
+    }
+
+    /// Commit validators of object downloaded, after it is uploaded.
+    pub fn commit(&self, key: &str) {
+        if let Some(entry) = self.staged.lock().unwrap().remove(key) {
+            self.saved.lock().unwrap().insert(key.to_string(), entry);
+        }
+    }
+
+    pub fn forget(&self, key: &str) {
+        self.saved.lock().unwrap().remove(key);
+        self.staged.lock().unwrap().remove(key);
+    }
+}
+
+/// Validator cache of the object being transferred.
+#[derive(Clone)]
+pub struct ValidatorScope {
+    cache: Arc<ValidatorCache>,
+    key: String,
+}
+
+impl ValidatorScope {
+    /// Validators of previous download of object from `url`.
+    pub fn get(&self, url: &str) -> Option<Validators> {
+        self.cache
+            .saved
+            .lock()
+            .unwrap()
+            .get(&self.key)
+            .filter(|entry| entry.url == url)
+            .map(|entry| entry.validators.clone())
+    }
+
+    /// Stage validators of object downloaded from `url`.
+    pub fn stage(&self, url: &str, validators: Validators) {
+        if validators == Validators::default() {
+            return;
+        }
+        self.cache.staged.lock().unwrap().insert(
+            self.key.clone(),
+            Entry {
+                url: url.to_string(),
+                validators,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validator_cache() {
+        let path = std::env::temp_dir().join(format!(
+            "validator-cache-{}.json",
+            crate::utils::unix_time()
+        ));
+        let path = path.display().to_string();
+        let cache = Arc::new(ValidatorCache::new(path.clone()));
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+
+        let scope = cache.scope("index.json");
+        scope.stage("https://example.com/index.json", validators.clone());
+        assert_eq!(scope.get("https://example.com/index.json"), None);
+        cache.commit("index.json");
+        assert_eq!(
+            scope.get("https://example.com/index.json"),
+            Some(validators.clone())
+        );
+        assert_eq!(scope.get("https://example.com/other.json"), None);
+
+        cache.save().await.unwrap();
+        let loaded = Arc::new(ValidatorCache::load(path.clone()).await.unwrap());
+        assert_eq!(
+            loaded
+                .scope("index.json")
+                .get("https://example.com/index.json"),
+            Some(validators)
+        );
+        loaded.forget("index.json");
+        assert_eq!(
+            loaded
+                .scope("index.json")
+                .get("https://example.com/index.json"),
+            None
+        );
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+//! vcpkg source
+//!
+//! vcpkg source mirrors upstream distfiles of vcpkg ports and tools into an
+//! asset cache. The ports tree is downloaded as a tarball, and distfiles are
+//! collected from `portfile.cmake` of every port (`vcpkg_download_distfile`,
+//! and archives of `vcpkg_from_github`, `vcpkg_from_gitlab` and
+//! `vcpkg_from_bitbucket`), together with tools in
+//! `scripts/vcpkg-tools.json`. `${VERSION}` in portfiles is substituted with
+//! version in `vcpkg.json` of the port, and calls with other variables are
+//! skipped.
+//!
+//! Every distfile is placed at its lowercase sha512, so that the mirror can
+//! be used with `X_VCPKG_ASSET_SOURCES=x-azurl,{mirror}`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use slog::info;
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct VcpkgConfig {
+    #[structopt(
+        long,
+        default_value = "https://github.com/microsoft/vcpkg/archive/master.tar.gz",
+        help = "Tarball of vcpkg ports tree"
+    )]
+    pub tarball: String,
+    #[structopt(long, use_delimiter = true, help = "Ports to mirror (all if not set)")]
+    pub ports: Vec<String>,
+}
+
+pub struct Vcpkg {
+    pub config: VcpkgConfig,
+    /// sha512 -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Tools {
+    tools: Vec<Tool>,
+}
+
+#[derive(Deserialize)]
+struct Tool {
+    url: String,
+    sha512: String,
+}
+
+#[derive(Deserialize)]
+struct PortManifest {
+    version: Option<String>,
+    #[serde(rename = "version-semver")]
+    version_semver: Option<String>,
+    #[serde(rename = "version-date")]
+    version_date: Option<String>,
+    #[serde(rename = "version-string")]
+    version_string: Option<String>,
+}
+
+impl PortManifest {
+    fn version(self) -> Option<String> {
+        self.version
+            .or(self.version_semver)
+            .or(self.version_date)
+            .or(self.version_string)
+    }
+}
+
+/// Functions yielding distfiles, with their keywords.
+const FUNCTIONS: &[(&str, &[&str])] = &[
+    (
+        "vcpkg_download_distfile",
+        &["URLS", "FILENAME", "SHA512", "HEADERS", "SKIP_SHA512"],
+    ),
+    (
+        "vcpkg_from_github",
+        &[
+            "OUT_SOURCE_PATH",
+            "REPO",
+            "REF",
+            "SHA512",
+            "HEAD_REF",
+            "PATCHES",
+            "GITHUB_HOST",
+            "AUTHORIZATION_TOKEN",
+            "FILE_DISAMBIGUATOR",
+        ],
+    ),
+    (
+        "vcpkg_from_gitlab",
+        &[
+            "OUT_SOURCE_PATH",
+            "GITLAB_URL",
+            "REPO",
+            "REF",
+            "SHA512",
+            "HEAD_REF",
+            "PATCHES",
+            "FILE_DISAMBIGUATOR",
+        ],
+    ),
+    (
+        "vcpkg_from_bitbucket",
+        &[
+            "OUT_SOURCE_PATH",
+            "REPO",
+            "REF",
+            "SHA512",
+            "HEAD_REF",
+            "PATCHES",
+        ],
+    ),
+];
+
+/// Split arguments of a CMake call, removing quotes and comments.
+fn split_arguments(args: &str) -> Vec<String> {
+    let mut result = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    result.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result
+}
+
+/// Find calls of `function` in a CMake script, returning their arguments
+/// grouped by keywords.
+fn find_calls(
+    script: &str,
+    function: &str,
+    keywords: &[&str],
+) -> Vec<BTreeMap<String, Vec<String>>> {
+    let mut calls = vec![];
+    let pattern = format!("{}(", function);
+    let mut rest = script;
+    while let Some(pos) = rest.find(&pattern) {
+        rest = &rest[pos + pattern.len()..];
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c == ')' && !quoted
+            })
+            .map(|(end, _)| end)
+            .unwrap_or(rest.len());
+        let mut call: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut keyword = None;
+        for arg in split_arguments(&rest[..end]) {
+            if keywords.contains(&arg.as_str()) {
+                call.entry(arg.clone()).or_default();
+                keyword = Some(arg);
+            } else if let Some(keyword) = &keyword {
+                call.get_mut(keyword).unwrap().push(arg);
+            }
+        }
+        calls.push(call);
+        rest = &rest[end..];
+    }
+    calls
+}
+
+fn is_sha512(value: &str) -> bool {
+    value.len() == 128 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parse distfiles of a portfile, as (sha512, URL).
+fn parse_portfile(portfile: &str, version: Option<&str>) -> Vec<(String, String)> {
+    let mut distfiles = vec![];
+    for (function, keywords) in FUNCTIONS {
+        for call in find_calls(portfile, function, keywords) {
+            let value = |keyword: &str| -> Option<String> {
+                let value = call.get(keyword)?.first()?;
+                let value = match version {
+                    Some(version) => value.replace("${VERSION}", version),
+                    None => value.clone(),
+                };
+                if value.contains("${") {
+                    None
+                } else {
+                    Some(value)
+                }
+            };
+            let sha512 = match value("SHA512") {
+                Some(sha512) if is_sha512(&sha512) => sha512.to_lowercase(),
+                _ => continue,
+            };
+            let url = match *function {
+                "vcpkg_download_distfile" => value("URLS"),
+                "vcpkg_from_github" if !call.contains_key("GITHUB_HOST") => value("REPO")
+                    .zip(value("REF"))
+                    .map(|(repo, r)| format!("https://github.com/{}/archive/{}.tar.gz", repo, r)),
+                "vcpkg_from_gitlab" => value("GITLAB_URL")
+                    .zip(value("REPO"))
+                    .zip(value("REF"))
+                    .map(|((gitlab, repo), r)| {
+                        let name = repo.rsplit('/').next().unwrap_or_default().to_string();
+                        format!("{}/{}/-/archive/{}/{}-{}.tar.gz", gitlab, repo, r, name, r)
+                    }),
+                "vcpkg_from_bitbucket" => value("REPO")
+                    .zip(value("REF"))
+                    .map(|(repo, r)| format!("https://bitbucket.org/{}/get/{}.tar.gz", repo, r)),
+                _ => None,
+            };
+            if let Some(url) = url {
+                distfiles.push((sha512, url));
+            }
+        }
+    }
+    distfiles
+}
+
+impl Vcpkg {
+    pub fn new(config: VcpkgConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Vcpkg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Vcpkg {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching ports tree...");
+        progress.set_message("fetching ports tree...");
+        let data = fetch(&client, &self.config.tarball).await?;
+
+        info!(logger, "parsing...");
+        // port -> (portfile, manifest)
+        let mut ports: BTreeMap<String, (Option<String>, Option<String>)> = BTreeMap::new();
+        let mut tools = None;
+        let mut archive = tar::Archive::new(GzDecoder::new(&data[..]));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            // strip top-level directory of the tarball, e.g. `vcpkg-master/`
+            let path = match path.split_once('/') {
+                Some((_, path)) => path.to_string(),
+                None => continue,
+            };
+            if path == "scripts/vcpkg-tools.json" {
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                tools = Some(content);
+                continue;
+            }
+            let (port, file) = match path
+                .strip_prefix("ports/")
+                .and_then(|path| path.split_once('/'))
+            {
+                Some((port, file)) => (port.to_string(), file.to_string()),
+                None => continue,
+            };
+            if !self.config.ports.is_empty() && !self.config.ports.contains(&port) {
+                continue;
+            }
+            if file == "portfile.cmake" || file == "vcpkg.json" {
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                let port = ports.entry(port).or_default();
+                if file == "portfile.cmake" {
+                    port.0 = Some(content);
+                } else {
+                    port.1 = Some(content);
+                }
+            }
+        }
+
+        let mut distfiles = vec![];
+        for (port, (portfile, manifest)) in &ports {
+            let portfile = match portfile {
+                Some(portfile) => portfile,
+                None => continue,
+            };
+            let version = match manifest {
+                Some(manifest) => serde_json::from_str::<PortManifest>(manifest)
+                    .map_err(|err| Error::ProcessError(format!("invalid {}: {:?}", port, err)))?
+                    .version(),
+                None => None,
+            };
+            distfiles.extend(parse_portfile(portfile, version.as_deref()));
+        }
+        info!(
+            logger,
+            "{} ports, {} distfiles",
+            ports.len(),
+            distfiles.len()
+        );
+        if let Some(tools) = tools {
+            let tools: Tools = serde_json::from_str(&tools)?;
+            info!(logger, "{} tools", tools.tools.len());
+            distfiles.extend(
+                tools
+                    .tools
+                    .into_iter()
+                    .filter(|tool| is_sha512(&tool.sha512))
+                    .map(|tool| (tool.sha512.to_lowercase(), tool.url)),
+            );
+        }
+
+        let mut snapshot = vec![];
+        for (sha512, url) in distfiles {
+            if self.urls.contains_key(&sha512) {
+                continue;
+            }
+            snapshot.push(SnapshotMeta {
+                key: sha512.clone(),
+                checksum_method: Some("sha512".to_string()),
+                checksum: Some(sha512.clone()),
+                ..Default::default()
+            });
+            self.urls.insert(sha512, url);
+        }
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("vcpkg, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Vcpkg {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        self.urls
+            .get(&snapshot.key)
+            .map(|url| TransferURL(url.clone()))
+            .ok_or_else(|| Error::ProcessError(format!("unknown key {}", snapshot.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_portfile() {
+        let a = "a".repeat(128);
+        let b = "B".repeat(128);
+        let portfile = format!(
+            r#"vcpkg_from_github(
+    OUT_SOURCE_PATH SOURCE_PATH
+    REPO madler/zlib
+    REF "v${{VERSION}}"
+    SHA512 {a}
+    HEAD_REF master
+    PATCHES
+        0001-Prevent-invalid-inclusions.patch # (fix)
+)
+
+vcpkg_download_distfile(ARCHIVE
+    URLS "https://example.com/foo-${{VERSION}}.tar.gz" "https://mirror.example.com/foo.tar.gz"
+    FILENAME "foo-${{VERSION}}.tar.gz"
+    SHA512 {b}
+)
+
+vcpkg_download_distfile(PATCH
+    URLS "https://example.com/${{PATCH_NAME}}"
+    FILENAME patch
+    SHA512 {a}
+)
+"#,
+            a = a,
+            b = b
+        );
+        assert_eq!(
+            parse_portfile(&portfile, Some("1.3")),
+            vec![
+                (
+                    b.to_lowercase(),
+                    "https://example.com/foo-1.3.tar.gz".to_string()
+                ),
+                (
+                    a.clone(),
+                    "https://github.com/madler/zlib/archive/v1.3.tar.gz".to_string()
+                ),
+            ]
+        );
+    }
+}
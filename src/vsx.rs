@@ -0,0 +1,379 @@
+//! Open VSX source
+//!
+//! Open VSX source pages through the search API of an Open VSX registry to
+//! enumerate all extensions, then queries all versions of every extension.
+//! Only the latest `versions_to_retain` versions are kept, including all
+//! target platforms of them.
+//!
+//! `.vsix` files are yielded with timestamps from the registry. The registry
+//! API doesn't report file sizes, so they are fetched with HEAD requests.
+//! Files are placed at `{namespace}/{name}/{version}/{file}` of the mirror.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::DateTime;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_json;
+use crate::metadata::SnapshotMeta;
+use crate::timeout::{TryTimeoutExt, TryTimeoutFutureExt};
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::bar;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct VsxConfig {
+    #[structopt(
+        long,
+        default_value = "https://open-vsx.org",
+        help = "Base of Open VSX registry"
+    )]
+    pub base: String,
+    #[structopt(
+        long,
+        help = "Versions to retain for each extension",
+        default_value = "3"
+    )]
+    pub versions_to_retain: usize,
+    #[structopt(
+        long,
+        help = "Extensions per page of search API",
+        default_value = "100"
+    )]
+    pub page_size: usize,
+    /// When debug mode is enabled, only first 100 extensions will be selected.
+    #[structopt(long)]
+    pub debug: bool,
+}
+
+pub struct Vsx {
+    pub config: VsxConfig,
+    /// file key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchResult {
+    total_size: usize,
+    #[serde(default)]
+    extensions: Vec<SearchEntry>,
+}
+
+#[derive(Deserialize)]
+struct SearchEntry {
+    namespace: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryResult {
+    total_size: usize,
+    #[serde(default)]
+    extensions: Vec<ExtensionVersion>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExtensionVersion {
+    namespace: String,
+    name: String,
+    version: String,
+    timestamp: Option<String>,
+    #[serde(default)]
+    files: HashMap<String, String>,
+}
+
+/// Select all files of the latest `versions_to_retain` versions.
+/// Versions are ordered by their latest timestamp among target platforms.
+fn latest_versions(
+    mut versions: Vec<ExtensionVersion>,
+    versions_to_retain: usize,
+) -> Vec<ExtensionVersion> {
+    versions.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    let mut retained: Vec<&str> = vec![];
+    for version in &versions {
+        if !retained.contains(&version.version.as_str()) {
+            if retained.len() == versions_to_retain {
+                continue;
+            }
+            retained.push(&version.version);
+        }
+    }
+    let retained: Vec<String> = retained.into_iter().map(|x| x.to_string()).collect();
+    versions
+        .into_iter()
+        .filter(|version| retained.contains(&version.version))
+        .collect()
+}
+
+/// Mirror key of the `.vsix` file of an extension version.
+fn vsix_key(version: &ExtensionVersion, url: &str) -> Option<String> {
+    let file = url.split('?').next()?.rsplit('/').next()?;
+    if file.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}/{}/{}/{}",
+        version.namespace, version.name, version.version, file
+    ))
+}
+
+async fn fetch_size(client: &Client, url: &str) -> Result<Option<u64>> {
+    let response = client
+        .head(url)
+        .send()
+        .timeout(Duration::from_secs(60))
+        .await
+        .into_result()?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::HTTPError(status));
+    }
+    Ok(response.content_length())
+}
+
+/// Generate snapshot of an extension, returning (file key, upstream URL) pairs.
+async fn extension_snapshot(
+    client: &Client,
+    base: &str,
+    extension: &SearchEntry,
+    versions_to_retain: usize,
+) -> Result<Vec<(SnapshotMeta, String)>> {
+    let mut versions = vec![];
+    loop {
+        let page: QueryResult = fetch_json(
+            client,
+            &format!(
+                "{}/api/-/query?namespaceName={}&extensionName={}&includeAllVersions=true&offset={}",
+                base,
+                extension.namespace,
+                extension.name,
+                versions.len()
+            ),
+        )
+        .await?;
+        if page.extensions.is_empty() {
+            break;
+        }
+        versions.extend(page.extensions);
+        if versions.len() >= page.total_size {
+            break;
+        }
+    }
+
+    let mut snapshot = vec![];
+    for version in latest_versions(versions, versions_to_retain) {
+        let url = match version.files.get("download") {
+            Some(url) => url.clone(),
+            None => continue,
+        };
+        let key = match vsix_key(&version, &url) {
+            Some(key) => key,
+            None => continue,
+        };
+        let last_modified = match &version.timestamp {
+            Some(timestamp) => Some(DateTime::parse_from_rfc3339(timestamp)?.timestamp() as u64),
+            None => None,
+        };
+        let size = fetch_size(client, &url).await?;
+        snapshot.push((
+            SnapshotMeta {
+                key,
+                size,
+                last_modified,
+                ..Default::default()
+            },
+            url,
+        ));
+    }
+    Ok(snapshot)
+}
+
+impl Vsx {
+    pub fn new(config: VsxConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Vsx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Vsx {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+        let base = self.config.base.clone();
+
+        info!(logger, "listing extensions...");
+        let mut extensions = vec![];
+        loop {
+            progress.set_message(&format!("listing extensions from {}", extensions.len()));
+            let page: SearchResult = fetch_json(
+                &client,
+                &format!(
+                    "{}/api/-/search?size={}&offset={}",
+                    base,
+                    self.config.page_size,
+                    extensions.len()
+                ),
+            )
+            .await?;
+            if page.extensions.is_empty() {
+                break;
+            }
+            extensions.extend(page.extensions);
+            if extensions.len() >= page.total_size || self.config.debug {
+                break;
+            }
+        }
+        if self.config.debug {
+            extensions.truncate(100);
+        }
+        info!(logger, "{} extensions found", extensions.len());
+
+        progress.set_length(extensions.len() as u64);
+        progress.set_style(bar());
+        progress.set_position(0);
+
+        let versions_to_retain = self.config.versions_to_retain;
+        let snapshots: Vec<Vec<(SnapshotMeta, String)>> =
+            stream::iter(extensions.into_iter().map(|extension| {
+                let client = client.clone();
+                let base = base.clone();
+                let logger = logger.clone();
+                let progress = progress.clone();
+                async move {
+                    let name = format!("{}.{}", extension.namespace, extension.name);
+                    progress.set_message(&name);
+                    let result =
+                        extension_snapshot(&client, &base, &extension, versions_to_retain).await;
+                    progress.inc(1);
+                    match result {
+                        Ok(snapshot) => Ok::<_, Error>(snapshot),
+                        Err(err) => {
+                            warn!(logger, "failed to fetch {}: {:?}", name, err);
+                            Ok(vec![])
+                        }
+                    }
+                }
+            }))
+            .buffer_unordered(config.concurrent_resolve)
+            .try_collect()
+            .await?;
+
+        let mut snapshot = vec![];
+        for (meta, url) in snapshots.into_iter().flatten() {
+            self.urls.insert(meta.key.clone(), url);
+            snapshot.push(meta);
+        }
+        info!(logger, "{} files to mirror", snapshot.len());
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    async fn estimate(&self, mission: &Mission) -> Option<usize> {
+        let page: SearchResult = fetch_json(
+            &mission.client,
+            &format!("{}/api/-/search?size=1", self.config.base),
+        )
+        .await
+        .ok()?;
+        let extensions = if self.config.debug {
+            page.total_size.min(100)
+        } else {
+            page.total_size
+        };
+        Some(extensions * self.config.versions_to_retain)
+    }
+
+    fn info(&self) -> String {
+        format!("vsx, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Vsx {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        match self.urls.get(&snapshot.key) {
+            Some(url) => Ok(TransferURL(url.clone())),
+            None => Err(Error::ProcessError(format!(
+                "no upstream URL for {}",
+                snapshot.key
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str, platform: &str, timestamp: &str) -> ExtensionVersion {
+        let file = if platform.is_empty() {
+            format!("rust-lang.rust-analyzer-{}.vsix", version)
+        } else {
+            format!("rust-lang.rust-analyzer-{}@{}.vsix", version, platform)
+        };
+        ExtensionVersion {
+            namespace: "rust-lang".to_string(),
+            name: "rust-analyzer".to_string(),
+            version: version.to_string(),
+            timestamp: Some(timestamp.to_string()),
+            files: vec![(
+                "download".to_string(),
+                format!(
+                    "https://open-vsx.org/api/rust-lang/rust-analyzer/{}/file/{}",
+                    version, file
+                ),
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn test_latest_versions() {
+        let versions = vec![
+            version("0.3.1", "linux-x64", "2024-01-08T00:00:00.1Z"),
+            version("0.3.2", "linux-x64", "2024-01-15T00:00:00.1Z"),
+            version("0.3.2", "win32-x64", "2024-01-15T00:01:00.1Z"),
+            version("0.3.0", "", "2024-01-01T00:00:00.1Z"),
+        ];
+        let latest = latest_versions(versions, 2);
+        let keys: Vec<String> = latest
+            .iter()
+            .map(|version| vsix_key(version, &version.files["download"]).unwrap())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "rust-lang/rust-analyzer/0.3.2/rust-lang.rust-analyzer-0.3.2@win32-x64.vsix",
+                "rust-lang/rust-analyzer/0.3.2/rust-lang.rust-analyzer-0.3.2@linux-x64.vsix",
+                "rust-lang/rust-analyzer/0.3.1/rust-lang.rust-analyzer-0.3.1@linux-x64.vsix",
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Zig source
+//!
+//! Zig source parses `download/index.json` of ziglang.org, which lists
+//! tarballs of all releases and the latest master build of every platform,
+//! together with sha256 checksums and sizes. Tarballs and their minisign
+//! signatures are placed at the same paths as upstream, e.g.
+//! `download/0.11.0/zig-linux-x86_64-0.11.0.tar.xz` and `builds/...`.
+//!
+//! `download/index.json` is always transferred at the end. If `target_mirror`
+//! is set, it should be piped through `RewritePipe` with `rewrite_index`, so
+//! that tools like `zigup` download tarballs from the mirror.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use slog::{info, warn};
+use structopt::StructOpt;
+
+use crate::common::{Mission, SnapshotConfig, TransferURL};
+use crate::error::{Error, Result};
+use crate::fetch::fetch_text;
+use crate::metadata::SnapshotMeta;
+use crate::traits::{SnapshotStorage, SourceStorage};
+use crate::utils::human_size;
+
+pub const INDEX_KEY: &str = "download/index.json";
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ZigConfig {
+    #[structopt(
+        long,
+        default_value = "https://ziglang.org",
+        help = "Base of Zig downloads"
+    )]
+    pub base: String,
+    #[structopt(long, help = "Mirror URL to rewrite download/index.json to")]
+    pub target_mirror: Option<String>,
+}
+
+pub struct Zig {
+    pub config: ZigConfig,
+    /// key -> upstream URL
+    urls: HashMap<String, String>,
+}
+
+/// A tarball in `index.json`.
+#[derive(Debug, PartialEq)]
+struct Tarball {
+    url: String,
+    shasum: Option<String>,
+    size: Option<u64>,
+}
+
+/// Collect tarballs of all versions and platforms in `index.json`.
+fn parse_index(index: &serde_json::Value) -> Vec<Tarball> {
+    let mut tarballs = vec![];
+    for version in index
+        .as_object()
+        .into_iter()
+        .flat_map(|index| index.values())
+    {
+        // fields like `date` and `docs` are not objects, and skipped here
+        for platform in version.as_object().into_iter().flat_map(|v| v.values()) {
+            if let Some(url) = platform.get("tarball").and_then(|url| url.as_str()) {
+                tarballs.push(Tarball {
+                    url: url.to_string(),
+                    shasum: platform
+                        .get("shasum")
+                        .and_then(|shasum| shasum.as_str())
+                        .map(|shasum| shasum.to_string()),
+                    // size is a string in upstream index
+                    size: platform.get("size").and_then(|size| match size {
+                        serde_json::Value::String(size) => size.parse().ok(),
+                        size => size.as_u64(),
+                    }),
+                });
+            }
+        }
+    }
+    tarballs
+}
+
+/// Resolve tarball URL to the mirror key, which is its path relative to
+/// `base`. Tarballs hosted elsewhere are keyed by their URL path.
+fn tarball_key(base: &str, url: &str) -> Result<String> {
+    if let Some(key) = url.strip_prefix(&format!("{}/", base)) {
+        return Ok(key.to_string());
+    }
+    let url = url::Url::parse(url)
+        .map_err(|err| Error::ProcessError(format!("invalid tarball URL: {:?}", err)))?;
+    let key = url.path().trim_start_matches('/');
+    if key.is_empty() {
+        return Err(Error::ProcessError(format!("invalid tarball URL: {}", url)));
+    }
+    Ok(key.to_string())
+}
+
+/// Rewrite tarball URLs in `index.json` to `target_mirror`. Content other
+/// than the index is returned as is.
+pub fn rewrite_index(base: &str, target_mirror: &str, content: String) -> Result<String> {
+    let mut index: serde_json::Value = match serde_json::from_str(&content) {
+        Ok(index) => index,
+        Err(_) => return Ok(content),
+    };
+    let versions = match index.as_object_mut() {
+        Some(versions) => versions,
+        None => return Ok(content),
+    };
+    for version in versions.values_mut() {
+        for platform in version
+            .as_object_mut()
+            .into_iter()
+            .flat_map(|v| v.values_mut())
+        {
+            if let Some(url) = platform.get_mut("tarball") {
+                if let Some(tarball_url) = url.as_str() {
+                    let key = tarball_key(base, tarball_url)?;
+                    *url = serde_json::Value::String(format!("{}/{}", target_mirror, key));
+                }
+            }
+        }
+    }
+    Ok(serde_json::to_string_pretty(&index)?)
+}
+
+impl Zig {
+    pub fn new(config: ZigConfig) -> Self {
+        Self {
+            config,
+            urls: HashMap::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Zig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+#[async_trait]
+impl SnapshotStorage<SnapshotMeta> for Zig {
+    async fn snapshot(
+        &mut self,
+        mission: Mission,
+        _config: &SnapshotConfig,
+    ) -> Result<Vec<SnapshotMeta>> {
+        let logger = mission.logger;
+        let progress = mission.progress;
+        let client = mission.client;
+
+        info!(logger, "fetching index.json...");
+        progress.set_message("fetching index.json...");
+        let data = fetch_text(&client, &format!("{}/{}", self.config.base, INDEX_KEY)).await?;
+        let index: serde_json::Value = serde_json::from_str(&data)?;
+
+        let mut snapshot = vec![];
+        for tarball in parse_index(&index) {
+            let key = match tarball_key(&self.config.base, &tarball.url) {
+                Ok(key) => key,
+                Err(err) => {
+                    warn!(logger, "failed to resolve {}: {:?}", tarball.url, err);
+                    continue;
+                }
+            };
+            if self.urls.contains_key(&key) {
+                continue;
+            }
+            snapshot.push(SnapshotMeta {
+                key: key.clone(),
+                size: tarball.size,
+                checksum_method: tarball.shasum.as_ref().map(|_| "sha256".to_string()),
+                checksum: tarball.shasum,
+                ..Default::default()
+            });
+            let signature = format!("{}.minisig", key);
+            snapshot.push(SnapshotMeta::new(signature.clone()));
+            self.urls
+                .insert(signature, format!("{}.minisig", tarball.url));
+            self.urls.insert(key, tarball.url);
+        }
+        let total_size: u64 = snapshot.iter().filter_map(|meta| meta.size).sum();
+        info!(
+            logger,
+            "{} tarballs ({})",
+            snapshot.len() / 2,
+            human_size(total_size)
+        );
+
+        snapshot.push(SnapshotMeta::force(INDEX_KEY.to_string()));
+
+        progress.finish_with_message("done");
+
+        Ok(snapshot)
+    }
+
+    fn info(&self) -> String {
+        format!("zig, {:?}", self)
+    }
+}
+
+#[async_trait]
+impl SourceStorage<SnapshotMeta, TransferURL> for Zig {
+    async fn get_object(&self, snapshot: &SnapshotMeta, _mission: &Mission) -> Result<TransferURL> {
+        Ok(TransferURL(match self.urls.get(&snapshot.key) {
+            Some(url) => url.clone(),
+            None => format!("{}/{}", self.config.base, snapshot.key),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: &str = r#"{
+  "master": {
+    "version": "0.12.0-dev.1828+225fe6ddb",
+    "date": "2023-12-09",
+    "x86_64-linux": {
+      "tarball": "https://ziglang.org/builds/zig-linux-x86_64-0.12.0-dev.1828+225fe6ddb.tar.xz",
+      "shasum": "ab01",
+      "size": "44288612"
+    }
+  },
+  "0.11.0": {
+    "date": "2023-08-04",
+    "docs": "https://ziglang.org/documentation/0.11.0/",
+    "src": {
+      "tarball": "https://ziglang.org/download/0.11.0/zig-0.11.0.tar.xz",
+      "shasum": "cd23",
+      "size": "15275316"
+    }
+  }
+}"#;
+
+    #[test]
+    fn test_parse_and_rewrite_index() {
+        let index: serde_json::Value = serde_json::from_str(INDEX).unwrap();
+        let mut tarballs = parse_index(&index);
+        tarballs.sort_by(|a, b| a.url.cmp(&b.url));
+        assert_eq!(
+            tarballs[1],
+            Tarball {
+                url: "https://ziglang.org/download/0.11.0/zig-0.11.0.tar.xz".to_string(),
+                shasum: Some("cd23".to_string()),
+                size: Some(15275316),
+            }
+        );
+        assert_eq!(
+            tarball_key("https://ziglang.org", &tarballs[0].url).unwrap(),
+            "builds/zig-linux-x86_64-0.12.0-dev.1828+225fe6ddb.tar.xz"
+        );
+
+        let rewritten = rewrite_index(
+            "https://ziglang.org",
+            "https://mirror.example.com/zig",
+            INDEX.to_string(),
+        )
+        .unwrap();
+        let rewritten: serde_json::Value = serde_json::from_str(&rewritten).unwrap();
+        assert_eq!(
+            rewritten["0.11.0"]["src"]["tarball"],
+            "https://mirror.example.com/zig/download/0.11.0/zig-0.11.0.tar.xz"
+        );
+        assert_eq!(rewritten["0.11.0"]["docs"], index["0.11.0"]["docs"]);
+        // signatures and other text files are not touched
+        let signature = "untrusted comment: signature from minisign secret key\n";
+        assert_eq!(
+            rewrite_index(
+                "https://ziglang.org",
+                "https://mirror.example.com/zig",
+                signature.to_string()
+            )
+            .unwrap(),
+            signature
+        );
+    }
+}